@@ -0,0 +1,171 @@
+extern crate serde_json;
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use self::serde_json::Value;
+use super::{audit, keys, replication, tenant, webhooks, API, APIErr, Operation};
+
+/// The document holding the rules, at the top of the storage tree.
+pub const ACL_PATH: &str = "_acl";
+/// A rule for this principal applies to every principal.
+pub const EVERYONE: &str = "*";
+/// A rule for `group:<name>` applies to every member of the group.
+pub const GROUP_PREFIX: &str = "group:";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Read,
+    Write,
+    Delete,
+    /// Every other permission, along with editing the rules and using the
+    /// maintenance endpoints.
+    Admin,
+}
+
+/// Grants `permissions` on `prefix` and everything below it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub prefix: String,
+    pub principal: String,
+    pub permissions: Vec<Permission>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Acl {
+    pub rules: Vec<Rule>,
+    pub groups: BTreeMap<String, Vec<String>>,
+}
+
+fn segments(prefix: &str) -> Vec<&str> {
+    prefix.split('/').filter(|p| !p.is_empty()).collect()
+}
+
+impl Acl {
+    pub fn from_value(value: Value) -> Result<Acl, APIErr> {
+        serde_json::from_value(value).map_err(|err| APIErr::Decode(err.to_string()))
+    }
+
+    pub fn to_value(&self) -> Value {
+        serde_json::to_value(self).unwrap_or(Value::Null)
+    }
+
+    fn applies_to(&self, rule: &Rule, user: &str) -> bool {
+        rule.principal == EVERYONE || rule.principal == user || (rule.principal.starts_with(GROUP_PREFIX) &&
+            self.groups.get(&rule.principal[GROUP_PREFIX.len()..])
+                .map(|members| members.iter().any(|member| member == user))
+                .unwrap_or(false))
+    }
+
+    /// Whether some rule for `user` on `path` or one of its ancestors grants
+    /// `permission`. Nothing is granted that no rule names.
+    pub fn allows(&self, path: &[String], user: &str, permission: Permission) -> bool {
+        self.rules.iter()
+            .filter(|rule| self.applies_to(rule, user))
+            .filter(|rule| rule.permissions.iter().any(|p| *p == permission || *p == Permission::Admin))
+            .any(|rule| {
+                let prefix = segments(rule.prefix.as_str());
+                prefix.len() <= path.len() && prefix.iter().zip(path.iter()).all(|(a, b)| *a == b.as_str())
+            })
+    }
+}
+
+fn acl_path<T: API + ?Sized>(app: &T) -> PathBuf {
+    let mut path = app.root_path();
+    path.push(ACL_PATH);
+    path
+}
+
+/// Whether `path` is the rules document, which is never listed as part of
+/// the tree around it.
+pub fn is_acl_path<T: API + ?Sized>(app: &T, path: &Path) -> bool {
+    path == acl_path(app).as_path()
+}
+
+/// The rules, or `None` while none have been stored, in which case every
+/// document is open to everyone.
+pub fn load<T: API + ?Sized>(app: &T) -> Result<Option<Acl>, APIErr> {
+    let path = acl_path(app);
+    if !path.exists() {
+        return Ok(None);
+    }
+    app.read_path(path, String::new())
+        .and_then(Acl::from_value)
+        .map(Some)
+}
+
+/// Replaces the rules with `acl`, or removes them, opening every document
+/// to everyone, when it is `None`. Both are published, so that followers
+/// keep the same rules.
+pub fn store<T: API + ?Sized>(app: &T, acl: Option<&Acl>) -> Result<(), APIErr> {
+    let path = acl_path(app);
+    let document = [String::from(ACL_PATH)];
+    let lock = app.write_lock();
+    let _guard = lock.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if path.exists() {
+        app.remove_path(path.clone())?;
+        app.publish(Operation::Delete, &document, Value::Null, None);
+    }
+    match acl {
+        Some(acl) => app.write_path(path, acl.to_value(), String::new())
+            .map(|()| app.publish(Operation::Write, &document, acl.to_value(), None)),
+        None => Ok(())
+    }
+}
+
+/// Whether `user` is one of the principals in `auth.admins`.
+pub fn is_admin<T: API + ?Sized>(app: &T, user: &str) -> bool {
+    app.admins().iter().any(|admin| admin == user)
+}
+
+/// What only administrators may touch, whatever the rules say.
+const RESTRICTED: &[&str] = &[keys::KEYS_PATH, audit::AUDIT_PATH, webhooks::WEBHOOKS_PATH, webhooks::DELIVERIES_PATH,
+    webhooks::DEAD_LETTERS_PATH, replication::REPLICATION_PATH, tenant::TENANTS_PATH];
+
+/// Whether `path` is the rules or below what only administrators may
+/// touch, which is never sent anywhere but to them.
+pub fn is_privileged(path: &[&str]) -> bool {
+    path.first().is_some_and(|first| *first == ACL_PATH || RESTRICTED.contains(first))
+}
+
+/// Fails with `Forbidden` unless `user` may do `permission` at `path`.
+/// Administrators may do anything, and only they may touch the rules, the
+/// API keys, the audit log, the webhooks, the replication status or the
+/// trees of the tenants. While no rules are stored, everything but `Admin`
+/// is allowed.
+pub fn check<T: API + ?Sized>(app: &T, path: &[String], user: &str, permission: Permission) -> Result<(), APIErr> {
+    if is_admin(app, user) {
+        return Ok(());
+    }
+    if path.first().is_some_and(|first| RESTRICTED.contains(&first.as_str())) {
+        info!("Denied {:?} on /{} to {}", permission, path.join("/"), user);
+        return Err(APIErr::Forbidden);
+    }
+    let permission = match path.first() {
+        Some(first) if first == ACL_PATH => Permission::Admin,
+        _ => permission
+    };
+    let allowed = match load(app)? {
+        Some(ref acl) => acl.allows(path, user, permission),
+        None => permission != Permission::Admin
+    };
+    if allowed {
+        Ok(())
+    } else {
+        info!("Denied {:?} on /{} to {}", permission, path.join("/"), user);
+        Err(APIErr::Forbidden)
+    }
+}
+
+/// `Forbidden` for anyone but an administrator.
+pub fn check_admin<T: API + ?Sized>(app: &T, user: &str) -> Result<(), APIErr> {
+    check(app, &[], user, Permission::Admin)
+}
+
+pub fn not_found() -> APIErr {
+    APIErr::IO(io::Error::new(io::ErrorKind::NotFound, "no rules are stored"))
+}
@@ -0,0 +1,156 @@
+extern crate serde_json;
+
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use self::serde_json::Value;
+use super::{API, APIErr};
+use super::super::auth::{self, hash};
+
+/// The log lives in `<storage>/_audit`, as `audit.log` and the older
+/// `audit.log.1`, `audit.log.2` and so on that it was rotated to.
+pub const AUDIT_PATH: &str = "_audit";
+const LOG_NAME: &str = "audit.log";
+const REQUEST_ID_LENGTH: usize = 16;
+
+/// One request that may have changed the tree.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Entry {
+    /// Unix seconds at which the request finished.
+    pub timestamp: u64,
+    pub user: String,
+    pub method: String,
+    pub path: String,
+    pub request_id: String,
+    pub status: u16,
+    /// Hashes of the document at `path` before and after the request, as
+    /// with `value_hash`. Absent where there was no document.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub old_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub new_hash: Option<String>,
+}
+
+/// Which entries a query returns. Times are Unix seconds and inclusive.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Filter {
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    /// Only entries for this path and the paths below it.
+    pub prefix: Vec<String>,
+}
+
+impl Filter {
+    fn matches(&self, entry: &Entry) -> bool {
+        let path = entry.path.split('/').filter(|p| !p.is_empty()).collect::<Vec<&str>>();
+        self.since.is_none_or(|since| entry.timestamp >= since) &&
+            self.until.is_none_or(|until| entry.timestamp <= until) &&
+            self.prefix.len() <= path.len() &&
+            self.prefix.iter().zip(path.iter()).all(|(a, b)| a == b)
+    }
+}
+
+/// An append-only log of entries, rotated once the current file would grow
+/// past `max_bytes`. At most `max_files` rotated files are kept.
+#[derive(Debug)]
+pub struct AuditLog {
+    dir: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    lock: Mutex<()>,
+}
+
+/// The hex SHA-256 of `value` as compact JSON.
+pub fn value_hash(value: &Value) -> String {
+    hash::to_hex(&hash::sha256(value.to_string().as_bytes()))
+}
+
+/// The hash of the document at `path`, or `None` if there is none.
+/// Access control is not applied: the hash is for the log, not the caller.
+pub fn document_hash<T: API + ?Sized>(app: &T, path: &[String]) -> Option<String> {
+    let mut path_buf = app.root_path();
+    path.iter().for_each(|p| path_buf.push(p));
+    if !path_buf.exists() {
+        return None;
+    }
+    app.read_path(path_buf, String::new())
+        .ok()
+        .map(|value| value_hash(&value))
+}
+
+/// Whether `path` is the directory holding the log, which is never listed
+/// as part of the tree around it.
+pub fn is_audit_path<T: API + ?Sized>(app: &T, path: &Path) -> bool {
+    let dir = app.root_path();
+    path == dir.join(AUDIT_PATH).as_path()
+}
+
+pub fn new_request_id() -> String {
+    auth::new_token()[..REQUEST_ID_LENGTH].to_string()
+}
+
+impl AuditLog {
+    pub fn new<P: AsRef<Path>>(storage_dir: P, max_bytes: u64, max_files: usize) -> AuditLog {
+        AuditLog {
+            dir: storage_dir.as_ref().join(AUDIT_PATH),
+            max_bytes,
+            max_files,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// `audit.log` for 0, and the file rotated `n` times for `n`.
+    fn file(&self, n: usize) -> PathBuf {
+        match n {
+            0 => self.dir.join(LOG_NAME),
+            n => self.dir.join(format!("{}.{}", LOG_NAME, n))
+        }
+    }
+
+    fn rotate(&self) -> Result<(), APIErr> {
+        let oldest = self.file(self.max_files);
+        if oldest.exists() {
+            fs::remove_file(oldest).map_err(APIErr::IO)?;
+        }
+        (0..self.max_files).rev()
+            .filter(|n| self.file(*n).exists())
+            .try_for_each(|n| fs::rename(self.file(n), self.file(n + 1)).map_err(APIErr::IO))
+    }
+
+    pub fn append(&self, entry: &Entry) -> Result<(), APIErr> {
+        let mut line = serde_json::to_vec(entry).map_err(|err| APIErr::Encode(err.to_string()))?;
+        line.push(b'\n');
+        let _guard = self.lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        fs::create_dir_all(&self.dir).map_err(APIErr::IO)?;
+        let size = fs::metadata(self.file(0)).map(|meta| meta.len()).unwrap_or(0);
+        if size > 0 && size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.file(0))
+            .and_then(|mut file| file.write_all(line.as_slice()))
+            .map_err(APIErr::IO)
+    }
+
+    /// The entries `filter` matches, oldest first. Lines that cannot be
+    /// read as entries are skipped.
+    pub fn query(&self, filter: &Filter) -> Result<Vec<Entry>, APIErr> {
+        let _guard = self.lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        (0..=self.max_files).rev()
+            .map(|n| self.file(n))
+            .filter(|file| file.exists())
+            .map(|file| fs::File::open(file)
+                .map_err(APIErr::IO)
+                .map(|file| BufReader::new(file).lines()
+                    .map_while(Result::ok)
+                    .flat_map(|line| serde_json::from_str::<Entry>(line.as_str()).ok())
+                    .filter(|entry| filter.matches(entry))
+                    .collect::<Vec<Entry>>()))
+            .collect::<Result<Vec<Vec<Entry>>, APIErr>>()
+            .map(|entries| entries.into_iter().flatten().collect())
+    }
+}
@@ -0,0 +1,315 @@
+extern crate serde_json;
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use self::serde_json::Value;
+use super::{tenant, API, APIErr, Operation};
+
+/// The feed is served at `/_changes`, and its sequence number is kept in
+/// `<storage>/_changes/sequence` so that numbers keep growing across
+/// restarts.
+pub const CHANGES_PATH: &str = "_changes";
+const SEQUENCE_FILE: &str = "sequence";
+
+/// A write or delete that went through the `API`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Change {
+    pub seq: u64,
+    pub operation: Operation,
+    pub path: String,
+    /// The value written, or `null` for a delete.
+    #[serde(default)]
+    pub value: Value,
+    /// When the value written expires, in Unix seconds, if it does.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// The tenant whose tree the change was made in, if it was made in
+    /// one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// The storage root the change was made under, which tells tenants
+    /// apart.
+    #[serde(skip)]
+    pub root: String,
+}
+
+impl Change {
+    /// Whether the change was made in the tree at `root`, or in the tree
+    /// of one of its tenants.
+    pub fn is_under(&self, root: &str) -> bool {
+        self.root == root || self.tenant.as_ref()
+            .is_some_and(|name| Path::new(root).join(tenant::TENANTS_PATH).join(name) == Path::new(self.root.as_str()))
+    }
+}
+
+/// The changes after some sequence number that a reader may see, and the
+/// last sequence number that was looked at, to resume from.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Batch {
+    pub changes: Vec<Change>,
+    pub last_seq: u64,
+}
+
+#[derive(Debug)]
+struct State {
+    last_seq: u64,
+    changes: VecDeque<Change>,
+}
+
+/// Called once the feed may have something for a reader that asked to be
+/// told, so that the reader need not hold a thread while it waits.
+pub type Waker = Box<Fn() + Send>;
+
+struct Watcher {
+    since: u64,
+    deadline: Option<Instant>,
+    wake: Waker,
+}
+
+/// The readers to wake, and whether a thread is waking them at their
+/// deadlines.
+#[derive(Default)]
+struct Watchers {
+    waiting: Vec<Watcher>,
+    timing: bool,
+}
+
+#[derive(Default)]
+struct Alarms {
+    watchers: Mutex<Watchers>,
+    changed: Condvar,
+}
+
+impl fmt::Debug for Alarms {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Alarms({} waiting)", self.lock().waiting.len())
+    }
+}
+
+impl Alarms {
+    fn lock(&self) -> MutexGuard<'_, Watchers> {
+        self.watchers.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Wakes every reader waiting for a change at or before `seq`.
+    fn ring(&self, seq: u64) {
+        let woken = {
+            let mut watchers = self.lock();
+            let (woken, waiting) = watchers.waiting.drain(..).partition::<Vec<Watcher>, _>(|watcher| watcher.since < seq);
+            watchers.waiting = waiting;
+            woken
+        };
+        woken.into_iter().for_each(|watcher| (watcher.wake)());
+    }
+
+    /// Wakes readers as their deadlines pass, for as long as any is
+    /// waiting with one.
+    fn run(&self) {
+        let mut watchers = self.lock();
+        loop {
+            let now = Instant::now();
+            let (due, waiting) = watchers.waiting.drain(..)
+                .partition::<Vec<Watcher>, _>(|watcher| watcher.deadline.is_some_and(|deadline| deadline <= now));
+            watchers.waiting = waiting;
+            if !due.is_empty() {
+                drop(watchers);
+                due.into_iter().for_each(|watcher| (watcher.wake)());
+                watchers = self.lock();
+                continue;
+            }
+            match watchers.waiting.iter().flat_map(|watcher| watcher.deadline).min() {
+                Some(deadline) => watchers = self.changed.wait_timeout(watchers, deadline - now)
+                    .map(|(watchers, _)| watchers)
+                    .unwrap_or_else(|poisoned| poisoned.into_inner().0),
+                None => {
+                    watchers.timing = false;
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// The most recent `capacity` changes, in the order they were made.
+#[derive(Debug)]
+pub struct ChangeFeed {
+    sequence_file: Option<PathBuf>,
+    capacity: usize,
+    state: Mutex<State>,
+    published: Condvar,
+    alarms: Arc<Alarms>,
+}
+
+/// Whether `path` is the directory holding the feed's sequence number,
+/// which is never listed as part of the tree around it.
+pub fn is_changes_path<T: API + ?Sized>(app: &T, path: &Path) -> bool {
+    let dir = app.root_path();
+    path == dir.join(CHANGES_PATH).as_path()
+}
+
+impl ChangeFeed {
+    /// A feed that starts from sequence number 0 every time.
+    pub fn new(capacity: usize) -> ChangeFeed {
+        ChangeFeed {
+            sequence_file: None,
+            capacity,
+            state: Mutex::new(State { last_seq: 0, changes: VecDeque::new() }),
+            published: Condvar::new(),
+            alarms: Arc::new(Alarms::default()),
+        }
+    }
+
+    /// A feed that carries on from the last sequence number recorded under
+    /// `storage_dir`. Changes made before it was opened are not kept.
+    pub fn open<P: AsRef<Path>>(storage_dir: P, capacity: usize) -> Result<ChangeFeed, APIErr> {
+        let dir = storage_dir.as_ref().join(CHANGES_PATH);
+        fs::create_dir_all(&dir).map_err(APIErr::IO)?;
+        let sequence_file = dir.join(SEQUENCE_FILE);
+        let last_seq = match fs::read_to_string(&sequence_file) {
+            Ok(content) => u64::from_str(content.trim())
+                .map_err(|err| APIErr::Decode(format!("{}: {}", sequence_file.display(), err)))?,
+            Err(_) => 0
+        };
+        let feed = ChangeFeed::new(capacity);
+        feed.lock().last_seq = last_seq;
+        Ok(ChangeFeed { sequence_file: Some(sequence_file), ..feed })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Records a change under `root` and wakes every waiting reader,
+    /// returning the change's sequence number.
+    pub fn publish(&self, root: &str, operation: Operation, path: &[String], value: Value, expires_at: Option<u64>) -> u64 {
+        let mut state = self.lock();
+        state.last_seq += 1;
+        let seq = state.last_seq;
+        state.changes.push_back(Change {
+            seq,
+            operation,
+            path: format!("/{}", path.join("/")),
+            value,
+            expires_at,
+            tenant: tenant::name_of(Path::new(root)),
+            root: String::from(root),
+        });
+        while state.changes.len() > self.capacity {
+            state.changes.pop_front();
+        }
+        if let Some(ref file) = self.sequence_file {
+            let temporary = file.with_extension("tmp");
+            if let Err(err) = fs::write(&temporary, seq.to_string()).and_then(|()| fs::rename(&temporary, file)) {
+                error!("Could not record change sequence number {}: {}", seq, err);
+            }
+        }
+        self.published.notify_all();
+        drop(state);
+        self.alarms.ring(seq);
+        seq
+    }
+
+    pub fn last_seq(&self) -> u64 {
+        self.lock().last_seq
+    }
+
+    /// The changes after `since` that `keep` accepts, or `Gone` if some of
+    /// the changes after it are no longer kept, or it was never reached.
+    fn after<F: Fn(&Change) -> bool>(state: &State, since: u64, keep: &F) -> Result<Batch, APIErr> {
+        let first = state.changes.front().map_or(state.last_seq + 1, |change| change.seq);
+        if since + 1 < first || since > state.last_seq {
+            return Err(APIErr::Gone(since));
+        }
+        Ok(Batch {
+            changes: state.changes.iter()
+                .filter(|change| change.seq > since && keep(change))
+                .cloned()
+                .collect(),
+            last_seq: state.last_seq.max(since),
+        })
+    }
+
+    /// Waits up to `timeout` for a change after `since` that `keep`
+    /// accepts, returning as soon as there is one.
+    pub fn wait<F: Fn(&Change) -> bool>(&self, since: u64, timeout: Duration, keep: F) -> Result<Batch, APIErr> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.lock();
+        loop {
+            let batch = ChangeFeed::after(&state, since, &keep)?;
+            let now = Instant::now();
+            if !batch.changes.is_empty() || now >= deadline {
+                return Ok(batch);
+            }
+            state = self.published.wait_timeout(state, deadline - now)
+                .map(|(state, _)| state)
+                .unwrap_or_else(|poisoned| poisoned.into_inner().0);
+        }
+    }
+
+    /// Calls `wake` once there is a change after `since`, or once
+    /// `deadline` has passed, whichever comes first. A single thread waits
+    /// for the deadlines of every reader.
+    pub fn watch(&self, since: u64, deadline: Option<Instant>, wake: Waker) {
+        let mut watchers = self.alarms.lock();
+        if self.last_seq() > since || deadline.is_some_and(|deadline| deadline <= Instant::now()) {
+            drop(watchers);
+            return wake();
+        }
+        watchers.waiting.push(Watcher { since, deadline, wake });
+        if deadline.is_none() {
+            return;
+        }
+        if watchers.timing {
+            self.alarms.changed.notify_one();
+        } else {
+            watchers.timing = true;
+            let alarms = self.alarms.clone();
+            thread::spawn(move || alarms.run());
+        }
+    }
+
+    /// The changes after `since` that `keep` accepts, if there are any or
+    /// `deadline` has passed. Otherwise `None`, and `wake` is called once
+    /// there may be, so that nothing has to wait meanwhile.
+    pub fn poll<F: Fn(&Change) -> bool>(&self, since: u64, deadline: Instant, keep: &F, wake: Waker) -> Result<Option<Batch>, APIErr> {
+        let batch = ChangeFeed::after(&self.lock(), since, keep)?;
+        if !batch.changes.is_empty() || Instant::now() >= deadline {
+            return Ok(Some(batch));
+        }
+        self.watch(batch.last_seq, Some(deadline), wake);
+        Ok(None)
+    }
+}
+
+/// An endless series of batches, one whenever there are changes and an
+/// empty one after every `heartbeat` without any. Ends after an error.
+pub struct Subscription<F> {
+    feed: Arc<ChangeFeed>,
+    since: Option<u64>,
+    heartbeat: Duration,
+    keep: F,
+}
+
+impl<F: Fn(&Change) -> bool> Subscription<F> {
+    pub fn new(feed: Arc<ChangeFeed>, since: u64, heartbeat: Duration, keep: F) -> Subscription<F> {
+        Subscription { feed, since: Some(since), heartbeat, keep }
+    }
+}
+
+impl<F: Fn(&Change) -> bool> Iterator for Subscription<F> {
+    type Item = Result<Batch, APIErr>;
+
+    fn next(&mut self) -> Option<Result<Batch, APIErr>> {
+        let since = self.since?;
+        let batch = self.feed.wait(since, self.heartbeat, &self.keep);
+        self.since = batch.as_ref().ok().map(|batch| batch.last_seq);
+        Some(batch)
+    }
+}
@@ -0,0 +1,156 @@
+extern crate serde;
+
+use std::error::Error;
+use std::fmt;
+
+use self::serde::ser::{Serialize, SerializeStruct, Serializer};
+use super::APIErr;
+
+/// What was being done to a document when an error occurred.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Read,
+    Write,
+    Delete,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Operation::Read => "read",
+            Operation::Write => "write",
+            Operation::Delete => "delete",
+        })
+    }
+}
+
+impl APIErr {
+    /// Records the document an error happened at. Errors that already name
+    /// a document keep it, since the innermost one is the most precise.
+    pub fn at(self, operation: Operation, path: String) -> APIErr {
+        match self {
+            APIErr::Context { .. } | APIErr::Conflict(_) | APIErr::Aggregate(_) | APIErr::Forbidden => self,
+            source => APIErr::Context { operation, path, source: Box::new(source) }
+        }
+    }
+
+    /// The error without any context recorded around it.
+    pub fn root_cause(&self) -> &APIErr {
+        match self {
+            APIErr::Context { source, .. } => source.root_cause(),
+            err => err
+        }
+    }
+
+    /// The logical path of the document the error is about, if known.
+    pub fn path(&self) -> Option<&str> {
+        match self {
+            APIErr::Context { path, .. } | APIErr::Conflict(path) => Some(path.as_str()),
+            _ => None
+        }
+    }
+
+    pub fn operation(&self) -> Option<Operation> {
+        match self {
+            APIErr::Context { operation, .. } => Some(*operation),
+            _ => None
+        }
+    }
+
+    /// A stable, machine readable name for the kind of error.
+    pub fn kind(&self) -> &'static str {
+        match self.root_cause() {
+            APIErr::Aggregate(_) => "aggregate",
+            APIErr::IO(_) => "io",
+            APIErr::Deserialize(_) | APIErr::Decode(_) => "decode",
+            APIErr::EmptyRequest => "empty_request",
+            APIErr::BodyTooLarge(_) => "body_too_large",
+            APIErr::TooDeep(_) => "too_deep",
+            APIErr::TooManyMembers(_) => "too_many_members",
+            APIErr::ArrayTooLong(_) => "array_too_long",
+            APIErr::Encode(_) => "encode",
+            APIErr::UnsupportedMediaType(_) => "unsupported_media_type",
+            APIErr::NotAcceptable(_) => "not_acceptable",
+            APIErr::UnsupportedVersion(_) => "unsupported_version",
+            APIErr::Conflict(_) => "conflict",
+            APIErr::Unauthorized(_) => "unauthorized",
+            APIErr::Forbidden => "forbidden",
+            APIErr::Gone(_) => "gone",
+            APIErr::Context { .. } => "context",
+        }
+    }
+
+    /// The errors an aggregate is made of, or none for any other error.
+    pub fn errors(&self) -> &[APIErr] {
+        match self.root_cause() {
+            APIErr::Aggregate(errs) => errs.as_slice(),
+            _ => &[]
+        }
+    }
+}
+
+impl fmt::Display for APIErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            APIErr::Aggregate(errs) => {
+                write!(f, "{} errors", errs.len())?;
+                errs.iter()
+                    .enumerate()
+                    .try_for_each(|(i, err)| write!(f, "{} {}", if i == 0 { ":" } else { ";" }, err))
+            },
+            APIErr::IO(err) => write!(f, "{}", err),
+            APIErr::Deserialize(err) => write!(f, "{:?}", err.cause),
+            APIErr::EmptyRequest => f.write_str("the body is empty"),
+            APIErr::BodyTooLarge(limit) => write!(f, "the body is larger than {} bytes", limit),
+            APIErr::TooDeep(limit) => write!(f, "the document is nested deeper than {} levels", limit),
+            APIErr::TooManyMembers(limit) => write!(f, "an object has more than {} members", limit),
+            APIErr::ArrayTooLong(limit) => write!(f, "an array has more than {} elements", limit),
+            APIErr::Decode(err) => write!(f, "could not decode the body: {}", err),
+            APIErr::Encode(err) => write!(f, "could not encode the document: {}", err),
+            APIErr::UnsupportedMediaType(media_type) => write!(f, "{} bodies are not supported", media_type),
+            APIErr::NotAcceptable(accept) => write!(f, "none of {} can be produced", accept),
+            APIErr::UnsupportedVersion(version) => write!(f, "storage format version {} is not supported", version),
+            APIErr::Conflict(path) => write!(f, "{} cannot change between a field and a collection", path),
+            APIErr::Unauthorized(reason) => f.write_str(reason),
+            APIErr::Forbidden => f.write_str("access denied"),
+            APIErr::Gone(seq) => write!(f, "changes after sequence number {} are no longer kept", seq),
+            APIErr::Context { operation, path, source } => write!(f, "could not {} {}: {}", operation, path, source),
+        }
+    }
+}
+
+impl Error for APIErr {
+    fn source(&self) -> Option<&(Error + 'static)> {
+        match self {
+            APIErr::IO(err) => Some(err),
+            APIErr::Context { source, .. } => Some(source.as_ref()),
+            _ => None
+        }
+    }
+}
+
+/// Errors are sent to clients as `{"kind", "message", "path", "operation",
+/// "errors"}`, with the members of an aggregate listed the same way.
+impl Serialize for APIErr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let errors = self.errors();
+        let mut state = serializer.serialize_struct("APIErr", 5)?;
+        state.serialize_field("kind", self.kind())?;
+        state.serialize_field("message", &self.root_cause().to_string())?;
+        match self.path() {
+            Some(path) => state.serialize_field("path", path)?,
+            None => state.skip_field("path")?
+        }
+        match self.operation() {
+            Some(operation) => state.serialize_field("operation", &operation)?,
+            None => state.skip_field("operation")?
+        }
+        if errors.is_empty() {
+            state.skip_field("errors")?;
+        } else {
+            state.serialize_field("errors", errors)?;
+        }
+        state.end()
+    }
+}
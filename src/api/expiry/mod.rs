@@ -0,0 +1,80 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::{API, APIErr, EXPIRY_FILE_PREFIX};
+
+pub const SWEEP_INTERVAL_SECS: u64 = 30;
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn expires_in(ttl: u64) -> u64 {
+    now() + ttl
+}
+
+pub fn is_expired(expires_at: u64) -> bool {
+    expires_at <= now()
+}
+
+pub fn remaining(expires_at: u64) -> u64 {
+    expires_at.saturating_sub(now())
+}
+
+pub fn expired_err() -> APIErr {
+    APIErr::IO(io::Error::new(io::ErrorKind::NotFound, "document expired"))
+}
+
+/// The expiry of a field or collection is recorded in a sibling file
+/// named after it, so it survives the collection being rewritten.
+pub fn metadata_path(path: &Path) -> Option<PathBuf> {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| path.parent()
+            .map(|parent| parent.join(format!("{}{}", EXPIRY_FILE_PREFIX, name))))
+}
+
+pub fn is_metadata_path(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.starts_with(EXPIRY_FILE_PREFIX))
+        .unwrap_or(false)
+}
+
+/// Removes every expiry recorded below the collection at `path`, whose
+/// members live as long as the collection once it is written again.
+pub fn clear_below(path: &Path) -> io::Result<()> {
+    if !path.is_dir() {
+        return Ok(());
+    }
+    fs::read_dir(path)?.try_for_each(|entry| {
+        let child = entry?.path();
+        if is_metadata_path(&child) {
+            fs::remove_file(child)
+        } else {
+            clear_below(&child)
+        }
+    })
+}
+
+pub fn spawn_sweeper<T: API + Send + 'static>(app: T, interval: Duration) -> thread::JoinHandle<()> {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let root = app.root_path();
+        if root.exists() {
+            let lock = app.write_lock();
+            let _guard = lock.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+            match app.sweep(root) {
+                Ok(0) => (),
+                Ok(removed) => info!("Swept {} expired documents", removed),
+                Err(err) => error!("Sweep err: {}", err)
+            }
+        }
+    })
+}
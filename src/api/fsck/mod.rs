@@ -0,0 +1,285 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use super::{API, APIErr, ARRAY_TYPE, CLASS_FILE_NAME, CLASS_TYPE, OBJECT_TYPE};
+use super::{BOOL_TYPE, EXPIRY_FILE_PREFIX, NULL_TYPE, NUMBER_TYPE, STRING_TYPE};
+use super::{audit, changes};
+use super::migrate::VERSION_FILE_NAME;
+use super::snapshot::{self, STAGING_SUFFIX};
+
+const RENUMBER_PREFIX: &str = "__renumber__.";
+
+#[derive(Serialize, Debug, PartialEq)]
+pub enum IssueKind {
+    /// A collection directory without a class declaration.
+    MissingClass,
+    /// A class declaration that is neither an object nor an array.
+    InvalidClass(String),
+    /// A field file that ends before its value line.
+    TruncatedField,
+    /// A field file whose type line is not one this build writes.
+    UnknownFieldType(String),
+    /// An array whose members are not numbered 0 to n - 1.
+    ArrayGaps,
+    /// An expiry recorded for a field or collection that no longer exists.
+    OrphanedExpiry,
+    /// A staging directory left behind by an interrupted restore or by a
+    /// process that has since exited.
+    OrphanedStaging,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Issue {
+    pub path: PathBuf,
+    pub kind: IssueKind,
+    pub repaired: bool,
+}
+
+struct Check<'a, T: API + 'a> {
+    app: &'a T,
+    root: PathBuf,
+    repair: bool,
+    issues: Vec<Issue>,
+}
+
+/// Walks the storage root and reports every structural inconsistency found.
+/// With `repair` set each one is also fixed where that is possible: missing
+/// or invalid class declarations are inferred from the members, truncated
+/// fields and orphaned files are removed, and arrays are renumbered. Paths
+/// are reported relative to the storage root.
+pub fn fsck<T: API>(app: &T, repair: bool) -> Result<Vec<Issue>, APIErr> {
+    let root = app.root_path();
+    let lock = app.write_lock();
+    let _guard = lock.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let mut check = Check { app, root: root.clone(), repair, issues: Vec::new() };
+    check.check_staging(&root)?;
+    if root.is_dir() {
+        check.check_members(&root)?;
+    }
+    Ok(check.issues)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(String::from)
+        .unwrap_or_default()
+}
+
+fn list(path: &Path) -> Result<Vec<PathBuf>, APIErr> {
+    fs::read_dir(path)
+        .map_err(APIErr::IO)
+        .and_then(|read_dir| read_dir
+            .map(|entry| entry.map_err(APIErr::IO).map(|e| e.path()))
+            .collect::<Result<Vec<PathBuf>, APIErr>>())
+        .map(|mut paths| {
+            paths.sort();
+            paths
+        })
+}
+
+fn is_member(path: &Path) -> bool {
+    let name = file_name(path);
+    name != CLASS_FILE_NAME && name != VERSION_FILE_NAME && !name.starts_with(EXPIRY_FILE_PREFIX)
+}
+
+/// Arrays are written with members numbered from zero, so a collection
+/// whose members are exactly that is taken to be one.
+fn infer_class(members: &[PathBuf]) -> &'static str {
+    let numbered = members.iter()
+        .map(|p| usize::from_str(file_name(p).as_str()).ok())
+        .collect::<Option<Vec<usize>>>()
+        .map(|mut indices| {
+            indices.sort();
+            indices.into_iter().enumerate().all(|(i, index)| i == index)
+        })
+        .unwrap_or(false);
+    if !members.is_empty() && numbered {
+        ARRAY_TYPE
+    } else {
+        OBJECT_TYPE
+    }
+}
+
+fn read_lines(path: &Path) -> Result<(Vec<String>, bool), APIErr> {
+    fs::read(path)
+        .map_err(APIErr::IO)
+        .map(|bytes| String::from_utf8_lossy(bytes.as_slice()).into_owned())
+        .map(|content| (content.lines().map(String::from).collect(), content.matches('\n').count() >= 2))
+}
+
+impl<'a, T: API> Check<'a, T> {
+    fn report(&mut self, path: &Path, kind: IssueKind, repair: Option<Result<(), APIErr>>) -> Result<(), APIErr> {
+        let repaired = match repair {
+            Some(result) => result.map(|()| true)?,
+            None => false
+        };
+        let path = match path.strip_prefix(&self.root) {
+            Ok(relative) => relative.to_path_buf(),
+            // staging directories sit next to the root
+            Err(_) => Path::new("..").join(file_name(path))
+        };
+        warn!("fsck: {} {:?}{}", path.display(), kind, if repaired { " (repaired)" } else { "" });
+        self.issues.push(Issue { path, kind, repaired });
+        Ok(())
+    }
+
+    fn check_staging(&mut self, root: &Path) -> Result<(), APIErr> {
+        let prefix = format!("{}{}", file_name(root), STAGING_SUFFIX);
+        let parent = root.parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+        if !parent.is_dir() {
+            return Ok(());
+        }
+        for path in list(parent)? {
+            if path.is_dir() && file_name(&path).starts_with(prefix.as_str()) && !snapshot::is_staging_in_use(&path) {
+                let repair = if self.repair { Some(fs::remove_dir_all(path.clone()).map_err(APIErr::IO)) } else { None };
+                self.report(&path, IssueKind::OrphanedStaging, repair)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn check_collection(&mut self, path: &Path) -> Result<(), APIErr> {
+        let members = list(path)?
+            .into_iter()
+            .filter(|p| is_member(p))
+            .collect::<Vec<PathBuf>>();
+        let class_path = path.join(CLASS_FILE_NAME);
+        let class = if !class_path.exists() {
+            let inferred = infer_class(&members);
+            let repair = self.write_class(&class_path, inferred);
+            self.report(path, IssueKind::MissingClass, repair)?;
+            String::from(inferred)
+        } else {
+            let (lines, _) = read_lines(&class_path)?;
+            match lines.get(1).map(|line| line.trim()) {
+                Some(OBJECT_TYPE) => String::from(OBJECT_TYPE),
+                Some(ARRAY_TYPE) => String::from(ARRAY_TYPE),
+                other => {
+                    let found = String::from(other.unwrap_or(""));
+                    let inferred = infer_class(&members);
+                    let repair = self.write_class(&class_path, inferred);
+                    self.report(path, IssueKind::InvalidClass(found), repair)?;
+                    String::from(inferred)
+                }
+            }
+        };
+        if class.as_str() == ARRAY_TYPE {
+            self.check_indices(path, members)?;
+        }
+        self.check_members(path)
+    }
+
+    fn write_class(&self, class_path: &Path, class: &str) -> Option<Result<(), APIErr>> {
+        if self.repair {
+            Some(self.app.write_field(class_path.to_path_buf(), String::from(class), CLASS_TYPE))
+        } else {
+            None
+        }
+    }
+
+    fn check_members(&mut self, path: &Path) -> Result<(), APIErr> {
+        for member in list(path)? {
+            let name = file_name(&member);
+            if name == CLASS_FILE_NAME || name == VERSION_FILE_NAME ||
+                audit::is_audit_path(self.app, &member) || changes::is_changes_path(self.app, &member) {
+                continue;
+            } else if name.starts_with(EXPIRY_FILE_PREFIX) {
+                self.check_expiry(&member, &name)?;
+            } else if member.is_dir() {
+                self.check_collection(&member)?;
+            } else {
+                self.check_field(&member)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn check_expiry(&mut self, path: &Path, name: &str) -> Result<(), APIErr> {
+        let target = path.with_file_name(&name[EXPIRY_FILE_PREFIX.len()..]);
+        if !target.exists() {
+            let repair = if self.repair { Some(fs::remove_file(path).map_err(APIErr::IO)) } else { None };
+            self.report(path, IssueKind::OrphanedExpiry, repair)?;
+        }
+        Ok(())
+    }
+
+    fn check_field(&mut self, path: &Path) -> Result<(), APIErr> {
+        let (lines, complete) = read_lines(path)?;
+        let field_type = lines.first().map(|line| String::from(line.trim())).unwrap_or_default();
+        match field_type.as_str() {
+            NULL_TYPE | BOOL_TYPE | STRING_TYPE | NUMBER_TYPE if complete => Ok(()),
+            NULL_TYPE => {
+                let repair = if self.repair {
+                    Some(self.app.write_field(path.to_path_buf(), String::from("null"), NULL_TYPE))
+                } else {
+                    None
+                };
+                self.report(path, IssueKind::TruncatedField, repair)
+            },
+            BOOL_TYPE | STRING_TYPE | NUMBER_TYPE => {
+                let repair = self.remove_member(path);
+                self.report(path, IssueKind::TruncatedField, repair)
+            },
+            other => {
+                let kind = IssueKind::UnknownFieldType(String::from(other));
+                self.report(path, kind, None)
+            }
+        }
+    }
+
+    fn remove_member(&self, path: &Path) -> Option<Result<(), APIErr>> {
+        if self.repair {
+            Some(self.app.remove_path(path.to_path_buf())
+                .and_then(|()| self.app.write_expiry(path.to_path_buf(), None)))
+        } else {
+            None
+        }
+    }
+
+    fn check_indices(&mut self, path: &Path, members: Vec<PathBuf>) -> Result<(), APIErr> {
+        let mut ordered = members.into_iter()
+            .map(|p| (usize::from_str(file_name(&p).as_str()).ok(), file_name(&p), p))
+            .collect::<Vec<(Option<usize>, String, PathBuf)>>();
+        ordered.sort_by(|(index, name, _), (index2, name2, _)| match (index, index2) {
+            (Some(i), Some(i2)) => i.cmp(i2),
+            (Some(_), None) => ::std::cmp::Ordering::Less,
+            (None, Some(_)) => ::std::cmp::Ordering::Greater,
+            (None, None) => name.cmp(name2)
+        });
+        let numbered = ordered.iter()
+            .enumerate()
+            .all(|(i, (index, _, _))| *index == Some(i));
+        if numbered {
+            return Ok(());
+        }
+        let repair = if self.repair {
+            Some(self.renumber(path, ordered.into_iter().map(|(_, _, p)| p).collect()))
+        } else {
+            None
+        };
+        self.report(path, IssueKind::ArrayGaps, repair)
+    }
+
+    /// Renames members to 0 to n - 1 in their current order, moving each
+    /// one's expiry along with it. Members are first moved to temporary names
+    /// so that no rename lands on a member that has not been moved yet.
+    fn renumber(&self, path: &Path, members: Vec<PathBuf>) -> Result<(), APIErr> {
+        let expiries = members.iter()
+            .map(|member| self.app.read_expiry(member.clone()))
+            .collect::<Result<Vec<Option<u64>>, APIErr>>()?;
+        for (i, member) in members.iter().enumerate() {
+            self.app.write_expiry(member.clone(), None)?;
+            fs::rename(member, path.join(format!("{}{}", RENUMBER_PREFIX, i))).map_err(APIErr::IO)?;
+        }
+        for (i, expires_at) in expiries.into_iter().enumerate() {
+            let target = path.join(i.to_string());
+            fs::rename(path.join(format!("{}{}", RENUMBER_PREFIX, i)), target.clone()).map_err(APIErr::IO)?;
+            self.app.write_expiry(target, expires_at)?;
+        }
+        Ok(())
+    }
+}
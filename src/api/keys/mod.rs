@@ -0,0 +1,199 @@
+extern crate serde_json;
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use self::serde_json::Value;
+use super::{expiry, API, APIErr, Operation, OBJECT_TYPE};
+use super::acl::Permission;
+use super::super::auth::{self, hash, Principal};
+
+/// Every key is a document `<storage>/_keys/<id>`.
+pub const KEYS_PATH: &str = "_keys";
+/// Every key secret starts with this, so that it is never mistaken for a
+/// token from the tokens file or a JWT.
+pub const KEY_PREFIX: &str = "rmk_";
+const ID_LENGTH: usize = 12;
+
+/// A long-lived key for a machine client. Only the hash of its secret is
+/// stored.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    /// The principal requests made with the key are attributed to.
+    pub owner: String,
+    pub created_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// The permissions the key was issued for, or `None` for every
+    /// permission its owner has.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scopes: Option<Vec<Permission>>,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    hash: String,
+}
+
+/// What an administrator asks for when creating a key.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct NewKey {
+    pub name: String,
+    pub owner: String,
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    #[serde(default)]
+    pub scopes: Option<Vec<Permission>>,
+}
+
+impl ApiKey {
+    /// The key as listed to administrators, without its hash.
+    pub fn to_value(&self) -> Value {
+        serde_json::to_value(ApiKey { hash: String::new(), ..self.clone() }).unwrap_or(Value::Null)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(expiry::is_expired)
+    }
+}
+
+fn keys_dir<T: API + ?Sized>(app: &T) -> PathBuf {
+    let mut path = app.root_path();
+    path.push(KEYS_PATH);
+    path
+}
+
+fn key_path<T: API + ?Sized>(app: &T, id: &str) -> Result<PathBuf, APIErr> {
+    if id.len() == ID_LENGTH && id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        Ok(keys_dir(app).join(id))
+    } else {
+        Err(not_found(id))
+    }
+}
+
+fn not_found(id: &str) -> APIErr {
+    APIErr::IO(io::Error::new(io::ErrorKind::NotFound, format!("no key {}", id)))
+}
+
+/// Whether `path` is the directory holding the keys, which is never listed
+/// as part of the tree around it.
+pub fn is_keys_path<T: API + ?Sized>(app: &T, path: &Path) -> bool {
+    path == keys_dir(app).as_path()
+}
+
+/// Whether `token` is shaped like a key secret rather than another kind
+/// of bearer token.
+pub fn is_key(token: &str) -> bool {
+    token.starts_with(KEY_PREFIX)
+}
+
+/// A fresh secret for the key `id`, as `rmk_<id>_<random>`.
+fn new_secret(id: &str) -> String {
+    format!("{}{}_{}", KEY_PREFIX, id, auth::new_token())
+}
+
+pub fn get<T: API + ?Sized>(app: &T, id: &str) -> Result<ApiKey, APIErr> {
+    let path = key_path(app, id)?;
+    if !path.is_dir() {
+        return Err(not_found(id));
+    }
+    app.read_path(path, String::new())
+        .and_then(|value| serde_json::from_value(value).map_err(|err| APIErr::Decode(err.to_string())))
+}
+
+/// Every key, oldest first.
+pub fn list<T: API + ?Sized>(app: &T) -> Result<Vec<ApiKey>, APIErr> {
+    let dir = keys_dir(app);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut keys = fs::read_dir(dir)
+        .map_err(APIErr::IO)?
+        .map(|entry| entry.map_err(APIErr::IO).map(|e| e.path()))
+        .collect::<Result<Vec<PathBuf>, APIErr>>()?
+        .into_iter()
+        .filter(|p| p.is_dir())
+        .flat_map(|p| p.file_name().and_then(|name| name.to_str()).map(String::from))
+        .map(|id| get(app, id.as_str()))
+        .collect::<Result<Vec<ApiKey>, APIErr>>()?;
+    keys.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+    Ok(keys)
+}
+
+fn document(id: &str) -> Vec<String> {
+    vec![String::from(KEYS_PATH), String::from(id)]
+}
+
+/// Replaces the key, publishing the removal of the old one and the write
+/// of the new one, so that followers keep the same keys.
+fn store<T: API + ?Sized>(app: &T, key: &ApiKey) -> Result<(), APIErr> {
+    let path = key_path(app, key.id.as_str())?;
+    let value = serde_json::to_value(key).map_err(|err| APIErr::Encode(err.to_string()))?;
+    let lock = app.write_lock();
+    let _guard = lock.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if path.exists() {
+        app.remove_path(path.clone())?;
+        app.publish(Operation::Delete, &document(key.id.as_str()), Value::Null, None);
+    }
+    app.ensure_path(keys_dir(app))
+        .and_then(|()| app.write_class_file(keys_dir(app), OBJECT_TYPE))
+        .and_then(|()| app.write_path(path, value.clone(), String::new()))
+        .map(|()| app.publish(Operation::Write, &document(key.id.as_str()), value, None))
+}
+
+/// Creates a key, returning it along with its secret, which is not stored
+/// and cannot be recovered later.
+pub fn create<T: API + ?Sized>(app: &T, new: NewKey) -> Result<(ApiKey, String), APIErr> {
+    if new.name.trim().is_empty() || new.owner.trim().is_empty() {
+        return Err(APIErr::IO(io::Error::new(io::ErrorKind::InvalidInput, "a key needs a name and an owner")));
+    }
+    let id = auth::new_token()[..ID_LENGTH].to_string();
+    let secret = new_secret(id.as_str());
+    let key = ApiKey {
+        id,
+        name: new.name,
+        owner: new.owner,
+        created_at: expiry::now(),
+        expires_at: new.expires_at,
+        scopes: new.scopes,
+        hash: auth::token_hash(secret.as_str()),
+    };
+    store(app, &key).map(|()| (key, secret))
+}
+
+/// Replaces the secret of the key `id`, so that the old one stops working.
+pub fn rotate<T: API + ?Sized>(app: &T, id: &str) -> Result<(ApiKey, String), APIErr> {
+    let key = get(app, id)?;
+    let secret = new_secret(id);
+    let key = ApiKey { hash: auth::token_hash(secret.as_str()), ..key };
+    store(app, &key).map(|()| (key, secret))
+}
+
+pub fn revoke<T: API + ?Sized>(app: &T, id: &str) -> Result<(), APIErr> {
+    let path = key_path(app, id)?;
+    let lock = app.write_lock();
+    let _guard = lock.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if !path.is_dir() {
+        return Err(not_found(id));
+    }
+    app.remove_path(path)
+        .map(|()| app.publish(Operation::Delete, &document(id), Value::Null, None))
+}
+
+/// The principal a key secret stands for, or `None` when no unexpired key
+/// has this secret.
+pub fn authenticate<T: API + ?Sized>(app: &T, secret: &str) -> Result<Option<Principal>, APIErr> {
+    let id = match secret[KEY_PREFIX.len()..].split('_').next() {
+        Some(id) if key_path(app, id).map(|path| path.is_dir()).unwrap_or(false) => id,
+        _ => return Ok(None)
+    };
+    let key = get(app, id)?;
+    let matches = hash::constant_time_eq(key.hash.as_bytes(), auth::token_hash(secret).as_bytes());
+    Ok(if matches && !key.is_expired() {
+        Some(Principal { name: key.owner, scopes: key.scopes })
+    } else {
+        None
+    })
+}
@@ -0,0 +1,60 @@
+use super::serde_json::Value;
+use super::APIErr;
+use super::super::{MAX_ARCHIVE_LENGTH, MAX_ARRAY_LENGTH, MAX_BODY_LENGTH, MAX_DEPTH, MAX_MEMBERS};
+
+/// Bounds on the size and shape of incoming documents. Every object and
+/// array becomes a directory on disk, so these also bound how many
+/// directories and files a single request can create.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub max_body_length: usize,
+    pub max_depth: usize,
+    pub max_members: usize,
+    pub max_array_length: usize,
+    /// Snapshot archives are restored from request bodies too, and are
+    /// expected to be much larger than documents.
+    pub max_archive_length: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_body_length: MAX_BODY_LENGTH,
+            max_depth: MAX_DEPTH,
+            max_members: MAX_MEMBERS,
+            max_array_length: MAX_ARRAY_LENGTH,
+            max_archive_length: MAX_ARCHIVE_LENGTH,
+        }
+    }
+}
+
+impl Limits {
+    pub fn check_body_length(&self, length: usize) -> Result<(), APIErr> {
+        if length > self.max_body_length {
+            Err(APIErr::BodyTooLarge(self.max_body_length))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn check(&self, value: &Value) -> Result<(), APIErr> {
+        self.check_at(value, 1)
+    }
+
+    fn check_at(&self, value: &Value, depth: usize) -> Result<(), APIErr> {
+        match value {
+            Value::Object(_) | Value::Array(_) if depth > self.max_depth =>
+                Err(APIErr::TooDeep(self.max_depth)),
+            Value::Object(o) if o.len() > self.max_members =>
+                Err(APIErr::TooManyMembers(self.max_members)),
+            Value::Array(a) if a.len() > self.max_array_length =>
+                Err(APIErr::ArrayTooLong(self.max_array_length)),
+            Value::Object(o) => o.values()
+                .try_for_each(|v| self.check_at(v, depth + 1)),
+            Value::Array(a) => a.iter()
+                .try_for_each(|v| self.check_at(v, depth + 1)),
+            _ => Ok(())
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use super::{API, APIErr, FORMAT_VERSION};
+
+/// Records the layout version at the storage root. Stores written before
+/// the marker existed have none and are taken to be version 0.
+pub const VERSION_FILE_NAME: &str = "__format_version__";
+const VERSION_TYPE: &str = "VERSION";
+
+/// Upgrades a store from version `from` to `from + 1`, working on the tree
+/// below the given root.
+pub struct Migration {
+    pub from: u32,
+    pub description: &'static str,
+    apply: fn(&Path) -> Result<(), APIErr>,
+}
+
+/// Every step from the first layout up to `FORMAT_VERSION`, in order.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 0,
+        description: "record the format version at the storage root",
+        apply: unchanged_layout,
+    },
+];
+
+fn unchanged_layout(_: &Path) -> Result<(), APIErr> {
+    Ok(())
+}
+
+pub fn root_path<T: API>(app: &T) -> PathBuf {
+    app.root_path()
+}
+
+pub fn is_version_path(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name == VERSION_FILE_NAME)
+        .unwrap_or(false)
+}
+
+/// The version of the store at `root`. A missing or empty root is a new
+/// store and is already at the current version.
+pub fn read_version(root: &Path) -> Result<u32, APIErr> {
+    let marker = root.join(VERSION_FILE_NAME);
+    if marker.exists() {
+        return fs::File::open(marker)
+            .map(BufReader::new)
+            .and_then(|reader| reader.lines().nth(1).unwrap_or_else(|| Ok(String::new())))
+            .map_err(APIErr::IO)
+            .and_then(|line| u32::from_str(line.trim())
+                .map_err(|err| APIErr::Decode(format!("invalid format version {}: {}", line.trim(), err))));
+    }
+    let is_empty = fs::read_dir(root)
+        .map(|mut entries| entries.next().is_none())
+        .unwrap_or(true);
+    Ok(if is_empty { FORMAT_VERSION } else { 0 })
+}
+
+pub fn write_version<T: API>(app: &T, root: &Path, version: u32) -> Result<(), APIErr> {
+    app.ensure_path(root.to_path_buf())
+        .and_then(|()| app.write_field(root.join(VERSION_FILE_NAME), version.to_string(), VERSION_TYPE))
+}
+
+/// Applies every migration the store at `root` still needs, recording the
+/// version after each step so an interrupted upgrade resumes where it
+/// stopped. Returns the descriptions of the steps applied.
+pub fn migrate_path<T: API>(app: &T, root: &Path) -> Result<Vec<&'static str>, APIErr> {
+    let mut version = read_version(root)?;
+    if version > FORMAT_VERSION {
+        return Err(APIErr::UnsupportedVersion(version));
+    }
+    let mut applied = Vec::new();
+    while version < FORMAT_VERSION {
+        let migration = MIGRATIONS.iter()
+            .find(|migration| migration.from == version)
+            .ok_or(APIErr::UnsupportedVersion(version))?;
+        info!("Migrating {} from format version {}: {}", root.display(), version, migration.description);
+        (migration.apply)(root)?;
+        version += 1;
+        write_version(app, root, version)?;
+        applied.push(migration.description);
+    }
+    if !root.join(VERSION_FILE_NAME).exists() {
+        write_version(app, root, version)?;
+    }
+    Ok(applied)
+}
+
+/// Brings the store up to the current version in place, holding off writes
+/// while it does. Called before a store is served or otherwise used, so a
+/// store from a newer release is refused rather than misread.
+pub fn open<T: API>(app: &T) -> Result<Vec<&'static str>, APIErr> {
+    let lock = app.write_lock();
+    let _guard = lock.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    migrate_path(app, root_path(app).as_path())
+}
+
+fn copy_tree(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir(to)?;
+    for entry in fs::read_dir(from)? {
+        let path = entry?.path();
+        let target = to.join(path.file_name().unwrap_or_default());
+        if path.is_dir() {
+            copy_tree(&path, &target)?;
+        } else {
+            fs::copy(&path, &target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Copies the store into `target`, which must not exist yet, and upgrades
+/// the copy. The original is left as it was.
+pub fn migrate_into<T: API>(app: &T, target: &Path) -> Result<Vec<&'static str>, APIErr> {
+    let root = root_path(app);
+    let version = read_version(&root)?;
+    if version > FORMAT_VERSION {
+        return Err(APIErr::UnsupportedVersion(version));
+    }
+    let lock = app.write_lock();
+    let _guard = lock.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+    copy_tree(&root, target)
+        .map_err(APIErr::IO)
+        .and_then(|()| migrate_path(app, target))
+}
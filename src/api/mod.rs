@@ -18,25 +18,203 @@ const ARRAY_TYPE: &str = "ARRAY";
 const OBJECT_TYPE: &str = "OBJECT";
 const CLASS_TYPE: &str = "CLASS";
 const CLASS_FILE_NAME: &str = "__class_declaration__";
+/// Version of the on-disk layout written by this build.
+pub const FORMAT_VERSION: u32 = 1;
+const EXPIRY_TYPE: &str = "EXPIRY";
+const EXPIRY_FILE_PREFIX: &str = "__expires__.";
 use std::io::BufReader;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+mod error;
+pub mod acl;
+pub mod audit;
+pub mod changes;
+pub mod expiry;
+pub mod fsck;
+pub mod keys;
+pub mod limits;
+pub mod migrate;
+pub mod ndjson;
+pub mod replication;
+pub mod snapshot;
+pub mod stream;
+pub mod tenant;
+pub mod webhooks;
+
+pub use self::error::Operation;
+
 #[derive(Debug)]
 pub enum APIErr {
     Aggregate(Vec<APIErr>),
     IO(std::io::Error),
     Deserialize(bodyparser::BodyError),
     EmptyRequest,
+    BodyTooLarge(usize),
+    TooDeep(usize),
+    TooManyMembers(usize),
+    ArrayTooLong(usize),
+    Decode(String),
+    Encode(String),
+    UnsupportedMediaType(String),
+    NotAcceptable(String),
+    UnsupportedVersion(u32),
+    /// A write that would turn a field into a collection or the other way
+    /// around, at the given document path.
+    Conflict(String),
+    /// The request carried no credentials, or ones that could not be verified.
+    Unauthorized(String),
+    /// The principal may not do this here. Says nothing about whether
+    /// anything exists at the path.
+    Forbidden,
+    /// The changes after this sequence number are no longer kept.
+    Gone(u64),
+    /// `source` happened while doing `operation` to the document at `path`.
+    Context { operation: Operation, path: String, source: Box<APIErr> },
+}
+
+/// Every segment of a path must name an entry of the directory before it,
+/// so that no path can reach outside the tree it is resolved in.
+fn check_segments(path: &[String]) -> Result<(), APIErr> {
+    match path.iter().find(|p| p.is_empty() || *p == "." || *p == ".." || p.contains(|c| c == '/' || c == '\\' || c == '\0')) {
+        Some(segment) => Err(APIErr::IO(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+            format!("{:?} is not a valid path segment", segment)))),
+        None => Ok(())
+    }
+}
+
+/// Whether `path` lies in what the server keeps about itself at the top of
+/// the tree, under names starting with `_`: the rules, the API keys, the
+/// tenants and the like.
+pub fn is_server_state(path: &[String]) -> bool {
+    path.first().is_some_and(|first| first.starts_with('_'))
+}
+
+/// The root holds the server's own state next to the documents, so it is
+/// never replaced or removed as a whole, only member by member. That state
+/// is only ever written by the module it belongs to, never as a document,
+/// so that nothing in it can be forged by whoever may write documents.
+fn check_document_path(path: &[String]) -> Result<(), APIErr> {
+    if path.is_empty() {
+        Err(APIErr::IO(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+            "the root cannot be replaced or removed, only its members")))
+    } else if is_server_state(path) {
+        Err(APIErr::IO(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+            format!("/{} is kept for the server's own state", path[0]))))
+    } else {
+        Ok(())
+    }
+}
+
+/// Members become entries of their collection's directory, so their names
+/// follow the rules for path segments and may not be taken for metadata.
+fn check_members(value: &Value) -> Result<(), APIErr> {
+    match value {
+        Value::Object(members) => members.iter().try_for_each(|(name, member)| {
+            check_segments(std::slice::from_ref(name))
+                .and_then(|()| if name == CLASS_FILE_NAME || name.starts_with(EXPIRY_FILE_PREFIX) {
+                    Err(APIErr::IO(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                        format!("{:?} is kept for metadata", name))))
+                } else {
+                    Ok(())
+                })
+                .and_then(|()| check_members(member))
+        }),
+        Value::Array(items) => items.iter().try_for_each(check_members),
+        _ => Ok(())
+    }
 }
 
 pub trait API {
-    fn root_path(&self) -> Vec<String>;
+    /// The directory the tree is stored in.
+    fn root_path(&self) -> PathBuf;
+    fn limits(&self) -> limits::Limits;
+
+    /// Held exclusively by every write, so that snapshots and restores see
+    /// the tree between writes rather than halfway through one.
+    fn write_lock(&self) -> Arc<RwLock<()>>;
+
+    /// Principals that every access control check lets through.
+    fn admins(&self) -> Vec<String>;
+
+    /// Where writes and deletes are published, if anywhere.
+    fn changes(&self) -> Option<Arc<changes::ChangeFeed>>;
+
+    /// Publishes a change to `path`, if there is anywhere to publish it.
+    fn publish(&self, operation: Operation, path: &[String], value: Value, expires_at: Option<u64>) {
+        if let Some(feed) = self.changes() {
+            feed.publish(&self.root_path().to_string_lossy(), operation, path, value, expires_at);
+        }
+    }
+
+    /// Fails unless `path` stays inside the tree and `user` may do
+    /// `permission` there.
+    fn authorize(&self, path: &[String], user: &str, permission: acl::Permission) -> Result<(), APIErr> {
+        check_segments(path)
+            .and_then(|()| acl::check(self, path, user, permission))
+    }
 
     fn get(&self, path: Vec<String>, user: String) -> Result<Value, APIErr> {
-        let mut path_buf = PathBuf::new();
-        self.root_path().into_iter().for_each(|p| path_buf.push(p));
-        path.into_iter().for_each(|p| path_buf.push(p));
-        self.read_path(path_buf, user)
+        let mut path_buf = self.root_path();
+        path.clone().into_iter().for_each(|p| path_buf.push(p));
+        self.authorize(&path, &user, acl::Permission::Read)
+            .and_then(|()| self.expires_at(path))
+            .and_then(|expires_at| match expires_at {
+                Some(t) if expiry::is_expired(t) => Err(expiry::expired_err()),
+                _ => self.read_path(path_buf, user)
+            })
+    }
+
+    /// Remaining time to live of the document at `path`, in seconds.
+    /// A document lives no longer than the shortest lived of its ancestors.
+    fn ttl(&self, path: Vec<String>, user: String) -> Result<Option<u64>, APIErr> {
+        self.authorize(&path, &user, acl::Permission::Read)
+            .and_then(|()| self.expires_at(path))
+            .and_then(|expires_at| match expires_at {
+                Some(t) if expiry::is_expired(t) => Err(expiry::expired_err()),
+                other => Ok(other.map(expiry::remaining))
+            })
+    }
+
+    fn expires_at(&self, path: Vec<String>) -> Result<Option<u64>, APIErr> {
+        let mut path_buf = self.root_path();
+        path.into_iter()
+            .filter(|p| !p.is_empty())
+            .map(|p| {
+                path_buf.push(p);
+                self.read_expiry(path_buf.clone())
+            })
+            .collect::<Result<Vec<Option<u64>>, APIErr>>()
+            .map(|expiries| expiries.into_iter().flatten().min())
+    }
+
+    fn read_expiry(&self, path: PathBuf) -> Result<Option<u64>, APIErr> {
+        match expiry::metadata_path(&path) {
+            Some(ref meta) if meta.exists() => self.read_field(meta.clone())
+                .map(|value| value.as_str()
+                    .and_then(|s| u64::from_str(s).ok())),
+            _ => Ok(None)
+        }
+    }
+
+    fn is_visible(&self, path: &Path) -> bool {
+        !expiry::is_metadata_path(path) && !migrate::is_version_path(path) &&
+            !acl::is_acl_path(self, path) && !tenant::is_tenants_path(self, path) &&
+            !keys::is_keys_path(self, path) && !audit::is_audit_path(self, path) &&
+            !changes::is_changes_path(self, path) && !webhooks::is_webhooks_path(self, path) &&
+            !replication::is_replication_path(self, path) &&
+            self.read_expiry(path.to_path_buf())
+                .map(|expires_at| !expires_at.map(expiry::is_expired).unwrap_or(false))
+                .unwrap_or(true)
+    }
+
+    /// The path of the file or directory at `path` as it appears in URLs,
+    /// relative to the storage root.
+    fn document_path(&self, path: &Path) -> String {
+        let root = self.root_path();
+        let relative = path.strip_prefix(&root).unwrap_or(path);
+        format!("/{}", relative.iter().map(|p| p.to_string_lossy()).collect::<Vec<_>>().join("/"))
     }
 
     fn read_path(&self, path: PathBuf, user: String) -> Result<Value, APIErr> {
@@ -46,18 +224,119 @@ pub trait API {
             .and_then(|is_dir| match is_dir {
                 false => self.read_field(path.clone()),
                 true => self.read_collection(path.clone(), user)
-            }) 
+            })
+            .map_err(|err| err.at(Operation::Read, self.document_path(&path)))
     }
 
 
     fn put(&self, path: Vec<String>, value: Value, user: String) -> Result<(), APIErr> {
-        let mut path_buf = PathBuf::new();
-        self.root_path().into_iter().for_each(|p| path_buf.push(p));
-        path.into_iter().for_each(|p| path_buf.push(p));
-        self.write_path(path_buf, value, user)
+        self.put_with_ttl(path, value, None, user)
+    }
+
+    /// Writes `value` at `path`, expiring it `ttl` seconds from now. Writing
+    /// without a ttl clears any expiry previously recorded for the path, and
+    /// any write clears those recorded below it.
+    fn put_with_ttl(&self, path: Vec<String>, value: Value, ttl: Option<u64>, user: String) -> Result<(), APIErr> {
+        self.authorize(&path, &user, acl::Permission::Write)?;
+        check_document_path(&path)?;
+        check_members(&value)?;
+        let mut path_buf = self.root_path();
+        path.iter().for_each(|p| path_buf.push(p));
+        let published = self.changes().map(|_| value.clone());
+        let expires_at = ttl.map(expiry::expires_in);
+        let lock = self.write_lock();
+        let _guard = lock.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        self.limits().check(&value)
+            .and_then(|()| self.check_ancestors(&path_buf)
+                .and_then(|()| self.write_path(path_buf.clone(), value, user))
+                .and_then(|()| expiry::clear_below(&path_buf).map_err(APIErr::IO))
+                .and_then(|()| self.write_expiry(path_buf.clone(), expires_at))
+                .map_err(|err| err.at(Operation::Write, self.document_path(&path_buf))))
+            .map(|()| if let Some(value) = published {
+                self.publish(Operation::Write, &path, value, expires_at);
+            })
+    }
+
+    /// Removes the document at `path`, along with any expiry recorded for it.
+    fn delete(&self, path: Vec<String>, user: String) -> Result<(), APIErr> {
+        let mut path_buf = self.root_path();
+        path.clone().into_iter().for_each(|p| path_buf.push(p));
+        self.authorize(&path, &user, acl::Permission::Delete)?;
+        check_document_path(&path)?;
+        let lock = self.write_lock();
+        let _guard = lock.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        self.expires_at(path.clone())
+            .and_then(|expires_at| match expires_at {
+                Some(t) if expiry::is_expired(t) => Err(expiry::expired_err()),
+                _ => Ok(())
+            })
+            .and_then(|()| fs::metadata(path_buf.clone()).map_err(APIErr::IO))
+            .and_then(|_| self.remove_path(path_buf.clone()))
+            .and_then(|()| self.write_expiry(path_buf.clone(), None))
+            .map_err(|err| err.at(Operation::Delete, self.document_path(&path_buf)))
+            .map(|()| self.publish(Operation::Delete, &path, Value::Null, None))
+    }
+
+    fn write_expiry(&self, path: PathBuf, expires_at: Option<u64>) -> Result<(), APIErr> {
+        match (expiry::metadata_path(&path), expires_at) {
+            (Some(meta), Some(t)) => self.write_field(meta, t.to_string(), EXPIRY_TYPE),
+            (Some(ref meta), None) if meta.exists() => self.remove_path(meta.clone()),
+            _ => Ok(())
+        }
+    }
+
+    /// Removes every expired document below `path`, returning how many were
+    /// removed. Each removal is published as a delete.
+    fn sweep(&self, path: PathBuf) -> Result<usize, APIErr> {
+        fs::read_dir(path)
+            .map_err(APIErr::IO)
+            .and_then(|read_dir| read_dir
+                .map(|entry| entry.map_err(APIErr::IO).map(|e| e.path()))
+                .collect::<Result<Vec<PathBuf>, APIErr>>())
+            .and_then(|paths| {
+                let (removed, errs): (Vec<_>, Vec<_>) = paths
+                    .into_iter()
+                    .filter(|p| !expiry::is_metadata_path(p))
+                    .map(|p| match self.read_expiry(p.clone()) {
+                        Ok(Some(t)) if expiry::is_expired(t) => self.remove_path(p.clone())
+                            .and_then(|()| self.write_expiry(p.clone(), None))
+                            .map(|()| {
+                                let root = self.root_path();
+                                let path = p.strip_prefix(&root).unwrap_or(&p).iter()
+                                    .map(|segment| segment.to_string_lossy().into_owned())
+                                    .collect::<Vec<String>>();
+                                self.publish(Operation::Delete, &path, Value::Null, None);
+                                1
+                            }),
+                        Ok(_) if p.is_dir() => self.sweep(p),
+                        Ok(_) => Ok(0),
+                        Err(err) => Err(err)
+                    })
+                    .partition(|res| res.is_ok());
+                self.aggregate_result(
+                    removed.into_iter().flat_map(|res| res.ok()).collect(),
+                    errs.into_iter().flat_map(|res| res.err()).collect())
+            })
+            .map(|removed| removed.into_iter().sum())
+    }
+
+    /// Fails if anything between the storage root and `path` is a field,
+    /// which a write below it would otherwise have to replace.
+    fn check_ancestors(&self, path: &Path) -> Result<(), APIErr> {
+        let root = self.root_path();
+        path.ancestors()
+            .skip(1)
+            .take_while(|ancestor| ancestor.starts_with(&root) && *ancestor != root.as_path())
+            .find(|ancestor| ancestor.is_file())
+            .map_or(Ok(()), |ancestor| Err(APIErr::Conflict(self.document_path(ancestor))))
     }
 
     fn write_path(&self, path: PathBuf, value: Value, user: String) -> Result<(), APIErr> {
+        let is_collection = value.is_object() || value.is_array();
+        if (is_collection && path.is_file()) || (!is_collection && path.is_dir()) {
+            return Err(APIErr::Conflict(self.document_path(&path)));
+        }
+        let document_path = self.document_path(&path);
         match value {
             Value::Null => self.write_field(path, String::from("null"), NULL_TYPE),
             Value::Bool(b) => self.write_field(path, b.to_string(), BOOL_TYPE),
@@ -80,7 +359,7 @@ pub trait API {
                     .map(|m| self.write_collection_members(path.clone(), m, user))
                     .and_then(|errs| self.aggregate(errs))
             },
-        }
+        }.map_err(|err| err.at(Operation::Write, document_path))
     }
 
     fn write_collection_members(&self, path: PathBuf, m: Vec<(String, Value)>, user: String) -> Vec<APIErr> {
@@ -101,6 +380,7 @@ pub trait API {
     }
 
     fn ensure_path(&self, path: PathBuf) -> Result<(), APIErr>;
+    fn remove_path(&self, path: PathBuf) -> Result<(), APIErr>;
 
     fn aggregate(&self, errors: Vec<APIErr>) -> Result<(), APIErr> {
         if errors.is_empty() {
@@ -121,8 +401,11 @@ pub trait API {
 
     fn write_field(&self, path: PathBuf, value: String, t: &str) -> Result<(), APIErr>;
     fn read_field(&self, path: std::path::PathBuf) -> Result<Value, APIErr>;
+    /// The root is never written as a whole, so it has no class of its own
+    /// and is always read as an object.
     fn read_collection(&self, path: PathBuf, user: String) -> Result<Value, APIErr> {
         self.read_collection_class(path.clone())
+            .or_else(|err| if path == self.root_path() { Ok(Class::Object) } else { Err(err) })
             .and_then(|class| 
                 match class {
                     Class::Array => self.read_array(path.clone(), user.clone()),
@@ -147,17 +430,34 @@ pub enum Class {
 }
 
 impl API for RestApp {
-    fn root_path(&self) -> Vec<String> {
-        self.storage_dir
-            .split("/")
-            .map(|p|p.to_string())
-            .collect::<Vec<String>>()
+    fn root_path(&self) -> PathBuf {
+        self.storage_dir.clone()
+    }
+    fn limits(&self) -> limits::Limits {
+        self.limits
+    }
+    fn write_lock(&self) -> Arc<RwLock<()>> {
+        self.write_lock.clone()
+    }
+    fn admins(&self) -> Vec<String> {
+        self.admins.as_ref().clone()
+    }
+    fn changes(&self) -> Option<Arc<changes::ChangeFeed>> {
+        self.changes.clone()
     }
     fn ensure_path(&self, path: PathBuf) -> Result<(), APIErr> {
         fs::create_dir_all(path)
             .map_err(APIErr::IO)
     }
 
+    fn remove_path(&self, path: PathBuf) -> Result<(), APIErr> {
+        if path.is_dir() {
+            fs::remove_dir_all(path)
+        } else {
+            fs::remove_file(path)
+        }.map_err(APIErr::IO)
+    }
+
     fn write_field(&self, path: PathBuf, value: String, t: &str) -> Result<(), APIErr>{
         fs::File::create(path)
             .and_then(|mut f| f.write_all(format!("{}\n{}\n", t, value).as_bytes()))
@@ -218,6 +518,9 @@ impl API for RestApp {
         fs::read_dir(path.clone())
             .map(|read_dir| read_dir
                 .into_iter()
+                .filter(|f| f.as_ref()
+                    .map(|dir_entry| self.is_visible(&dir_entry.path()))
+                    .unwrap_or(true))
                 .map(|f| f
                     .map_err(|err| APIErr::IO(err))
                     .map(|dir_entry| {
@@ -247,6 +550,9 @@ impl API for RestApp {
         fs::read_dir(path.clone())
             .map(|read_dir| read_dir
                 .into_iter()
+                .filter(|f| f.as_ref()
+                    .map(|dir_entry| self.is_visible(&dir_entry.path()))
+                    .unwrap_or(true))
                 .map(|f| f
                     .map_err(|err| APIErr::IO(err))
                     .map(|dir_entry| {
@@ -0,0 +1,196 @@
+use std::fs;
+use std::io::BufRead;
+use std::path::PathBuf;
+use std::vec;
+
+use super::serde_json;
+use super::serde_json::{Map, Value};
+use super::{API, APIErr, Class, CLASS_FILE_NAME};
+use super::expiry;
+use super::stream::CHUNK_SIZE;
+
+/// One line of an import or export: a document and where it lives,
+/// relative to the prefix being imported into or exported from.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Record {
+    pub path: Vec<String>,
+    pub value: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct LineResult {
+    pub line: usize,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Applies every record read from `input` below `prefix`. Each line succeeds
+/// or fails on its own; blank lines are skipped.
+pub fn import<T: API, R: BufRead>(app: &T, prefix: Vec<String>, input: R, user: String) -> Vec<LineResult> {
+    input.lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line
+            .map_err(APIErr::IO)
+            .and_then(|line| if line.trim().is_empty() {
+                Ok(())
+            } else {
+                serde_json::from_str::<Record>(line.as_str())
+                    .map_err(|err| APIErr::Decode(err.to_string()))
+                    .and_then(|record| {
+                        let path = [prefix.clone(), record.path].concat();
+                        // Exports of the whole tree open with the root, which
+                        // is always there as an object.
+                        if path.is_empty() && record.value == Value::Object(Map::new()) {
+                            Ok(())
+                        } else {
+                            app.put_with_ttl(path, record.value, record.ttl, user.clone())
+                        }
+                    })
+            })))
+        .map(|(line, result)| LineResult {
+            line,
+            ok: result.is_ok(),
+            error: result.err().map(|err| err.to_string()),
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportMode {
+    /// One record per field, preceded by an empty record for every
+    /// collection so that arrays and empty collections survive a restore.
+    Leaves,
+    /// One record per direct member of the prefix, holding its whole value,
+    /// after an empty record for the prefix itself.
+    Documents,
+}
+
+impl ExportMode {
+    pub fn from_param(param: Option<&str>) -> ExportMode {
+        match param {
+            Some("documents") => ExportMode::Documents,
+            _ => ExportMode::Leaves,
+        }
+    }
+}
+
+/// Writes the records under a prefix as NDJSON, a chunk at a time.
+pub struct ExportStream<T: API> {
+    app: T,
+    mode: ExportMode,
+    user: String,
+    root: Option<PathBuf>,
+    stack: Vec<vec::IntoIter<(Vec<String>, PathBuf)>>,
+    failed: bool,
+}
+
+pub fn export<T: API>(app: T, prefix: Vec<String>, mode: ExportMode, user: String) -> Result<ExportStream<T>, APIErr> {
+    let mut path_buf = app.root_path();
+    prefix.clone().into_iter().for_each(|p| path_buf.push(p));
+    app.ttl(prefix, user.clone())
+        .and_then(|_| fs::metadata(path_buf.clone()).map_err(APIErr::IO))
+        .map(|_| ExportStream { app, mode, user, root: Some(path_buf), stack: Vec::new(), failed: false })
+}
+
+impl<T: API> ExportStream<T> {
+    fn list_entries(&self, path: Vec<String>, path_buf: PathBuf) -> Result<Vec<(Vec<String>, PathBuf)>, APIErr> {
+        fs::read_dir(path_buf)
+            .map_err(APIErr::IO)
+            .and_then(|read_dir| read_dir
+                .map(|entry| entry.map_err(APIErr::IO).map(|e| e.path()))
+                .collect::<Result<Vec<PathBuf>, APIErr>>())
+            .map(|paths| {
+                let mut entries = paths
+                    .into_iter()
+                    .filter(|p| self.app.is_visible(p))
+                    .flat_map(|p| p.file_name()
+                        .and_then(|os_str| os_str.to_str())
+                        .map(String::from)
+                        .map(|name| (name, p.clone())))
+                    .filter(|(name, _)| name.as_str() != CLASS_FILE_NAME)
+                    .map(|(name, p)| ([path.clone(), [name].to_vec()].concat(), p))
+                    .collect::<Vec<(Vec<String>, PathBuf)>>();
+                entries.sort_by(|(name, _), (name2, _)| name.cmp(name2));
+                entries
+            })
+    }
+
+    fn write_record(&self, path: Vec<String>, path_buf: PathBuf, value: Value, out: &mut Vec<u8>) -> Result<(), APIErr> {
+        self.app.read_expiry(path_buf)
+            .map(|expires_at| Record { path, value, ttl: expires_at.map(expiry::remaining) })
+            .and_then(|record| serde_json::to_vec(&record).map_err(|err| APIErr::Encode(err.to_string())))
+            .map(|bytes| {
+                out.extend_from_slice(bytes.as_slice());
+                out.push(b'\n');
+            })
+    }
+
+    /// Records an empty collection, which a restore writes before its members.
+    fn open_collection(&mut self, path: Vec<String>, path_buf: PathBuf, out: &mut Vec<u8>) -> Result<(), APIErr> {
+        let shell = match self.app.read_collection_class(path_buf.clone())? {
+            Class::Array => Value::Array(Vec::new()),
+            _ => Value::Object(Map::new())
+        };
+        self.write_record(path.clone(), path_buf.clone(), shell, out)?;
+        let entries = self.list_entries(path, path_buf)?;
+        self.stack.push(entries.into_iter());
+        Ok(())
+    }
+
+    fn visit(&mut self, path: Vec<String>, path_buf: PathBuf, out: &mut Vec<u8>) -> Result<(), APIErr> {
+        match self.mode {
+            ExportMode::Documents if !path.is_empty() || !path_buf.is_dir() => {
+                let value = self.app.read_path(path_buf.clone(), self.user.clone())?;
+                self.write_record(path, path_buf, value, out)
+            },
+            ExportMode::Leaves if !path_buf.is_dir() => {
+                let value = self.app.read_field(path_buf.clone())?;
+                self.write_record(path, path_buf, value, out)
+            },
+            _ => self.open_collection(path, path_buf, out)
+        }
+    }
+
+    fn step(&mut self, out: &mut Vec<u8>) -> Result<(), APIErr> {
+        let next = match self.stack.last_mut() {
+            Some(entries) => entries.next(),
+            None => return Ok(())
+        };
+        match next {
+            Some((path, path_buf)) => self.visit(path, path_buf, out),
+            None => {
+                self.stack.pop();
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<T: API> Iterator for ExportStream<T> {
+    type Item = Result<Vec<u8>, APIErr>;
+
+    fn next(&mut self) -> Option<Result<Vec<u8>, APIErr>> {
+        if self.failed {
+            return None;
+        }
+        let mut out = Vec::with_capacity(CHUNK_SIZE);
+        let mut result = match self.root.take() {
+            Some(root) => self.visit(Vec::new(), root, &mut out),
+            None => Ok(())
+        };
+        while result.is_ok() && !self.stack.is_empty() && out.len() < CHUNK_SIZE {
+            result = self.step(&mut out);
+        }
+        match result {
+            Err(err) => {
+                self.failed = true;
+                Some(Err(err))
+            },
+            Ok(()) if out.is_empty() => None,
+            Ok(()) => Some(Ok(out))
+        }
+    }
+}
@@ -0,0 +1,318 @@
+extern crate serde_json;
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use self::serde_json::Value;
+use super::{check_segments, expiry, is_server_state, snapshot, tenant, API, APIErr, Operation, CLASS_FILE_NAME, OBJECT_TYPE};
+use super::changes::{Batch, Change, CHANGES_PATH};
+use super::super::server::http::client;
+
+/// A follower is reported on at `/_replication`, and keeps how far it got
+/// in `<storage>/_replication/position`.
+pub const REPLICATION_PATH: &str = "_replication";
+const POSITION_FILE: &str = "position";
+/// The principal changes are applied as. Only the `API` the replication
+/// thread is given lists it among the administrators.
+pub const USER: &str = "_replication";
+
+/// Which primary to follow, and how.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    /// The primary's base URL, such as `http://primary:3000`.
+    pub primary: String,
+    /// Sent as the `Authorization` header of every request to the primary,
+    /// whose credentials must be an administrator's to take snapshots.
+    pub authorization: Option<String>,
+    /// How long each request for changes waits for one to be made.
+    pub poll: Duration,
+    /// How long to wait before trying again after a failure.
+    pub retry: Duration,
+    /// How long the primary may take to answer, on top of `poll`.
+    pub timeout: Duration,
+}
+
+/// The last of the primary's changes that has been applied.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct Position {
+    primary: String,
+    seq: u64,
+}
+
+/// How far a follower is behind its primary, as served at `/_replication`.
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct Status {
+    pub primary: String,
+    pub position: u64,
+    /// The primary's last sequence number when it was last heard from.
+    pub primary_seq: u64,
+    /// How many of those changes have not been applied yet.
+    pub lag: u64,
+    /// When the follower was last known to have applied every change, and
+    /// how many seconds ago that was.
+    pub synced_at: Option<u64>,
+    pub lag_secs: Option<u64>,
+    /// How many times the tree was replaced with a snapshot of the primary.
+    pub resyncs: u64,
+    pub last_error: Option<String>,
+}
+
+/// A follower's side of replication, shared by the thread that pulls
+/// changes and the requests that ask how it is doing.
+#[derive(Debug)]
+pub struct Replica {
+    settings: Settings,
+    status: Mutex<Status>,
+    resync: AtomicBool,
+}
+
+impl Replica {
+    pub fn new(settings: Settings) -> Replica {
+        let status = Status { primary: settings.primary.clone(), ..Status::default() };
+        Replica { settings, status: Mutex::new(status), resync: AtomicBool::new(false) }
+    }
+
+    pub fn primary(&self) -> &str {
+        self.settings.primary.as_str()
+    }
+
+    pub fn status(&self) -> Status {
+        let mut status = self.lock().clone();
+        status.lag = status.primary_seq.saturating_sub(status.position);
+        status.lag_secs = status.synced_at.map(|synced_at| expiry::now().saturating_sub(synced_at));
+        status
+    }
+
+    /// Has the tree replaced with a snapshot of the primary once the
+    /// request for changes in progress has been answered.
+    pub fn request_resync(&self) {
+        self.resync.store(true, Ordering::SeqCst);
+    }
+
+    fn lock(&self) -> ::std::sync::MutexGuard<'_, Status> {
+        self.status.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn synced(&self, position: u64, primary_seq: u64, at: u64) {
+        let mut status = self.lock();
+        status.position = position;
+        status.primary_seq = primary_seq;
+        status.synced_at = Some(at);
+        status.last_error = None;
+    }
+}
+
+fn position_path<T: API + ?Sized>(app: &T) -> PathBuf {
+    let mut path = app.root_path();
+    path.push(REPLICATION_PATH);
+    path.push(POSITION_FILE);
+    path
+}
+
+/// Whether `path` is the directory holding a follower's position, which
+/// is never listed as part of the tree around it.
+pub fn is_replication_path<T: API + ?Sized>(app: &T, path: &Path) -> bool {
+    position_path(app).parent() == Some(path)
+}
+
+/// The position recorded for `primary`. A position recorded while
+/// following another primary is worthless.
+fn load_position<T: API + ?Sized>(app: &T, primary: &str) -> Option<u64> {
+    fs::read(position_path(app)).ok()
+        .and_then(|content| serde_json::from_slice::<Position>(content.as_slice()).ok())
+        .filter(|position| position.primary == primary)
+        .map(|position| position.seq)
+}
+
+/// Writes the position to a temporary file first, so that a crash leaves
+/// either the old position or the new one.
+fn store_position<T: API + ?Sized>(app: &T, primary: &str, seq: u64) -> Result<(), APIErr> {
+    let path = position_path(app);
+    let temporary = path.with_extension("tmp");
+    let position = serde_json::to_vec(&Position { primary: String::from(primary), seq })
+        .map_err(|err| APIErr::Encode(err.to_string()))?;
+    path.parent().map_or(Ok(()), fs::create_dir_all)
+        .and_then(|()| fs::write(&temporary, position))
+        .and_then(|()| fs::rename(&temporary, &path))
+        .map_err(APIErr::IO)
+}
+
+fn authorization(settings: &Settings) -> Vec<(&str, String)> {
+    settings.authorization.iter()
+        .map(|authorization| ("authorization", authorization.clone()))
+        .collect()
+}
+
+fn fetch(settings: &Settings, target: &str, timeout: Duration) -> Result<client::Response, APIErr> {
+    let url = format!("{}/{}", settings.primary.trim_end_matches('/'), target);
+    client::get(url.as_str(), authorization(settings).as_slice(), timeout).map_err(APIErr::IO)
+}
+
+/// Asks the primary for `target`, writing the body of its answer to `out`
+/// as it arrives, up to `limit` bytes.
+fn fetch_into<W: io::Write>(settings: &Settings, target: &str, timeout: Duration, limit: u64, out: &mut W) -> Result<client::Response, APIErr> {
+    let url = format!("{}/{}", settings.primary.trim_end_matches('/'), target);
+    client::exchange("GET", url.as_str(), authorization(settings).as_slice(), &[], timeout, limit, out).map_err(APIErr::IO)
+}
+
+fn unexpected(response: &client::Response, target: &str) -> APIErr {
+    APIErr::IO(io::Error::other(format!("the primary answered {} to GET /{}", response.status, target)))
+}
+
+fn decode_batch(response: &client::Response) -> Result<Batch, APIErr> {
+    serde_json::from_slice(response.body.as_slice()).map_err(|err| APIErr::Decode(err.to_string()))
+}
+
+/// Removes the document at `path` if it has expired. Expired documents are
+/// only hidden until they are swept, which the primary publishes as a
+/// delete that `API::delete` refuses.
+fn remove_expired<T: API + ?Sized>(app: &T, path: &[String]) -> Result<(), APIErr> {
+    let mut path_buf = app.root_path();
+    path.iter().for_each(|p| path_buf.push(p));
+    let lock = app.write_lock();
+    let _guard = lock.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    match app.read_expiry(path_buf.clone())? {
+        Some(t) if expiry::is_expired(t) => app.remove_path(path_buf.clone())
+            .and_then(|()| app.write_expiry(path_buf, None))
+            .map(|()| app.publish(Operation::Delete, path, Value::Null, None)),
+        _ => Ok(())
+    }
+}
+
+/// Makes a change the primary made to its own state, such as its rules or
+/// API keys, which are never written as documents.
+fn apply_state<T: API + ?Sized>(app: &T, path: &[String], change: &Change) -> Result<(), APIErr> {
+    check_segments(path)?;
+    let mut path_buf = app.root_path();
+    path.iter().for_each(|p| path_buf.push(p));
+    let lock = app.write_lock();
+    let _guard = lock.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if path_buf.exists() {
+        app.remove_path(path_buf.clone())?;
+    }
+    match change.operation {
+        Operation::Write => {
+            let parent = path_buf.parent().map(PathBuf::from).unwrap_or_default();
+            let needs_class = parent != app.root_path() && !parent.join(CLASS_FILE_NAME).exists();
+            app.ensure_path(parent.clone())
+                .and_then(|()| if needs_class { app.write_class_file(parent, OBJECT_TYPE) } else { Ok(()) })
+                .and_then(|()| app.write_path(path_buf, change.value.clone(), String::from(USER)))
+                .map(|()| app.publish(Operation::Write, path, change.value.clone(), None))
+        }
+        Operation::Delete => {
+            app.publish(Operation::Delete, path, Value::Null, None);
+            Ok(())
+        }
+        Operation::Read => Ok(())
+    }
+}
+
+/// Makes `change` to the follower's tree, or to the tree of the tenant it
+/// was made for, expiring what the primary expires. Changes may be applied
+/// twice after a crash, so deleting what is already gone is not an error.
+fn apply<T: API + ?Sized>(app: &T, change: &Change) -> Result<(), APIErr> {
+    match change.tenant {
+        Some(ref name) => apply_in(&tenant::Tree::new(app, name.as_str())?, change),
+        None => apply_in(app, change)
+    }
+}
+
+fn apply_in<T: API + ?Sized>(app: &T, change: &Change) -> Result<(), APIErr> {
+    let path = change.path.split('/')
+        .filter(|p| !p.is_empty())
+        .map(String::from)
+        .collect::<Vec<String>>();
+    if is_server_state(&path) {
+        return apply_state(app, &path, change);
+    }
+    match change.operation {
+        Operation::Write => app.put_with_ttl(path, change.value.clone(), change.expires_at.map(expiry::remaining), String::from(USER)),
+        Operation::Delete => app.delete(path.clone(), String::from(USER))
+            .or_else(|err| match err.root_cause() {
+                APIErr::IO(io) if io.kind() == io::ErrorKind::NotFound => remove_expired(app, &path),
+                _ => Err(err)
+            }),
+        Operation::Read => Ok(())
+    }
+}
+
+/// Waits for the changes the primary made after `since` and applies them,
+/// returning the position reached, or `Gone` if the primary no longer
+/// keeps them.
+fn pull<T: API + ?Sized>(app: &T, replica: &Replica, since: u64) -> Result<u64, APIErr> {
+    let settings = &replica.settings;
+    let target = format!("{}?since={}&timeout={}", CHANGES_PATH, since, settings.poll.as_secs());
+    let response = fetch(settings, target.as_str(), settings.poll + settings.timeout)?;
+    let batch = match response.status {
+        200 => decode_batch(&response)?,
+        410 => return Err(APIErr::Gone(since)),
+        _ => return Err(unexpected(&response, target.as_str()))
+    };
+    let answered_at = expiry::now();
+    batch.changes.iter().try_for_each(|change| apply(app, change))?;
+    store_position(app, settings.primary.as_str(), batch.last_seq)?;
+    replica.synced(batch.last_seq, batch.last_seq, answered_at);
+    Ok(batch.last_seq)
+}
+
+/// Replaces the whole tree with a snapshot of the primary, returning the
+/// primary's sequence number from before the snapshot was taken. Changes
+/// after it that the snapshot already holds are applied again.
+fn resync<T: API + Clone>(app: &T, replica: &Replica) -> Result<u64, APIErr> {
+    let settings = &replica.settings;
+    info!("Resyncing from a snapshot of {}", settings.primary);
+    let target = format!("{}?timeout=0", CHANGES_PATH);
+    let response = fetch(settings, target.as_str(), settings.timeout)?;
+    if response.status != 200 {
+        return Err(unexpected(&response, target.as_str()));
+    }
+    let seq = decode_batch(&response)?.last_seq;
+    let answered_at = expiry::now();
+    let target = format!("{}?secrets=true", snapshot::SNAPSHOT_PATH);
+    let mut archive = snapshot::Spool::create(app)?;
+    let response = fetch_into(settings, target.as_str(), settings.timeout, app.limits().max_archive_length as u64, &mut archive)?;
+    if response.status != 200 {
+        return Err(unexpected(&response, target.as_str()));
+    }
+    archive.rewind().map_err(APIErr::IO)?;
+    snapshot::restore(app, Vec::new(), archive, true)?;
+    store_position(app, settings.primary.as_str(), seq)?;
+    replica.lock().resyncs += 1;
+    replica.synced(seq, seq, answered_at);
+    Ok(seq)
+}
+
+fn run<T: API + Clone>(app: &T, replica: &Replica) {
+    let mut position = load_position(app, replica.primary());
+    loop {
+        let requested = replica.resync.swap(false, Ordering::SeqCst);
+        let result = match position {
+            Some(since) if !requested => pull(app, replica, since),
+            _ => resync(app, replica)
+        };
+        match result {
+            Ok(seq) => position = Some(seq),
+            Err(APIErr::Gone(since)) => {
+                warn!("{} no longer keeps the changes after {}", replica.primary(), since);
+                position = None;
+            }
+            Err(err) => {
+                error!("Could not replicate from {}: {}", replica.primary(), err);
+                replica.lock().last_error = Some(err.to_string());
+                thread::sleep(replica.settings.retry);
+            }
+        }
+    }
+}
+
+/// Follows the primary in the background, applying its changes to `app`
+/// as `USER`. Without a recorded position, the tree is replaced with a
+/// snapshot of the primary first.
+pub fn spawn<T: API + Clone + Send + 'static>(app: T, replica: Arc<Replica>) -> thread::JoinHandle<()> {
+    thread::spawn(move || run(&app, &replica))
+}
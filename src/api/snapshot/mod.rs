@@ -0,0 +1,485 @@
+extern crate flate2;
+extern crate tar;
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use self::flate2::Compression;
+use self::flate2::read::GzDecoder;
+use self::flate2::write::GzEncoder;
+use super::serde_json;
+use super::serde_json::Value;
+use super::{API, APIErr, Operation, CLASS_FILE_NAME, EXPIRY_FILE_PREFIX, FORMAT_VERSION};
+use super::{acl, audit, changes, expiry, keys, migrate, replication, stream, tenant, webhooks};
+
+/// Snapshots are taken with `GET` and restored with `PUT` on `/_snapshot`.
+pub const SNAPSHOT_PATH: &str = "_snapshot";
+const MANIFEST_NAME: &str = "manifest.json";
+const DATA_NAME: &str = "data";
+const SPOOL_NAME: &str = "archive";
+pub const STAGING_SUFFIX: &str = ".staging";
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+/// What a server keeps about itself at the top of its tree. It is left out
+/// of archives, and kept when the whole tree is restored.
+const LOCAL_STATE: &[&str] = &[changes::CHANGES_PATH, audit::AUDIT_PATH, replication::REPLICATION_PATH,
+    webhooks::DELIVERIES_PATH];
+/// What only administrators may see: API key hashes, webhook secrets and
+/// dead letters, the access rules and the trees of tenants. Archives of
+/// the whole tree only hold it when asked to, and restoring one without it
+/// keeps the server's own.
+const PRIVILEGED_STATE: &[&str] = &[keys::KEYS_PATH, webhooks::WEBHOOKS_PATH, webhooks::DEAD_LETTERS_PATH,
+    acl::ACL_PATH, tenant::TENANTS_PATH];
+/// How many chunks of an archive being streamed may wait to be sent.
+const CHUNKS_IN_FLIGHT: usize = 4;
+/// Tells apart staging directories made in the same instant.
+static STAGED: AtomicUsize = AtomicUsize::new(0);
+/// The names of the staging directories this process is still working in,
+/// which the counter above keeps apart even across storage roots.
+static IN_USE: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Describes a snapshot archive. It is always the first entry, followed by
+/// the stored tree under `data`.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Manifest {
+    pub format_version: u32,
+    pub prefix: Vec<String>,
+    pub created_at: u64,
+    /// Whether the archive holds the privileged state of the whole tree.
+    #[serde(default)]
+    pub secrets: bool,
+}
+
+fn full_path<T: API>(app: &T, prefix: Vec<String>) -> PathBuf {
+    let mut path_buf = app.root_path();
+    prefix.into_iter().for_each(|p| path_buf.push(p));
+    path_buf
+}
+
+/// Restores are unpacked next to the storage root, so the final rename
+/// stays on one filesystem without ever being visible inside the tree.
+fn staging_path<T: API>(app: &T) -> PathBuf {
+    let root = full_path(app, Vec::new());
+    let name = root.file_name()
+        .and_then(|name| name.to_str())
+        .map(String::from)
+        .unwrap_or_default();
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    let count = STAGED.fetch_add(1, Ordering::SeqCst);
+    let staging = format!("{}{}-{}-{}-{}-{}", name, STAGING_SUFFIX, process::id(), expiry::now(), nanos, count);
+    in_use().push(staging.clone());
+    root.with_file_name(staging)
+}
+
+fn in_use() -> MutexGuard<'static, Vec<String>> {
+    IN_USE.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Removes a staging directory and lets it be cleaned up again.
+fn remove_staging(path: &Path) -> io::Result<()> {
+    let result = fs::remove_dir_all(path);
+    let name = path.file_name().and_then(|name| name.to_str()).unwrap_or("");
+    in_use().retain(|staging| staging != name);
+    result
+}
+
+/// Tells whether a staging directory may still be written to: either this
+/// process is working in it, or the process that made it is still running.
+/// Where running processes cannot be looked up they are all taken to be.
+pub fn is_staging_in_use(path: &Path) -> bool {
+    let name = path.file_name().and_then(|name| name.to_str()).unwrap_or("");
+    if in_use().iter().any(|staging| staging == name) {
+        return true;
+    }
+    let pid = name.rfind(STAGING_SUFFIX)
+        .map(|at| &name[at + STAGING_SUFFIX.len()..])
+        .and_then(|rest| rest.trim_start_matches('-').split('-').next())
+        .and_then(|pid| pid.parse::<u32>().ok());
+    let processes = Path::new("/proc");
+    match pid {
+        Some(pid) if pid == process::id() => false,
+        Some(pid) => !processes.is_dir() || processes.join(pid.to_string()).exists(),
+        None => false
+    }
+}
+
+/// A file that an archive is written to before it is sent, or that an
+/// archive being restored is written to as it is received, so that neither
+/// is held in memory. It sits in a staging directory of its own, which is
+/// removed when the spool is dropped.
+pub struct Spool {
+    dir: PathBuf,
+    file: fs::File,
+}
+
+impl Spool {
+    pub fn create<T: API>(app: &T) -> Result<Spool, APIErr> {
+        let dir = staging_path(app);
+        fs::create_dir_all(&dir)
+            .and_then(|()| fs::OpenOptions::new().read(true).write(true).create_new(true).open(dir.join(SPOOL_NAME)))
+            .map(|file| Spool { dir: dir.clone(), file })
+            .map_err(|err| {
+                remove_staging(&dir).ok();
+                APIErr::IO(err)
+            })
+    }
+
+    /// Goes back to the start, to read what was written.
+    pub fn rewind(&mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(0)).map(|_| ())
+    }
+}
+
+impl Read for Spool {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for Spool {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        if let Err(err) = remove_staging(&self.dir) {
+            warn!("Could not remove {}: {}", self.dir.display(), err);
+        }
+    }
+}
+
+fn is_left_out(name: &str, secrets: bool) -> bool {
+    LOCAL_STATE.contains(&name) || (!secrets && PRIVILEGED_STATE.contains(&name))
+}
+
+/// Appends the whole tree at `path` as `data`, without the local state, and
+/// without the privileged state unless `secrets` is set.
+fn append_root<W: Write>(builder: &mut tar::Builder<W>, path: &Path, secrets: bool) -> io::Result<()> {
+    builder.append_dir(DATA_NAME, path)?;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_name().to_str().is_some_and(|name| is_left_out(name, secrets)) {
+            continue;
+        }
+        let name = Path::new(DATA_NAME).join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            builder.append_dir_all(name, entry.path())?;
+        } else {
+            fs::File::open(entry.path()).and_then(|mut file| builder.append_file(name, &mut file))?;
+        }
+    }
+    Ok(())
+}
+
+fn write_archive<T: API, W: Write>(app: &T, prefix: Vec<String>, out: W, secrets: bool) -> Result<W, APIErr> {
+    let path = full_path(app, prefix.clone());
+    let whole = prefix.is_empty();
+    let secrets = whole && secrets;
+    let manifest = Manifest { format_version: FORMAT_VERSION, prefix, created_at: expiry::now(), secrets };
+    let manifest_bytes = serde_json::to_vec(&manifest).map_err(|err| APIErr::Encode(err.to_string()))?;
+
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest_bytes.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(manifest.created_at);
+    header.set_cksum();
+
+    let mut builder = tar::Builder::new(out);
+    builder.append_data(&mut header, MANIFEST_NAME, manifest_bytes.as_slice())
+        .and_then(|()| if whole && path.is_dir() {
+            append_root(&mut builder, &path, secrets)
+        } else if path.is_dir() {
+            builder.append_dir_all(DATA_NAME, path.clone())
+        } else {
+            fs::File::open(path.clone()).and_then(|mut file| builder.append_file(DATA_NAME, &mut file))
+        })
+        .and_then(|()| builder.into_inner())
+        .map_err(APIErr::IO)
+}
+
+/// Writes a tar archive of everything below `prefix`, gzipped if asked to,
+/// with the privileged state of the whole tree if `secrets` is set.
+pub fn snapshot<T: API, W: Write>(app: &T, prefix: Vec<String>, mut out: W, gzip: bool, secrets: bool) -> Result<(), APIErr> {
+    let mut spool = spool_archive(app, prefix, gzip, secrets)?;
+    io::copy(&mut spool, &mut out)
+        .and_then(|_| out.flush())
+        .map_err(APIErr::IO)
+}
+
+/// Writes the archive to a spool while writes are held off, so that they
+/// wait for the disk, but never for whoever reads the archive.
+fn spool_archive<T: API>(app: &T, prefix: Vec<String>, gzip: bool, secrets: bool) -> Result<Spool, APIErr> {
+    let spool = Spool::create(app)?;
+    let lock = app.write_lock();
+    let guard = lock.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    fs::metadata(full_path(app, prefix.clone())).map_err(APIErr::IO)?;
+    let mut spool = if gzip {
+        write_archive(app, prefix, GzEncoder::new(spool, Compression::default()), secrets)
+            .and_then(|encoder| encoder.finish().map_err(APIErr::IO))
+    } else {
+        write_archive(app, prefix, spool, secrets)
+    }?;
+    drop(guard);
+    spool.rewind().map_err(APIErr::IO)?;
+    Ok(spool)
+}
+
+/// Hands what is written to it over to `ArchiveStream` a chunk at a time.
+struct ChunkWriter {
+    sender: mpsc::SyncSender<Result<Vec<u8>, APIErr>>,
+    buffer: Vec<u8>,
+}
+
+impl ChunkWriter {
+    fn send(&mut self) -> io::Result<()> {
+        let chunk = ::std::mem::replace(&mut self.buffer, Vec::with_capacity(stream::CHUNK_SIZE));
+        self.sender.send(Ok(chunk))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the snapshot is no longer read"))
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(bytes);
+        if self.buffer.len() >= stream::CHUNK_SIZE {
+            self.send()?;
+        }
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() { Ok(()) } else { self.send() }
+    }
+}
+
+/// The chunks of an archive written by `snapshot` on a thread of its own.
+pub type ArchiveStream = mpsc::IntoIter<Result<Vec<u8>, APIErr>>;
+
+/// Like `snapshot`, but hands the archive out a chunk at a time as it is
+/// written, instead of holding all of it. Fails up front if there is
+/// nothing at `prefix`; later failures end the stream with an error.
+pub fn snapshot_stream<T: API + Clone + Send + 'static>(app: &T, prefix: Vec<String>, gzip: bool, secrets: bool) -> Result<ArchiveStream, APIErr> {
+    fs::metadata(full_path(app, prefix.clone())).map_err(APIErr::IO)?;
+    let (sender, receiver) = mpsc::sync_channel(CHUNKS_IN_FLIGHT);
+    let app = app.clone();
+    thread::spawn(move || {
+        let out = ChunkWriter { sender: sender.clone(), buffer: Vec::with_capacity(stream::CHUNK_SIZE) };
+        if let Err(err) = snapshot(&app, prefix, out, gzip, secrets) {
+            sender.send(Err(err)).ok();
+        }
+    });
+    Ok(receiver.into_iter())
+}
+
+fn read_manifest(staging: &Path) -> Result<Manifest, APIErr> {
+    fs::File::open(staging.join(MANIFEST_NAME))
+        .map_err(APIErr::IO)
+        .and_then(|file| serde_json::from_reader::<_, Manifest>(file)
+            .map_err(|err| APIErr::Decode(err.to_string())))
+        .and_then(|manifest| if manifest.format_version == FORMAT_VERSION {
+            Ok(manifest)
+        } else {
+            Err(APIErr::Decode(format!("unsupported format version {}", manifest.format_version)))
+        })
+}
+
+/// Reads the whole unpacked tree once, so a damaged archive is rejected
+/// before anything is replaced. The root of a whole tree has no class of
+/// its own, so its entries are read one by one instead.
+fn validate<T: API + Clone>(app: &T, data: PathBuf, whole: bool) -> Result<(), APIErr> {
+    fs::metadata(data.clone()).map_err(|_| APIErr::Decode(String::from("archive has no data")))?;
+    let documents = if whole {
+        fs::read_dir(&data)
+            .and_then(|entries| entries.map(|entry| entry.map(|entry| entry.path())).collect::<io::Result<Vec<PathBuf>>>())
+            .map_err(APIErr::IO)?
+            .into_iter()
+            .filter(|path| app.is_visible(path) && path.file_name().is_some_and(|name| name != CLASS_FILE_NAME))
+            .collect()
+    } else {
+        vec![data]
+    };
+    documents.into_iter().try_for_each(|document| stream::stream_path(app.clone(), document)
+        .try_for_each(|chunk| chunk.map(|_| ())))
+}
+
+/// Replaces everything below `prefix` with the contents of a snapshot archive
+/// of that same prefix, gzipped or not. The archive is unpacked and checked in
+/// a staging directory first; the old tree is then renamed out of the way and
+/// the new one renamed into place while writes are held off. Archives with
+/// privileged state are refused unless `secrets` allows them, and whatever
+/// privileged state an archive without it holds anyway is left out.
+pub fn restore<T: API + Clone, R: Read>(app: &T, prefix: Vec<String>, input: R, secrets: bool) -> Result<Manifest, APIErr> {
+    let mut reader = BufReader::new(input);
+    let gzipped = reader.fill_buf()
+        .map(|head| head.starts_with(&GZIP_MAGIC))
+        .map_err(APIErr::IO)?;
+    let reader: Box<Read> = if gzipped { Box::new(GzDecoder::new(reader)) } else { Box::new(reader) };
+
+    let staging = staging_path(app);
+    let result = fs::create_dir_all(staging.clone())
+        .map_err(APIErr::IO)
+        .and_then(|()| unpack(reader, &staging))
+        .and_then(|()| read_manifest(&staging))
+        .and_then(|manifest| if manifest.prefix == prefix {
+            Ok(manifest)
+        } else {
+            Err(APIErr::Decode(format!("the archive holds /{}, not /{}", manifest.prefix.join("/"), prefix.join("/"))))
+        })
+        .and_then(|manifest| if manifest.secrets && !secrets {
+            info!("Refused to restore an archive with privileged state");
+            Err(APIErr::Forbidden)
+        } else {
+            Ok(manifest)
+        })
+        .and_then(|manifest| validate(app, staging.join(DATA_NAME), prefix.is_empty()).map(|()| manifest))
+        .and_then(|manifest| swap(app, prefix, &staging, manifest.secrets).map(|()| manifest));
+    let cleanup = remove_staging(&staging).map_err(APIErr::IO);
+    result.and_then(|manifest| cleanup.map(|()| manifest))
+}
+
+/// Unpacks an archive into `staging`. Only regular files and directories
+/// are unpacked, and only below `staging`: anything else fails the restore.
+fn unpack<R: Read>(reader: R, staging: &Path) -> Result<(), APIErr> {
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries().map_err(APIErr::IO)? {
+        let mut entry = entry.map_err(APIErr::IO)?;
+        let kind = entry.header().entry_type();
+        if kind.is_pax_global_extensions() {
+            continue;
+        }
+        let path = entry.path().map_err(APIErr::IO)?.into_owned();
+        if !kind.is_file() && !kind.is_dir() {
+            return Err(APIErr::Decode(format!("{} is neither a file nor a directory", path.display())));
+        }
+        if !path.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir)) {
+            return Err(APIErr::Decode(format!("{} is not a path inside the archive", path.display())));
+        }
+        entry.unpack_in(staging).map_err(APIErr::IO)?;
+    }
+    Ok(())
+}
+
+fn swap<T: API>(app: &T, prefix: Vec<String>, staging: &Path, secrets: bool) -> Result<(), APIErr> {
+    let target = full_path(app, prefix.clone());
+    let previous = staging.join("previous");
+    let lock = app.write_lock();
+    let _guard = lock.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let parent = target.parent().map(PathBuf::from).unwrap_or_default();
+    if !parent.as_os_str().is_empty() {
+        if prefix.is_empty() {
+            fs::create_dir_all(parent.clone()).map_err(APIErr::IO)?;
+        }
+        fs::metadata(parent).map_err(APIErr::IO)?;
+    }
+    let replaced = target.exists();
+    if replaced {
+        fs::rename(target.clone(), previous.clone()).map_err(APIErr::IO)?;
+    }
+    fs::rename(staging.join(DATA_NAME), target.clone())
+        .map_err(|err| {
+            if replaced {
+                fs::rename(previous.clone(), target.clone()).ok();
+            }
+            APIErr::IO(err)
+        })
+        .and_then(|()| app.write_expiry(target.clone(), None))
+        .and_then(|()| if prefix.is_empty() {
+            keep_local_state(&previous, &target, secrets)
+                .and_then(|()| migrate::write_version(app, &target, FORMAT_VERSION))
+        } else {
+            Ok(())
+        })
+        .and_then(|()| publish_restore(app, &prefix, &previous, secrets))
+}
+
+fn entry_names(dir: &Path) -> Result<BTreeSet<String>, APIErr> {
+    if !dir.is_dir() {
+        return Ok(BTreeSet::new());
+    }
+    fs::read_dir(dir)
+        .and_then(|entries| entries
+            .map(|entry| entry.map(|entry| entry.file_name().to_string_lossy().into_owned()))
+            .collect())
+        .map_err(APIErr::IO)
+}
+
+/// Publishes what a restore replaced, so that followers and webhooks see it
+/// too: the restored prefix as a write, or for the whole tree, every
+/// document at the top as a write and every one that is gone as a delete.
+/// The rules and the API keys are only published when the archive held them.
+fn publish_restore<T: API>(app: &T, prefix: &[String], previous: &Path, secrets: bool) -> Result<(), APIErr> {
+    if app.changes().is_none() {
+        return Ok(());
+    }
+    let target = full_path(app, prefix.to_vec());
+    if !prefix.is_empty() {
+        let value = app.read_path(target, String::new())?;
+        app.publish(Operation::Write, prefix, value, None);
+        return Ok(());
+    }
+    let is_published = |name: &String| if name.starts_with('_') {
+        secrets && (name == acl::ACL_PATH || name == keys::KEYS_PATH)
+    } else {
+        name != CLASS_FILE_NAME && name != migrate::VERSION_FILE_NAME && !name.starts_with(EXPIRY_FILE_PREFIX)
+    };
+    let mut names = entry_names(previous)?;
+    names.extend(entry_names(&target)?);
+    names.into_iter().filter(is_published).try_for_each(|name| {
+        let path = target.join(name.as_str());
+        let document = [name];
+        if path.exists() {
+            let expires_at = app.read_expiry(path.clone())?;
+            app.read_path(path, String::new())
+                .map(|value| app.publish(Operation::Write, &document, value, expires_at))
+        } else {
+            app.publish(Operation::Delete, &document, Value::Null, None);
+            Ok(())
+        }
+    })
+}
+
+/// Moves the local state from the tree that was replaced into `target`,
+/// along with the privileged state unless the archive held its own. What
+/// the archive held of either in their place is dropped.
+fn keep_local_state(previous: &Path, target: &Path, secrets: bool) -> Result<(), APIErr> {
+    LOCAL_STATE.iter()
+        .chain(PRIVILEGED_STATE.iter().filter(|_| !secrets))
+        .try_for_each(|name| {
+            let restored = target.join(name);
+            if restored.is_dir() {
+                fs::remove_dir_all(&restored)?;
+            } else if restored.exists() {
+                fs::remove_file(&restored)?;
+            }
+            if previous.join(name).exists() {
+                fs::rename(previous.join(name), restored)
+            } else {
+                Ok(())
+            }
+        })
+        .map_err(APIErr::IO)
+}
+
+pub fn is_gzip_param(param: Option<&str>) -> bool {
+    param != Some("tar")
+}
+
+/// Whether `?secrets=true` asked for the privileged state to be archived.
+pub fn is_secrets_param(param: Option<&str>) -> bool {
+    param == Some("true")
+}
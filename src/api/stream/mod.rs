@@ -0,0 +1,150 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::vec;
+
+use super::serde_json;
+use super::{API, APIErr, Class, CLASS_FILE_NAME};
+
+/// Output is handed out in chunks of roughly this many bytes.
+pub const CHUNK_SIZE: usize = 8 * 1024;
+
+struct Frame {
+    class: Class,
+    entries: vec::IntoIter<(String, PathBuf)>,
+    first: bool,
+}
+
+/// Serializes the document at a path one chunk at a time while walking the
+/// storage tree, holding at most one directory listing per level of nesting.
+/// The output is byte for byte what `API::get` followed by `to_string` gives.
+pub struct JsonStream<T: API> {
+    app: T,
+    root: Option<PathBuf>,
+    stack: Vec<Frame>,
+    failed: bool,
+}
+
+/// Drains a stream of chunks into `write`, for engines that write bodies directly.
+pub fn write_chunks<I: Iterator<Item=Result<Vec<u8>, APIErr>>>(chunks: I, write: &mut Write) -> io::Result<()> {
+    for chunk in chunks {
+        chunk
+            .map_err(|err| io::Error::other(err.to_string()))
+            .and_then(|bytes| write.write_all(bytes.as_slice()))
+            .and_then(|()| write.flush())?;
+    }
+    Ok(())
+}
+
+/// Streams the document at `path`, failing up front rather than halfway
+/// through the body when it is missing, expired, or a collection whose
+/// class cannot be read.
+pub fn read_stream<T: API>(app: T, path: Vec<String>, user: String) -> Result<JsonStream<T>, APIErr> {
+    let mut path_buf = app.root_path();
+    path.clone().into_iter().for_each(|p| path_buf.push(p));
+    app.ttl(path, user)
+        .and_then(|_| fs::metadata(path_buf.clone()).map_err(APIErr::IO))
+        .and_then(|meta| if meta.is_dir() {
+            app.read_collection_class(path_buf.clone()).map(|_| ())
+        } else {
+            Ok(())
+        })
+        .map(|()| stream_path(app, path_buf))
+}
+
+/// Streams whatever is stored at `path`, without the expiry checks of `read_stream`.
+pub fn stream_path<T: API>(app: T, path: PathBuf) -> JsonStream<T> {
+    JsonStream { app, root: Some(path), stack: Vec::new(), failed: false }
+}
+
+impl<T: API> JsonStream<T> {
+    fn list_entries(&self, path: PathBuf) -> Result<Vec<(String, PathBuf)>, APIErr> {
+        fs::read_dir(path)
+            .map_err(APIErr::IO)
+            .and_then(|read_dir| read_dir
+                .map(|entry| entry.map_err(APIErr::IO).map(|e| e.path()))
+                .collect::<Result<Vec<PathBuf>, APIErr>>())
+            .map(|paths| {
+                let mut entries = paths
+                    .into_iter()
+                    .filter(|p| self.app.is_visible(p))
+                    .flat_map(|p| p.file_name()
+                        .and_then(|os_str| os_str.to_str())
+                        .map(String::from)
+                        .map(|name| (name, p.clone())))
+                    .filter(|(name, _)| name.as_str() != CLASS_FILE_NAME)
+                    .collect::<Vec<(String, PathBuf)>>();
+                entries.sort_by(|(name, _), (name2, _)| name.cmp(name2));
+                entries
+            })
+    }
+
+    /// Writes a leaf in full, or the opening bracket of a collection whose
+    /// members are then written as the stack unwinds.
+    fn open(&mut self, path: PathBuf, out: &mut Vec<u8>) -> Result<(), APIErr> {
+        if path.is_dir() {
+            let class = self.app.read_collection_class(path.clone())?;
+            let entries = self.list_entries(path)?;
+            out.push(if class == Class::Array { b'[' } else { b'{' });
+            self.stack.push(Frame { class, entries: entries.into_iter(), first: true });
+            Ok(())
+        } else {
+            self.app.read_field(path)
+                .map(|value| out.extend_from_slice(value.to_string().as_bytes()))
+        }
+    }
+
+    fn step(&mut self, out: &mut Vec<u8>) -> Result<(), APIErr> {
+        let next = match self.stack.last_mut() {
+            Some(frame) => {
+                let next = frame.entries.next().map(|entry| (entry, frame.first, frame.class == Class::Array));
+                frame.first = false;
+                next
+            },
+            None => return Ok(())
+        };
+        match next {
+            Some(((name, path), first, is_array)) => {
+                if !first {
+                    out.push(b',');
+                }
+                if !is_array {
+                    out.extend_from_slice(serde_json::to_string(&name).unwrap_or_default().as_bytes());
+                    out.push(b':');
+                }
+                self.open(path, out)
+            },
+            None => {
+                let frame = self.stack.pop();
+                out.push(if frame.map(|f| f.class == Class::Array).unwrap_or(false) { b']' } else { b'}' });
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<T: API> Iterator for JsonStream<T> {
+    type Item = Result<Vec<u8>, APIErr>;
+
+    fn next(&mut self) -> Option<Result<Vec<u8>, APIErr>> {
+        if self.failed {
+            return None;
+        }
+        let mut out = Vec::with_capacity(CHUNK_SIZE);
+        let mut result = match self.root.take() {
+            Some(root) => self.open(root, &mut out),
+            None => Ok(())
+        };
+        while result.is_ok() && !self.stack.is_empty() && out.len() < CHUNK_SIZE {
+            result = self.step(&mut out);
+        }
+        match result {
+            Err(err) => {
+                self.failed = true;
+                Some(Err(err))
+            },
+            Ok(()) if out.is_empty() => None,
+            Ok(()) => Some(Ok(out))
+        }
+    }
+}
@@ -0,0 +1,187 @@
+extern crate serde_json;
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use self::serde_json::{json, Value};
+use super::{changes, limits, Class, API, APIErr, Operation, OBJECT_TYPE};
+
+/// Every tenant's tree lives in `<storage>/_tenants/<name>`.
+pub const TENANTS_PATH: &str = "_tenants";
+const MAX_NAME_LENGTH: usize = 64;
+
+/// How requests are assigned to tenants.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Tenancy {
+    /// Every request sees the whole storage directory.
+    #[default]
+    Off,
+    /// Each principal is its own tenant.
+    PerUser,
+    /// The tenant is named by this request header, in lower case.
+    PerHeader(String),
+}
+
+impl Tenancy {
+    pub fn from_config(mode: &str, header: &str) -> Option<Tenancy> {
+        match mode {
+            "off" => Some(Tenancy::Off),
+            "user" => Some(Tenancy::PerUser),
+            "header" => Some(Tenancy::PerHeader(header.to_lowercase())),
+            _ => None
+        }
+    }
+}
+
+/// Tenant names become a single directory name, so they are limited to
+/// letters, digits, `-`, `_` and `.`, and may not start with `_` or `.`.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= MAX_NAME_LENGTH &&
+        !name.starts_with('_') && !name.starts_with('.') &&
+        name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+fn tenants_dir<T: API + ?Sized>(app: &T) -> PathBuf {
+    let mut path = app.root_path();
+    path.push(TENANTS_PATH);
+    path
+}
+
+fn tenant_dir<T: API + ?Sized>(app: &T, name: &str) -> Result<PathBuf, APIErr> {
+    if is_valid_name(name) {
+        Ok(tenants_dir(app).join(name))
+    } else {
+        Err(APIErr::IO(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a valid tenant name", name))))
+    }
+}
+
+/// The tenant whose tree is stored at `root`, if it is a tenant's.
+pub fn name_of(root: &Path) -> Option<String> {
+    let name = root.file_name()?.to_str()?;
+    let parent = root.parent()?;
+    Some(String::from(name)).filter(|name| parent.file_name().is_some_and(|dir| dir == TENANTS_PATH) && is_valid_name(name))
+}
+
+/// The tree of a tenant of `app`, for what is given the whole storage
+/// directory, such as a follower applying its primary's changes.
+pub struct Tree<'a, T: API + ?Sized + 'a> {
+    app: &'a T,
+    root: PathBuf,
+}
+
+impl<'a, T: API + ?Sized> Tree<'a, T> {
+    pub fn new(app: &'a T, name: &str) -> Result<Tree<'a, T>, APIErr> {
+        tenant_dir(app, name).map(|root| Tree { app, root })
+    }
+}
+
+impl<'a, T: API + ?Sized> API for Tree<'a, T> {
+    fn root_path(&self) -> PathBuf {
+        self.root.clone()
+    }
+
+    fn limits(&self) -> limits::Limits {
+        self.app.limits()
+    }
+
+    fn write_lock(&self) -> Arc<RwLock<()>> {
+        self.app.write_lock()
+    }
+
+    fn admins(&self) -> Vec<String> {
+        self.app.admins()
+    }
+
+    fn changes(&self) -> Option<Arc<changes::ChangeFeed>> {
+        self.app.changes()
+    }
+
+    fn ensure_path(&self, path: PathBuf) -> Result<(), APIErr> {
+        self.app.ensure_path(path)
+    }
+
+    fn remove_path(&self, path: PathBuf) -> Result<(), APIErr> {
+        self.app.remove_path(path)
+    }
+
+    fn write_field(&self, path: PathBuf, value: String, t: &str) -> Result<(), APIErr> {
+        self.app.write_field(path, value, t)
+    }
+
+    fn read_field(&self, path: PathBuf) -> Result<Value, APIErr> {
+        self.app.read_field(path)
+    }
+
+    fn read_object(&self, path: PathBuf, user: String) -> Result<Value, APIErr> {
+        self.app.read_object(path, user)
+    }
+
+    fn read_array(&self, path: PathBuf, user: String) -> Result<Value, APIErr> {
+        self.app.read_array(path, user)
+    }
+
+    fn read_collection_class(&self, path: PathBuf) -> Result<Class, APIErr> {
+        self.app.read_collection_class(path)
+    }
+}
+
+/// Whether `path` is the directory holding the tenants, which is never
+/// listed as part of the tree around it.
+pub fn is_tenants_path<T: API + ?Sized>(app: &T, path: &Path) -> bool {
+    path == tenants_dir(app).as_path()
+}
+
+pub fn exists<T: API + ?Sized>(app: &T, name: &str) -> bool {
+    tenant_dir(app, name).map(|dir| dir.is_dir()).unwrap_or(false)
+}
+
+pub fn list<T: API + ?Sized>(app: &T) -> Result<Vec<String>, APIErr> {
+    let dir = tenants_dir(app);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut names = fs::read_dir(dir)
+        .map_err(APIErr::IO)?
+        .map(|entry| entry.map_err(APIErr::IO).map(|e| e.path()))
+        .collect::<Result<Vec<PathBuf>, APIErr>>()?
+        .into_iter()
+        .filter(|p| p.is_dir())
+        .flat_map(|p| p.file_name().and_then(|name| name.to_str()).map(String::from))
+        .filter(|name| is_valid_name(name))
+        .collect::<Vec<String>>();
+    names.sort();
+    Ok(names)
+}
+
+/// Where the tree of tenant `name` is published as created or removed.
+fn tenant_path(name: &str) -> Vec<String> {
+    vec![String::from(TENANTS_PATH), String::from(name)]
+}
+
+/// Creates an empty tree for the tenant `name`.
+pub fn create<T: API + ?Sized>(app: &T, name: &str) -> Result<(), APIErr> {
+    let dir = tenant_dir(app, name)?;
+    let lock = app.write_lock();
+    let _guard = lock.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if dir.exists() {
+        return Err(APIErr::IO(io::Error::new(io::ErrorKind::AlreadyExists, format!("tenant {} already exists", name))));
+    }
+    app.ensure_path(dir.clone())
+        .and_then(|()| app.write_class_file(tenants_dir(app), OBJECT_TYPE))
+        .and_then(|()| app.write_class_file(dir, OBJECT_TYPE))
+        .map(|()| app.publish(Operation::Write, &tenant_path(name), json!({}), None))
+}
+
+/// Removes the tenant `name` along with everything stored for it.
+pub fn drop<T: API + ?Sized>(app: &T, name: &str) -> Result<(), APIErr> {
+    let dir = tenant_dir(app, name)?;
+    let lock = app.write_lock();
+    let _guard = lock.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if !dir.is_dir() {
+        return Err(APIErr::IO(io::Error::new(io::ErrorKind::NotFound, format!("no tenant {}", name))));
+    }
+    app.remove_path(dir)
+        .map(|()| app.publish(Operation::Delete, &tenant_path(name), Value::Null, None))
+}
@@ -0,0 +1,431 @@
+extern crate serde;
+extern crate serde_json;
+
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SendError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use self::serde_json::Value;
+use super::{acl, expiry, tenant, API, APIErr, Operation, OBJECT_TYPE};
+use super::changes::{Change, ChangeFeed};
+use super::super::auth::{self, hash};
+use super::super::server::http::client;
+
+/// Every webhook is a document `<storage>/_webhooks/<id>`, every delivery
+/// still being made one `<storage>/_deliveries/<id>`, and every delivery
+/// that was given up on one `<storage>/_dead_letters/<id>`.
+pub const WEBHOOKS_PATH: &str = "_webhooks";
+pub const DELIVERIES_PATH: &str = "_deliveries";
+pub const DEAD_LETTERS_PATH: &str = "_dead_letters";
+/// `sha256=` and the hex HMAC-SHA-256 of the body under the webhook's
+/// secret.
+pub const SIGNATURE_HEADER: &str = "x-webhook-signature";
+pub const EVENT_HEADER: &str = "x-webhook-event";
+pub const DELIVERY_HEADER: &str = "x-webhook-delivery";
+const ID_LENGTH: usize = 12;
+/// How long the delivery thread waits for changes before looking again.
+const IDLE_SECS: u64 = 60;
+
+/// Where to send which changes below `prefix`, in the storage root or in
+/// the tree of `tenant`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Webhook {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    pub prefix: String,
+    pub url: String,
+    pub events: Vec<Operation>,
+    pub created_at: u64,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    secret: String,
+}
+
+/// What an administrator asks for when registering a webhook.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct NewWebhook {
+    #[serde(default)]
+    pub tenant: Option<String>,
+    #[serde(default)]
+    pub prefix: String,
+    pub url: String,
+    #[serde(default = "all_events")]
+    pub events: Vec<Operation>,
+    pub secret: String,
+}
+
+fn all_events() -> Vec<Operation> {
+    vec![Operation::Write, Operation::Delete]
+}
+
+/// The body POSTed for a change.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Payload {
+    pub delivery: String,
+    pub webhook: String,
+    pub event: Operation,
+    pub seq: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    pub path: String,
+    #[serde(default)]
+    pub value: Value,
+    pub timestamp: u64,
+}
+
+/// A delivery that failed `attempts` times, the last time with `error`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DeadLetter {
+    pub id: String,
+    pub webhook: String,
+    pub url: String,
+    pub attempts: u32,
+    pub error: String,
+    pub failed_at: u64,
+    pub payload: Payload,
+}
+
+/// How deliveries are attempted: up to `max_attempts` times, waiting
+/// `backoff` after the first failure and twice as long after each one
+/// after that.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    pub max_attempts: u32,
+    pub backoff: Duration,
+    pub timeout: Duration,
+}
+
+impl Webhook {
+    /// The webhook as listed to administrators, without its secret.
+    pub fn to_value(&self) -> Value {
+        serde_json::to_value(Webhook { secret: String::new(), ..self.clone() }).unwrap_or(Value::Null)
+    }
+
+    /// Whether `change` is sent to the webhook. Changes to the rules, the
+    /// API keys and the like never are.
+    pub fn matches(&self, change: &Change) -> bool {
+        let prefix = self.prefix.split('/').filter(|p| !p.is_empty()).collect::<Vec<&str>>();
+        let path = change.path.split('/').filter(|p| !p.is_empty()).collect::<Vec<&str>>();
+        self.tenant == change.tenant && self.events.contains(&change.operation) && !acl::is_privileged(&path) &&
+            prefix.len() <= path.len() &&
+            prefix.iter().zip(path.iter()).all(|(a, b)| a == b)
+    }
+}
+
+pub fn signature(secret: &str, body: &[u8]) -> String {
+    format!("sha256={}", hash::to_hex(&hash::hmac_sha256(secret.as_bytes(), body)))
+}
+
+fn dir<T: API + ?Sized>(app: &T, name: &str) -> PathBuf {
+    let mut path = app.root_path();
+    path.push(name);
+    path
+}
+
+fn not_found(what: &str, id: &str) -> APIErr {
+    APIErr::IO(io::Error::new(io::ErrorKind::NotFound, format!("no {} {}", what, id)))
+}
+
+fn document_path<T: API + ?Sized>(app: &T, name: &str, id: &str) -> Option<PathBuf> {
+    if id.len() == ID_LENGTH && id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        Some(dir(app, name).join(id))
+    } else {
+        None
+    }
+}
+
+/// Whether `path` is a directory holding webhooks, deliveries or dead
+/// letters, which is never listed as part of the tree around it.
+pub fn is_webhooks_path<T: API + ?Sized>(app: &T, path: &Path) -> bool {
+    [WEBHOOKS_PATH, DELIVERIES_PATH, DEAD_LETTERS_PATH].iter().any(|name| path == dir(app, name).as_path())
+}
+
+fn read<T: API + ?Sized, D: serde::de::DeserializeOwned>(app: &T, name: &str, what: &str, id: &str) -> Result<D, APIErr> {
+    match document_path(app, name, id) {
+        Some(path) if path.is_dir() => app.read_path(path, String::new())
+            .and_then(|value| serde_json::from_value(value).map_err(|err| APIErr::Decode(err.to_string()))),
+        _ => Err(not_found(what, id))
+    }
+}
+
+/// Every document below `name`, in no particular order.
+fn read_all<T: API + ?Sized, D: serde::de::DeserializeOwned>(app: &T, name: &str, what: &str) -> Result<Vec<D>, APIErr> {
+    let dir = dir(app, name);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    fs::read_dir(dir)
+        .map_err(APIErr::IO)?
+        .map(|entry| entry.map_err(APIErr::IO).map(|e| e.path()))
+        .collect::<Result<Vec<PathBuf>, APIErr>>()?
+        .into_iter()
+        .filter(|p| p.is_dir())
+        .flat_map(|p| p.file_name().and_then(|name| name.to_str()).map(String::from))
+        .map(|id| read(app, name, what, id.as_str()))
+        .collect()
+}
+
+fn store<T: API + ?Sized, D: serde::Serialize>(app: &T, name: &str, id: &str, document: &D) -> Result<(), APIErr> {
+    let path = document_path(app, name, id).ok_or_else(|| not_found(name, id))?;
+    let value = serde_json::to_value(document).map_err(|err| APIErr::Encode(err.to_string()))?;
+    let lock = app.write_lock();
+    let _guard = lock.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    app.ensure_path(dir(app, name))
+        .and_then(|()| app.write_class_file(dir(app, name), OBJECT_TYPE))
+        .and_then(|()| app.write_path(path, value, String::new()))
+}
+
+fn remove<T: API + ?Sized>(app: &T, name: &str, what: &str, id: &str) -> Result<(), APIErr> {
+    let lock = app.write_lock();
+    let _guard = lock.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    match document_path(app, name, id) {
+        Some(path) if path.is_dir() => app.remove_path(path),
+        _ => Err(not_found(what, id))
+    }
+}
+
+pub fn get<T: API + ?Sized>(app: &T, id: &str) -> Result<Webhook, APIErr> {
+    read(app, WEBHOOKS_PATH, "webhook", id)
+}
+
+/// Every webhook, oldest first.
+pub fn list<T: API + ?Sized>(app: &T) -> Result<Vec<Webhook>, APIErr> {
+    let mut webhooks = read_all::<T, Webhook>(app, WEBHOOKS_PATH, "webhook")?;
+    webhooks.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+    Ok(webhooks)
+}
+
+pub fn create<T: API + ?Sized>(app: &T, new: NewWebhook) -> Result<Webhook, APIErr> {
+    let invalid = |detail: String| APIErr::IO(io::Error::new(io::ErrorKind::InvalidInput, detail));
+    client::Url::parse(new.url.as_str()).map_err(invalid)?;
+    if let Some(ref name) = new.tenant.as_ref().filter(|name| !tenant::is_valid_name(name)) {
+        return Err(invalid(format!("{} is not a valid tenant name", name)));
+    }
+    if new.secret.is_empty() {
+        return Err(invalid(String::from("a webhook needs a secret")));
+    }
+    if new.events.is_empty() || new.events.contains(&Operation::Read) {
+        return Err(invalid(String::from("a webhook is for write or delete events, or both")));
+    }
+    let webhook = Webhook {
+        id: auth::new_token()[..ID_LENGTH].to_string(),
+        tenant: new.tenant,
+        prefix: new.prefix,
+        url: new.url,
+        events: new.events,
+        created_at: expiry::now(),
+        secret: new.secret,
+    };
+    store(app, WEBHOOKS_PATH, webhook.id.as_str(), &webhook).map(|()| webhook)
+}
+
+/// Unregisters a webhook. Deliveries already being retried still are.
+pub fn delete<T: API + ?Sized>(app: &T, id: &str) -> Result<(), APIErr> {
+    remove(app, WEBHOOKS_PATH, "webhook", id)
+}
+
+pub fn dead_letter<T: API + ?Sized>(app: &T, id: &str) -> Result<DeadLetter, APIErr> {
+    read(app, DEAD_LETTERS_PATH, "dead letter", id)
+}
+
+/// Every dead letter, oldest first.
+pub fn dead_letters<T: API + ?Sized>(app: &T) -> Result<Vec<DeadLetter>, APIErr> {
+    let mut letters = read_all::<T, DeadLetter>(app, DEAD_LETTERS_PATH, "dead letter")?;
+    letters.sort_by_key(|letter| (letter.failed_at, letter.payload.seq));
+    Ok(letters)
+}
+
+pub fn delete_dead_letter<T: API + ?Sized>(app: &T, id: &str) -> Result<(), APIErr> {
+    remove(app, DEAD_LETTERS_PATH, "dead letter", id)
+}
+
+/// A delivery still to be made. It is kept under `<storage>/_deliveries`
+/// until it succeeds or becomes a dead letter, so that a restart carries on
+/// with it.
+#[derive(Serialize, Deserialize)]
+struct Attempt {
+    #[serde(skip, default = "Instant::now")]
+    due: Instant,
+    attempts: u32,
+    webhook: Webhook,
+    payload: Payload,
+}
+
+impl Attempt {
+    fn new(webhook: &Webhook, change: &Change) -> Attempt {
+        Attempt {
+            due: Instant::now(),
+            attempts: 0,
+            webhook: webhook.clone(),
+            payload: Payload {
+                delivery: auth::new_token()[..ID_LENGTH].to_string(),
+                webhook: webhook.id.clone(),
+                event: change.operation,
+                seq: change.seq,
+                tenant: change.tenant.clone(),
+                path: change.path.clone(),
+                value: change.value.clone(),
+                timestamp: expiry::now(),
+            },
+        }
+    }
+}
+
+fn keep<T: API + ?Sized>(app: &T, attempt: &Attempt) {
+    if let Err(err) = store(app, DELIVERIES_PATH, attempt.payload.delivery.as_str(), attempt) {
+        error!("Could not keep webhook delivery {}: {}", attempt.payload.delivery, err);
+    }
+}
+
+fn forget<T: API + ?Sized>(app: &T, delivery: &str) {
+    if let Err(err) = remove(app, DELIVERIES_PATH, "delivery", delivery) {
+        error!("Could not forget webhook delivery {}: {}", delivery, err);
+    }
+}
+
+/// Makes one attempt, returning the next one if there is to be one.
+fn deliver<T: API + ?Sized>(app: &T, settings: &Settings, attempt: Attempt) -> Option<Attempt> {
+    let body = match serde_json::to_vec(&attempt.payload) {
+        Ok(body) => body,
+        Err(err) => {
+            error!("Could not encode webhook delivery {}: {}", attempt.payload.delivery, err);
+            forget(app, attempt.payload.delivery.as_str());
+            return None;
+        }
+    };
+    let headers = [
+        ("content-type", String::from("application/json")),
+        (SIGNATURE_HEADER, signature(attempt.webhook.secret.as_str(), body.as_slice())),
+        (EVENT_HEADER, attempt.payload.event.to_string()),
+        (DELIVERY_HEADER, attempt.payload.delivery.clone()),
+    ];
+    let error = match client::post(attempt.webhook.url.as_str(), &headers, body.as_slice(), settings.timeout) {
+        Ok(ref response) if response.is_success() => {
+            forget(app, attempt.payload.delivery.as_str());
+            return None;
+        }
+        Ok(response) => format!("the receiver answered {}", response.status),
+        Err(err) => err.to_string()
+    };
+    let attempts = attempt.attempts + 1;
+    if attempts < settings.max_attempts {
+        let delay = settings.backoff * 2u32.saturating_pow(attempts - 1);
+        warn!("Webhook delivery {} to {} failed, retrying in {:?}: {}", attempt.payload.delivery, attempt.webhook.url, delay, error);
+        let retry = Attempt { due: Instant::now() + delay, attempts, ..attempt };
+        keep(app, &retry);
+        return Some(retry);
+    }
+    error!("Giving up on webhook delivery {} to {}: {}", attempt.payload.delivery, attempt.webhook.url, error);
+    let letter = DeadLetter {
+        id: attempt.payload.delivery.clone(),
+        webhook: attempt.webhook.id,
+        url: attempt.webhook.url,
+        attempts,
+        error,
+        failed_at: expiry::now(),
+        payload: attempt.payload,
+    };
+    match store(app, DEAD_LETTERS_PATH, letter.id.as_str(), &letter) {
+        Ok(()) => forget(app, letter.id.as_str()),
+        Err(err) => error!("Could not keep dead letter {}: {}", letter.id, err)
+    }
+    None
+}
+
+/// Makes the deliveries to one webhook in the order they were queued, so
+/// that a slow or failing receiver only holds up its own. Returns once no
+/// more are queued and none are waiting to be retried.
+fn work<T: API + ?Sized>(app: &T, settings: &Settings, queued: &Receiver<Attempt>) {
+    let mut pending: VecDeque<Attempt> = VecDeque::new();
+    loop {
+        let received = match pending.front() {
+            Some(attempt) => queued.recv_timeout(attempt.due.saturating_duration_since(Instant::now())),
+            None => queued.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        };
+        match received {
+            Ok(attempt) => pending.push_back(attempt),
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => match pending.front() {
+                Some(attempt) => thread::sleep(attempt.due.saturating_duration_since(Instant::now())),
+                None => return
+            }
+        }
+        while pending.front().is_some_and(|attempt| attempt.due <= Instant::now()) {
+            if let Some(retry) = pending.pop_front().and_then(|attempt| deliver(app, settings, attempt)) {
+                pending.push_front(retry);
+                break;
+            }
+        }
+    }
+}
+
+/// Hands `attempt` to the worker of its webhook, starting one if there is
+/// none.
+fn dispatch<T: API + Clone + Send + 'static>(app: &T, settings: &Settings, workers: &mut HashMap<String, Sender<Attempt>>, attempt: Attempt) {
+    let worker = workers.entry(attempt.webhook.id.clone()).or_insert_with(|| {
+        let (queue, queued) = mpsc::channel();
+        let (app, settings) = (app.clone(), settings.clone());
+        thread::spawn(move || work(&app, &settings, &queued));
+        queue
+    });
+    if let Err(SendError(attempt)) = worker.send(attempt) {
+        error!("Webhook delivery {} was not queued", attempt.payload.delivery);
+    }
+}
+
+fn run<T: API + Clone + Send + 'static>(app: &T, feed: &ChangeFeed, since: u64, settings: &Settings) {
+    let root = app.root_path().to_string_lossy().into_owned();
+    let mut since = since;
+    let mut workers: HashMap<String, Sender<Attempt>> = HashMap::new();
+    match read_all::<T, Attempt>(app, DELIVERIES_PATH, "delivery") {
+        Ok(mut left) => {
+            left.sort_by_key(|attempt| attempt.payload.seq);
+            left.into_iter().for_each(|attempt| dispatch(app, settings, &mut workers, attempt));
+        }
+        Err(err) => error!("Could not read the webhook deliveries left from before: {}", err)
+    }
+    loop {
+        let batch = match feed.wait(since, Duration::from_secs(IDLE_SECS), |change| change.is_under(root.as_str())) {
+            Ok(batch) => batch,
+            Err(err) => {
+                error!("Webhook deliveries were skipped: {}", err);
+                since = feed.last_seq();
+                continue;
+            }
+        };
+        since = batch.last_seq;
+        if batch.changes.is_empty() {
+            continue;
+        }
+        let webhooks = match list(app) {
+            Ok(webhooks) => webhooks,
+            Err(err) => {
+                error!("Could not read the webhooks for changes up to {}: {}", since, err);
+                continue;
+            }
+        };
+        workers.retain(|id, _| webhooks.iter().any(|webhook| &webhook.id == id));
+        for change in batch.changes.iter() {
+            for webhook in webhooks.iter().filter(|webhook| webhook.matches(change)) {
+                let attempt = Attempt::new(webhook, change);
+                keep(app, &attempt);
+                dispatch(app, settings, &mut workers, attempt);
+            }
+        }
+    }
+}
+
+/// Delivers every change made from now on to the webhooks it matches, and
+/// the deliveries left unfinished before, on threads of their own. Does
+/// nothing without a change feed.
+pub fn spawn<T: API + Clone + Send + 'static>(app: T, settings: Settings) -> Option<thread::JoinHandle<()>> {
+    let feed = app.changes()?;
+    let since = feed.last_seq();
+    Some(thread::spawn(move || run(&app, &feed, since, &settings)))
+}
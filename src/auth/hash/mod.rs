@@ -0,0 +1,48 @@
+//! SHA-256, HMAC and PBKDF2, enough to store passwords and tokens without
+//! keeping them in the clear, from the RustCrypto crates. SHA-1 is only
+//! here for the WebSocket handshake, which is not a secret.
+
+extern crate hmac;
+extern crate pbkdf2;
+extern crate sha1 as sha1_crate;
+extern crate sha2;
+extern crate subtle;
+
+use self::hmac::{Hmac, Mac};
+use self::sha1_crate::Sha1;
+use self::sha2::{Digest, Sha256};
+use self::subtle::ConstantTimeEq;
+
+pub const DIGEST_LENGTH: usize = 32;
+pub const SHA1_LENGTH: usize = 20;
+
+pub fn sha256(data: &[u8]) -> [u8; DIGEST_LENGTH] {
+    Sha256::digest(data).into()
+}
+
+pub fn sha1(data: &[u8]) -> [u8; SHA1_LENGTH] {
+    Sha1::digest(data).into()
+}
+
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; DIGEST_LENGTH] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+/// PBKDF2 with HMAC-SHA-256, deriving `length` bytes.
+pub fn pbkdf2_sha256(password: &[u8], salt: &[u8], iterations: u32, length: usize) -> Vec<u8> {
+    let mut key = vec![0u8; length];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password, salt, iterations, &mut key);
+    key
+}
+
+/// Compares in time that depends only on the lengths, so that a match
+/// cannot be found a byte at a time.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
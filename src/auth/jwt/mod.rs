@@ -0,0 +1,201 @@
+extern crate base64;
+extern crate serde_json;
+
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use self::serde_json::Value;
+use super::super::api::acl::Permission;
+use super::super::config::JwtConfig;
+use super::rsa::PublicKey;
+use super::{hash, AuthErr};
+
+/// A key a token may be signed with. HS256 tokens are only checked against
+/// secrets and RS256 tokens only against RSA keys, so that a public key can
+/// never be used as an HMAC secret.
+#[derive(Clone, Debug)]
+enum Key {
+    Secret(Vec<u8>),
+    Rsa(PublicKey),
+}
+
+#[derive(Clone, Debug)]
+struct KeyEntry {
+    kid: Option<String>,
+    key: Key,
+}
+
+/// Verifies JWTs and finds the principal and scopes they carry.
+#[derive(Clone, Debug)]
+pub struct Verifier {
+    keys: Vec<KeyEntry>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    user_claim: String,
+    scopes_claim: Option<String>,
+    leeway: u64,
+    allow_missing_exp: bool,
+}
+
+/// What a valid token says about its bearer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Claims {
+    pub user: String,
+    /// `None` unless a scopes claim is configured.
+    pub scopes: Option<Vec<Permission>>,
+}
+
+fn invalid<T: ToString>(reason: T) -> AuthErr {
+    AuthErr::InvalidToken(reason.to_string())
+}
+
+fn decode_segment(segment: &str) -> Result<Vec<u8>, AuthErr> {
+    base64::decode_config(segment, base64::URL_SAFE_NO_PAD).map_err(|_| invalid("malformed encoding"))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn read_file(path: &str) -> Result<String, AuthErr> {
+    fs::read_to_string(path).map_err(|err| AuthErr::File(Path::new(path).to_path_buf(), err))
+}
+
+/// The keys of a JWK set: `oct` keys as HS256 secrets and `RSA` keys for
+/// RS256. Keys for other algorithms or uses are skipped.
+fn read_jwks(content: &str, path: &str) -> Result<Vec<KeyEntry>, AuthErr> {
+    let unreadable = || AuthErr::Parse { file: Path::new(path).to_path_buf(), line: 0 };
+    let jwks = serde_json::from_str::<Value>(content).map_err(|_| unreadable())?;
+    let keys = jwks["keys"].as_array().ok_or_else(unreadable)?;
+    Ok(keys.iter()
+        .filter(|jwk| jwk["use"].as_str().is_none_or(|u| u == "sig"))
+        .flat_map(|jwk| {
+            let field = |name: &str| jwk[name].as_str()
+                .and_then(|value| base64::decode_config(value, base64::URL_SAFE_NO_PAD).ok());
+            let key = match (jwk["kty"].as_str(), jwk["alg"].as_str()) {
+                (Some("oct"), None) | (Some("oct"), Some("HS256")) => field("k").map(Key::Secret),
+                (Some("RSA"), None) | (Some("RSA"), Some("RS256")) => field("n")
+                    .and_then(|n| field("e").and_then(|e| PublicKey::from_components(&n, &e)))
+                    .map(Key::Rsa),
+                _ => None
+            };
+            key.map(|key| KeyEntry { kid: jwk["kid"].as_str().map(String::from), key })
+        })
+        .collect())
+}
+
+fn audience_matches(aud: &Value, audience: &str) -> bool {
+    match aud {
+        Value::String(aud) => aud == audience,
+        Value::Array(auds) => auds.iter().any(|aud| aud.as_str() == Some(audience)),
+        _ => false
+    }
+}
+
+/// Scopes given as a space separated string or a list. Only `read`,
+/// `write`, `delete` and `admin` grant anything.
+fn parse_scopes(claim: &Value) -> Vec<Permission> {
+    let names = match claim {
+        Value::String(scopes) => scopes.split_whitespace().map(String::from).collect(),
+        Value::Array(scopes) => scopes.iter().flat_map(|s| s.as_str()).map(String::from).collect(),
+        _ => Vec::new()
+    };
+    names.into_iter()
+        .flat_map(|name| serde_json::from_value(Value::String(name)).ok())
+        .collect()
+}
+
+impl Verifier {
+    /// `None` when no key is configured, in which case bearer tokens are
+    /// only looked up in the tokens file.
+    pub fn from_config(config: &JwtConfig) -> Result<Option<Verifier>, AuthErr> {
+        let mut keys = Vec::new();
+        if let Some(ref secret) = config.secret {
+            keys.push(KeyEntry { kid: None, key: Key::Secret(secret.clone().into_bytes()) });
+        }
+        if let Some(ref file) = config.public_key_file {
+            let key = PublicKey::from_pem(read_file(file)?.as_str())
+                .ok_or_else(|| AuthErr::Parse { file: Path::new(file.as_str()).to_path_buf(), line: 0 })?;
+            keys.push(KeyEntry { kid: None, key: Key::Rsa(key) });
+        }
+        if let Some(ref file) = config.jwks_file {
+            keys.extend(read_jwks(read_file(file)?.as_str(), file.as_str())?);
+        }
+        if keys.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Verifier {
+            keys,
+            issuer: config.issuer.clone(),
+            audience: config.audience.clone(),
+            user_claim: config.user_claim.clone(),
+            scopes_claim: config.scopes_claim.clone(),
+            leeway: config.leeway,
+            allow_missing_exp: config.allow_missing_exp,
+        }))
+    }
+
+    /// Whether `token` has the shape of a JWT rather than an opaque token.
+    pub fn is_jwt(token: &str) -> bool {
+        token.split('.').count() == 3
+    }
+
+    fn check_signature(&self, alg: &str, kid: Option<&str>, message: &[u8], signature: &[u8]) -> bool {
+        self.keys.iter()
+            .filter(|entry| kid.is_none() || entry.kid.is_none() || entry.kid.as_deref() == kid)
+            .any(|entry| match (alg, &entry.key) {
+                ("HS256", Key::Secret(secret)) => hash::constant_time_eq(&hash::hmac_sha256(secret, message), signature),
+                ("RS256", Key::Rsa(key)) => key.verify(message, signature),
+                _ => false
+            })
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Claims, AuthErr> {
+        let parts = token.split('.').collect::<Vec<&str>>();
+        if parts.len() != 3 {
+            return Err(invalid("malformed token"));
+        }
+        let header = serde_json::from_slice::<Value>(&decode_segment(parts[0])?).map_err(|_| invalid("malformed header"))?;
+        let alg = header["alg"].as_str().unwrap_or("");
+        if alg != "HS256" && alg != "RS256" {
+            return Err(invalid(format!("unsupported algorithm {:?}", alg)));
+        }
+        let message = format!("{}.{}", parts[0], parts[1]);
+        if !self.check_signature(alg, header["kid"].as_str(), message.as_bytes(), &decode_segment(parts[2])?) {
+            return Err(invalid("bad signature"));
+        }
+
+        let claims = serde_json::from_slice::<Value>(&decode_segment(parts[1])?).map_err(|_| invalid("malformed claims"))?;
+        let now = now();
+        match claims["exp"].as_u64() {
+            Some(exp) if exp.saturating_add(self.leeway) < now => return Err(invalid("expired")),
+            None if !claims["exp"].is_null() => return Err(invalid("malformed exp")),
+            None if !self.allow_missing_exp => return Err(invalid("no exp claim")),
+            _ => ()
+        }
+        if claims["nbf"].as_u64().is_some_and(|nbf| nbf > now.saturating_add(self.leeway)) {
+            return Err(invalid("not yet valid"));
+        }
+        if let Some(ref issuer) = self.issuer {
+            if claims["iss"].as_str() != Some(issuer.as_str()) {
+                return Err(invalid("wrong issuer"));
+            }
+        }
+        if let Some(ref audience) = self.audience {
+            if !audience_matches(&claims["aud"], audience) {
+                return Err(invalid("wrong audience"));
+            }
+        }
+        let user = claims[self.user_claim.as_str()].as_str()
+            .filter(|user| !user.is_empty())
+            .ok_or_else(|| invalid(format!("no {} claim", self.user_claim)))?;
+        Ok(Claims {
+            user: String::from(user),
+            scopes: self.scopes_claim.as_ref().map(|claim| parse_scopes(&claims[claim.as_str()])),
+        })
+    }
+}
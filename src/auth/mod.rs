@@ -0,0 +1,357 @@
+extern crate base64;
+extern crate rand;
+
+pub mod hash;
+pub mod jwt;
+pub mod rsa;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use self::rand::Rng;
+use self::rand::distributions::Alphanumeric;
+use self::rand::rngs::OsRng;
+use super::api::acl::Permission;
+use super::config::{AuthConfig, JwtConfig};
+
+/// The principal of every request when authentication is turned off.
+pub const ANONYMOUS: &str = "anon";
+pub const REALM: &str = "rust-monad";
+const PBKDF2_SCHEME: &str = "pbkdf2-sha256";
+/// What OWASP recommends for PBKDF2-HMAC-SHA-256. Hashes made with fewer
+/// still verify, since every hash records its own count.
+const PBKDF2_ITERATIONS: u32 = 600_000;
+const SALT_LENGTH: usize = 16;
+const TOKEN_LENGTH: usize = 32;
+/// How long a password that verified is trusted without being hashed
+/// again, and how many such passwords are remembered at most.
+const VERIFIED_SECS: u64 = 60;
+const MAX_VERIFIED: usize = 4096;
+/// How many wrong passwords a user may be tried with in a window before
+/// the rest of the window's attempts are refused without being hashed.
+const MAX_FAILURES: u32 = 10;
+const FAILURE_WINDOW_SECS: u64 = 60;
+
+#[derive(Debug)]
+pub enum AuthErr {
+    File(PathBuf, io::Error),
+    /// A line of a users or tokens file that could not be understood.
+    Parse { file: PathBuf, line: usize },
+    MissingCredentials,
+    InvalidCredentials,
+    UnsupportedScheme(String),
+    /// A JWT that is malformed, badly signed, expired or not meant for us.
+    InvalidToken(String),
+    /// The user's password was wrong too many times lately.
+    TooManyAttempts(String),
+}
+
+impl fmt::Display for AuthErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthErr::File(path, err) => write!(f, "could not read {}: {}", path.display(), err),
+            AuthErr::Parse { file, line } => write!(f, "{} line {} is not a valid entry", file.display(), line),
+            AuthErr::MissingCredentials => f.write_str("credentials are required"),
+            AuthErr::InvalidCredentials => f.write_str("the credentials are not valid"),
+            AuthErr::UnsupportedScheme(scheme) => write!(f, "the {} scheme is not supported", scheme),
+            AuthErr::InvalidToken(reason) => write!(f, "the token is not valid: {}", reason),
+            AuthErr::TooManyAttempts(user) => write!(f, "too many wrong passwords for {}, try again later", user),
+        }
+    }
+}
+
+/// A password as stored in a users file:
+/// `$pbkdf2-sha256$<iterations>$<base64 salt>$<base64 key>`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PasswordHash {
+    iterations: u32,
+    salt: Vec<u8>,
+    key: Vec<u8>,
+}
+
+impl PasswordHash {
+    pub fn new(password: &str) -> PasswordHash {
+        let mut rng = rand::thread_rng();
+        let salt = (0..SALT_LENGTH).map(|_| rng.gen::<u8>()).collect::<Vec<u8>>();
+        PasswordHash::with_salt(password, salt, PBKDF2_ITERATIONS)
+    }
+
+    pub fn with_salt(password: &str, salt: Vec<u8>, iterations: u32) -> PasswordHash {
+        let key = hash::pbkdf2_sha256(password.as_bytes(), &salt, iterations, hash::DIGEST_LENGTH);
+        PasswordHash { iterations, salt, key }
+    }
+
+    pub fn parse(encoded: &str) -> Option<PasswordHash> {
+        let parts = encoded.split('$').collect::<Vec<&str>>();
+        match parts.as_slice() {
+            ["", PBKDF2_SCHEME, iterations, salt, key] => Some(PasswordHash {
+                iterations: u32::from_str(iterations).ok().filter(|i| *i > 0)?,
+                salt: base64::decode(salt).ok()?,
+                key: base64::decode(key).ok()?,
+            }),
+            _ => None
+        }
+    }
+
+    pub fn verify(&self, password: &str) -> bool {
+        let key = hash::pbkdf2_sha256(password.as_bytes(), &self.salt, self.iterations, self.key.len());
+        hash::constant_time_eq(&key, &self.key)
+    }
+}
+
+impl fmt::Display for PasswordHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "${}${}${}${}", PBKDF2_SCHEME, self.iterations,
+            base64::encode(&self.salt), base64::encode(&self.key))
+    }
+}
+
+/// Tokens are stored as the hex SHA-256 of the token, never the token itself.
+pub fn token_hash(token: &str) -> String {
+    hash::to_hex(&hash::sha256(token.as_bytes()))
+}
+
+pub fn new_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(TOKEN_LENGTH)
+        .collect()
+}
+
+/// Reads `<name>:<value>` lines, with their line numbers, skipping blank
+/// lines and `#` comments.
+fn read_entries(path: &Path) -> Result<Vec<(usize, String, String)>, AuthErr> {
+    fs::read_to_string(path)
+        .map_err(|err| AuthErr::File(path.to_path_buf(), err))?
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        .map(|(i, line)| {
+            let mut entry = line.trim().splitn(2, ':');
+            match (entry.next(), entry.next()) {
+                (Some(name), Some(value)) if !name.is_empty() && !value.is_empty() =>
+                    Ok((i + 1, String::from(name), String::from(value))),
+                _ => Err(AuthErr::Parse { file: path.to_path_buf(), line: i + 1 })
+            }
+        })
+        .collect()
+}
+
+/// The token of `Bearer` credentials.
+pub fn bearer_token(authorization: &str) -> Option<&str> {
+    let mut credentials = authorization.trim().splitn(2, ' ');
+    match (credentials.next(), credentials.next()) {
+        (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") => Some(token.trim()),
+        _ => None
+    }
+}
+
+/// Who a request comes from, and what its credentials allow it to do.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Principal {
+    pub name: String,
+    /// The permissions the credentials were issued for, or `None` when they
+    /// are not limited to any.
+    pub scopes: Option<Vec<Permission>>,
+}
+
+impl Principal {
+    pub fn new(name: &str) -> Principal {
+        Principal { name: String::from(name), scopes: None }
+    }
+
+    /// Whether the credentials allow `permission` at all. Access control
+    /// rules may still refuse it.
+    pub fn allows(&self, permission: Permission) -> bool {
+        self.scopes.as_ref()
+            .is_none_or(|scopes| scopes.iter().any(|p| *p == permission || *p == Permission::Admin))
+    }
+}
+
+/// What `Basic` credentials were recently found to be, so that a password
+/// is not hashed on every request, nor guessed at a hash's pace.
+#[derive(Debug, Default)]
+struct Attempts {
+    /// When credentials that verified stop being trusted, keyed by their
+    /// HMAC under a key of the authenticator's own.
+    verified: HashMap<[u8; hash::DIGEST_LENGTH], Instant>,
+    /// How many wrong passwords each user was tried with since when.
+    failures: HashMap<String, (u32, Instant)>,
+}
+
+impl Attempts {
+    fn remember(&mut self, credentials: [u8; hash::DIGEST_LENGTH], now: Instant) {
+        if self.verified.len() >= MAX_VERIFIED {
+            self.verified.retain(|_, until| *until > now);
+        }
+        if self.verified.len() >= MAX_VERIFIED {
+            self.verified.clear();
+        }
+        self.verified.insert(credentials, now + Duration::from_secs(VERIFIED_SECS));
+    }
+
+    /// Counts an attempt for `user` before its password is checked, or
+    /// fails if the user was tried too many times in the current window.
+    fn try_user(&mut self, user: &str, now: Instant) -> Result<(), AuthErr> {
+        let window = Duration::from_secs(FAILURE_WINDOW_SECS);
+        let failures = self.failures.entry(String::from(user)).or_insert((0, now));
+        if now.duration_since(failures.1) >= window {
+            *failures = (0, now);
+        }
+        if failures.0 >= MAX_FAILURES {
+            return Err(AuthErr::TooManyAttempts(String::from(user)));
+        }
+        failures.0 += 1;
+        Ok(())
+    }
+}
+
+/// Decides who a request comes from, given its `Authorization` header.
+#[derive(Clone, Debug, Default)]
+pub struct Authenticator {
+    enabled: bool,
+    users: HashMap<String, PasswordHash>,
+    /// Principals keyed by the hash of their token.
+    tokens: HashMap<String, String>,
+    jwt: Option<jwt::Verifier>,
+    attempts: Arc<Mutex<Attempts>>,
+    attempts_key: Vec<u8>,
+}
+
+impl Authenticator {
+    /// Lets every request through as `ANONYMOUS`.
+    pub fn disabled() -> Authenticator {
+        Authenticator::default()
+    }
+
+    pub fn new(users: HashMap<String, PasswordHash>, tokens: HashMap<String, String>) -> Authenticator {
+        // a MAC key, so it comes straight from the OS
+        let attempts_key = OsRng::new()
+            .map(|mut rng| {
+                let mut key = vec![0; hash::DIGEST_LENGTH];
+                rng.fill(key.as_mut_slice());
+                key
+            })
+            .unwrap_or_else(|_| new_token().into_bytes());
+        Authenticator { enabled: true, users, tokens, attempts_key, ..Authenticator::default() }
+    }
+
+    /// Whether requests need credentials at all.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Accepts bearer tokens that are JWTs verified by `verifier`.
+    pub fn with_jwt(self, verifier: jwt::Verifier) -> Authenticator {
+        Authenticator { jwt: Some(verifier), ..self }
+    }
+
+    /// Reads the users file, with one `<name>:<password hash>` line per
+    /// user, the tokens file, with one `<principal>:<token hash>` line per
+    /// token, and the JWT keys.
+    pub fn from_config(config: &AuthConfig, jwt_config: &JwtConfig) -> Result<Authenticator, AuthErr> {
+        if !config.enabled {
+            return Ok(Authenticator::disabled());
+        }
+        let users = match config.users_file {
+            Some(ref file) => {
+                let path = Path::new(file.as_str());
+                read_entries(path)?
+                    .into_iter()
+                    .map(|(line, name, encoded)| PasswordHash::parse(encoded.as_str())
+                        .map(|hash| (name, hash))
+                        .ok_or_else(|| AuthErr::Parse { file: path.to_path_buf(), line }))
+                    .collect::<Result<HashMap<String, PasswordHash>, AuthErr>>()?
+            },
+            None => HashMap::new()
+        };
+        let tokens = match config.tokens_file {
+            Some(ref file) => read_entries(Path::new(file.as_str()))?
+                .into_iter()
+                .map(|(_, principal, hash)| (hash.to_lowercase(), principal))
+                .collect(),
+            None => HashMap::new()
+        };
+        let authenticator = Authenticator::new(users, tokens);
+        Ok(match jwt::Verifier::from_config(jwt_config)? {
+            Some(verifier) => authenticator.with_jwt(verifier),
+            None => authenticator
+        })
+    }
+
+    /// The principal named by `authorization`, which may be `Basic` or
+    /// `Bearer` credentials.
+    pub fn authenticate(&self, authorization: Option<&str>) -> Result<Principal, AuthErr> {
+        if !self.enabled {
+            return Ok(Principal::new(ANONYMOUS));
+        }
+        let mut credentials = authorization.ok_or(AuthErr::MissingCredentials)?.trim().splitn(2, ' ');
+        match (credentials.next(), credentials.next().map(str::trim)) {
+            (Some(scheme), Some(value)) if scheme.eq_ignore_ascii_case("basic") => self.basic(value),
+            (Some(scheme), Some(value)) if scheme.eq_ignore_ascii_case("bearer") => self.bearer(value),
+            (Some(scheme), _) => Err(AuthErr::UnsupportedScheme(String::from(scheme))),
+            (None, _) => Err(AuthErr::MissingCredentials)
+        }
+    }
+
+    fn basic(&self, encoded: &str) -> Result<Principal, AuthErr> {
+        let decoded = base64::decode(encoded)
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .ok_or(AuthErr::InvalidCredentials)?;
+        let mut credentials = decoded.splitn(2, ':');
+        let (name, password) = match (credentials.next(), credentials.next()) {
+            (Some(name), Some(password)) => (name, password),
+            _ => return Err(AuthErr::InvalidCredentials)
+        };
+        let hash = self.users.get(name).ok_or(AuthErr::InvalidCredentials)?;
+        let key = hash::hmac_sha256(&self.attempts_key, decoded.as_bytes());
+        let now = Instant::now();
+        {
+            let mut attempts = self.lock_attempts();
+            if attempts.verified.get(&key).is_some_and(|until| *until > now) {
+                return Ok(Principal::new(name));
+            }
+            attempts.try_user(name, now)?;
+        }
+        if !hash.verify(password) {
+            return Err(AuthErr::InvalidCredentials);
+        }
+        let mut attempts = self.lock_attempts();
+        attempts.failures.remove(name);
+        attempts.remember(key, now);
+        Ok(Principal::new(name))
+    }
+
+    fn lock_attempts(&self) -> MutexGuard<'_, Attempts> {
+        self.attempts.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn bearer(&self, token: &str) -> Result<Principal, AuthErr> {
+        match self.jwt {
+            Some(ref verifier) if jwt::Verifier::is_jwt(token) => verifier.verify(token)
+                .map(|claims| Principal { name: claims.user, scopes: claims.scopes }),
+            _ => self.tokens.get(&token_hash(token))
+                .map(|name| Principal::new(name))
+                .ok_or(AuthErr::InvalidCredentials)
+        }
+    }
+
+    /// The `WWW-Authenticate` challenges a 401 response carries, one for
+    /// each scheme that has credentials configured.
+    pub fn challenges(&self) -> String {
+        let bearer = !self.tokens.is_empty() || self.jwt.is_some();
+        [(!bearer || !self.users.is_empty(), "Basic"), (bearer, "Bearer")]
+            .iter()
+            .filter(|(offered, _)| *offered)
+            .map(|(_, scheme)| format!("{} realm=\"{}\"", scheme, REALM))
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+}
@@ -0,0 +1,48 @@
+//! RSASSA-PKCS1-v1_5 signature verification with SHA-256 (RFC 8017), from
+//! the `rsa` crate.
+
+extern crate rsa as rsa_crate;
+extern crate sha2;
+
+use self::rsa_crate::{BigUint, Pkcs1v15Sign, RsaPublicKey};
+use self::rsa_crate::pkcs1::DecodeRsaPublicKey;
+use self::rsa_crate::pkcs8::DecodePublicKey;
+use self::rsa_crate::traits::PublicKeyParts;
+use self::sha2::Sha256;
+
+use super::hash;
+
+const MIN_MODULUS_BITS: usize = 1024;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PublicKey(RsaPublicKey);
+
+impl PublicKey {
+    fn checked(key: RsaPublicKey) -> Option<PublicKey> {
+        if key.n().bits() < MIN_MODULUS_BITS {
+            return None;
+        }
+        Some(PublicKey(key))
+    }
+
+    /// A key from its big endian modulus and exponent, refusing moduli
+    /// shorter than 1024 bits.
+    pub fn from_components(n: &[u8], e: &[u8]) -> Option<PublicKey> {
+        RsaPublicKey::new(BigUint::from_bytes_be(n), BigUint::from_bytes_be(e)).ok()
+            .and_then(PublicKey::checked)
+    }
+
+    /// Reads a `PUBLIC KEY` (SubjectPublicKeyInfo) or `RSA PUBLIC KEY`
+    /// (PKCS #1) PEM block.
+    pub fn from_pem(pem: &str) -> Option<PublicKey> {
+        RsaPublicKey::from_public_key_pem(pem.trim())
+            .or_else(|_| RsaPublicKey::from_pkcs1_pem(pem.trim()))
+            .ok()
+            .and_then(PublicKey::checked)
+    }
+
+    /// Whether `signature` is this key's RS256 signature of `message`.
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        self.0.verify(Pkcs1v15Sign::new::<Sha256>(), &hash::sha256(message), signature).is_ok()
+    }
+}
@@ -0,0 +1,514 @@
+extern crate clap;
+extern crate simple_logger;
+extern crate serde_json;
+
+use self::clap::{App, Arg, ArgMatches, SubCommand};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::RestApp;
+use super::api::{API, APIErr};
+use self::serde_json::Value;
+use super::api::{fsck, migrate, ndjson, stream};
+use super::api::audit::AuditLog;
+use super::api::changes::ChangeFeed;
+use super::api::replication::{self, Replica};
+use super::api::webhooks;
+use super::api::tenant::Tenancy;
+use super::auth::{self, AuthErr, Authenticator, PasswordHash};
+use super::config::{Config, ConfigErr};
+use super::server::http::Engine;
+use super::server::http::codec::Format;
+use super::server::http::cors::Cors;
+use super::server::http::tls::{self, TlsErr};
+use super::server;
+
+const CLI_USER: &str = "cli";
+const STDIO: &str = "-";
+
+#[derive(Debug)]
+pub enum CliErr {
+    Api(APIErr),
+    IO(io::Error),
+    Usage(String),
+    Config(ConfigErr),
+    Auth(AuthErr),
+    Tls(TlsErr),
+}
+
+impl fmt::Display for CliErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CliErr::Api(err) => write!(f, "{}", err),
+            CliErr::IO(err) => write!(f, "{}", err),
+            CliErr::Usage(usage) => write!(f, "{}", usage),
+            CliErr::Config(err) => write!(f, "invalid configuration: {}", err),
+            CliErr::Auth(err) => write!(f, "{}", err),
+            CliErr::Tls(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+fn storage_dir_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("storage-dir")
+        .long("storage-dir")
+        .short("d")
+        .takes_value(true)
+        .help("Directory documents are stored in, overriding storage.root")
+}
+
+fn path_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("path")
+        .required(true)
+        .help("Document path, such as my/thing")
+}
+
+fn prefix_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("prefix")
+        .long("prefix")
+        .short("p")
+        .takes_value(true)
+        .default_value("")
+        .help("Path the documents are read from or written below")
+}
+
+fn format_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("format")
+        .long("format")
+        .short("f")
+        .takes_value(true)
+        .possible_values(&["json", "cbor", "msgpack", "yaml"])
+        .default_value("json")
+}
+
+fn file_format_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("format")
+        .long("format")
+        .short("f")
+        .takes_value(true)
+        .possible_values(&["json", "ndjson"])
+        .help("File format, guessed from the file extension when not given")
+}
+
+pub fn app<'a, 'b>() -> App<'a, 'b> {
+    App::new("rust-monad")
+        .about("A REST API that stores JSON documents as a directory tree")
+        .arg(Arg::with_name("config")
+            .long("config")
+            .short("c")
+            .takes_value(true)
+            .global(true)
+            .help("TOML configuration file, also read from RUST_MONAD_CONFIG"))
+        .arg(Arg::with_name("log-level")
+            .long("log-level")
+            .takes_value(true)
+            .global(true)
+            .help("Overrides logging.level"))
+        .arg(Arg::with_name("set")
+            .long("set")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .global(true)
+            .help("Overrides any setting, as in --set limits.max_depth=8"))
+        .subcommand(SubCommand::with_name("serve")
+            .about("Serves the storage directory over HTTP (the default)")
+            .arg(storage_dir_arg())
+            .arg(Arg::with_name("listen")
+                .long("listen")
+                .short("l")
+                .takes_value(true)
+                .help("Address and port to listen on, overriding listener.address"))
+            .arg(Arg::with_name("engine")
+                .long("engine")
+                .short("e")
+                .takes_value(true)
+                .possible_values(&["hyper", "iron"])
+                .help("HTTP engine to serve with, overriding listener.engine")))
+        .subcommand(SubCommand::with_name("get")
+            .about("Prints the document at a path")
+            .arg(storage_dir_arg())
+            .arg(path_arg())
+            .arg(format_arg()))
+        .subcommand(SubCommand::with_name("put")
+            .about("Writes a document at a path")
+            .arg(storage_dir_arg())
+            .arg(path_arg())
+            .arg(Arg::with_name("value")
+                .help("The document, read from stdin when not given"))
+            .arg(format_arg())
+            .arg(Arg::with_name("ttl")
+                .long("ttl")
+                .takes_value(true)
+                .help("Seconds until the document expires")))
+        .subcommand(SubCommand::with_name("delete")
+            .about("Removes the document at a path")
+            .arg(storage_dir_arg())
+            .arg(path_arg()))
+        .subcommand(SubCommand::with_name("import")
+            .about("Writes the documents in a JSON or NDJSON file")
+            .arg(storage_dir_arg())
+            .arg(Arg::with_name("file").required(true).help("File to read, or - for stdin"))
+            .arg(prefix_arg())
+            .arg(file_format_arg()))
+        .subcommand(SubCommand::with_name("export")
+            .about("Writes the documents below a prefix to a JSON or NDJSON file")
+            .arg(storage_dir_arg())
+            .arg(Arg::with_name("file").required(true).help("File to write, or - for stdout"))
+            .arg(prefix_arg())
+            .arg(file_format_arg())
+            .arg(Arg::with_name("mode")
+                .long("mode")
+                .takes_value(true)
+                .possible_values(&["leaves", "documents"])
+                .default_value("leaves")
+                .help("One NDJSON record per field, or per top level document")))
+        .subcommand(SubCommand::with_name("fsck")
+            .about("Checks the storage directory for damaged or inconsistent files")
+            .arg(storage_dir_arg())
+            .arg(Arg::with_name("repair")
+                .long("repair")
+                .help("Fix every issue that can be fixed")))
+        .subcommand(SubCommand::with_name("migrate")
+            .about("Upgrades the storage directory to the current format version")
+            .arg(storage_dir_arg())
+            .arg(Arg::with_name("into")
+                .long("into")
+                .takes_value(true)
+                .help("Upgrade a copy in this new directory, leaving the original as it is")))
+        .subcommand(SubCommand::with_name("hash-password")
+            .about("Reads a password from stdin and prints a line for auth.users_file")
+            .arg(Arg::with_name("user")
+                .required(true)
+                .help("Name the user signs in with")))
+        .subcommand(SubCommand::with_name("issue-token")
+            .about("Prints a new bearer token, and the line for auth.tokens_file that accepts it")
+            .arg(Arg::with_name("user")
+                .required(true)
+                .help("Principal requests made with the token act as")))
+}
+
+fn parse_path(path: &str) -> Vec<String> {
+    path.split('/')
+        .filter(|p| !p.is_empty())
+        .map(String::from)
+        .collect::<Vec<String>>()
+}
+
+/// The configuration file and environment, with any flags given applied
+/// on top and the result validated.
+fn configure(args: &ArgMatches) -> Result<Config, ConfigErr> {
+    let mut config = Config::load(args.value_of("config"))?;
+    args.values_of("set")
+        .into_iter()
+        .flatten()
+        .try_for_each(|setting| {
+            let mut kv = setting.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(key), Some(value)) => config.set(key.trim(), value),
+                _ => Err(ConfigErr::Invalid { key: String::from(setting), message: String::from("expected key=value") })
+            }
+        })?;
+    [("storage-dir", "storage.root"), ("listen", "listener.address"),
+        ("engine", "listener.engine"), ("log-level", "logging.level")]
+        .iter()
+        .flat_map(|(flag, key)| args.value_of(flag).map(|value| (key, value)))
+        .try_for_each(|(key, value)| config.set(key, value))?;
+    config.validate()?;
+    Ok(config)
+}
+
+/// The storage directory as the subcommands see it. They act with the
+/// same rights as anyone who can read and write its files, so no access
+/// control rule applies to them.
+fn storage_at(config: &Config) -> RestApp {
+    RestApp::new(config.storage.root.clone())
+        .with_limits(config.limits)
+        .with_admins(vec![String::from(CLI_USER)])
+}
+
+/// The storage directory, upgraded to the current format version first.
+/// A store written by a newer release is refused.
+fn storage(config: &Config) -> Result<RestApp, CliErr> {
+    let app = storage_at(config);
+    migrate::open(&app)
+        .map(|_| app)
+        .map_err(CliErr::Api)
+}
+
+fn format(args: &ArgMatches) -> Format {
+    args.value_of("format")
+        .and_then(Format::from_name)
+        .unwrap_or(Format::Json)
+}
+
+fn is_ndjson(args: &ArgMatches) -> bool {
+    match args.value_of("format") {
+        Some(format) => format == "ndjson",
+        None => args.value_of("file")
+            .map(|file| file.ends_with(".ndjson"))
+            .unwrap_or(false)
+    }
+}
+
+fn open_input(file: &str) -> Result<Box<Read>, CliErr> {
+    if file == STDIO {
+        Ok(Box::new(io::stdin()))
+    } else {
+        File::open(file)
+            .map(|f| Box::new(f) as Box<Read>)
+            .map_err(CliErr::IO)
+    }
+}
+
+fn open_output(file: &str) -> Result<Box<Write>, CliErr> {
+    if file == STDIO {
+        Ok(Box::new(io::stdout()))
+    } else {
+        File::create(file)
+            .map(|f| Box::new(f) as Box<Write>)
+            .map_err(CliErr::IO)
+    }
+}
+
+fn read_all(mut input: Box<Read>) -> Result<Vec<u8>, CliErr> {
+    let mut bytes = Vec::new();
+    input.read_to_end(&mut bytes)
+        .map(|_| bytes)
+        .map_err(CliErr::IO)
+}
+
+fn serve(config: &Config) -> Result<(), CliErr> {
+    let authenticator = Authenticator::from_config(&config.auth, &config.jwt).map_err(CliErr::Auth)?;
+    let app = storage(config)?;
+    let feed = ChangeFeed::open(&config.storage.root, config.changes.capacity).map_err(CliErr::Api)?;
+    let app = app.with_changes(feed);
+    let app = if config.audit.enabled {
+        app.with_audit(AuditLog::new(&config.storage.root, config.audit.max_bytes, config.audit.max_files))
+    } else {
+        app
+    };
+    let app = app
+        .with_authenticator(authenticator)
+        .with_admins(config.auth.admins.clone())
+        .with_tenancy(Tenancy::from_config(config.tenancy.mode.as_str(), config.tenancy.header.as_str())
+            .unwrap_or_default());
+    let addr = config.listen_addr().map_err(CliErr::Config)?;
+    let app = match config.replication.primary {
+        Some(ref primary) => {
+            let replica = Arc::new(Replica::new(replication::Settings {
+                primary: primary.clone(),
+                authorization: config.replication.authorization.clone(),
+                poll: Duration::from_secs(config.replication.poll_secs),
+                retry: Duration::from_secs(config.replication.retry_secs),
+                timeout: Duration::from_secs(config.replication.timeout_secs),
+            }));
+            info!("Following {}", primary);
+            replication::spawn(app.clone().with_admins(vec![String::from(replication::USER)]), replica.clone());
+            app.with_replica(replica)
+        }
+        None => app
+    };
+    let app = if config.cors.allowed_origins.is_empty() {
+        app
+    } else {
+        app.with_cors(Cors {
+            allowed_origins: config.cors.allowed_origins.clone(),
+            allowed_methods: config.cors.allowed_methods.clone(),
+            allowed_headers: config.cors.allowed_headers.clone(),
+            allow_credentials: config.cors.allow_credentials,
+            max_age: config.cors.max_age_secs,
+        })
+    };
+    if config.webhooks.enabled {
+        webhooks::spawn(app.clone(), webhooks::Settings {
+            max_attempts: config.webhooks.max_attempts,
+            backoff: Duration::from_secs(config.webhooks.backoff_secs),
+            timeout: Duration::from_secs(config.webhooks.timeout_secs),
+        });
+    }
+
+    let acceptor = match (&config.tls.cert_file, &config.tls.key_file) {
+        (Some(cert_file), Some(key_file)) => {
+            let acceptor = tls::Acceptor::new(tls::Settings {
+                cert_file: cert_file.clone(),
+                key_file: key_file.clone(),
+                client_ca_file: config.tls.client_ca_file.clone(),
+                require_client_cert: config.tls.require_client_cert,
+            }).map_err(CliErr::Tls)?;
+            acceptor.watch().map_err(CliErr::Tls)?;
+            Some(acceptor)
+        }
+        _ => None
+    };
+    info!("Serving {} on {}{}", app.storage_dir.display(), addr, if acceptor.is_some() { " over TLS" } else { "" });
+    let engine = Engine::from_name(config.listener.engine.as_str())
+        .ok_or_else(|| CliErr::Usage(format!("unknown engine {}", config.listener.engine)))?;
+    server::http::serve(engine, app, addr, acceptor);
+    Ok(())
+}
+
+fn get(args: &ArgMatches, config: &Config) -> Result<(), CliErr> {
+    let app = storage(config)?;
+    let path = parse_path(args.value_of("path").unwrap_or(""));
+    let format = format(args);
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    app.get(path, String::from(CLI_USER))
+        .and_then(|value| format.encode(&value))
+        .map_err(CliErr::Api)
+        .and_then(|bytes| out.write_all(bytes.as_slice()).map_err(CliErr::IO))
+        .and_then(|()| if format == Format::Json {
+            out.write_all(b"\n").map_err(CliErr::IO)
+        } else {
+            Ok(())
+        })
+}
+
+fn put(args: &ArgMatches, config: &Config) -> Result<(), CliErr> {
+    let app = storage(config)?;
+    let path = parse_path(args.value_of("path").unwrap_or(""));
+    let format = format(args);
+    let ttl = match args.value_of("ttl") {
+        Some(ttl) => Some(u64::from_str(ttl)
+            .map_err(|err| CliErr::Usage(format!("invalid ttl {}: {}", ttl, err)))?),
+        None => None
+    };
+    match args.value_of("value") {
+        Some(value) => Ok(value.as_bytes().to_vec()),
+        None => read_all(Box::new(io::stdin()))
+    }
+        .and_then(|bytes| format.decode(bytes.as_slice()).map_err(CliErr::Api))
+        .and_then(|value| app.put_with_ttl(path, value, ttl, String::from(CLI_USER)).map_err(CliErr::Api))
+}
+
+fn delete(args: &ArgMatches, config: &Config) -> Result<(), CliErr> {
+    let app = storage(config)?;
+    let path = parse_path(args.value_of("path").unwrap_or(""));
+    app.delete(path, String::from(CLI_USER))
+        .map_err(CliErr::Api)
+}
+
+fn import(args: &ArgMatches, config: &Config) -> Result<(), CliErr> {
+    let app = storage(config)?;
+    let prefix = parse_path(args.value_of("prefix").unwrap_or(""));
+    let input = open_input(args.value_of("file").unwrap_or(STDIO))?;
+    if is_ndjson(args) {
+        let failed = ndjson::import(&app, prefix, BufReader::new(input), String::from(CLI_USER))
+            .into_iter()
+            .filter(|result| !result.ok)
+            .map(|result| eprintln!("line {}: {}", result.line, result.error.unwrap_or_default()))
+            .count();
+        if failed == 0 {
+            Ok(())
+        } else {
+            Err(CliErr::Usage(format!("{} lines could not be imported", failed)))
+        }
+    } else {
+        read_all(input)
+            .and_then(|bytes| Format::Json.decode(bytes.as_slice()).map_err(CliErr::Api))
+            .and_then(|value| match value {
+                // The root is only ever written member by member.
+                Value::Object(members) if prefix.is_empty() => members.into_iter()
+                    .try_for_each(|(name, member)| app.put(vec![name], member, String::from(CLI_USER))),
+                value => app.put(prefix, value, String::from(CLI_USER))
+            }.map_err(CliErr::Api))
+    }
+}
+
+fn export(args: &ArgMatches, config: &Config) -> Result<(), CliErr> {
+    let app = storage(config)?;
+    let prefix = parse_path(args.value_of("prefix").unwrap_or(""));
+    let mode = ndjson::ExportMode::from_param(args.value_of("mode"));
+    let mut output = open_output(args.value_of("file").unwrap_or(STDIO))?;
+    if is_ndjson(args) {
+        ndjson::export(app, prefix, mode, String::from(CLI_USER))
+            .map_err(CliErr::Api)
+            .and_then(|records| stream::write_chunks(records, &mut output).map_err(CliErr::IO))
+    } else {
+        stream::read_stream(app, prefix, String::from(CLI_USER))
+            .map_err(CliErr::Api)
+            .and_then(|json| stream::write_chunks(json, &mut output).map_err(CliErr::IO))
+            .and_then(|()| output.write_all(b"\n").map_err(CliErr::IO))
+    }
+}
+
+/// Prints one line per issue found. Exits with an error while any issue is
+/// left unrepaired, so the check can gate a deployment.
+fn check(args: &ArgMatches, config: &Config) -> Result<(), CliErr> {
+    let app = storage(config)?;
+    let issues = fsck::fsck(&app, args.is_present("repair")).map_err(CliErr::Api)?;
+    issues.iter()
+        .for_each(|issue| println!("{}: {:?}{}", issue.path.display(), issue.kind,
+            if issue.repaired { " (repaired)" } else { "" }));
+    let unrepaired = issues.iter().filter(|issue| !issue.repaired).count();
+    if unrepaired == 0 {
+        Ok(())
+    } else {
+        Err(CliErr::Usage(format!("{} issues found", unrepaired)))
+    }
+}
+
+fn migrate(args: &ArgMatches, config: &Config) -> Result<(), CliErr> {
+    let app = storage_at(config);
+    match args.value_of("into") {
+        Some(into) => migrate::migrate_into(&app, Path::new(into)),
+        None => migrate::open(&app)
+    }
+        .map(|applied| applied.into_iter().for_each(|description| println!("{}", description)))
+        .map_err(CliErr::Api)
+}
+
+fn hash_password(args: &ArgMatches) -> Result<(), CliErr> {
+    let user = args.value_of("user").unwrap_or("");
+    let mut password = String::new();
+    io::stdin().read_line(&mut password).map_err(CliErr::IO)?;
+    let password = password.trim_end_matches(['\n', '\r']);
+    if password.is_empty() {
+        return Err(CliErr::Usage(String::from("the password is empty")));
+    }
+    println!("{}:{}", user, PasswordHash::new(password));
+    Ok(())
+}
+
+fn issue_token(args: &ArgMatches) -> Result<(), CliErr> {
+    let user = args.value_of("user").unwrap_or("");
+    let token = auth::new_token();
+    eprintln!("token: {}", token);
+    println!("{}:{}", user, auth::token_hash(token.as_str()));
+    Ok(())
+}
+
+/// Runs the subcommand named on the command line. With no subcommand the
+/// server is started, as it always has been. Hashing a password and issuing
+/// a token need no configuration, so they work whatever state it is in.
+pub fn run() -> Result<(), CliErr> {
+    let matches = app().get_matches();
+    let (name, sub_args) = matches.subcommand();
+    let args = sub_args.unwrap_or(&matches);
+    match name {
+        "hash-password" => return hash_password(args),
+        "issue-token" => return issue_token(args),
+        _ => ()
+    }
+    let config = configure(args).map_err(CliErr::Config)?;
+    config.log_level()
+        .map_err(CliErr::Config)
+        .and_then(|level| simple_logger::init_with_level(level)
+            .map_err(|err| CliErr::Usage(err.to_string())))?;
+    match name {
+        "get" => get(args, &config),
+        "put" => put(args, &config),
+        "delete" => delete(args, &config),
+        "import" => import(args, &config),
+        "export" => export(args, &config),
+        "fsck" => check(args, &config),
+        "migrate" => migrate(args, &config),
+        _ => serve(&config)
+    }
+}
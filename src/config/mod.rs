@@ -0,0 +1,492 @@
+extern crate toml;
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use log::Level;
+
+use super::api::limits::Limits;
+use super::server::http::client;
+use super::server::http::cors::ANY_ORIGIN;
+
+pub const DEFAULT_LISTEN: &str = "0.0.0.0:3000";
+pub const DEFAULT_STORAGE_DIR: &str = "rest-storage";
+const ENV_PREFIX: &str = "RUST_MONAD_";
+pub const CONFIG_ENV: &str = "RUST_MONAD_CONFIG";
+const ENGINES: &[&str] = &["hyper", "iron"];
+const BACKENDS: &[&str] = &["filesystem"];
+const TENANCY_MODES: &[&str] = &["off", "user", "header"];
+/// The longest a primary lets a request for changes wait.
+const MAX_POLL_SECS: u64 = 300;
+
+#[derive(Debug)]
+pub enum ConfigErr {
+    File(PathBuf, io::Error),
+    Parse(PathBuf, String),
+    UnknownKey(String),
+    Invalid { key: String, message: String },
+}
+
+impl fmt::Display for ConfigErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigErr::File(path, err) => write!(f, "could not read {}: {}", path.display(), err),
+            ConfigErr::Parse(path, err) => write!(f, "could not parse {}: {}", path.display(), err),
+            ConfigErr::UnknownKey(key) => write!(f, "unknown setting {}", key),
+            ConfigErr::Invalid { key, message } => write!(f, "{}: {}", key, message),
+        }
+    }
+}
+
+fn invalid<T: fmt::Display>(key: &str, message: T) -> ConfigErr {
+    ConfigErr::Invalid { key: String::from(key), message: message.to_string() }
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub address: String,
+    pub engine: String,
+}
+
+impl Default for ListenerConfig {
+    fn default() -> ListenerConfig {
+        ListenerConfig { address: String::from(DEFAULT_LISTEN), engine: String::from(ENGINES[0]) }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: String,
+    /// The storage directory, absolute or relative to the working directory.
+    pub root: PathBuf,
+}
+
+impl Default for StorageConfig {
+    fn default() -> StorageConfig {
+        StorageConfig { backend: String::from(BACKENDS[0]), root: PathBuf::from(DEFAULT_STORAGE_DIR) }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub level: String,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> LoggingConfig {
+        LoggingConfig { level: String::from("info") }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub enabled: bool,
+    pub users_file: Option<String>,
+    pub tokens_file: Option<String>,
+    /// Principals that every access control rule lets through.
+    pub admins: Vec<String>,
+}
+
+/// Keys and checks for JWT bearer tokens. Tokens are accepted once any of
+/// `secret` (HS256), `public_key_file` or `jwks_file` is set.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct JwtConfig {
+    pub secret: Option<String>,
+    pub public_key_file: Option<String>,
+    pub jwks_file: Option<String>,
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    /// The claim naming the principal.
+    pub user_claim: String,
+    /// The claim listing the permissions a token grants, if tokens are to
+    /// be limited to them.
+    pub scopes_claim: Option<String>,
+    /// Seconds of clock skew allowed when checking `exp` and `nbf`.
+    pub leeway: u64,
+    /// Accepts tokens without an `exp` claim, which are otherwise refused
+    /// since they would never expire.
+    pub allow_missing_exp: bool,
+}
+
+impl Default for JwtConfig {
+    fn default() -> JwtConfig {
+        JwtConfig {
+            secret: None,
+            public_key_file: None,
+            jwks_file: None,
+            issuer: None,
+            audience: None,
+            user_claim: String::from("sub"),
+            scopes_claim: None,
+            leeway: 60,
+            allow_missing_exp: false,
+        }
+    }
+}
+
+impl JwtConfig {
+    pub fn has_keys(&self) -> bool {
+        self.secret.is_some() || self.public_key_file.is_some() || self.jwks_file.is_some()
+    }
+}
+
+/// The log of requests that may change the tree, kept under the storage
+/// root and rotated once it would grow past `max_bytes`.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    pub enabled: bool,
+    pub max_bytes: u64,
+    /// How many rotated files are kept besides the current one.
+    pub max_files: usize,
+}
+
+impl Default for AuditConfig {
+    fn default() -> AuditConfig {
+        AuditConfig { enabled: false, max_bytes: 10 * 1024 * 1024, max_files: 5 }
+    }
+}
+
+/// How many recent changes are kept for `/_changes` readers to catch up on.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChangesConfig {
+    pub capacity: usize,
+}
+
+impl Default for ChangesConfig {
+    fn default() -> ChangesConfig {
+        ChangesConfig { capacity: 10000 }
+    }
+}
+
+/// How changes are delivered to webhooks: each delivery is attempted up to
+/// `max_attempts` times, `backoff_secs` apart at first and twice as far
+/// apart after every failure.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhooksConfig {
+    pub enabled: bool,
+    pub max_attempts: u32,
+    pub backoff_secs: u64,
+    pub timeout_secs: u64,
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> WebhooksConfig {
+        WebhooksConfig { enabled: false, max_attempts: 5, backoff_secs: 1, timeout_secs: 10 }
+    }
+}
+
+/// With a `primary` set, the server is a read-only follower of that one,
+/// asking it for changes that wait up to `poll_secs` to be made.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplicationConfig {
+    pub primary: Option<String>,
+    /// The `Authorization` header sent to the primary.
+    pub authorization: Option<String>,
+    pub poll_secs: u64,
+    pub retry_secs: u64,
+    pub timeout_secs: u64,
+}
+
+impl Default for ReplicationConfig {
+    fn default() -> ReplicationConfig {
+        ReplicationConfig { primary: None, authorization: None, poll_secs: 30, retry_secs: 5, timeout_secs: 60 }
+    }
+}
+
+/// Which browser origins may call the API. Nothing is allowed while
+/// `allowed_origins` is empty.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    pub allow_credentials: bool,
+    pub max_age_secs: u64,
+}
+
+impl Default for CorsConfig {
+    fn default() -> CorsConfig {
+        CorsConfig {
+            allowed_origins: Vec::new(),
+            allowed_methods: ["GET", "PUT", "POST", "DELETE"].iter().map(|m| String::from(*m)).collect(),
+            allowed_headers: ["authorization", "content-type", "accept", "x-ttl", "x-request-id", "x-tenant", "last-event-id"]
+                .iter().map(|h| String::from(*h)).collect(),
+            allow_credentials: false,
+            max_age_secs: 600,
+        }
+    }
+}
+
+/// With `cert_file` and `key_file` set, the server speaks HTTPS only, and
+/// with `client_ca_file` set, it asks clients for certificates issued by
+/// one of those authorities.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert_file: Option<String>,
+    pub key_file: Option<String>,
+    pub client_ca_file: Option<String>,
+    /// Refuses connections without a client certificate, rather than
+    /// leaving them to other credentials.
+    pub require_client_cert: bool,
+}
+
+impl TlsConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.cert_file.is_some() || self.key_file.is_some()
+    }
+}
+
+/// Whether each principal, or the tenant named in `header`, gets a tree of
+/// its own.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TenancyConfig {
+    pub mode: String,
+    pub header: String,
+}
+
+impl Default for TenancyConfig {
+    fn default() -> TenancyConfig {
+        TenancyConfig { mode: String::from(TENANCY_MODES[0]), header: String::from("x-tenant") }
+    }
+}
+
+/// Everything the server can be configured with. Settings are taken from
+/// the defaults, then a TOML file, then `RUST_MONAD_*` environment variables
+/// and finally command line flags, each overriding the ones before.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listener: ListenerConfig,
+    pub tls: TlsConfig,
+    pub storage: StorageConfig,
+    pub limits: Limits,
+    pub logging: LoggingConfig,
+    pub auth: AuthConfig,
+    pub jwt: JwtConfig,
+    pub tenancy: TenancyConfig,
+    pub audit: AuditConfig,
+    pub changes: ChangesConfig,
+    pub webhooks: WebhooksConfig,
+    pub replication: ReplicationConfig,
+    pub cors: CorsConfig,
+}
+
+fn parse<T: FromStr>(key: &str, value: &str) -> Result<T, ConfigErr> where T::Err: fmt::Display {
+    T::from_str(value.trim()).map_err(|err| invalid(key, format!("{} ({})", err, value)))
+}
+
+fn optional(value: &str) -> Option<String> {
+    if value.is_empty() { None } else { Some(String::from(value)) }
+}
+
+fn list(value: &str) -> Vec<String> {
+    value.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+impl Config {
+    pub fn parse_toml(content: &str, path: &Path) -> Result<Config, ConfigErr> {
+        toml::from_str(content).map_err(|err| ConfigErr::Parse(path.to_path_buf(), err.to_string()))
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config, ConfigErr> {
+        fs::read_to_string(path)
+            .map_err(|err| ConfigErr::File(path.to_path_buf(), err))
+            .and_then(|content| Config::parse_toml(content.as_str(), path))
+    }
+
+    /// Overrides a single setting, named by its section and key as in the
+    /// file, such as `listener.address`.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigErr> {
+        match key {
+            "listener.address" => self.listener.address = String::from(value),
+            "listener.engine" => self.listener.engine = String::from(value),
+            "tls.cert_file" => self.tls.cert_file = optional(value),
+            "tls.key_file" => self.tls.key_file = optional(value),
+            "tls.client_ca_file" => self.tls.client_ca_file = optional(value),
+            "tls.require_client_cert" => self.tls.require_client_cert = parse(key, value)?,
+            "storage.backend" => self.storage.backend = String::from(value),
+            "storage.root" => self.storage.root = PathBuf::from(value),
+            "limits.max_body_length" => self.limits.max_body_length = parse(key, value)?,
+            "limits.max_depth" => self.limits.max_depth = parse(key, value)?,
+            "limits.max_members" => self.limits.max_members = parse(key, value)?,
+            "limits.max_array_length" => self.limits.max_array_length = parse(key, value)?,
+            "limits.max_archive_length" => self.limits.max_archive_length = parse(key, value)?,
+            "logging.level" => self.logging.level = String::from(value),
+            "auth.enabled" => self.auth.enabled = parse(key, value)?,
+            "auth.users_file" => self.auth.users_file = optional(value),
+            "auth.tokens_file" => self.auth.tokens_file = optional(value),
+            "auth.admins" => self.auth.admins = list(value),
+            "jwt.secret" => self.jwt.secret = optional(value),
+            "jwt.public_key_file" => self.jwt.public_key_file = optional(value),
+            "jwt.jwks_file" => self.jwt.jwks_file = optional(value),
+            "jwt.issuer" => self.jwt.issuer = optional(value),
+            "jwt.audience" => self.jwt.audience = optional(value),
+            "jwt.user_claim" => self.jwt.user_claim = String::from(value),
+            "jwt.scopes_claim" => self.jwt.scopes_claim = optional(value),
+            "jwt.leeway" => self.jwt.leeway = parse(key, value)?,
+            "jwt.allow_missing_exp" => self.jwt.allow_missing_exp = parse(key, value)?,
+            "tenancy.mode" => self.tenancy.mode = String::from(value),
+            "tenancy.header" => self.tenancy.header = String::from(value),
+            "audit.enabled" => self.audit.enabled = parse(key, value)?,
+            "audit.max_bytes" => self.audit.max_bytes = parse(key, value)?,
+            "audit.max_files" => self.audit.max_files = parse(key, value)?,
+            "changes.capacity" => self.changes.capacity = parse(key, value)?,
+            "webhooks.enabled" => self.webhooks.enabled = parse(key, value)?,
+            "webhooks.max_attempts" => self.webhooks.max_attempts = parse(key, value)?,
+            "webhooks.backoff_secs" => self.webhooks.backoff_secs = parse(key, value)?,
+            "webhooks.timeout_secs" => self.webhooks.timeout_secs = parse(key, value)?,
+            "replication.primary" => self.replication.primary = optional(value),
+            "replication.authorization" => self.replication.authorization = optional(value),
+            "replication.poll_secs" => self.replication.poll_secs = parse(key, value)?,
+            "replication.retry_secs" => self.replication.retry_secs = parse(key, value)?,
+            "replication.timeout_secs" => self.replication.timeout_secs = parse(key, value)?,
+            "cors.allowed_origins" => self.cors.allowed_origins = list(value),
+            "cors.allowed_methods" => self.cors.allowed_methods = list(value),
+            "cors.allowed_headers" => self.cors.allowed_headers = list(value),
+            "cors.allow_credentials" => self.cors.allow_credentials = parse(key, value)?,
+            "cors.max_age_secs" => self.cors.max_age_secs = parse(key, value)?,
+            _ => return Err(ConfigErr::UnknownKey(String::from(key))),
+        }
+        Ok(())
+    }
+
+    /// Applies every `RUST_MONAD_<SECTION>_<KEY>` variable, so that
+    /// `RUST_MONAD_LISTENER_ADDRESS` sets `listener.address`.
+    pub fn apply_env<I: IntoIterator<Item=(String, String)>>(&mut self, vars: I) -> Result<(), ConfigErr> {
+        vars.into_iter()
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX) && name.as_str() != CONFIG_ENV)
+            .try_for_each(|(name, value)| {
+                let key = name[ENV_PREFIX.len()..].to_lowercase().replacen('_', ".", 1);
+                self.set(key.as_str(), value.as_str())
+                    .map_err(|err| match err {
+                        ConfigErr::UnknownKey(_) => ConfigErr::UnknownKey(name.clone()),
+                        err => err
+                    })
+            })
+    }
+
+    /// Loads the file named on the command line or in `RUST_MONAD_CONFIG`,
+    /// if any, then applies the environment.
+    pub fn load(file: Option<&str>) -> Result<Config, ConfigErr> {
+        let file = file.map(String::from).or_else(|| env::var(CONFIG_ENV).ok());
+        let mut config = match file {
+            Some(file) => Config::from_file(Path::new(file.as_str()))?,
+            None => Config::default()
+        };
+        config.apply_env(env::vars())?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigErr> {
+        self.listen_addr()?;
+        self.log_level()?;
+        if !ENGINES.contains(&self.listener.engine.as_str()) {
+            return Err(invalid("listener.engine", format!("expected one of {}", ENGINES.join(", "))));
+        }
+        match (&self.tls.cert_file, &self.tls.key_file) {
+            (Some(_), None) => return Err(invalid("tls.cert_file", "needs tls.key_file as well")),
+            (None, Some(_)) => return Err(invalid("tls.key_file", "needs tls.cert_file as well")),
+            _ => ()
+        }
+        if self.tls.is_enabled() && self.listener.engine != ENGINES[0] {
+            return Err(invalid("listener.engine", format!("only {} can serve TLS", ENGINES[0])));
+        }
+        if self.tls.client_ca_file.is_some() && !self.tls.is_enabled() {
+            return Err(invalid("tls.client_ca_file", "needs tls.cert_file and tls.key_file"));
+        }
+        if self.tls.require_client_cert && self.tls.client_ca_file.is_none() {
+            return Err(invalid("tls.require_client_cert", "needs tls.client_ca_file"));
+        }
+        if !BACKENDS.contains(&self.storage.backend.as_str()) {
+            return Err(invalid("storage.backend", format!("expected one of {}", BACKENDS.join(", "))));
+        }
+        if self.storage.root.as_os_str().is_empty() {
+            return Err(invalid("storage.root", "must not be empty"));
+        }
+        if self.storage.root.file_name().is_none() {
+            return Err(invalid("storage.root", "must name a directory, not / or .."));
+        }
+        [("limits.max_body_length", self.limits.max_body_length),
+            ("limits.max_depth", self.limits.max_depth),
+            ("limits.max_members", self.limits.max_members),
+            ("limits.max_array_length", self.limits.max_array_length),
+            ("limits.max_archive_length", self.limits.max_archive_length)]
+            .iter()
+            .find(|(_, limit)| *limit == 0)
+            .map_or(Ok(()), |(key, _)| Err(invalid(key, "must be greater than zero")))?;
+        if !TENANCY_MODES.contains(&self.tenancy.mode.as_str()) {
+            return Err(invalid("tenancy.mode", format!("expected one of {}", TENANCY_MODES.join(", "))));
+        }
+        if self.tenancy.header.trim().is_empty() {
+            return Err(invalid("tenancy.header", "must not be empty"));
+        }
+        if self.changes.capacity == 0 {
+            return Err(invalid("changes.capacity", "must be greater than zero"));
+        }
+        if self.webhooks.max_attempts == 0 {
+            return Err(invalid("webhooks.max_attempts", "must be greater than zero"));
+        }
+        if self.webhooks.timeout_secs == 0 {
+            return Err(invalid("webhooks.timeout_secs", "must be greater than zero"));
+        }
+        if let Some(ref primary) = self.replication.primary {
+            let url = client::Url::parse(primary.as_str()).map_err(|err| invalid("replication.primary", err))?;
+            if self.replication.authorization.is_some() && !url.may_carry_credentials() {
+                return Err(invalid("replication.authorization", "is only sent to a primary over https://, or on this machine"));
+            }
+        }
+        if self.replication.poll_secs == 0 || self.replication.poll_secs > MAX_POLL_SECS {
+            return Err(invalid("replication.poll_secs", format!("must be between 1 and {}", MAX_POLL_SECS)));
+        }
+        if self.replication.timeout_secs == 0 {
+            return Err(invalid("replication.timeout_secs", "must be greater than zero"));
+        }
+        if let Some(origin) = self.cors.allowed_origins.iter()
+            .find(|origin| origin.as_str() != ANY_ORIGIN && !origin.starts_with("http://") && !origin.starts_with("https://")) {
+            return Err(invalid("cors.allowed_origins", format!("{} is neither * nor an http:// or https:// origin", origin)));
+        }
+        if self.cors.allow_credentials && self.cors.allowed_origins.iter().any(|origin| origin == ANY_ORIGIN) {
+            return Err(invalid("cors.allow_credentials", "cannot be combined with the origin *"));
+        }
+        if self.audit.max_bytes == 0 {
+            return Err(invalid("audit.max_bytes", "must be greater than zero"));
+        }
+        if self.jwt.user_claim.trim().is_empty() {
+            return Err(invalid("jwt.user_claim", "must not be empty"));
+        }
+        if self.auth.enabled && self.auth.users_file.is_none() && self.auth.tokens_file.is_none() && !self.jwt.has_keys()
+            && self.tls.client_ca_file.is_none() {
+            return Err(invalid("auth.enabled", "needs auth.users_file, auth.tokens_file, a jwt key or tls.client_ca_file"));
+        }
+        Ok(())
+    }
+
+    pub fn listen_addr(&self) -> Result<SocketAddr, ConfigErr> {
+        parse("listener.address", self.listener.address.as_str())
+    }
+
+    pub fn log_level(&self) -> Result<Level, ConfigErr> {
+        parse("logging.level", self.logging.level.as_str())
+    }
+}
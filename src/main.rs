@@ -1,4 +1,7 @@
 mod api;
+mod auth;
+mod cli;
+mod config;
 mod server;
 
 #[cfg(test)]
@@ -9,23 +12,125 @@ extern crate serde_derive;
 extern crate log;
 
 const MAX_BODY_LENGTH: usize = 1024 * 1024 * 10;
+const MAX_DEPTH: usize = 32;
+const MAX_MEMBERS: usize = 1024;
+const MAX_ARRAY_LENGTH: usize = 10000;
+const MAX_ARCHIVE_LENGTH: usize = 1024 * 1024 * 1024;
 
 
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
 struct RestApp {
-    storage_dir: String
+    storage_dir: PathBuf,
+    limits: api::limits::Limits,
+    write_lock: Arc<RwLock<()>>,
+    authenticator: Arc<auth::Authenticator>,
+    admins: Arc<Vec<String>>,
+    tenancy: api::tenant::Tenancy,
+    audit: Option<Arc<api::audit::AuditLog>>,
+    changes: Option<Arc<api::changes::ChangeFeed>>,
+    replica: Option<Arc<api::replication::Replica>>,
+    cors: Option<Arc<server::http::cors::Cors>>
+}
+
+impl RestApp {
+    fn new<P: Into<PathBuf>>(storage_dir: P) -> RestApp {
+        RestApp {
+            storage_dir: storage_dir.into(),
+            limits: api::limits::Limits::default(),
+            write_lock: Arc::new(RwLock::new(())),
+            authenticator: Arc::new(auth::Authenticator::disabled()),
+            admins: Arc::new(Vec::new()),
+            tenancy: api::tenant::Tenancy::Off,
+            audit: None,
+            changes: None,
+            replica: None,
+            cors: None
+        }
+    }
+
+    /// Holds incoming documents to `limits` instead of the defaults.
+    fn with_limits(self, limits: api::limits::Limits) -> RestApp {
+        RestApp { limits, ..self }
+    }
+
+    /// Requires every HTTP request to be authenticated by `authenticator`.
+    fn with_authenticator(self, authenticator: auth::Authenticator) -> RestApp {
+        RestApp { authenticator: Arc::new(authenticator), ..self }
+    }
+
+    /// Lets `admins` past every access control rule.
+    fn with_admins(self, admins: Vec<String>) -> RestApp {
+        RestApp { admins: Arc::new(admins), ..self }
+    }
+
+    /// Serves each request from its tenant's tree, as `tenancy` decides.
+    fn with_tenancy(self, tenancy: api::tenant::Tenancy) -> RestApp {
+        RestApp { tenancy, ..self }
+    }
+
+    /// Records every request that may change the tree in `log`.
+    fn with_audit(self, log: api::audit::AuditLog) -> RestApp {
+        RestApp { audit: Some(Arc::new(log)), ..self }
+    }
+
+    /// Publishes every write and delete to `feed`.
+    fn with_changes(self, feed: api::changes::ChangeFeed) -> RestApp {
+        RestApp { changes: Some(Arc::new(feed)), ..self }
+    }
+
+    /// Serves reads only, while `replica` follows a primary.
+    fn with_replica(self, replica: Arc<api::replication::Replica>) -> RestApp {
+        RestApp { replica: Some(replica), ..self }
+    }
+
+    /// Lets browsers call the API from the origins `cors` allows.
+    fn with_cors(self, cors: server::http::cors::Cors) -> RestApp {
+        RestApp { cors: Some(Arc::new(cors)), ..self }
+    }
+
+    /// The same server, with its root moved to the tree of tenant `name`.
+    /// The name must already have been checked with `is_valid_name`.
+    fn for_tenant(&self, name: &str) -> RestApp {
+        RestApp {
+            storage_dir: self.storage_dir.join(api::tenant::TENANTS_PATH).join(name),
+            tenancy: api::tenant::Tenancy::Off,
+            ..self.clone()
+        }
+    }
 }
 
 impl Clone for RestApp {
     fn clone(&self) -> RestApp {
-        RestApp { storage_dir: self.storage_dir.clone() }
+        RestApp {
+            storage_dir: self.storage_dir.clone(),
+            limits: self.limits,
+            write_lock: self.write_lock.clone(),
+            authenticator: self.authenticator.clone(),
+            admins: self.admins.clone(),
+            tenancy: self.tenancy.clone(),
+            audit: self.audit.clone(),
+            changes: self.changes.clone(),
+            replica: self.replica.clone(),
+            cors: self.cors.clone()
+        }
     }
 }
  
 
 fn main() {
-    simple_logger::init().unwrap();
-
-    server::http::hyper::server();
+    match cli::run() {
+        Ok(()) => (),
+        Err(cli::CliErr::Config(err)) => {
+            eprintln!("Invalid configuration: {}", err);
+            std::process::exit(2);
+        },
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    }
 }
 
 
@@ -0,0 +1,215 @@
+//! A blocking HTTP/1.1 client for the few requests the server makes
+//! itself, such as webhook deliveries and replication. `http://` and
+//! `https://` URLs are supported, and every request is made on a
+//! connection of its own.
+
+extern crate openssl;
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::time::Duration;
+
+use self::openssl::ssl::{SslConnector, SslMethod};
+
+/// The most a response body may hold, unless a request allows more.
+pub const MAX_BODY_LENGTH: u64 = 64 * 1024 * 1024;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Url {
+    /// Whether the URL is `https://`, which is verified against the
+    /// system's certificate authorities.
+    pub tls: bool,
+    pub host: String,
+    pub port: u16,
+    /// The path and query, starting with `/`.
+    pub target: String,
+}
+
+impl Url {
+    pub fn parse(url: &str) -> Result<Url, String> {
+        let (tls, rest) = match (url.strip_prefix("https://"), url.strip_prefix("http://")) {
+            (Some(rest), _) => (true, rest),
+            (None, Some(rest)) => (false, rest),
+            (None, None) => return Err(format!("{} is not an http:// or https:// URL", url))
+        };
+        let (authority, target) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], &rest[slash..]),
+            None => (rest, "/")
+        };
+        let (host, port) = match authority.rfind(':') {
+            Some(colon) => (&authority[..colon], u16::from_str(&authority[colon + 1..])
+                .map_err(|_| format!("{} has an invalid port", url))?),
+            None => (authority, if tls { 443 } else { 80 })
+        };
+        if host.is_empty() {
+            return Err(format!("{} has no host", url));
+        }
+        Ok(Url { tls, host: String::from(host), port, target: String::from(target) })
+    }
+
+    /// Whether requests to the URL never leave this machine.
+    pub fn is_loopback(&self) -> bool {
+        self.host.eq_ignore_ascii_case("localhost") ||
+            IpAddr::from_str(self.host.trim_start_matches('[').trim_end_matches(']'))
+                .is_ok_and(|ip| ip.is_loopback())
+    }
+
+    /// Whether credentials may be sent to the URL: only over TLS, or to
+    /// this machine.
+    pub fn may_carry_credentials(&self) -> bool {
+        self.tls || self.is_loopback()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    /// With lower case names.
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+fn invalid(detail: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("malformed response: {}", detail))
+}
+
+fn too_long(limit: u64) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("the response body is longer than {} bytes", limit))
+}
+
+/// Copies exactly `length` bytes of the body to `out`.
+fn copy_exact<R: Read, W: Write + ?Sized>(reader: &mut R, length: u64, out: &mut W) -> io::Result<()> {
+    if io::copy(&mut reader.take(length), out)? < length {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the response body was cut short"));
+    }
+    Ok(())
+}
+
+/// Copies a chunked body to `out` as it is read, failing once it grows
+/// past `limit`.
+fn read_chunked<R: BufRead, W: Write + ?Sized>(reader: &mut R, limit: u64, out: &mut W) -> io::Result<()> {
+    let mut length = 0u64;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let size = line.trim().split(';').next()
+            .and_then(|size| u64::from_str_radix(size, 16).ok())
+            .ok_or_else(|| invalid("bad chunk size"))?;
+        if size == 0 {
+            return Ok(());
+        }
+        length = length.saturating_add(size);
+        if length > limit {
+            return Err(too_long(limit));
+        }
+        copy_exact(reader, size, out)?;
+        reader.read_line(&mut line)?;
+    }
+}
+
+/// Copies the body to `out` as it is read, failing once it grows past
+/// `limit`.
+fn read_body<R: BufRead, W: Write + ?Sized>(reader: &mut R, headers: &HashMap<String, String>, limit: u64, out: &mut W) -> io::Result<()> {
+    if headers.get("transfer-encoding").is_some_and(|encoding| encoding.contains("chunked")) {
+        return read_chunked(reader, limit, out);
+    }
+    match headers.get("content-length") {
+        Some(length) => {
+            let length = u64::from_str(length).map_err(|_| invalid("bad content-length"))?;
+            if length > limit {
+                return Err(too_long(limit));
+            }
+            copy_exact(reader, length, out)
+        }
+        None => if io::copy(&mut reader.take(limit + 1), out)? > limit {
+            Err(too_long(limit))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+trait Connection: Read + Write {}
+
+impl<S: Read + Write> Connection for S {}
+
+fn connect(url: &Url, timeout: Duration) -> io::Result<Box<Connection>> {
+    let addr = (url.host.as_str(), url.port).to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} has no address", url.host)))?;
+    let stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    if !url.tls {
+        return Ok(Box::new(stream));
+    }
+    let connector = SslConnector::builder(SslMethod::tls_client()).map_err(io::Error::other)?.build();
+    connector.connect(url.host.as_str(), stream)
+        .map(|stream| Box::new(stream) as Box<Connection>)
+        .map_err(|err| io::Error::other(format!("could not connect to {} over TLS: {}", url.host, err)))
+}
+
+/// Sends a request with `body` and reads the whole response, giving up on
+/// any step that takes longer than `timeout`.
+pub fn request(method: &str, url: &str, headers: &[(&str, String)], body: &[u8], timeout: Duration) -> io::Result<Response> {
+    let mut received = Vec::new();
+    let response = exchange(method, url, headers, body, timeout, MAX_BODY_LENGTH, &mut received)?;
+    Ok(Response { body: received, ..response })
+}
+
+/// Sends a request with `body` and writes the response body to `out` as
+/// it is read, up to `limit` bytes. The response returned has no body.
+/// Credentials are only sent over TLS or to this machine.
+pub fn exchange<W: Write + ?Sized>(method: &str, url: &str, headers: &[(&str, String)], body: &[u8], timeout: Duration, limit: u64,
+                                   out: &mut W) -> io::Result<Response> {
+    let url = Url::parse(url).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    if !url.may_carry_credentials() && headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("authorization")) {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+            format!("credentials are only sent to {} over https://", url.host)));
+    }
+    let mut stream = connect(&url, timeout)?;
+
+    let mut head = format!("{} {} HTTP/1.1\r\nhost: {}:{}\r\nconnection: close\r\ncontent-length: {}\r\n",
+        method, url.target, url.host, url.port, body.len());
+    headers.iter().for_each(|(name, value)| head.push_str(format!("{}: {}\r\n", name, value).as_str()));
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()?;
+
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let status = line.split_whitespace().nth(1)
+        .and_then(|status| u16::from_str(status).ok())
+        .ok_or_else(|| invalid("bad status line"))?;
+    let mut headers = HashMap::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        let mut header = line.splitn(2, ':');
+        if let (Some(name), Some(value)) = (header.next(), header.next()) {
+            headers.insert(name.trim().to_lowercase(), String::from(value.trim()));
+        }
+    }
+    read_body(&mut reader, &headers, limit, out)?;
+    Ok(Response { status, headers, body: Vec::new() })
+}
+
+pub fn post(url: &str, headers: &[(&str, String)], body: &[u8], timeout: Duration) -> io::Result<Response> {
+    request("POST", url, headers, body, timeout)
+}
+
+pub fn get(url: &str, headers: &[(&str, String)], timeout: Duration) -> io::Result<Response> {
+    request("GET", url, headers, &[], timeout)
+}
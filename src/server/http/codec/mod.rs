@@ -0,0 +1,124 @@
+extern crate serde_json;
+extern crate serde_cbor;
+extern crate rmp_serde;
+extern crate serde_yaml;
+
+use self::serde_json::Value;
+use super::super::super::api::APIErr;
+
+const JSON: &str = "application/json";
+const CBOR: &str = "application/cbor";
+const MSGPACK: &str = "application/msgpack";
+const YAML: &str = "application/yaml";
+
+/// The wire formats documents can be read and written in. All of them map
+/// onto the same `Value` model that the storage tree is built from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    Cbor,
+    MsgPack,
+    Yaml
+}
+
+fn media_type(value: &str) -> String {
+    value.split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_lowercase()
+}
+
+fn quality(range: &str) -> f32 {
+    range.split(';')
+        .skip(1)
+        .flat_map(|param| {
+            let mut kv = param.splitn(2, '=');
+            match (kv.next().map(str::trim), kv.next()) {
+                (Some("q"), Some(q)) => q.trim().parse::<f32>().ok(),
+                _ => None
+            }
+        })
+        .next()
+        .unwrap_or(1.0)
+}
+
+impl Format {
+    pub fn from_media_type(media_type: &str) -> Option<Format> {
+        match media_type {
+            JSON | "text/json" => Some(Format::Json),
+            CBOR => Some(Format::Cbor),
+            MSGPACK | "application/x-msgpack" => Some(Format::MsgPack),
+            YAML | "application/x-yaml" | "text/yaml" | "text/x-yaml" => Some(Format::Yaml),
+            _ => None
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "json" => Some(Format::Json),
+            "cbor" => Some(Format::Cbor),
+            "msgpack" => Some(Format::MsgPack),
+            "yaml" => Some(Format::Yaml),
+            _ => None
+        }
+    }
+
+    /// The format of a request body. Requests without a `Content-Type` are
+    /// taken to be JSON.
+    pub fn from_content_type(content_type: Option<&str>) -> Result<Format, APIErr> {
+        match content_type {
+            None => Ok(Format::Json),
+            Some(value) => Format::from_media_type(media_type(value).as_str())
+                .ok_or_else(|| APIErr::UnsupportedMediaType(String::from(value)))
+        }
+    }
+
+    /// The most preferred format named in an `Accept` header. Wildcards and
+    /// a missing header select JSON.
+    pub fn from_accept(accept: Option<&str>) -> Result<Format, APIErr> {
+        let value = match accept {
+            None => return Ok(Format::Json),
+            Some(value) => value
+        };
+        let mut ranges = value.split(',')
+            .map(|range| (media_type(range), quality(range)))
+            .filter(|(_, q)| *q > 0.0)
+            .collect::<Vec<(String, f32)>>();
+        ranges.sort_by(|(_, q), (_, q2)| q2.partial_cmp(q).unwrap_or(::std::cmp::Ordering::Equal));
+        ranges.into_iter()
+            .flat_map(|(range, _)| match range.as_str() {
+                "*/*" | "application/*" => Some(Format::Json),
+                other => Format::from_media_type(other)
+            })
+            .next()
+            .ok_or_else(|| APIErr::NotAcceptable(String::from(value)))
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Json => JSON,
+            Format::Cbor => CBOR,
+            Format::MsgPack => MSGPACK,
+            Format::Yaml => YAML
+        }
+    }
+
+    pub fn decode(&self, body: &[u8]) -> Result<Value, APIErr> {
+        match self {
+            Format::Json => serde_json::from_slice(body).map_err(|err| err.to_string()),
+            Format::Cbor => serde_cbor::from_slice(body).map_err(|err| err.to_string()),
+            Format::MsgPack => rmp_serde::from_slice(body).map_err(|err| err.to_string()),
+            Format::Yaml => serde_yaml::from_slice(body).map_err(|err| err.to_string())
+        }.map_err(APIErr::Decode)
+    }
+
+    pub fn encode(&self, value: &Value) -> Result<Vec<u8>, APIErr> {
+        match self {
+            Format::Json => serde_json::to_vec(value).map_err(|err| err.to_string()),
+            Format::Cbor => serde_cbor::to_vec(value).map_err(|err| err.to_string()),
+            Format::MsgPack => rmp_serde::to_vec(value).map_err(|err| err.to_string()),
+            Format::Yaml => serde_yaml::to_vec(value).map_err(|err| err.to_string())
+        }.map_err(APIErr::Encode)
+    }
+}
@@ -0,0 +1,855 @@
+extern crate serde;
+extern crate serde_json;
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use self::serde_json::Value;
+use super::super::super::RestApp;
+use super::super::super::api::{API, APIErr};
+use super::super::super::api::{acl, audit, changes, expiry, fsck, keys, ndjson, replication, snapshot, stream, tenant, webhooks};
+use super::super::super::api::limits::Limits;
+use super::super::super::api::tenant::Tenancy;
+use super::super::super::auth::{self, AuthErr, Principal};
+use super::codec::Format;
+use super::cors::Cors;
+use super::problem::{self, Problem};
+use super::websocket;
+
+pub const TTL_HEADER: &str = "x-ttl";
+/// Names a request in the audit log. Taken from the request if it has one.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+const TTL_PARAM: &str = "ttl";
+const BULK_PATH: &str = "_bulk";
+const EXPORT_PATH: &str = "_export";
+const FSCK_PATH: &str = "_fsck";
+const MODE_PARAM: &str = "mode";
+const FORMAT_PARAM: &str = "format";
+const SECRETS_PARAM: &str = "secrets";
+const JSON: &str = "application/json";
+const EVENT_STREAM: &str = "text/event-stream";
+/// How long a long-polling `/_changes` request waits by default, and at
+/// most, for a change.
+const CHANGES_WAIT_SECS: u64 = 30;
+const MAX_CHANGES_WAIT_SECS: u64 = 300;
+/// How often an event stream with nothing to report sends a comment, so
+/// that proxies keep it open and a closed connection is noticed.
+const HEARTBEAT_SECS: u64 = 15;
+const NDJSON: &str = "application/x-ndjson";
+
+/// A request as every engine sees it: the path split into segments, the
+/// query and headers with lower case names, and the body read in full, or
+/// spooled for archives being restored.
+pub struct Request {
+    pub method: String,
+    pub path: Vec<String>,
+    pub query: HashMap<String, String>,
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+    /// Who the verified client certificate of the connection names, if it
+    /// presented one.
+    pub client_subject: Option<String>,
+    /// The body, when it was written to a spool as it was received instead
+    /// of being read into `body`, as archives being restored are.
+    pub spooled: Option<snapshot::Spool>,
+}
+
+pub type Chunks = Box<Iterator<Item=Result<Vec<u8>, APIErr>> + Send>;
+
+/// What a `Pending` body has ready.
+pub enum Next {
+    Chunk(Result<Vec<u8>, APIErr>),
+    /// Nothing yet; the waker given will be called once there may be.
+    Wait,
+    End,
+}
+
+/// A body whose chunks come when something happens, such as a change
+/// being published, rather than from work a thread has to do.
+pub trait Pending: Send {
+    fn poll_next(&mut self, wake: changes::Waker) -> Next;
+}
+
+/// The chunks of a `Pending` body, blocking the calling thread while
+/// there are none, for engines that write out iterators.
+pub fn wait_for(mut pending: Box<Pending>) -> Chunks {
+    let (woken, wait) = mpsc::channel::<()>();
+    Box::new(::std::iter::from_fn(move || loop {
+        let woken = woken.clone();
+        match pending.poll_next(Box::new(move || {
+            let _ = woken.send(());
+        })) {
+            Next::Chunk(chunk) => return Some(chunk),
+            Next::End => return None,
+            Next::Wait => if wait.recv().is_err() {
+                return None;
+            }
+        }
+    }))
+}
+/// Takes over the connection, given its two halves, once the response has
+/// been sent.
+pub type Upgrade = Box<FnOnce(Box<Read + Send>, Box<Write + Send>) + Send>;
+
+pub enum Body {
+    Empty,
+    Bytes(Vec<u8>),
+    /// Written out a chunk at a time as the iterator yields them.
+    Stream(Chunks),
+    /// Written out a chunk at a time as they become ready.
+    Pending(Box<Pending>),
+    /// Sent with a 101 response, to switch the connection to another
+    /// protocol. Engines that cannot answer with `upgrade_unsupported`.
+    Upgrade(Upgrade),
+}
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Body,
+}
+
+impl Response {
+    fn new(status: u16, body: Body) -> Response {
+        Response { status, headers: Vec::new(), body }
+    }
+
+    fn ok(content_type: &str, body: Body) -> Response {
+        Response::new(200, body).with_header("content-type", String::from(content_type))
+    }
+
+    fn with_header(mut self, name: &'static str, value: String) -> Response {
+        self.headers.push((name, value));
+        self
+    }
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+
+    fn param(&self, name: &str) -> Option<&str> {
+        self.query.get(name).map(String::as_str)
+    }
+
+    /// The time to live asked for by the `X-TTL` header or the `ttl`
+    /// parameter, which must be a number of seconds when given.
+    fn ttl(&self) -> Result<Option<u64>, APIErr> {
+        self.header(TTL_HEADER)
+            .or_else(|| self.param(TTL_PARAM))
+            .map(|ttl| u64::from_str(ttl.trim())
+                .map_err(|_| APIErr::IO(io::Error::new(io::ErrorKind::InvalidInput, "ttl must be a number of seconds"))))
+            .transpose()
+    }
+
+    /// The path below a reserved endpoint such as `/_bulk`.
+    fn prefix(&self) -> Vec<String> {
+        self.path.iter().skip(1).cloned().collect()
+    }
+}
+
+/// Splits a URL path into its non-empty segments.
+pub fn split_path(path: &str) -> Vec<String> {
+    path.split('/')
+        .filter(|p| !p.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+pub fn parse_query(query: Option<&str>) -> HashMap<String, String> {
+    query.unwrap_or("")
+        .split('&')
+        .flat_map(|pair| {
+            let mut kv = pair.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(key), value) if !key.is_empty() => Some((String::from(key), String::from(value.unwrap_or("")))),
+                _ => None
+            }
+        })
+        .collect()
+}
+
+/// Whether an engine should write the body of `req` to a spool rather than
+/// read it into memory, which archives being restored are.
+pub fn spools_body(req: &Request) -> bool {
+    req.method == "PUT" && req.path.first().map(String::as_str) == Some(snapshot::SNAPSHOT_PATH)
+}
+
+/// The limits an engine checks the length of the body of `req` against as
+/// it reads it, giving up with `error_response(APIErr::BodyTooLarge(..), ..)`.
+/// Archives being restored have a limit of their own.
+pub fn body_limits(app: &RestApp, req: &Request) -> Limits {
+    let limits = app.limits();
+    if spools_body(req) {
+        Limits { max_body_length: limits.max_archive_length, ..limits }
+    } else {
+        limits
+    }
+}
+
+/// The URL path of a request, as named in problem details.
+pub fn instance(path: &[String]) -> String {
+    format!("/{}", path.join("/"))
+}
+
+fn problem_response(problem: Problem) -> Response {
+    Response::new(problem.status, Body::Bytes(problem.to_vec()))
+        .with_header("content-type", String::from(problem::PROBLEM_JSON))
+}
+
+pub fn upgrade_unsupported(path: &[String]) -> Response {
+    problem_response(Problem::new(501, String::from("this server engine cannot upgrade connections")).at(instance(path)))
+}
+
+pub fn error_response(err: APIErr, path: &[String]) -> Response {
+    error!("Service err: {}", err);
+    problem_response(Problem::from_err(&err).at(instance(path)))
+}
+
+/// Maps a request onto the `API` and its outcome onto a response. Engines
+/// only translate to and from these types.
+pub fn handle(app: &RestApp, req: Request) -> Response {
+    info!("{} /{}", req.method, req.path.join("/"));
+    let path = req.path.clone();
+    let request_id = req.header(REQUEST_ID_HEADER)
+        .map(String::from)
+        .unwrap_or_else(audit::new_request_id);
+    let cors = match (app.cors.as_ref(), req.header("origin")) {
+        (Some(cors), Some(origin)) => Some((cors.clone(), String::from(origin))),
+        _ => None
+    };
+    if let Some(response) = cors.as_ref().and_then(|(cors, origin)| preflight(cors, &req, origin)) {
+        return response.with_header(REQUEST_ID_HEADER, request_id);
+    }
+    let response = authenticate(app, &req)
+        .and_then(|principal| check_scopes(&principal, &req).map(|()| principal.name))
+        .and_then(|user| match app.audit {
+            Some(ref log) if is_mutating(&req) => audited(app, log, req, user, request_id.as_str()),
+            _ => dispatch(app, req, user)
+        })
+        .unwrap_or_else(|err| {
+            let response = error_response(err, &path);
+            match response.status {
+                401 => response.with_header("www-authenticate", app.authenticator.challenges()),
+                _ => response
+            }
+        })
+        .with_header(REQUEST_ID_HEADER, request_id);
+    match cors {
+        Some((cors, origin)) => cors.headers(origin.as_str()).into_iter()
+            .fold(response, |response, (name, value)| response.with_header(name, value)),
+        None => response
+    }
+}
+
+/// Answers a CORS preflight, which never carries credentials, before
+/// anything is authenticated. Other `OPTIONS` requests are served as usual.
+fn preflight(cors: &Cors, req: &Request, origin: &str) -> Option<Response> {
+    if req.method != "OPTIONS" {
+        return None;
+    }
+    let method = req.header("access-control-request-method")?;
+    Some(match cors.preflight(origin, method, req.header("access-control-request-headers")) {
+        Ok(headers) => headers.into_iter()
+            .fold(Response::new(204, Body::Empty), |response, (name, value)| response.with_header(name, value)),
+        Err(detail) => problem_response(Problem::new(403, detail).at(instance(&req.path)))
+    })
+}
+
+fn is_mutating(req: &Request) -> bool {
+    ["PUT", "POST", "PATCH", "DELETE"].contains(&req.method.as_str())
+}
+
+fn dispatch(app: &RestApp, req: Request, user: String) -> Result<Response, APIErr> {
+    let first = req.path.first().map(String::as_str);
+    if let Some(ref replica) = app.replica {
+        if is_mutating(&req) && first != Some(replication::REPLICATION_PATH) {
+            return Ok(read_only(&req, replica));
+        }
+    }
+    match first {
+        Some(tenant::TENANTS_PATH) => manage_tenants(app, req, user),
+        Some(keys::KEYS_PATH) => manage_keys(app, req, user),
+        Some(audit::AUDIT_PATH) => query_audit(app, req, user),
+        Some(webhooks::WEBHOOKS_PATH) => manage_webhooks(app, req, user),
+        Some(webhooks::DEAD_LETTERS_PATH) => manage_dead_letters(app, req, user),
+        Some(replication::REPLICATION_PATH) => manage_replication(app, req, user),
+        _ => scope(app, &req, &user).and_then(|app| route(&app, req, user))
+    }
+}
+
+/// Serves a request that may change the tree and appends it to `log`,
+/// along with hashes of the document it names before and after. A request
+/// the log cannot be written for is still served.
+fn audited(app: &RestApp, log: &audit::AuditLog, req: Request, user: String, request_id: &str) -> Result<Response, APIErr> {
+    let method = req.method.clone();
+    let path = req.path.clone();
+    let document = match path.first() {
+        Some(first) if first.starts_with('_') => None,
+        _ => scope(app, &req, &user).ok()
+    };
+    let old_hash = document.as_ref().and_then(|app| audit::document_hash(app, &path));
+    let result = dispatch(app, req, user.clone());
+    let new_hash = match result {
+        Ok(_) => document.as_ref().and_then(|app| audit::document_hash(app, &path)),
+        Err(_) => old_hash.clone()
+    };
+    let entry = audit::Entry {
+        timestamp: expiry::now(),
+        user,
+        method,
+        path: instance(&path),
+        request_id: String::from(request_id),
+        status: result.as_ref().map(|response| response.status).unwrap_or_else(problem::status),
+        old_hash,
+        new_hash,
+    };
+    if let Err(err) = log.append(&entry) {
+        error!("Could not write the audit log: {}", err);
+    }
+    result
+}
+
+fn method_not_allowed(req: &Request, allow: &str) -> Response {
+    let problem = Problem::new(405, format!("{} is not supported", req.method)).at(instance(&req.path));
+    problem_response(problem).with_header("allow", String::from(allow))
+}
+
+/// A follower only changes as its primary does.
+fn read_only(req: &Request, replica: &replication::Replica) -> Response {
+    let detail = format!("this server is a read-only replica of {}", replica.primary());
+    problem_response(Problem::new(405, detail).at(instance(&req.path))).with_header("allow", String::from("GET"))
+}
+
+fn route(app: &RestApp, req: Request, user: String) -> Result<Response, APIErr> {
+    let method = req.method.clone();
+    let reserved = req.path.first().filter(|segment| segment.starts_with('_')).cloned();
+    match (method.as_str(), reserved.as_deref()) {
+        ("POST", Some(BULK_PATH)) => bulk_import(app, req, user),
+        ("GET", Some(changes::CHANGES_PATH)) => read_changes(app, req, user),
+        ("GET", Some(websocket::SUBSCRIBE_PATH)) => subscribe(app, req, user),
+        ("GET", Some(EXPORT_PATH)) => export(app, req, user),
+        ("GET", Some(snapshot::SNAPSHOT_PATH)) => take_snapshot(app, req, user),
+        ("PUT", Some(snapshot::SNAPSHOT_PATH)) => restore_snapshot(app, req, user),
+        ("GET", Some(FSCK_PATH)) => check_storage(app, false, user),
+        ("POST", Some(FSCK_PATH)) => check_storage(app, true, user),
+        ("GET", Some(acl::ACL_PATH)) => get_acl(app, user),
+        ("PUT", Some(acl::ACL_PATH)) => put_acl(app, req, user),
+        ("DELETE", Some(acl::ACL_PATH)) => delete_acl(app, user),
+        ("PUT", None) | ("DELETE", None) if req.path.is_empty() => Ok(method_not_allowed(&req, "GET")),
+        (_, Some(BULK_PATH)) => Ok(method_not_allowed(&req, "POST")),
+        (_, Some(changes::CHANGES_PATH)) | (_, Some(websocket::SUBSCRIBE_PATH)) | (_, Some(EXPORT_PATH)) =>
+            Ok(method_not_allowed(&req, "GET")),
+        (_, Some(snapshot::SNAPSHOT_PATH)) => Ok(method_not_allowed(&req, "GET, PUT")),
+        (_, Some(FSCK_PATH)) => Ok(method_not_allowed(&req, "GET, POST")),
+        ("GET", _) => get_document(app, req, user),
+        ("PUT", _) => put_document(app, req, user),
+        ("DELETE", _) => delete_document(app, req, user),
+        _ => Ok(method_not_allowed(&req, "GET, PUT, DELETE"))
+    }
+}
+
+/// The tree a request is served from: the whole storage directory, or the
+/// tree of the request's tenant. A tenant that has not been created is
+/// refused exactly like one the principal may not use. Followers follow
+/// every tenant, so they are always served the whole storage directory.
+fn scope(app: &RestApp, req: &Request, user: &str) -> Result<RestApp, APIErr> {
+    let name = match app.tenancy {
+        _ if user == replication::USER => return Ok(app.clone()),
+        Tenancy::Off => return Ok(app.clone()),
+        Tenancy::PerUser => user,
+        Tenancy::PerHeader(ref header) => req.header(header.as_str())
+            .ok_or_else(|| APIErr::IO(io::Error::new(io::ErrorKind::InvalidInput, format!("the {} header is required", header))))?
+    };
+    if tenant::exists(app, name) {
+        Ok(app.for_tenant(name))
+    } else {
+        info!("No tenant {} for {}", name, user);
+        Err(APIErr::Forbidden)
+    }
+}
+
+/// The principal the request's credentials name, which every `API` call
+/// made for it is attributed to. API keys are looked up in the storage
+/// root, whatever tenant the request is for.
+fn authenticate(app: &RestApp, req: &Request) -> Result<Principal, APIErr> {
+    let authorization = req.header("authorization");
+    match req.client_subject {
+        Some(ref subject) if authorization.is_none() && app.authenticator.is_enabled() => return Ok(Principal::new(subject)),
+        _ => ()
+    }
+    match authorization.and_then(auth::bearer_token).filter(|token| keys::is_key(token)) {
+        Some(secret) if app.authenticator.is_enabled() => return keys::authenticate(app, secret)?
+            .ok_or_else(|| {
+                warn!("Rejected API key for {} /{}", req.method, req.path.join("/"));
+                APIErr::Unauthorized(AuthErr::InvalidCredentials.to_string())
+            }),
+        _ => ()
+    }
+    app.authenticator.authenticate(authorization)
+        .map_err(|err| {
+            if let AuthErr::InvalidCredentials | AuthErr::InvalidToken(_) | AuthErr::TooManyAttempts(_) = err {
+                warn!("Rejected credentials for {} /{}", req.method, req.path.join("/"));
+            }
+            APIErr::Unauthorized(err.to_string())
+        })
+}
+
+/// The permission the request's credentials must have been issued for.
+/// Reserved paths other than bulk import and export are administrative.
+fn required_permission(req: &Request) -> acl::Permission {
+    let reserved = req.path.first()
+        .filter(|segment| segment.starts_with('_'))
+        .map(String::as_str);
+    match (req.method.as_str(), reserved) {
+        ("POST", Some(BULK_PATH)) => acl::Permission::Write,
+        ("GET", Some(changes::CHANGES_PATH)) => acl::Permission::Read,
+        ("GET", Some(websocket::SUBSCRIBE_PATH)) => acl::Permission::Read,
+        ("GET", Some(EXPORT_PATH)) => acl::Permission::Read,
+        (_, Some(_)) => acl::Permission::Admin,
+        ("GET", None) => acl::Permission::Read,
+        ("DELETE", None) => acl::Permission::Delete,
+        _ => acl::Permission::Write
+    }
+}
+
+/// Refuses requests that scoped credentials, such as a JWT with a scopes
+/// claim, were not issued for, whatever the access control rules say.
+fn check_scopes(principal: &Principal, req: &Request) -> Result<(), APIErr> {
+    let permission = required_permission(req);
+    if principal.allows(permission) {
+        Ok(())
+    } else {
+        info!("Credentials of {} are not scoped for {:?}", principal.name, permission);
+        Err(APIErr::Forbidden)
+    }
+}
+
+fn get_document(app: &RestApp, req: Request, user: String) -> Result<Response, APIErr> {
+    let format = Format::from_accept(req.header("accept"))?;
+    let ttl = app.ttl(req.path.clone(), user.clone())?;
+    let body = match format {
+        Format::Json => stream::read_stream(app.clone(), req.path, user)
+            .map(|json| Body::Stream(Box::new(json)))?,
+        other => app.get(req.path, user)
+            .and_then(|value| other.encode(&value))
+            .map(Body::Bytes)?
+    };
+    let response = Response::ok(format.content_type(), body);
+    Ok(match ttl {
+        Some(t) => response.with_header(TTL_HEADER, t.to_string()),
+        None => response
+    })
+}
+
+fn put_document(app: &RestApp, req: Request, user: String) -> Result<Response, APIErr> {
+    let format = Format::from_content_type(req.header("content-type"))?;
+    let ttl = req.ttl()?;
+    if req.body.is_empty() {
+        return Err(APIErr::EmptyRequest);
+    }
+    format.decode(req.body.as_slice())
+        .and_then(|value| app.put_with_ttl(req.path, value, ttl, user))
+        .map(|()| Response::new(200, Body::Empty))
+}
+
+fn delete_document(app: &RestApp, req: Request, user: String) -> Result<Response, APIErr> {
+    app.delete(req.path, user)
+        .map(|()| Response::new(200, Body::Empty))
+}
+
+fn bulk_import(app: &RestApp, req: Request, user: String) -> Result<Response, APIErr> {
+    let lines = ndjson::import(app, req.prefix(), req.body.as_slice(), user)
+        .into_iter()
+        .flat_map(|result| serde_json::to_string(&result).ok())
+        .map(|line| line + "\n")
+        .collect::<String>();
+    Ok(Response::ok(NDJSON, Body::Bytes(lines.into_bytes())))
+}
+
+fn export(app: &RestApp, req: Request, user: String) -> Result<Response, APIErr> {
+    let mode = ndjson::ExportMode::from_param(req.param(MODE_PARAM));
+    ndjson::export(app.clone(), req.prefix(), mode, user)
+        .map(|records| Response::ok(NDJSON, Body::Stream(Box::new(records))))
+}
+
+fn change_feed(app: &RestApp) -> Result<Arc<changes::ChangeFeed>, APIErr> {
+    app.changes()
+        .ok_or_else(|| APIErr::IO(io::Error::new(io::ErrorKind::NotFound, "the change feed is not enabled")))
+}
+
+/// Which changes a reader sees: those in its tree, below `prefix`, at
+/// paths it may read. Followers also see those in the trees of tenants,
+/// which name their tenant.
+fn change_filter(app: &RestApp, prefix: Vec<String>, user: String) -> Box<Fn(&changes::Change) -> bool + Send> {
+    let app = app.clone();
+    let root = app.root_path().to_string_lossy().into_owned();
+    Box::new(move |change: &changes::Change| {
+        let path = split_path(change.path.as_str());
+        let tree = match change.tenant {
+            Some(ref name) if user == replication::USER && change.is_under(root.as_str()) => app.for_tenant(name),
+            _ if change.root == root => app.clone(),
+            _ => return false
+        };
+        prefix.len() <= path.len() && prefix.iter().zip(path.iter()).all(|(a, b)| a == b) &&
+            acl::check(&tree, &path, &user, acl::Permission::Read).is_ok()
+    })
+}
+
+fn event(batch: Result<changes::Batch, APIErr>) -> Result<Vec<u8>, APIErr> {
+    let events = match batch {
+        Ok(ref batch) if batch.changes.is_empty() => String::from(": heartbeat\n\n"),
+        Ok(batch) => batch.changes.iter()
+            .map(|change| serde_json::to_string(change)
+                .map(|data| format!("id: {}\nevent: change\ndata: {}\n\n", change.seq, data))
+                .map_err(|err| APIErr::Encode(err.to_string())))
+            .collect::<Result<String, APIErr>>()?,
+        Err(err) => format!("event: error\ndata: {}\n\n", String::from_utf8_lossy(Problem::from_err(&err).to_vec().as_slice()))
+    };
+    Ok(events.into_bytes())
+}
+
+/// A long poll waiting for a change after `since`, until `deadline`.
+struct LongPoll<F> {
+    feed: Arc<changes::ChangeFeed>,
+    since: u64,
+    deadline: Instant,
+    keep: F,
+    done: bool,
+}
+
+impl<F: Fn(&changes::Change) -> bool + Send> Pending for LongPoll<F> {
+    fn poll_next(&mut self, wake: changes::Waker) -> Next {
+        if self.done {
+            return Next::End;
+        }
+        let batch = match self.feed.poll(self.since, self.deadline, &self.keep, wake) {
+            Ok(None) => return Next::Wait,
+            Ok(Some(batch)) => Ok(batch),
+            Err(err) => Err(err)
+        };
+        self.done = true;
+        Next::Chunk(batch.and_then(|batch| serde_json::to_vec(&batch).map_err(|err| APIErr::Encode(err.to_string()))))
+    }
+}
+
+/// The events of a `/_changes` stream, with a heartbeat whenever there
+/// have been none for a while. Ends after an error.
+struct EventStream<F> {
+    feed: Arc<changes::ChangeFeed>,
+    since: Option<u64>,
+    heartbeat: Duration,
+    next_beat: Instant,
+    keep: F,
+}
+
+impl<F: Fn(&changes::Change) -> bool + Send> Pending for EventStream<F> {
+    fn poll_next(&mut self, wake: changes::Waker) -> Next {
+        let since = match self.since {
+            Some(since) => since,
+            None => return Next::End
+        };
+        let batch = match self.feed.poll(since, self.next_beat, &self.keep, wake) {
+            Ok(None) => return Next::Wait,
+            Ok(Some(batch)) => Ok(batch),
+            Err(err) => Err(err)
+        };
+        self.since = batch.as_ref().ok().map(|batch| batch.last_seq);
+        self.next_beat = Instant::now() + self.heartbeat;
+        Next::Chunk(event(batch))
+    }
+}
+
+/// `GET /_changes` answers with the changes after `since` (or the
+/// `Last-Event-ID` header), waiting up to `timeout` seconds for one if
+/// there are none yet. With `Accept: text/event-stream` it streams them
+/// instead, as they happen.
+fn read_changes(app: &RestApp, req: Request, user: String) -> Result<Response, APIErr> {
+    let feed = change_feed(app)?;
+    let number = |name: &str, value: Option<&str>| value
+        .map(|value| u64::from_str(value.trim())
+            .map_err(|_| APIErr::IO(io::Error::new(io::ErrorKind::InvalidInput, format!("{} must be a number", name)))))
+        .unwrap_or_else(|| Ok(feed.last_seq()));
+    let since = number("since", req.header("last-event-id").or_else(|| req.param("since")))?;
+    let keep = change_filter(app, split_path(req.param("prefix").unwrap_or("")), user);
+    if req.header("accept").is_some_and(|accept| accept.contains(EVENT_STREAM)) {
+        feed.wait(since, Duration::from_secs(0), &keep)?;
+        let heartbeat = Duration::from_secs(HEARTBEAT_SECS);
+        let events = EventStream { feed, since: Some(since), heartbeat, next_beat: Instant::now() + heartbeat, keep };
+        return Ok(Response::ok(EVENT_STREAM, Body::Pending(Box::new(events)))
+            .with_header("cache-control", String::from("no-cache")));
+    }
+    let timeout = match req.param("timeout") {
+        Some(_) => number("timeout", req.param("timeout"))?.min(MAX_CHANGES_WAIT_SECS),
+        None => CHANGES_WAIT_SECS
+    };
+    let batch = feed.wait(since, Duration::from_secs(0), &keep)?;
+    if !batch.changes.is_empty() || timeout == 0 {
+        return serde_json::to_vec(&batch)
+            .map_err(|err| APIErr::Encode(err.to_string()))
+            .map(|batch| Response::ok(JSON, Body::Bytes(batch)));
+    }
+    let deadline = Instant::now() + Duration::from_secs(timeout);
+    Ok(Response::ok(JSON, Body::Pending(Box::new(LongPoll { feed, since, deadline, keep, done: false }))))
+}
+
+/// `GET /_subscribe/<prefix>` upgrades to a WebSocket on which the value
+/// below `prefix`, and then every change to it, are sent. More prefixes
+/// can be subscribed to over the socket.
+fn subscribe(app: &RestApp, req: Request, user: String) -> Result<Response, APIErr> {
+    change_feed(app)?;
+    let key = match (req.header("upgrade"), req.header("sec-websocket-key")) {
+        (Some(upgrade), Some(key)) if upgrade.eq_ignore_ascii_case("websocket") => websocket::accept_key(key),
+        _ => return Ok(problem_response(Problem::new(426, String::from("a WebSocket handshake is required")).at(instance(&req.path)))
+            .with_header("upgrade", String::from("websocket")))
+    };
+    let prefix = req.prefix();
+    if !prefix.is_empty() {
+        acl::check(app, &prefix, &user, acl::Permission::Read)?;
+    }
+    let app = app.clone();
+    let session: Upgrade = Box::new(move |input, output| websocket::serve(app, user, prefix, input, output));
+    Ok(Response::new(101, Body::Upgrade(session))
+        .with_header("upgrade", String::from("websocket"))
+        .with_header("connection", String::from("Upgrade"))
+        .with_header("sec-websocket-accept", key))
+}
+
+/// `GET /_snapshot/<prefix>` streams an archive of the prefix. Only the
+/// principals in `auth.admins` may ask for the privileged state of the
+/// whole tree with `?secrets=true`.
+fn take_snapshot(app: &RestApp, req: Request, user: String) -> Result<Response, APIErr> {
+    acl::check(app, &req.prefix(), &user, acl::Permission::Admin)?;
+    let secrets = snapshot::is_secrets_param(req.param(SECRETS_PARAM));
+    if secrets && !acl::is_admin(app, &user) {
+        info!("Denied a snapshot with secrets to {}", user);
+        return Err(APIErr::Forbidden);
+    }
+    let gzip = snapshot::is_gzip_param(req.param(FORMAT_PARAM));
+    let content_type = if gzip { "application/gzip" } else { "application/x-tar" };
+    snapshot::snapshot_stream(app, req.prefix(), gzip, secrets)
+        .map(|archive| Response::ok(content_type, Body::Stream(Box::new(archive))))
+}
+
+fn restore_snapshot(app: &RestApp, req: Request, user: String) -> Result<Response, APIErr> {
+    let prefix = req.prefix();
+    acl::check(app, &prefix, &user, acl::Permission::Admin)?;
+    let secrets = acl::is_admin(app, &user);
+    match req.spooled {
+        Some(mut spool) => spool.rewind()
+            .map_err(APIErr::IO)
+            .and_then(|()| snapshot::restore(app, prefix, spool, secrets)),
+        None => snapshot::restore(app, prefix, req.body.as_slice(), secrets)
+    }
+        .and_then(|manifest| serde_json::to_vec(&manifest).map_err(|err| APIErr::Encode(err.to_string())))
+        .map(|manifest| Response::ok(JSON, Body::Bytes(manifest)))
+}
+
+/// Checks the storage tree, repairing what it can when `repair` is set.
+fn check_storage(app: &RestApp, repair: bool, user: String) -> Result<Response, APIErr> {
+    acl::check_admin(app, &user)?;
+    fsck::fsck(app, repair)
+        .and_then(|issues| serde_json::to_vec(&issues).map_err(|err| APIErr::Encode(err.to_string())))
+        .map(|issues| Response::ok(JSON, Body::Bytes(issues)))
+}
+
+fn get_acl(app: &RestApp, user: String) -> Result<Response, APIErr> {
+    acl::check_admin(app, &user)?;
+    acl::load(app)?
+        .ok_or_else(acl::not_found)
+        .and_then(|acl| serde_json::to_vec(&acl).map_err(|err| APIErr::Encode(err.to_string())))
+        .map(|acl| Response::ok(JSON, Body::Bytes(acl)))
+}
+
+/// Replaces every rule at once with the `Acl` in the body.
+fn put_acl(app: &RestApp, req: Request, user: String) -> Result<Response, APIErr> {
+    acl::check_admin(app, &user)?;
+    let format = Format::from_content_type(req.header("content-type"))?;
+    format.decode(req.body.as_slice())
+        .and_then(acl::Acl::from_value)
+        .and_then(|acl| acl::store(app, Some(&acl)))
+        .map(|()| Response::new(200, Body::Empty))
+}
+
+/// Removes every rule, leaving the tree open to everyone again.
+fn delete_acl(app: &RestApp, user: String) -> Result<Response, APIErr> {
+    acl::check_admin(app, &user)?;
+    acl::store(app, None)
+        .map(|()| Response::new(200, Body::Empty))
+}
+
+/// The key and its secret, which is only ever shown in this response.
+fn key_response(status: u16, key: &keys::ApiKey, secret: String) -> Result<Response, APIErr> {
+    let mut value = key.to_value();
+    value["secret"] = Value::String(secret);
+    serde_json::to_vec(&value)
+        .map_err(|err| APIErr::Encode(err.to_string()))
+        .map(|body| Response::new(status, Body::Bytes(body)).with_header("content-type", String::from(JSON)))
+}
+
+/// `GET /_keys` lists the API keys and `POST /_keys` creates one. `GET`
+/// and `DELETE` on `/_keys/<id>` show and revoke a key, and
+/// `POST /_keys/<id>/rotate` replaces its secret. Only the principals in
+/// `auth.admins` may use any of them, even while no rules are stored.
+fn manage_keys(app: &RestApp, req: Request, user: String) -> Result<Response, APIErr> {
+    acl::check(app, &req.path, &user, acl::Permission::Admin)?;
+    if req.path.len() > 3 {
+        return Err(APIErr::IO(io::Error::new(io::ErrorKind::NotFound, "no such key operation")));
+    }
+    let id = req.path.get(1).cloned();
+    let action = req.path.get(2).cloned();
+    match (req.method.as_str(), id, action.as_deref()) {
+        ("GET", None, _) => keys::list(app)
+            .and_then(|keys| serde_json::to_vec(&keys.iter().map(keys::ApiKey::to_value).collect::<Vec<Value>>())
+                .map_err(|err| APIErr::Encode(err.to_string())))
+            .map(|keys| Response::ok(JSON, Body::Bytes(keys))),
+        ("POST", None, _) => Format::from_content_type(req.header("content-type"))?
+            .decode(req.body.as_slice())
+            .and_then(|value| serde_json::from_value::<keys::NewKey>(value).map_err(|err| APIErr::Decode(err.to_string())))
+            .and_then(|new| keys::create(app, new))
+            .and_then(|(key, secret)| key_response(201, &key, secret)),
+        ("GET", Some(id), None) => keys::get(app, id.as_str())
+            .and_then(|key| serde_json::to_vec(&key.to_value()).map_err(|err| APIErr::Encode(err.to_string())))
+            .map(|key| Response::ok(JSON, Body::Bytes(key))),
+        ("DELETE", Some(id), None) => keys::revoke(app, id.as_str())
+            .map(|()| Response::new(200, Body::Empty)),
+        ("POST", Some(id), Some("rotate")) => keys::rotate(app, id.as_str())
+            .and_then(|(key, secret)| key_response(200, &key, secret)),
+        (_, None, _) => Ok(method_not_allowed(&req, "GET, POST")),
+        (_, Some(_), None) => Ok(method_not_allowed(&req, "GET, DELETE")),
+        _ => Err(APIErr::IO(io::Error::new(io::ErrorKind::NotFound, "no such key operation")))
+    }
+}
+
+fn json_response<T: serde::Serialize>(status: u16, value: &T) -> Result<Response, APIErr> {
+    serde_json::to_vec(value)
+        .map_err(|err| APIErr::Encode(err.to_string()))
+        .map(|bytes| Response::new(status, Body::Bytes(bytes)).with_header("content-type", String::from(JSON)))
+}
+
+/// `GET /_webhooks` lists the webhooks and `POST /_webhooks` registers one.
+/// `GET` and `DELETE` on `/_webhooks/<id>` show and unregister a webhook.
+/// Only administrators may use them.
+fn manage_webhooks(app: &RestApp, req: Request, user: String) -> Result<Response, APIErr> {
+    acl::check(app, &req.path, &user, acl::Permission::Admin)?;
+    if req.path.len() > 2 {
+        return Err(APIErr::IO(io::Error::new(io::ErrorKind::NotFound, "no such webhook operation")));
+    }
+    match (req.method.as_str(), req.path.get(1)) {
+        ("GET", None) => webhooks::list(app)
+            .and_then(|webhooks| json_response(200, &webhooks.iter().map(webhooks::Webhook::to_value).collect::<Vec<Value>>())),
+        ("POST", None) => Format::from_content_type(req.header("content-type"))?
+            .decode(req.body.as_slice())
+            .and_then(|value| serde_json::from_value::<webhooks::NewWebhook>(value).map_err(|err| APIErr::Decode(err.to_string())))
+            .and_then(|new| webhooks::create(app, new))
+            .and_then(|webhook| json_response(201, &webhook.to_value())),
+        ("GET", Some(id)) => webhooks::get(app, id.as_str())
+            .and_then(|webhook| json_response(200, &webhook.to_value())),
+        ("DELETE", Some(id)) => webhooks::delete(app, id.as_str())
+            .map(|()| Response::new(200, Body::Empty)),
+        (_, None) => Ok(method_not_allowed(&req, "GET, POST")),
+        (_, Some(_)) => Ok(method_not_allowed(&req, "GET, DELETE"))
+    }
+}
+
+/// `GET /_dead_letters` lists the deliveries that were given up on, and
+/// `GET` and `DELETE` on `/_dead_letters/<id>` show and discard one.
+fn manage_dead_letters(app: &RestApp, req: Request, user: String) -> Result<Response, APIErr> {
+    acl::check(app, &req.path, &user, acl::Permission::Admin)?;
+    if req.path.len() > 2 {
+        return Err(APIErr::IO(io::Error::new(io::ErrorKind::NotFound, "no such dead letter operation")));
+    }
+    match (req.method.as_str(), req.path.get(1)) {
+        ("GET", None) => webhooks::dead_letters(app).and_then(|letters| json_response(200, &letters)),
+        ("GET", Some(id)) => webhooks::dead_letter(app, id.as_str()).and_then(|letter| json_response(200, &letter)),
+        ("DELETE", Some(id)) => webhooks::delete_dead_letter(app, id.as_str())
+            .map(|()| Response::new(200, Body::Empty)),
+        (_, None) => Ok(method_not_allowed(&req, "GET")),
+        (_, Some(_)) => Ok(method_not_allowed(&req, "GET, DELETE"))
+    }
+}
+
+/// `GET /_replication` tells how far a follower is behind its primary, and
+/// `POST /_replication/resync` has it replace its tree with a snapshot of
+/// the primary before following it again.
+fn manage_replication(app: &RestApp, req: Request, user: String) -> Result<Response, APIErr> {
+    acl::check(app, &req.path, &user, acl::Permission::Admin)?;
+    let replica = app.replica.as_ref()
+        .ok_or_else(|| APIErr::IO(io::Error::new(io::ErrorKind::NotFound, "this server is not a replica")))?;
+    match (req.method.as_str(), req.path.get(1).map(String::as_str)) {
+        ("GET", None) => json_response(200, &replica.status()),
+        ("POST", Some("resync")) => {
+            replica.request_resync();
+            json_response(202, &replica.status())
+        }
+        (_, None) => Ok(method_not_allowed(&req, "GET")),
+        (_, Some("resync")) => Ok(method_not_allowed(&req, "POST")),
+        _ => Err(APIErr::IO(io::Error::new(io::ErrorKind::NotFound, "no such replication operation")))
+    }
+}
+
+/// `GET /_audit` lists the entries of the audit log, filtered by `since`
+/// and `until` in Unix seconds and a path `prefix`.
+fn query_audit(app: &RestApp, req: Request, user: String) -> Result<Response, APIErr> {
+    acl::check(app, &req.path, &user, acl::Permission::Admin)?;
+    if req.method != "GET" {
+        return Ok(method_not_allowed(&req, "GET"));
+    }
+    if req.path.len() > 1 {
+        return Err(APIErr::IO(io::Error::new(io::ErrorKind::NotFound, "no such audit operation")));
+    }
+    let time = |name: &str| match req.param(name) {
+        Some(value) => u64::from_str(value)
+            .map(Some)
+            .map_err(|_| APIErr::IO(io::Error::new(io::ErrorKind::InvalidInput, format!("{} must be Unix seconds", name)))),
+        None => Ok(None)
+    };
+    let filter = audit::Filter {
+        since: time("since")?,
+        until: time("until")?,
+        prefix: split_path(req.param("prefix").unwrap_or("")),
+    };
+    let log = app.audit.as_ref()
+        .ok_or_else(|| APIErr::IO(io::Error::new(io::ErrorKind::NotFound, "the audit log is not enabled")))?;
+    log.query(&filter)
+        .and_then(|entries| serde_json::to_vec(&entries).map_err(|err| APIErr::Encode(err.to_string())))
+        .map(|entries| Response::ok(JSON, Body::Bytes(entries)))
+}
+
+/// `GET /_tenants` lists the tenants. `PUT`, `GET` and `DELETE` on
+/// `/_tenants/<name>` create a tenant, export its tree as NDJSON and drop
+/// it. Only the principals in `auth.admins` may use them, whatever rules
+/// grant `admin` on the tree.
+fn manage_tenants(app: &RestApp, req: Request, user: String) -> Result<Response, APIErr> {
+    if !acl::is_admin(app, &user) {
+        info!("Denied tenant management to {}", user);
+        return Err(APIErr::Forbidden);
+    }
+    if req.path.len() > 2 {
+        return Err(APIErr::IO(io::Error::new(io::ErrorKind::NotFound, "no such tenant operation")));
+    }
+    let name = req.path.get(1).cloned();
+    match (req.method.as_str(), name) {
+        ("GET", None) => tenant::list(app)
+            .and_then(|names| serde_json::to_vec(&names).map_err(|err| APIErr::Encode(err.to_string())))
+            .map(|names| Response::ok(JSON, Body::Bytes(names))),
+        ("PUT", Some(name)) => tenant::create(app, name.as_str())
+            .map(|()| Response::new(201, Body::Empty)),
+        ("GET", Some(ref name)) if tenant::exists(app, name) => {
+            let mode = ndjson::ExportMode::from_param(req.param(MODE_PARAM));
+            let scoped = app.for_tenant(name).with_admins(vec![user.clone()]);
+            ndjson::export(scoped, Vec::new(), mode, user)
+                .map(|records| Response::ok(NDJSON, Body::Stream(Box::new(records))))
+        },
+        ("DELETE", Some(name)) => tenant::drop(app, name.as_str())
+            .map(|()| Response::new(200, Body::Empty)),
+        ("GET", Some(name)) => Err(APIErr::IO(io::Error::new(io::ErrorKind::NotFound, format!("no tenant {}", name)))),
+        (_, None) => Ok(method_not_allowed(&req, "GET")),
+        (_, Some(_)) => Ok(method_not_allowed(&req, "GET, PUT, DELETE"))
+    }
+}
@@ -0,0 +1,75 @@
+//! Cross-origin resource sharing: the headers that let browsers call the
+//! API from pages served by other origins.
+
+use super::core::{REQUEST_ID_HEADER, TTL_HEADER};
+
+/// Allows every origin, as long as credentials are not allowed as well.
+pub const ANY_ORIGIN: &str = "*";
+/// The headers the API answers with that pages may read, besides the few
+/// that browsers always let through.
+const EXPOSED_HEADERS: &[&str] = &[TTL_HEADER, REQUEST_ID_HEADER, "allow", "www-authenticate"];
+
+/// Which origins may call the API, and with what.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cors {
+    /// Origins such as `https://app.example.com`, or `*`.
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    /// Whether pages may send cookies and `Authorization` headers.
+    pub allow_credentials: bool,
+    /// How many seconds browsers may cache the answer to a preflight.
+    pub max_age: u64,
+}
+
+impl Cors {
+    fn allows_origin(&self, origin: &str) -> bool {
+        self.allowed_origins.iter()
+            .any(|allowed| allowed == ANY_ORIGIN || allowed.trim_end_matches('/').eq_ignore_ascii_case(origin))
+    }
+
+    /// What every response to a request from `origin` carries, which is
+    /// nothing unless the origin is allowed. Pages may read the API's own
+    /// headers as well.
+    pub fn headers(&self, origin: &str) -> Vec<(&'static str, String)> {
+        if !self.allows_origin(origin) {
+            return Vec::new();
+        }
+        let mut headers = if self.allowed_origins.iter().any(|allowed| allowed == ANY_ORIGIN) && !self.allow_credentials {
+            vec![("access-control-allow-origin", String::from(ANY_ORIGIN))]
+        } else {
+            vec![("access-control-allow-origin", String::from(origin)), ("vary", String::from("origin"))]
+        };
+        if self.allow_credentials {
+            headers.push(("access-control-allow-credentials", String::from("true")));
+        }
+        headers.push(("access-control-expose-headers", EXPOSED_HEADERS.join(", ")));
+        headers
+    }
+
+    /// The answer to a preflight from `origin` asking to send `method` with
+    /// the comma separated `headers`, or why it is refused.
+    pub fn preflight(&self, origin: &str, method: &str, headers: Option<&str>) -> Result<Vec<(&'static str, String)>, String> {
+        if !self.allows_origin(origin) {
+            return Err(format!("{} is not an allowed origin", origin));
+        }
+        if !self.allowed_methods.iter().any(|allowed| allowed.eq_ignore_ascii_case(method)) {
+            return Err(format!("{} is not an allowed method", method));
+        }
+        let refused = headers.unwrap_or("")
+            .split(',')
+            .map(str::trim)
+            .filter(|header| !header.is_empty())
+            .find(|header| !self.allowed_headers.iter().any(|allowed| allowed.eq_ignore_ascii_case(header)));
+        if let Some(header) = refused {
+            return Err(format!("the {} header is not allowed", header));
+        }
+        let mut answer = self.headers(origin);
+        answer.push(("access-control-allow-methods", self.allowed_methods.join(", ")));
+        if !self.allowed_headers.is_empty() {
+            answer.push(("access-control-allow-headers", self.allowed_headers.join(", ")));
+        }
+        answer.push(("access-control-max-age", self.max_age.to_string()));
+        Ok(answer)
+    }
+}
@@ -1,107 +1,320 @@
 extern crate hyper;
 extern crate futures;
-extern crate serde_json;
-extern crate serde;
-extern crate log;
-
-use self::futures::future;
-use self::futures::Stream;
-use self::serde_json::{Value, Error};
-use self::hyper::{Body, Request, Response, Server, Method, StatusCode, Chunk};
+extern crate tokio_io;
+extern crate tokio_reactor;
+extern crate tokio_tcp;
+extern crate tokio_threadpool;
+
+use self::futures::future::{self, Either, Loop};
+use self::futures::{Async, Sink, Stream};
+use self::futures::sync::mpsc;
+use self::hyper::{Body, Request, Response, Server, StatusCode, Chunk};
 use self::hyper::rt::{self, Future};
+use self::hyper::server::conn::Http;
 use self::hyper::service::service_fn;
+use self::hyper::header::{HeaderValue, CONTENT_LENGTH};
+use self::hyper::upgrade::Upgraded;
+use self::tokio_io::AsyncRead;
+use self::tokio_reactor::Handle;
+use self::tokio_tcp::{TcpListener, TcpStream};
+use self::tokio_threadpool::blocking;
 use super::super::super::RestApp;
-use super::super::super::api::API;
-use self::hyper::Uri;
-
-const PHRASE: &str = "Hello, World!";
+use super::super::super::api::{expiry, APIErr};
+use super::super::super::api::limits::Limits;
+use super::super::super::api::snapshot::Spool;
+use super::core;
+use super::tls;
+use super::websocket;
+use std::io::{self, Read, Write};
+use std::net::{self, SocketAddr};
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
 
 type BoxFut = Box<Future<Item=Response<Body>, Error=hyper::Error> + Send>;
 
-fn read_path(uri: &Uri) -> Vec<String> {
-    uri.path()
-        .clone()
-        .split("/")
+fn read_content_length(req: &Request<Body>) -> Option<usize> {
+    req.headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| usize::from_str(value).ok())
+}
+
+/// Buffers the request body, failing as soon as it grows past the body
+/// length limit rather than after the whole body has been received.
+fn collect_body(body: Body, limits: Limits) -> Box<Future<Item=Vec<u8>, Error=APIErr> + Send> {
+    Box::new(body
+        .map_err(|err| APIErr::IO(io::Error::new(io::ErrorKind::InvalidInput, err.to_string())))
+        .fold(Vec::new(), move |mut acc, chunk: Chunk| limits.check_body_length(acc.len() + chunk.len())
+            .map(|()| {
+                acc.extend_from_slice(&chunk);
+                acc
+            })))
+}
+
+/// Writes the request body to `spool` as it is received, failing as soon as
+/// it grows past the body length limit.
+fn spool_body(body: Body, limits: Limits, spool: Spool) -> Box<Future<Item=Spool, Error=APIErr> + Send> {
+    Box::new(body
+        .map_err(|err| APIErr::IO(io::Error::new(io::ErrorKind::InvalidInput, err.to_string())))
+        .fold((spool, 0), move |(mut spool, length), chunk: Chunk| limits.check_body_length(length + chunk.len())
+            .and_then(|()| spool.write_all(&chunk).map_err(APIErr::IO))
+            .map(|()| (spool, length + chunk.len())))
+        .map(|(spool, _)| spool))
+}
+
+/// Reads the request body into memory, or to a spool if `spooled` is set.
+fn receive_body(app: &RestApp, body: Body, limits: Limits, spooled: bool) -> Box<Future<Item=(Vec<u8>, Option<Spool>), Error=APIErr> + Send> {
+    if !spooled {
+        return Box::new(collect_body(body, limits).map(|body| (body, None)));
+    }
+    match Spool::create(app) {
+        Ok(spool) => Box::new(spool_body(body, limits, spool).map(|spool| (Vec::new(), Some(spool)))),
+        Err(err) => Box::new(future::err(err))
+    }
+}
+
+fn read_request(req: &Request<Body>, client_subject: &Option<String>) -> core::Request {
+    core::Request {
+        method: req.method().as_str().to_uppercase(),
+        path: core::split_path(req.uri().path()),
+        query: core::parse_query(req.uri().query()),
+        headers: req.headers()
+            .iter()
+            .flat_map(|(name, value)| value.to_str().ok()
+                .map(|value| (name.as_str().to_lowercase(), String::from(value))))
+            .collect(),
+        body: Vec::new(),
+        client_subject: client_subject.clone(),
+        spooled: None,
+    }
+}
+
+/// Reads `chunks` on the blocking pool, since reading one may block, and
+/// the reactor must not. The pool bounds the threads doing so; streams
+/// beyond that wait their turn. Ends after an error.
+fn pump(chunks: core::Chunks) -> Box<Stream<Item=Vec<u8>, Error=String> + Send> {
+    let mut chunks = Some(chunks);
+    Box::new(futures::stream::poll_fn(move || {
+        let chunk = match chunks.as_mut() {
+            Some(chunks) => match blocking(|| chunks.next()).map_err(|err| err.to_string())? {
+                Async::Ready(chunk) => chunk,
+                Async::NotReady => return Ok(Async::NotReady)
+            },
+            None => None
+        };
+        match chunk {
+            Some(Ok(chunk)) => Ok(Async::Ready(Some(chunk))),
+            Some(Err(err)) => {
+                chunks = None;
+                Err(err.to_string())
+            }
+            None => Ok(Async::Ready(None))
+        }
+    }))
+}
+
+/// Writes out `pending` as its chunks become ready, waiting on nothing
+/// but the task meanwhile. Ends after an error.
+fn watch(pending: Box<core::Pending>) -> Box<Stream<Item=Vec<u8>, Error=String> + Send> {
+    let mut pending = Some(pending);
+    Box::new(futures::stream::poll_fn(move || {
+        let next = match pending.as_mut() {
+            Some(pending) => {
+                let task = futures::task::current();
+                pending.poll_next(Box::new(move || task.notify()))
+            }
+            None => core::Next::End
+        };
+        match next {
+            core::Next::Chunk(Ok(chunk)) => Ok(Async::Ready(Some(chunk))),
+            core::Next::Chunk(Err(err)) => {
+                pending = None;
+                Err(err.to_string())
+            }
+            core::Next::Wait => Ok(Async::NotReady),
+            core::Next::End => Ok(Async::Ready(None))
+        }
+    }))
+}
+
+fn write_response(res: core::Response) -> Response<Body> {
+    let body = match res.body {
+        core::Body::Empty => Body::empty(),
+        core::Body::Bytes(bytes) => Body::from(bytes),
+        core::Body::Stream(chunks) => Body::wrap_stream(pump(chunks)),
+        core::Body::Pending(pending) => Body::wrap_stream(watch(pending)),
+        core::Body::Upgrade(_) => Body::empty(),
+    };
+    let mut response = Response::new(body);
+    *response.status_mut() = StatusCode::from_u16(res.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    res.headers
         .into_iter()
-        .map(String::from)
-        .collect::<Vec<String>>()
-}
-
-fn parse_json(body: Vec<u8>) -> Result<Value, ServerError> {
-    serde_json::from_slice(body.as_slice())
-        .map_err(ServerError::ParseJson)
-}
-
-fn collect_body(chunk: Chunk) -> Vec<u8> {
-    chunk
-        .iter()
-        .cloned()
-        .collect::<Vec<u8>>()
-}
-
-fn response(status: StatusCode, body: Body) -> Response<Body> {
-    Response::builder()
-        .status(status)
-        .body(body)
-        .unwrap()
-}
-
-#[derive(Debug)]
-enum ServerError {
-    Api(super::super::super::api::APIErr),
-    ParseJson(Error)
-}
-
-fn handle_request(req: Request<Body>) -> BoxFut {
-    let app = RestApp { storage_dir: String::from("rest-storage")};
-    match req.method() {
-        &Method::PUT => {
-            let path = read_path(req.uri());
-
-            info!("PUT/{}", path.clone().join("/"));
-            let response_fut = req
-                .into_body()
-                .concat2()
-                .map(collect_body)
-                .map(move |body| parse_json(body)
-                    .and_then(|json| app.put(path, json, String::from("anon")).map_err(ServerError::Api))
-                    .or_else(|err| {
-                        error!("Service err: {:?}", err);
-                        Err(err)
-                    })
-                    .map(|()| response(StatusCode::OK,Body::empty()))
-                    .unwrap_or_else(|err| response(StatusCode::BAD_REQUEST, Body::from("Error"))));
-
-            Box::new(response_fut)
-        },
-        &Method::GET => {
-            let path = read_path(req.uri());
-
-            info!("GET/{}", path.clone().join("/"));
-            let response = app.get(path, String::from("anon"))
-                .map_err(ServerError::Api)
-                .or_else(|err| {
-                    error!("Service err: {:?}", err);
-                    Err(err)
-                })
-                .map(|json| response(StatusCode::OK, Body::from(json.to_string())))
-                .unwrap_or_else(|err| response(StatusCode::INTERNAL_SERVER_ERROR, Body::from("Error")));
-
-            Box::new(future::ok(response))
-        },
-        _ => {
-            Box::new(future::ok(response(StatusCode::NOT_IMPLEMENTED, Body::empty())))
-        },
+        .flat_map(|(name, value)| HeaderValue::from_str(value.as_str()).ok().map(|value| (name, value)))
+        .for_each(|(name, value)| {
+            response.headers_mut().insert(name, value);
+        });
+    response
+}
+
+/// The incoming half of an upgraded connection, read on the thread that
+/// serves it.
+struct Incoming {
+    chunks: futures::stream::Wait<mpsc::Receiver<Vec<u8>>>,
+    chunk: io::Cursor<Vec<u8>>,
+}
+
+impl Read for Incoming {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.position() as usize == self.chunk.get_ref().len() {
+            match self.chunks.next() {
+                Some(Ok(chunk)) => self.chunk = io::Cursor::new(chunk),
+                _ => return Ok(0)
+            }
+        }
+        self.chunk.read(buf)
+    }
+}
+
+/// The outgoing half of an upgraded connection.
+struct Outgoing(Option<mpsc::Sender<Vec<u8>>>);
+
+impl Write for Outgoing {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let sender = self.0.take().ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        self.0 = Some(sender.send(buf.to_vec()).wait().map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Hands the connection over to `session` on a thread of its own once it
+/// has been upgraded, pumping bytes between it and the socket meanwhile.
+fn upgrade(upgraded: Upgraded, session: core::Upgrade) {
+    let (reader, writer) = upgraded.split();
+    let (incoming, chunks) = mpsc::channel::<Vec<u8>>(1);
+    let (outgoing, frames) = mpsc::channel::<Vec<u8>>(1);
+    rt::spawn(future::loop_fn((reader, incoming), |(reader, incoming)| tokio_io::io::read(reader, vec![0u8; 4096])
+        .map_err(|err| debug!("Could not read from an upgraded connection: {}", err))
+        .and_then(|(reader, mut chunk, read)| if read == 0 {
+            Either::A(future::ok(Loop::Break(())))
+        } else {
+            chunk.truncate(read);
+            Either::B(incoming.send(chunk)
+                .map_err(|_| ())
+                .map(|incoming| Loop::Continue((reader, incoming))))
+        })));
+    rt::spawn(frames
+        .fold(writer, |writer, frame| tokio_io::io::write_all(writer, frame)
+            .map(|(writer, _)| writer)
+            .map_err(|err| debug!("Could not write to an upgraded connection: {}", err)))
+        .and_then(|writer| tokio_io::io::shutdown(writer).map(|_| ()).map_err(|_| ())));
+    thread::spawn(move || session(
+        Box::new(Incoming { chunks: chunks.wait(), chunk: io::Cursor::new(Vec::new()) }),
+        Box::new(Outgoing(Some(outgoing)))));
+}
+
+/// Answers a request to switch protocols without reading its body, which
+/// the upgraded connection needs.
+fn handle_upgrade(app: RestApp, client_subject: &Option<String>, req: Request<Body>) -> BoxFut {
+    let request = read_request(&req, client_subject);
+    let on_upgrade = req.into_body().on_upgrade();
+    Box::new(handle_blocking(app, request).map(move |response| match response.body {
+        core::Body::Upgrade(session) => {
+            rt::spawn(on_upgrade
+                .map_err(|err| warn!("Could not upgrade a connection: {}", err))
+                .map(move |upgraded| upgrade(upgraded, session)));
+            write_response(core::Response { body: core::Body::Empty, ..response })
+        }
+        body => write_response(core::Response { body, ..response })
+    }))
+}
+
+/// Answers `request` where blocking is allowed, since answering may wait
+/// for as long as a change feed does, and the reactor must not.
+fn handle_blocking(app: RestApp, request: core::Request) -> impl Future<Item=core::Response, Error=hyper::Error> {
+    let path = request.path.clone();
+    let mut request = Some(request);
+    future::poll_fn(move || blocking(|| match request.take() {
+        Some(request) => core::handle(&app, request),
+        None => core::error_response(APIErr::IO(io::Error::other("the request was already answered")), &path)
+    }))
+        .or_else(move |err| {
+            error!("Could not answer a request on the blocking pool: {}", err);
+            Ok(core::error_response(APIErr::IO(io::Error::other(err.to_string())), &[]))
+        })
+}
+
+fn handle_request(app: RestApp, client_subject: &Option<String>, req: Request<Body>) -> BoxFut {
+    // Only subscriptions switch protocols; any other request asking to is
+    // answered, body and all, like the rest.
+    let subscribe = core::split_path(req.uri().path()).first().map(String::as_str) == Some(websocket::SUBSCRIBE_PATH);
+    if req.method() == hyper::Method::GET && subscribe {
+        return handle_upgrade(app, client_subject, req);
     }
+    let request = read_request(&req, client_subject);
+    let limits = core::body_limits(&app, &request);
+    let declared_length = read_content_length(&req)
+        .map_or(Ok(()), |length| limits.check_body_length(length));
+
+    let spooled = core::spools_body(&request);
+    let receiving = app.clone();
+    let response_fut = future::result(declared_length)
+        .and_then(move |()| receive_body(&receiving, req.into_body(), limits, spooled))
+        .then(move |body| match body {
+            Ok((body, spooled)) => Either::A(handle_blocking(app, core::Request { body, spooled, ..request })),
+            Err(err) => Either::B(future::ok(core::error_response(err, &request.path)))
+        })
+        .map(write_response);
+
+    Box::new(response_fut)
 }
 
+/// Serves HTTPS, handshaking with each connection on a task of its own so
+/// that a slow client holds up nobody else.
+fn serve_tls(app: RestApp, listener: TcpListener, acceptor: tls::Acceptor) -> impl Future<Item=(), Error=()> {
+    listener.incoming()
+        .then(|stream| Ok::<Option<TcpStream>, ()>(stream
+            .map_err(|err| warn!("Could not accept a connection: {}", err))
+            .ok()))
+        .filter_map(|stream| stream)
+        .for_each(move |stream| {
+            let app = app.clone();
+            rt::spawn(acceptor.accept(stream)
+                .map_err(|err| debug!("Could not complete a TLS handshake: {}", err))
+                .and_then(move |stream| {
+                    let client_subject = stream.client_subject();
+                    let service = service_fn(move |req| handle_request(app.clone(), &client_subject, req));
+                    Http::new()
+                        .serve_connection(stream, service)
+                        .with_upgrades()
+                        .map_err(|err| debug!("Could not serve a TLS connection: {}", err))
+                }));
+            Ok(())
+        })
+}
 
-pub fn server() {
-    let addr = ([0, 0, 0, 0], 3000).into();
+/// Serves plain HTTP, or HTTPS with `tls`.
+pub fn server(app: RestApp, addr: SocketAddr, tls: Option<tls::Acceptor>) {
+    expiry::spawn_sweeper(app.clone(), Duration::from_secs(expiry::SWEEP_INTERVAL_SECS));
+
+    if let Some(acceptor) = tls {
+        match net::TcpListener::bind(addr).and_then(|listener| TcpListener::from_std(listener, &Handle::default())) {
+            Ok(listener) => rt::run(serve_tls(app, listener, acceptor)),
+            Err(err) => eprintln!("server error: {}", err)
+        }
+        return;
+    }
 
     let server = Server::bind(&addr)
-        .serve(|| service_fn(handle_request))
+        .serve(move || {
+            let app = app.clone();
+            service_fn(move |req| handle_request(app.clone(), &None, req))
+        })
         .map_err(|e| eprintln!("server error: {}", e));
 
     rt::run(server);
-}
\ No newline at end of file
+}
@@ -1,106 +1,98 @@
 extern crate iron;
-extern crate bodyparser;
-extern crate persistent;
-extern crate serde;
-extern crate serde_json;
 
-extern crate router;
-extern crate simple_logger;
-
-use self::serde_json::Serializer;
-use self::serde_json::Value;
-use self::serde::ser::{Serialize, SerializeStruct};
 use self::iron::prelude::*;
 use self::iron::status;
-use self::iron::mime::Mime;
-use self::iron::{typemap, AfterMiddleware, BeforeMiddleware};
-use self::persistent::Read;
-use self::router::Router;
-use std::clone::Clone;
-use std::collections::HashMap;
+use self::iron::response::WriteBody;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::time::Duration;
 
 use super::super::super::RestApp;
-use super::super::super::api;
-use super::super::super::api::API;
-
-
-struct ResponseTime;
-
-impl AfterMiddleware for ResponseTime {
-    fn after(&self, req: &mut Request, res: Response) -> IronResult<Response> {
-        println!("after!");
-        Ok(res)
-    }
+use super::super::super::api::{expiry, stream, APIErr};
+use super::super::super::api::limits::Limits;
+use super::super::super::api::snapshot::Spool;
+use super::core;
+
+fn read_body(req: &mut Request, limits: Limits) -> Result<Vec<u8>, APIErr> {
+    let mut body = Vec::new();
+    io::Read::read_to_end(&mut io::Read::take(&mut req.body, limits.max_body_length as u64 + 1), &mut body)
+        .map_err(|err| APIErr::IO(io::Error::new(io::ErrorKind::InvalidInput, err.to_string())))?;
+    limits.check_body_length(body.len())
+        .map(|()| body)
 }
 
-fn get_resource(app: RestApp, req: &mut Request) -> IronResult<Response> {
-    let (host, port, path, params) = parse_url(req);
-    
-    let user = String::from("anon");
-    let content_type = "application/json".parse::<Mime>().unwrap();
-    app.get(path, user)
-        .map(|body| Response::with((content_type, status::Ok, body.to_string())))
-        .or_else(|_| Ok(Response::with(status::ImATeapot)))
-}
-
-
-fn to_key_value(string: String) -> Option<(String, String)> {
-    let parts = string
-        .split("=")
-        .map(String::from)
-        .collect::<Vec<String>>();
-
-    parts
-        .first()
-        .map(|key: &String| (
-            key.clone(), 
-            parts[1..].to_vec().join("=")))
+fn spool_body(app: &RestApp, req: &mut Request, limits: Limits) -> Result<Spool, APIErr> {
+    let mut spool = Spool::create(app)?;
+    io::copy(&mut io::Read::take(&mut req.body, limits.max_body_length as u64 + 1), &mut spool)
+        .map_err(|err| APIErr::IO(io::Error::new(io::ErrorKind::InvalidInput, err.to_string())))
+        .and_then(|length| limits.check_body_length(length as usize))
+        .map(|()| spool)
 }
 
-fn parse_url(req: &mut Request) -> (String, u16, Vec<String>, HashMap<String, String>) {
-    (    
-        String::from(format!("{}", req.url.host())),
-        req.url.port(),
-        req.url.path()
+fn read_request(req: &Request) -> core::Request {
+    core::Request {
+        method: req.method.to_string().to_uppercase(),
+        path: req.url.path()
             .into_iter()
+            .filter(|p| !p.is_empty())
             .map(String::from)
-            .collect::<Vec<String>>(),
-        req.url.query()
-            .map(String::from)
-            .unwrap_or(String::from(""))
-            .split("&")
-            .map(String::from)
-            .flat_map(to_key_value)
-            .filter(|(k, _)| !k.is_empty())
-            .collect::<HashMap<String, String>>()
-    )
+            .collect(),
+        query: core::parse_query(req.url.query()),
+        headers: req.headers
+            .iter()
+            .map(|header| (header.name().to_lowercase(), header.value_string()))
+            .collect(),
+        body: Vec::new(),
+        client_subject: None,
+        spooled: None,
+    }
 }
 
+struct Chunked(core::Chunks);
 
-fn put_resource(app: RestApp, req: &mut Request) ->  IronResult<Response> {   
-    let (host, port, path, params) = parse_url(req);
+impl WriteBody for Chunked {
+    fn write_body(&mut self, res: &mut Write) -> io::Result<()> {
+        stream::write_chunks(&mut self.0, res)
+    }
+}
 
-    let user = String::from("anon");
-   
+fn write_response(res: core::Response) -> Response {
+    let mut response = Response::with(status::Status::from_u16(res.status));
+    res.headers
+        .into_iter()
+        .for_each(|(name, value)| response.headers.set_raw(name, vec![value.into_bytes()]));
+    match res.body {
+        core::Body::Empty | core::Body::Upgrade(_) => (),
+        core::Body::Bytes(bytes) => response.body = Some(Box::new(bytes)),
+        core::Body::Stream(chunks) => response.body = Some(Box::new(Chunked(chunks))),
+        core::Body::Pending(pending) => response.body = Some(Box::new(Chunked(core::wait_for(pending)))),
+    }
+    response
+}
 
-    let content_type = "application/json".parse::<Mime>().unwrap();
-    req.get::<bodyparser::Json>()
-        .map_err(api::APIErr::Deserialize)
-        .and_then(|json_opt| json_opt.map(|j|Ok(j)).unwrap_or(Err(api::APIErr::EmptyRequest)))
-        .and_then(|json| app.put(path, json, user))
-        .map(|()| Response::with((status::Ok)))
-        .or_else(|_| Ok(Response::with(status::ImATeapot)))
+fn handle_request(app: &RestApp, req: &mut Request) -> IronResult<Response> {
+    let request = read_request(req);
+    let path = request.path.clone();
+    let limits = core::body_limits(app, &request);
+    let received = if core::spools_body(&request) {
+        spool_body(app, req, limits).map(|spool| core::Request { spooled: Some(spool), ..request })
+    } else {
+        read_body(req, limits).map(|body| core::Request { body, ..request })
+    };
+    let response = match received {
+        Ok(request) => core::handle(app, request),
+        Err(err) => core::error_response(err, &path)
+    };
+    // Iron only ever writes a body after the head it sent, so the
+    // connection cannot be handed over.
+    Ok(write_response(match response.body {
+        core::Body::Upgrade(_) => core::upgrade_unsupported(&path),
+        _ => response
+    }))
 }
 
-fn server() {
-    let app = RestApp { storage_dir: String::from("rest-storage") };
-    let mut routes = Router::new();
-    let getApp = app.clone();
-    let mut get_resource_chain = Chain::new(move |req: &mut Request| get_resource(getApp.clone(), req));
-    let putApp = app.clone();
-    let mut put_resource_chain = Chain::new(move |req: &mut Request| put_resource(putApp.clone(), req));
-    routes.get("**", get_resource_chain, "get");
-    routes.put("**", put_resource_chain, "put");
-    
-    Iron::new(routes).http("0.0.0.0:3000").unwrap();
-}
\ No newline at end of file
+pub fn server(app: RestApp, addr: SocketAddr) {
+    expiry::spawn_sweeper(app.clone(), Duration::from_secs(expiry::SWEEP_INTERVAL_SECS));
+
+    Iron::new(move |req: &mut Request| handle_request(&app, req)).http(addr).unwrap();
+}
@@ -1,2 +1,40 @@
+pub mod client;
+pub mod codec;
+pub mod core;
+pub mod cors;
 pub mod hyper;
-pub mod iron;
\ No newline at end of file
+pub mod iron;
+pub mod problem;
+pub mod tls;
+pub mod websocket;
+
+use std::net::SocketAddr;
+
+use super::super::RestApp;
+
+/// The HTTP implementations the server can run on. Both hand every request
+/// to `core::handle`, so they differ only in how bytes reach the socket.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Engine {
+    Hyper,
+    Iron,
+}
+
+impl Engine {
+    pub fn from_name(name: &str) -> Option<Engine> {
+        match name {
+            "hyper" => Some(Engine::Hyper),
+            "iron" => Some(Engine::Iron),
+            _ => None
+        }
+    }
+}
+
+/// Serves `app` on `addr` until the process exits. Only hyper serves
+/// `tls`, which the configuration refuses for iron.
+pub fn serve(engine: Engine, app: RestApp, addr: SocketAddr, tls: Option<tls::Acceptor>) {
+    match engine {
+        Engine::Hyper => hyper::server(app, addr, tls),
+        Engine::Iron => iron::server(app, addr),
+    }
+}
@@ -0,0 +1,111 @@
+extern crate serde_json;
+
+use std::io;
+
+use super::super::super::api::APIErr;
+
+pub const PROBLEM_JSON: &str = "application/problem+json";
+const ENOSPC: i32 = 28;
+
+/// An RFC 7807 problem details body. Every problem uses the `about:blank`
+/// type, so its title is always the reason phrase of its status.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub problem_type: &'static str,
+    pub title: &'static str,
+    pub status: u16,
+    pub detail: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<Problem>,
+}
+
+pub fn title(status: u16) -> &'static str {
+    match status {
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        409 => "Conflict",
+        410 => "Gone",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        422 => "Unprocessable Entity",
+        426 => "Upgrade Required",
+        501 => "Not Implemented",
+        507 => "Insufficient Storage",
+        _ => "Internal Server Error"
+    }
+}
+
+fn io_status(err: &io::Error) -> u16 {
+    match err.kind() {
+        io::ErrorKind::NotFound => 404,
+        io::ErrorKind::AlreadyExists => 409,
+        io::ErrorKind::InvalidInput => 400,
+        _ if err.raw_os_error() == Some(ENOSPC) => 507,
+        _ => 500
+    }
+}
+
+pub fn status(err: &APIErr) -> u16 {
+    match err {
+        APIErr::Context { source, .. } => status(source),
+        APIErr::Aggregate(errs) => errs.iter().map(status).max().unwrap_or(500),
+        APIErr::IO(io) => io_status(io),
+        APIErr::Conflict(_) => 409,
+        APIErr::Unauthorized(_) => 401,
+        APIErr::Forbidden => 403,
+        APIErr::Gone(_) => 410,
+        APIErr::BodyTooLarge(_) | APIErr::TooDeep(_) | APIErr::TooManyMembers(_) | APIErr::ArrayTooLong(_) => 413,
+        APIErr::UnsupportedMediaType(_) => 415,
+        APIErr::NotAcceptable(_) => 406,
+        APIErr::Decode(_) | APIErr::Deserialize(_) | APIErr::EmptyRequest => 422,
+        APIErr::Encode(_) | APIErr::UnsupportedVersion(_) => 500,
+    }
+}
+
+fn detail(err: &APIErr) -> String {
+    match err.root_cause() {
+        APIErr::Aggregate(errs) => format!("{} errors occurred", errs.len()),
+        APIErr::IO(io) if io.kind() == io::ErrorKind::NotFound => String::from("no document exists at this path"),
+        cause => cause.to_string()
+    }
+}
+
+impl Problem {
+    pub fn new(status: u16, detail: String) -> Problem {
+        Problem {
+            problem_type: "about:blank",
+            title: title(status),
+            status,
+            detail,
+            instance: None,
+            path: None,
+            errors: Vec::new(),
+        }
+    }
+
+    /// Describes `err`, and every error nested in it, as a problem.
+    pub fn from_err(err: &APIErr) -> Problem {
+        let mut problem = Problem::new(status(err), detail(err));
+        problem.path = err.path().map(String::from);
+        problem.errors = err.errors().iter().map(Problem::from_err).collect();
+        problem
+    }
+
+    pub fn at(mut self, instance: String) -> Problem {
+        self.instance = Some(instance);
+        self
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap_or_default()
+    }
+}
@@ -0,0 +1,229 @@
+//! HTTPS for the hyper engine, from OpenSSL. The certificate can be
+//! replaced while the server runs, and client certificates verified
+//! against the configured authorities name the principal of a connection.
+
+extern crate futures;
+extern crate openssl;
+extern crate signal_hook;
+extern crate tokio_io;
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use self::futures::{Async, Future, Poll};
+use self::openssl::error::ErrorStack;
+use self::openssl::nid::Nid;
+use self::openssl::ssl::{self, HandshakeError, MidHandshakeSslStream, SslAcceptor, SslFiletype, SslMethod, SslStream,
+                         SslVerifyMode};
+use self::openssl::x509::X509Name;
+use self::tokio_io::{AsyncRead, AsyncWrite};
+
+/// How often the certificate files are checked for changes.
+const RELOAD_CHECK_SECS: u64 = 1;
+
+#[derive(Debug)]
+pub enum TlsErr {
+    Setup(ErrorStack),
+    File(String, ErrorStack),
+    Signal(io::Error),
+}
+
+impl fmt::Display for TlsErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TlsErr::Setup(err) => write!(f, "could not set up TLS: {}", err),
+            TlsErr::File(file, err) => write!(f, "could not load {}: {}", file, err),
+            TlsErr::Signal(err) => write!(f, "could not listen for SIGHUP: {}", err),
+        }
+    }
+}
+
+fn in_file(file: &str) -> impl FnOnce(ErrorStack) -> TlsErr {
+    let file = String::from(file);
+    move |err| TlsErr::File(file, err)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    pub cert_file: String,
+    pub key_file: String,
+    pub client_ca_file: Option<String>,
+    pub require_client_cert: bool,
+}
+
+impl Settings {
+    fn files(&self) -> Vec<&str> {
+        let mut files = vec![self.cert_file.as_str(), self.key_file.as_str()];
+        files.extend(self.client_ca_file.as_deref());
+        files
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        self.files()
+            .into_iter()
+            .map(|file| fs::metadata(file).and_then(|metadata| metadata.modified()).ok())
+            .collect()
+    }
+
+    fn build(&self) -> Result<SslAcceptor, TlsErr> {
+        let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server()).map_err(TlsErr::Setup)?;
+        builder.set_certificate_chain_file(self.cert_file.as_str()).map_err(in_file(self.cert_file.as_str()))?;
+        builder.set_private_key_file(self.key_file.as_str(), SslFiletype::PEM).map_err(in_file(self.key_file.as_str()))?;
+        builder.check_private_key().map_err(in_file(self.key_file.as_str()))?;
+        if let Some(ref ca_file) = self.client_ca_file {
+            builder.set_ca_file(ca_file.as_str()).map_err(in_file(ca_file.as_str()))?;
+            builder.set_client_ca_list(X509Name::load_client_ca_file(ca_file.as_str()).map_err(in_file(ca_file.as_str()))?);
+            builder.set_verify(if self.require_client_cert {
+                SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT
+            } else {
+                SslVerifyMode::PEER
+            });
+        }
+        Ok(builder.build())
+    }
+}
+
+/// Accepts TLS connections with the certificate most recently loaded.
+#[derive(Clone)]
+pub struct Acceptor {
+    settings: Arc<Settings>,
+    current: Arc<RwLock<Arc<SslAcceptor>>>,
+}
+
+impl Acceptor {
+    pub fn new(settings: Settings) -> Result<Acceptor, TlsErr> {
+        let acceptor = settings.build()?;
+        Ok(Acceptor { settings: Arc::new(settings), current: Arc::new(RwLock::new(Arc::new(acceptor))) })
+    }
+
+    /// Loads the files again. Connections already made keep the
+    /// certificate they were made with, and when the files cannot be
+    /// loaded, new ones do too.
+    pub fn reload(&self) -> Result<(), TlsErr> {
+        let acceptor = self.settings.build()?;
+        *self.current.write().unwrap_or_else(|err| err.into_inner()) = Arc::new(acceptor);
+        Ok(())
+    }
+
+    /// Reloads the files on a thread of its own whenever one of them
+    /// changes or the process receives SIGHUP.
+    pub fn watch(&self) -> Result<(), TlsErr> {
+        let hangup = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register(signal_hook::SIGHUP, hangup.clone()).map_err(TlsErr::Signal)?;
+        let acceptor = self.clone();
+        thread::spawn(move || {
+            let mut seen = acceptor.settings.modified();
+            loop {
+                thread::sleep(Duration::from_secs(RELOAD_CHECK_SECS));
+                let modified = acceptor.settings.modified();
+                if !hangup.swap(false, Ordering::SeqCst) && modified == seen {
+                    continue;
+                }
+                seen = modified;
+                match acceptor.reload() {
+                    Ok(()) => info!("Reloaded the TLS certificate from {}", acceptor.settings.cert_file),
+                    Err(err) => error!("Kept the previous TLS certificate: {}", err)
+                }
+            }
+        });
+        Ok(())
+    }
+
+    pub fn accept<S: Read + Write>(&self, stream: S) -> Handshake<S> {
+        let acceptor = self.current.read().unwrap_or_else(|err| err.into_inner()).clone();
+        Handshake::Start(acceptor, stream)
+    }
+}
+
+/// The server's side of a TLS handshake on a non-blocking stream.
+pub enum Handshake<S> {
+    Start(Arc<SslAcceptor>, S),
+    Pending(MidHandshakeSslStream<S>),
+    Done,
+}
+
+fn handshake_error<S>(err: HandshakeError<S>) -> io::Error {
+    match err {
+        HandshakeError::SetupFailure(err) => io::Error::other(err),
+        HandshakeError::Failure(stream) | HandshakeError::WouldBlock(stream) => io::Error::other(stream.into_error()),
+    }
+}
+
+impl<S: Read + Write> Future for Handshake<S> {
+    type Item = TlsStream<S>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<TlsStream<S>, io::Error> {
+        let attempt = match mem::replace(self, Handshake::Done) {
+            Handshake::Start(acceptor, stream) => acceptor.accept(stream),
+            Handshake::Pending(stream) => stream.handshake(),
+            Handshake::Done => panic!("polled a finished handshake")
+        };
+        match attempt {
+            Ok(stream) => Ok(Async::Ready(TlsStream(stream))),
+            Err(HandshakeError::WouldBlock(stream)) => {
+                *self = Handshake::Pending(stream);
+                Ok(Async::NotReady)
+            }
+            Err(err) => Err(handshake_error(err))
+        }
+    }
+}
+
+/// A connection once its handshake is done.
+pub struct TlsStream<S>(SslStream<S>);
+
+impl<S: Read + Write> TlsStream<S> {
+    /// The common name in the subject of the client's certificate. Only
+    /// certificates issued by one of the configured authorities get this
+    /// far, and names with control characters, which could pass for
+    /// another principal's, are ignored.
+    pub fn client_subject(&self) -> Option<String> {
+        self.0.ssl()
+            .peer_certificate()
+            .and_then(|cert| cert.subject_name()
+                .entries_by_nid(Nid::COMMONNAME)
+                .next()
+                .and_then(|entry| entry.data().to_string().ok()))
+            .filter(|name| !name.is_empty() && !name.chars().any(char::is_control))
+    }
+}
+
+impl<S: Read + Write> Read for TlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<S: Read + Write> Write for TlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> AsyncRead for TlsStream<S> {}
+
+impl<S: AsyncRead + AsyncWrite> AsyncWrite for TlsStream<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self.0.shutdown() {
+            Ok(_) => (),
+            Err(ref err) if err.code() == ssl::ErrorCode::ZERO_RETURN => (),
+            Err(err) => match err.into_io_error() {
+                Ok(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Ok(err) => return Err(err),
+                Err(err) => return Err(io::Error::other(err))
+            }
+        }
+        self.0.get_mut().shutdown()
+    }
+}
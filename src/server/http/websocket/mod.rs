@@ -0,0 +1,328 @@
+//! Just enough of RFC 6455 to push changes to subscribers: the handshake,
+//! unfragmented frames, pings and the close handshake.
+
+extern crate base64;
+extern crate serde_json;
+
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+
+use self::serde_json::Value;
+use super::super::super::RestApp;
+use super::super::super::api::API;
+use super::super::super::api::acl::{self, Permission};
+use super::super::super::api::changes::{Change, ChangeFeed, Subscription};
+use super::super::super::auth::hash;
+use super::core;
+use super::problem;
+
+pub const SUBSCRIBE_PATH: &str = "_subscribe";
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+pub const OP_TEXT: u8 = 0x1;
+pub const OP_CLOSE: u8 = 0x8;
+pub const OP_PING: u8 = 0x9;
+pub const OP_PONG: u8 = 0xa;
+
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+/// Sent after an `overflow` message, when a subscriber fell too far behind.
+pub const CLOSE_OVERFLOW: u16 = 1008;
+
+/// Frames from clients only carry subscription commands, so anything
+/// larger is refused.
+const MAX_PAYLOAD: u64 = 64 * 1024;
+/// How many messages may wait to be sent to a subscriber before it is
+/// disconnected as too slow.
+pub const BUFFER: usize = 256;
+/// Room kept past `BUFFER` for the `overflow` message and the close frame
+/// that follows it, so that queueing them never waits on the subscriber.
+const RESERVED: usize = 2;
+/// How often an idle subscriber is pinged.
+const HEARTBEAT_SECS: u64 = 15;
+
+/// The `Sec-WebSocket-Accept` answer to a `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    base64::encode(&hash::sha1(format!("{}{}", key.trim(), GUID).as_bytes()))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+/// A final frame, masked with `mask` as clients must and servers must not.
+pub fn encode_frame(opcode: u8, payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    let masked = if mask.is_some() { 0x80 } else { 0 };
+    match payload.len() {
+        n if n < 126 => frame.push(masked | n as u8),
+        n if n <= 0xffff => {
+            frame.push(masked | 126);
+            frame.extend_from_slice(&(n as u16).to_be_bytes());
+        }
+        n => {
+            frame.push(masked | 127);
+            frame.extend_from_slice(&(n as u64).to_be_bytes());
+        }
+    }
+    match mask {
+        Some(mask) => {
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        }
+        None => frame.extend_from_slice(payload)
+    }
+    frame
+}
+
+/// Reads one frame sent by a client, unmasking it.
+pub fn read_frame(input: &mut Read) -> io::Result<Frame> {
+    let mut head = [0u8; 2];
+    input.read_exact(&mut head)?;
+    let length = match head[1] & 0x7f {
+        126 => {
+            let mut length = [0u8; 2];
+            input.read_exact(&mut length)?;
+            u64::from(u16::from_be_bytes(length))
+        }
+        127 => {
+            let mut length = [0u8; 8];
+            input.read_exact(&mut length)?;
+            u64::from_be_bytes(length)
+        }
+        n => u64::from(n)
+    };
+    if head[1] & 0x80 == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "client frames must be masked"));
+    }
+    if length > MAX_PAYLOAD {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frames may carry at most {} bytes", MAX_PAYLOAD)));
+    }
+    let mut mask = [0u8; 4];
+    input.read_exact(&mut mask)?;
+    let mut payload = vec![0u8; length as usize];
+    input.read_exact(&mut payload)?;
+    payload.iter_mut().enumerate().for_each(|(i, b)| *b ^= mask[i % 4]);
+    Ok(Frame { opcode: head[0] & 0x0f, payload })
+}
+
+/// What a subscriber is sent, as JSON text frames.
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Message {
+    /// The value below a prefix when it was subscribed to, or `null` if
+    /// there was none. Changes to it follow.
+    Value { path: String, value: Value },
+    Change(Change),
+    Error { detail: String },
+    /// The last message before the connection is closed because more than
+    /// `BUFFER` messages were waiting to be sent.
+    Overflow { detail: String },
+}
+
+/// What a subscriber may send, as `{"subscribe": "/path"}` and
+/// `{"unsubscribe": "/path"}`.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+enum Command {
+    Subscribe(String),
+    Unsubscribe(String),
+}
+
+enum Outgoing {
+    Text(String),
+    Ping,
+    Pong(Vec<u8>),
+    Close(u16, String),
+}
+
+struct Session {
+    app: RestApp,
+    user: String,
+    root: String,
+    feed: Arc<ChangeFeed>,
+    /// Each prefix subscribed to, with the sequence number its value was
+    /// read after. Older changes are already part of that value.
+    prefixes: Mutex<Vec<(Vec<String>, u64)>>,
+    queue: SyncSender<Outgoing>,
+    /// How many messages are queued, which the writer counts down.
+    queued: Arc<AtomicUsize>,
+    overflowed: AtomicBool,
+    closed: AtomicBool,
+}
+
+fn is_below(prefix: &[String], path: &[String]) -> bool {
+    prefix.len() <= path.len() && prefix.iter().zip(path.iter()).all(|(a, b)| a == b)
+}
+
+impl Session {
+    fn prefixes(&self) -> MutexGuard<'_, Vec<(Vec<String>, u64)>> {
+        self.prefixes.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn is_done(&self) -> bool {
+        self.overflowed.load(Ordering::SeqCst) || self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Queues `outgoing` without waiting: a full queue means the
+    /// subscriber is too slow, and a closed one that it is gone.
+    fn push(&self, outgoing: Outgoing) {
+        if self.queued.fetch_add(1, Ordering::SeqCst) >= BUFFER {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            return self.overflowed.store(true, Ordering::SeqCst);
+        }
+        match self.queue.try_send(outgoing) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => self.overflowed.store(true, Ordering::SeqCst),
+            Err(TrySendError::Disconnected(_)) => self.closed.store(true, Ordering::SeqCst),
+        }
+    }
+
+    /// Queues one of the `RESERVED` messages sent after an overflow, which
+    /// always find room.
+    fn push_reserved(&self, outgoing: Outgoing) {
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        if self.queue.try_send(outgoing).is_err() {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    fn send(&self, message: &Message) {
+        match serde_json::to_string(message) {
+            Ok(text) => self.push(Outgoing::Text(text)),
+            Err(err) => error!("Could not encode a subscription message: {}", err)
+        }
+    }
+
+    /// Sends the value below `prefix`, after which its changes are sent.
+    /// The prefixes stay locked meanwhile, so that no change is sent before
+    /// the value it follows.
+    fn subscribe(&self, prefix: Vec<String>) {
+        if let Err(err) = acl::check(&self.app, &prefix, &self.user, Permission::Read) {
+            return self.send(&Message::Error { detail: err.to_string() });
+        }
+        let since = self.feed.last_seq();
+        let mut prefixes = self.prefixes();
+        let value = match self.app.get(prefix.clone(), self.user.clone()) {
+            Ok(value) => value,
+            Err(ref err) if problem::status(err) == 404 => Value::Null,
+            Err(err) => return self.send(&Message::Error { detail: err.to_string() })
+        };
+        self.send(&Message::Value { path: core::instance(&prefix), value });
+        prefixes.retain(|(subscribed, _)| subscribed != &prefix);
+        prefixes.push((prefix, since));
+    }
+
+    fn unsubscribe(&self, prefix: &[String]) {
+        self.prefixes().retain(|(subscribed, _)| subscribed.as_slice() != prefix);
+    }
+
+    fn keeps(&self, change: &Change) -> bool {
+        let path = core::split_path(change.path.as_str());
+        change.root == self.root &&
+            self.prefixes().iter().any(|(prefix, since)| change.seq > *since && is_below(prefix, &path)) &&
+            acl::check(&self.app, &path, &self.user, Permission::Read).is_ok()
+    }
+}
+
+fn write_frames(outgoing: Receiver<Outgoing>, queued: &AtomicUsize, mut output: Box<Write + Send>) {
+    for message in outgoing {
+        queued.fetch_sub(1, Ordering::SeqCst);
+        let (opcode, payload) = match message {
+            Outgoing::Text(text) => (OP_TEXT, text.into_bytes()),
+            Outgoing::Ping => (OP_PING, Vec::new()),
+            Outgoing::Pong(payload) => (OP_PONG, payload),
+            Outgoing::Close(code, reason) => (OP_CLOSE, [&code.to_be_bytes()[..], reason.as_bytes()].concat()),
+        };
+        let written = output.write_all(encode_frame(opcode, payload.as_slice(), None).as_slice())
+            .and_then(|()| output.flush());
+        if written.is_err() || opcode == OP_CLOSE {
+            return;
+        }
+    }
+}
+
+fn read_commands(session: &Session, mut input: Box<Read + Send>) {
+    loop {
+        let frame = match read_frame(&mut input) {
+            Ok(frame) => frame,
+            Err(err) => {
+                if err.kind() == io::ErrorKind::InvalidData {
+                    session.push(Outgoing::Close(CLOSE_PROTOCOL_ERROR, err.to_string()));
+                }
+                return session.closed.store(true, Ordering::SeqCst);
+            }
+        };
+        match frame.opcode {
+            OP_CLOSE => {
+                session.push(Outgoing::Close(CLOSE_NORMAL, String::new()));
+                return session.closed.store(true, Ordering::SeqCst);
+            }
+            OP_PING => session.push(Outgoing::Pong(frame.payload)),
+            OP_TEXT => match serde_json::from_slice::<Command>(frame.payload.as_slice()) {
+                Ok(Command::Subscribe(path)) => session.subscribe(core::split_path(path.as_str())),
+                Ok(Command::Unsubscribe(path)) => session.unsubscribe(&core::split_path(path.as_str())),
+                Err(err) => session.send(&Message::Error { detail: err.to_string() })
+            },
+            _ => ()
+        }
+    }
+}
+
+/// Runs a subscription on a connection that has been upgraded, starting
+/// with `prefix` unless it is empty, until either side closes it.
+pub fn serve(app: RestApp, user: String, prefix: Vec<String>, input: Box<Read + Send>, output: Box<Write + Send>) {
+    let feed = match app.changes() {
+        Some(feed) => feed,
+        None => return
+    };
+    let (queue, outgoing) = mpsc::sync_channel(BUFFER + RESERVED);
+    let queued = Arc::new(AtomicUsize::new(0));
+    let session = Arc::new(Session {
+        root: app.root_path().to_string_lossy().into_owned(),
+        app,
+        user,
+        feed: feed.clone(),
+        prefixes: Mutex::new(Vec::new()),
+        queue,
+        queued: queued.clone(),
+        overflowed: AtomicBool::new(false),
+        closed: AtomicBool::new(false),
+    });
+    let writer = thread::spawn(move || write_frames(outgoing, &queued, output));
+    let reader = session.clone();
+    thread::spawn(move || read_commands(&reader, input));
+
+    let since = feed.last_seq();
+    if !prefix.is_empty() {
+        session.subscribe(prefix);
+    }
+    for batch in Subscription::new(feed, since, Duration::from_secs(HEARTBEAT_SECS), |_: &Change| true) {
+        if session.is_done() {
+            break;
+        }
+        match batch {
+            Ok(ref batch) if batch.changes.is_empty() => session.push(Outgoing::Ping),
+            Ok(batch) => batch.changes.into_iter()
+                .filter(|change| session.keeps(change))
+                .for_each(|change| session.send(&Message::Change(change))),
+            Err(_) => session.overflowed.store(true, Ordering::SeqCst)
+        }
+        if session.is_done() {
+            break;
+        }
+    }
+    if session.overflowed.load(Ordering::SeqCst) && !session.closed.load(Ordering::SeqCst) {
+        let overflow = Message::Overflow { detail: format!("more than {} messages were waiting to be sent", BUFFER) };
+        let _ = serde_json::to_string(&overflow).map(|text| session.push_reserved(Outgoing::Text(text)));
+        session.push_reserved(Outgoing::Close(CLOSE_OVERFLOW, String::from("overflow")));
+    }
+    drop(session);
+    let _ = writer.join();
+}
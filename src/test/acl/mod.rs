@@ -0,0 +1,178 @@
+extern crate serde_json;
+extern crate rand;
+use std::collections::HashMap;
+use std::fs;
+
+use self::serde_json::{json, Value};
+use self::rand::Rng;
+
+use super::super::api::API;
+use super::super::api::APIErr;
+use super::super::api::acl::{self, Acl, Permission};
+use super::super::api::tenant;
+use super::super::server::http::core::{self, Request};
+use super::super::RestApp;
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .gen_ascii_chars()
+        .take(len)
+        .collect::<String>()
+}
+
+fn path(p: &str) -> Vec<String> {
+    p.split('/').filter(|p| !p.is_empty()).map(String::from).collect()
+}
+
+fn request(method: &str, p: &str, body: &[u8]) -> Request {
+    Request {
+        method: String::from(method),
+        path: path(p),
+        query: HashMap::new(),
+        headers: HashMap::new(),
+        body: body.to_vec(),
+        client_subject: None,
+        spooled: None,
+    }
+}
+
+fn rules() -> Acl {
+    Acl::from_value(json!({
+        "rules": [
+            {"prefix": "/team", "principal": "group:devs", "permissions": ["read", "write"]},
+            {"prefix": "/team/secrets", "principal": "carol", "permissions": ["admin"]},
+            {"prefix": "/public", "principal": "*", "permissions": ["read"]}
+        ],
+        "groups": {"devs": ["alice", "bob"]}
+    })).unwrap()
+}
+
+#[test]
+fn test_rules_grant_only_what_they_name() {
+    let acl = rules();
+    assert!(acl.allows(&path("/team/doc"), "alice", Permission::Write));
+    assert!(acl.allows(&path("/team"), "bob", Permission::Read));
+    assert!(!acl.allows(&path("/team/doc"), "alice", Permission::Delete));
+    assert!(!acl.allows(&path("/"), "alice", Permission::Read));
+    assert!(!acl.allows(&path("/teammates"), "alice", Permission::Read));
+    assert!(acl.allows(&path("/team/secrets/key"), "carol", Permission::Delete));
+    assert!(!acl.allows(&path("/team/doc"), "carol", Permission::Read));
+    assert!(acl.allows(&path("/public/page"), "anyone", Permission::Read));
+    assert!(!acl.allows(&path("/public/page"), "anyone", Permission::Write));
+    assert!(Acl::from_value(json!({"rules": [{"prefix": "/", "principal": "x", "permissions": ["fly"]}]})).is_err());
+}
+
+#[test]
+fn test_every_api_method_enforces_the_rules() {
+    let storage_dir = random_string(16);
+    let api = RestApp::new(storage_dir.clone()).with_admins(vec![String::from("root")]);
+    let doc = json!({"a": 1});
+    let alice = String::from("alice");
+    let mallory = String::from("mallory");
+
+    assert!(api.put(path("/team/doc"), doc.clone(), alice.clone()).is_ok());
+    assert!(acl::store(&api, Some(&rules())).is_ok());
+
+    assert_eq!(api.get(path("/team/doc"), alice.clone()).ok(), Some(doc.clone()));
+    assert!(api.put(path("/team/other"), doc.clone(), alice.clone()).is_ok());
+    match api.delete(path("/team/other"), alice.clone()) {
+        Err(APIErr::Forbidden) => (),
+        other => panic!("expected Forbidden, got {:?}", other)
+    }
+    match api.get(path("/team/doc"), mallory.clone()) {
+        Err(APIErr::Forbidden) => (),
+        other => panic!("expected Forbidden, got {:?}", other)
+    }
+    match api.ttl(path("/team/missing"), mallory.clone()) {
+        Err(APIErr::Forbidden) => (),
+        other => panic!("expected Forbidden, got {:?}", other)
+    }
+    assert!(api.put(path("/_acl"), json!({}), alice.clone()).is_err());
+    assert_eq!(api.get(path("/team/other"), String::from("root")).ok(), Some(doc));
+    assert!(!api.is_visible(&std::path::Path::new(storage_dir.as_str()).join(acl::ACL_PATH)));
+
+    assert!(acl::store(&api, None).is_ok());
+    assert!(api.get(path("/team/doc"), mallory).is_ok());
+    fs::remove_dir_all(storage_dir).unwrap();
+}
+
+#[test]
+fn test_denials_look_the_same_whether_or_not_the_document_exists() {
+    let storage_dir = random_string(16);
+    let app = RestApp::new(storage_dir.clone());
+    let acl = rules().to_value().to_string().into_bytes();
+
+    assert_eq!(core::handle(&app, request("PUT", "/team/doc", b"{\"a\":1}")).status, 200);
+    assert_eq!(core::handle(&app, request("PUT", "/_acl", acl.as_slice())).status, 403);
+    assert_eq!(core::handle(&app, request("GET", "/_snapshot", b"")).status, 403);
+    assert!(acl::store(&app, Some(&rules())).is_ok());
+
+    let existing = core::handle(&app, request("GET", "/team/doc", b""));
+    let missing = core::handle(&app, request("GET", "/team/missing", b""));
+    assert_eq!((existing.status, missing.status), (403, 403));
+    let detail = |response: core::Response| match response.body {
+        core::Body::Bytes(bytes) => serde_json::from_slice::<Value>(bytes.as_slice()).ok()
+            .map(|problem| (problem["detail"].clone(), problem.get("path").cloned())),
+        _ => None
+    };
+    assert_eq!(detail(existing), detail(missing));
+
+    assert_eq!(core::handle(&app, request("GET", "/_acl", b"")).status, 403);
+    assert_eq!(core::handle(&app, request("DELETE", "/_acl", b"")).status, 403);
+    assert_eq!(core::handle(&app, request("GET", "/_snapshot", b"")).status, 403);
+    assert_eq!(core::handle(&app, request("POST", "/_fsck", b"")).status, 403);
+    assert_eq!(core::handle(&app, request("GET", "/_export/team", b"")).status, 403);
+    assert_eq!(core::handle(&app, request("GET", "/public/page", b"")).status, 404);
+    fs::remove_dir_all(storage_dir).unwrap();
+}
+
+#[test]
+fn test_the_root_is_never_written_or_removed_as_a_whole() {
+    let storage_dir = random_string(16);
+    let app = RestApp::new(storage_dir.clone());
+    let planted = json!({"_keys": {"rmk_planted": {"owner": "root"}}, "_acl": {"rules": []}});
+
+    assert_eq!(core::handle(&app, request("PUT", "/team/doc", b"{\"a\":1}")).status, 200);
+    assert_eq!(core::handle(&app, request("PUT", "/", planted.to_string().as_bytes())).status, 405);
+    assert!(app.put(Vec::new(), planted, String::from("mallory")).is_err());
+    assert!(!app.root_path().join("_keys").exists());
+    assert!(!app.root_path().join("_acl").exists());
+
+    assert_eq!(core::handle(&app, request("DELETE", "/", b"")).status, 405);
+    assert!(app.delete(Vec::new(), String::from("mallory")).is_err());
+    assert_eq!(app.get(path("/team/doc"), String::from("mallory")).ok(), Some(json!({"a": 1})));
+    fs::remove_dir_all(storage_dir).unwrap();
+}
+
+#[test]
+fn test_members_cannot_reach_outside_their_collection() {
+    let storage_dir = random_string(16);
+    let app = RestApp::new(storage_dir.clone());
+    let user = String::from("mallory");
+
+    assert!(app.put(path("/team/doc"), json!({"..": {"_keys": {"a": 1}}}), user.clone()).is_err());
+    assert!(app.put(path("/team/doc"), json!({"x": [{"a/b": 1}]}), user.clone()).is_err());
+    assert!(app.put(path("/team/doc"), json!({"__class_declaration__": "ARRAY"}), user.clone()).is_err());
+    assert!(app.put(path("/team/doc"), json!({"__expires__.a": "1"}), user.clone()).is_err());
+    assert!(!app.root_path().join("team").join("_keys").exists());
+    assert!(!app.root_path().join("team").exists());
+    fs::remove_dir_all(storage_dir).ok();
+}
+
+
+#[test]
+fn test_only_administrators_reach_the_tenants() {
+    let storage_dir = random_string(16);
+    let app = RestApp::new(storage_dir.clone()).with_admins(vec![String::from("root")]);
+    tenant::create(&app, "red").unwrap();
+    app.for_tenant("red").put(path("/doc"), json!({"a": 1}), String::from("root")).unwrap();
+    let everyone = json!({"rules": [{"prefix": "/", "principal": "*", "permissions": ["admin"]}]});
+    assert!(acl::store(&app, Some(&Acl::from_value(everyone).unwrap())).is_ok());
+
+    assert_eq!(core::handle(&app, request("GET", "/_snapshot/_tenants/red", b"")).status, 403);
+    assert_eq!(core::handle(&app, request("PUT", "/_snapshot/_tenants/red", b"")).status, 403);
+    assert_eq!(core::handle(&app, request("GET", "/_tenants/red/doc", b"")).status, 403);
+    assert!(app.delete(path("/_tenants/red"), String::from("mallory")).is_err());
+    assert_eq!(app.for_tenant("red").get(path("/doc"), String::from("root")).ok(), Some(json!({"a": 1})));
+    fs::remove_dir_all(storage_dir).ok();
+}
\ No newline at end of file
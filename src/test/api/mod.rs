@@ -7,7 +7,9 @@ use std::path::PathBuf;
 use self::serde_json::json;
 use self::serde_json::Value;
 use super::super::api::API;
-use super::super::api::Class;
+use super::super::api::{APIErr, Class, Operation};
+use super::super::api::limits::Limits;
+use super::super::api::stream;
 use super::super::RestApp;
 use self::rand::Rng;
 
@@ -27,7 +29,7 @@ fn to_path_buf(path: Vec<String>) -> PathBuf {
 #[test]
 fn test_read_collection_class() {
     let storage_dir = random_string(16) ;
-    let api = RestApp { storage_dir: storage_dir.clone() };
+    let api = RestApp::new(storage_dir.clone());
     let json = json!({
         "name":"test_obj"
     });
@@ -66,9 +68,9 @@ fn test_read_collection_class() {
 #[test]
 fn test_read_field() {
     let storage_dir = random_string(16) ;
-    let api = RestApp { storage_dir: storage_dir.clone() };
+    let api = RestApp::new(storage_dir.clone());
 
-    let api = RestApp { storage_dir: storage_dir.clone() };
+    let api = RestApp::new(storage_dir.clone());
     let json = json!({
         "name":"test_obj"
     });
@@ -96,7 +98,7 @@ fn test_read_field() {
 #[test]
 fn test_read_object() {
     let storage_dir = random_string(16) ;
-    let api = RestApp { storage_dir: storage_dir.clone() };
+    let api = RestApp::new(storage_dir.clone());
 
     let json = json!({
         "name":"test_obj",
@@ -130,4 +132,189 @@ fn test_read_object() {
     
     
     fs::remove_dir_all(storage_dir);
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_put_with_ttl() {
+    let storage_dir = random_string(16) ;
+    let api = RestApp::new(storage_dir.clone());
+    let user = String::from("tilda");
+    let path = ["root".to_string(), "session".to_string()].to_vec();
+
+    assert!(api.put_with_ttl(path.clone(), json!({"token":"abc"}), Some(60), user.clone()).is_ok());
+    assert_eq!(api.get(path.clone(), user.clone()).ok(), Some(json!({"token":"abc"})));
+    assert!(api.ttl(path.clone(), user.clone())
+        .map(|ttl| assert!(ttl.map(|t| t > 0 && t <= 60).unwrap_or(false)))
+        .is_ok());
+
+    assert!(api.put(path.clone(), json!({"token":"abc"}), user.clone()).is_ok());
+    assert_eq!(api.ttl(path.clone(), user.clone()).ok(), Some(None));
+
+    let token = [path.clone(), ["token".to_string()].to_vec()].concat();
+    assert!(api.put_with_ttl(token.clone(), json!("abc"), Some(0), user.clone()).is_ok());
+    assert!(api.get(token.clone(), user.clone()).is_err());
+    assert!(api.put(path.clone(), json!({"token":"def"}), user.clone()).is_ok());
+    assert_eq!(api.get(token.clone(), user.clone()).ok(), Some(json!("def")));
+
+    fs::remove_dir_all(storage_dir);
+}
+
+#[test]
+fn test_expired_documents_are_hidden_and_swept() {
+    let storage_dir = random_string(16) ;
+    let api = RestApp::new(storage_dir.clone());
+    let user = String::from("tilda");
+    let root = ["root".to_string()].to_vec();
+    let path = ["root".to_string(), "session".to_string()].to_vec();
+
+    assert!(api.put(root.clone(), json!({"name":"test_obj"}), user.clone()).is_ok());
+    assert!(api.put_with_ttl(path.clone(), json!({"token":"abc"}), Some(0), user.clone()).is_ok());
+    assert!(api.get(path.clone(), user.clone()).is_err());
+    assert!(api.get([path.clone(), ["token".to_string()].to_vec()].concat(), user.clone()).is_err());
+    assert_eq!(api.get(root.clone(), user.clone()).ok(), Some(json!({"name":"test_obj"})));
+
+    assert_eq!(api.sweep(PathBuf::from(storage_dir.clone())).ok(), Some(1));
+    assert!(!to_path_buf([storage_dir.clone(), "root".to_string(), "session".to_string()].to_vec()).exists());
+
+    fs::remove_dir_all(storage_dir);
+}
+
+
+#[test]
+fn test_put_rejects_documents_over_limits() {
+    let storage_dir = random_string(16) ;
+    let limits = Limits { max_depth: 2, max_members: 2, max_array_length: 2, ..Limits::default() };
+    let api = RestApp::new(storage_dir.clone()).with_limits(limits);
+    let user = String::from("tilda");
+    let path = ["root".to_string()].to_vec();
+
+    match api.put(path.clone(), json!({"a":{"b":{"c":1}}}), user.clone()) {
+        Err(APIErr::TooDeep(2)) => (),
+        other => panic!("expected TooDeep, got {:?}", other)
+    }
+    match api.put(path.clone(), json!({"a":1,"b":2,"c":3}), user.clone()) {
+        Err(APIErr::TooManyMembers(2)) => (),
+        other => panic!("expected TooManyMembers, got {:?}", other)
+    }
+    match api.put(path.clone(), json!({"a":[1,2,3]}), user.clone()) {
+        Err(APIErr::ArrayTooLong(2)) => (),
+        other => panic!("expected ArrayTooLong, got {:?}", other)
+    }
+    assert!(!PathBuf::from(storage_dir.clone()).exists());
+
+    assert!(api.put(path.clone(), json!({"a":[1,2],"b":{"c":3}}), user.clone()).is_ok());
+    assert!(limits.check_body_length(limits.max_body_length + 1).is_err());
+
+    fs::remove_dir_all(storage_dir);
+}
+
+#[test]
+fn test_stream_matches_get() {
+    let storage_dir = random_string(16) ;
+    let api = RestApp::new(storage_dir.clone());
+    let user = String::from("tilda");
+    let path = ["root".to_string(), "domain".to_string()].to_vec();
+    let json = json!({
+        "name":"test \"obj\"",
+        "lat":48.9999,
+        "empty":{},
+        "none":[],
+        "conquests":[
+            "birth",
+            {"event":"learned","activity":1,"children":["frank", {"nodeId":1}]},
+            ["data","is",true,"or",null]
+        ],
+        "padding":(0..500).map(|i| format!("{}-{}", i, random_string(32))).collect::<Vec<String>>()
+    });
+    assert!(api.put(path.clone(), json, user.clone()).is_ok());
+
+    let expected = api.get(path.clone(), user.clone()).map(|v| v.to_string()).ok();
+    let chunks = stream::read_stream(api.clone(), path.clone(), user.clone())
+        .map(|s| s.collect::<Result<Vec<Vec<u8>>, APIErr>>())
+        .ok()
+        .and_then(|res| res.ok());
+    assert!(chunks.as_ref().map(|c| c.len() > 1).unwrap_or(false));
+    let streamed = chunks.map(|c| String::from_utf8(c.concat()).unwrap());
+    assert_eq!(streamed, expected);
+
+    let leaf = [path.clone(), ["lat".to_string()].to_vec()].concat();
+    let streamed_leaf = stream::read_stream(api.clone(), leaf, user.clone())
+        .ok()
+        .and_then(|s| s.collect::<Result<Vec<Vec<u8>>, APIErr>>().ok())
+        .map(|c| String::from_utf8(c.concat()).unwrap());
+    assert_eq!(streamed_leaf, Some(String::from("48.9999")));
+
+    assert!(stream::read_stream(api.clone(), [path.clone(), ["missing".to_string()].to_vec()].concat(), user.clone()).is_err());
+
+    let classless = [path, ["classless".to_string()].to_vec()].concat();
+    fs::create_dir_all(to_path_buf([[storage_dir.clone()].to_vec(), classless.clone()].concat())).unwrap();
+    assert!(stream::read_stream(api.clone(), classless, user).is_err());
+
+    fs::remove_dir_all(storage_dir);
+}
+
+#[test]
+fn test_delete() {
+    let storage_dir = random_string(16) ;
+    let api = RestApp::new(storage_dir.clone());
+    let user = String::from("tilda");
+    let root = ["root".to_string()].to_vec();
+    let path = ["root".to_string(), "session".to_string()].to_vec();
+
+    assert!(api.put(root.clone(), json!({"name":"test_obj"}), user.clone()).is_ok());
+    assert!(api.put_with_ttl(path.clone(), json!({"token":"abc"}), Some(60), user.clone()).is_ok());
+    assert!(api.delete(path.clone(), user.clone()).is_ok());
+    assert!(api.get(path.clone(), user.clone()).is_err());
+    assert!(api.delete(path.clone(), user.clone()).is_err());
+    assert_eq!(api.get(root.clone(), user.clone()).ok(), Some(json!({"name":"test_obj"})));
+    assert_eq!(fs::read_dir(to_path_buf([storage_dir.clone(), "root".to_string()].to_vec())).unwrap().count(), 2);
+
+    fs::remove_dir_all(storage_dir);
+}
+
+#[test]
+fn test_errors_name_the_document() {
+    let storage_dir = random_string(16) ;
+    let api = RestApp::new(storage_dir.clone());
+    let user = String::from("tilda");
+    let root = ["root".to_string()].to_vec();
+
+    assert!(api.put(root.clone(), json!({"name":"test_obj"}), user.clone()).is_ok());
+    let err = api.get([root.clone(), ["missing".to_string()].to_vec()].concat(), user.clone()).err().unwrap();
+    assert_eq!(err.path(), Some("/root/missing"));
+    assert_eq!(err.operation(), Some(Operation::Read));
+    assert!(err.to_string().starts_with("could not read /root/missing: "));
+    assert!(std::error::Error::source(&err)
+        .and_then(|source| source.downcast_ref::<APIErr>())
+        .map(|source| source.kind() == "io")
+        .unwrap_or(false));
+
+    let err = APIErr::Aggregate(vec![
+        APIErr::Conflict(String::from("/root/name")),
+        APIErr::TooDeep(2).at(Operation::Write, String::from("/root/deep"))]);
+    assert_eq!(serde_json::to_value(&err).ok(), Some(json!({
+        "kind": "aggregate",
+        "message": "2 errors: /root/name cannot change between a field and a collection; could not write /root/deep: the document is nested deeper than 2 levels",
+        "errors": [
+            {"kind": "conflict", "message": "/root/name cannot change between a field and a collection", "path": "/root/name"},
+            {"kind": "too_deep", "message": "the document is nested deeper than 2 levels", "path": "/root/deep", "operation": "write"}
+        ]
+    })));
+
+    fs::remove_dir_all(storage_dir);
+}
+
+#[test]
+fn test_absolute_storage_dir() {
+    let storage_dir = std::env::temp_dir().join(random_string(16));
+    let api = RestApp::new(storage_dir.clone());
+    let user = String::from("tilda");
+    let path = ["root".to_string()].to_vec();
+
+    assert!(api.put(path.clone(), json!({"name":"test_obj"}), user.clone()).is_ok());
+    assert!(storage_dir.join("root").is_dir());
+    assert_eq!(api.get(path.clone(), user.clone()).ok(), Some(json!({"name":"test_obj"})));
+    assert!(stream::read_stream(api.clone(), path, user).is_ok());
+
+    fs::remove_dir_all(storage_dir).unwrap();
+}
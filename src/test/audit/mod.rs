@@ -0,0 +1,114 @@
+extern crate rand;
+extern crate serde_json;
+use std::fs;
+use std::path::Path;
+
+use self::rand::Rng;
+use self::serde_json::{json, Value};
+
+use super::super::api::API;
+use super::super::api::audit::{self, AuditLog, Entry, Filter};
+use super::super::server::http::core::{self, Body, Request, Response};
+use super::super::RestApp;
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .gen_ascii_chars()
+        .take(len)
+        .collect::<String>()
+}
+
+fn request(method: &str, path: &str, query: &str, body: &[u8]) -> Request {
+    Request {
+        method: String::from(method),
+        path: core::split_path(path),
+        query: core::parse_query(Some(query)),
+        headers: vec![(String::from(core::REQUEST_ID_HEADER), format!("{} {}", method, path))].into_iter().collect(),
+        body: body.to_vec(),
+        client_subject: None,
+        spooled: None,
+    }
+}
+
+fn entries(response: Response) -> Vec<Entry> {
+    match response.body {
+        Body::Bytes(bytes) => serde_json::from_slice(bytes.as_slice()).unwrap(),
+        _ => Vec::new()
+    }
+}
+
+fn entry(timestamp: u64, path: &str) -> Entry {
+    Entry {
+        timestamp,
+        user: String::from("anon"),
+        method: String::from("PUT"),
+        path: String::from(path),
+        request_id: random_string(8),
+        status: 200,
+        old_hash: None,
+        new_hash: None,
+    }
+}
+
+#[test]
+fn test_log_rotates_and_filters() {
+    let storage_dir = random_string(16);
+    let log = AuditLog::new(storage_dir.as_str(), 250, 2);
+    (0..12u64).for_each(|n| log.append(&entry(1000 + n, if n % 2 == 0 { "/a/doc" } else { "/b" })).unwrap());
+
+    let dir = Path::new(storage_dir.as_str()).join(audit::AUDIT_PATH);
+    assert!(dir.join("audit.log.1").exists() && dir.join("audit.log.2").exists());
+    assert!(!dir.join("audit.log.3").exists());
+    let sizes = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().metadata().unwrap().len());
+    assert!(sizes.into_iter().all(|size| size <= 250));
+
+    let kept = log.query(&Filter::default()).unwrap();
+    assert!(kept.len() < 12);
+    assert_eq!(kept.last().map(|entry| entry.timestamp), Some(1011));
+    assert!(kept.windows(2).all(|pair| pair[0].timestamp < pair[1].timestamp));
+
+    let filter = Filter { since: Some(1006), until: Some(1010), prefix: vec![String::from("a")] };
+    let timestamps = log.query(&filter).unwrap().into_iter().map(|entry| entry.timestamp).collect::<Vec<u64>>();
+    assert_eq!(timestamps, vec![1006, 1008, 1010]);
+    fs::remove_dir_all(storage_dir).unwrap();
+}
+
+#[test]
+fn test_mutating_requests_are_recorded() {
+    let storage_dir = random_string(16);
+    let app = RestApp::new(storage_dir.clone())
+        .with_audit(AuditLog::new(storage_dir.as_str(), 1024 * 1024, 1));
+    assert_eq!(core::handle(&app, request("GET", "/_audit", "", b"")).status, 403);
+    let app = app.with_admins(vec![String::from("anon")]);
+
+    let put = core::handle(&app, request("PUT", "/doc", "", b"{\"a\":1}"));
+    assert_eq!(put.status, 200);
+    assert!(put.headers.contains(&(core::REQUEST_ID_HEADER, String::from("PUT /doc"))));
+    assert_eq!(core::handle(&app, request("PUT", "/doc", "", b"{\"a\":2}")).status, 200);
+    assert_eq!(core::handle(&app, request("GET", "/doc", "", b"")).status, 200);
+    assert_eq!(core::handle(&app, request("PUT", "/doc", "", b"")).status, 422);
+    assert_eq!(core::handle(&app, request("DELETE", "/doc", "", b"")).status, 200);
+    assert_eq!(core::handle(&app, request("PUT", "/other/doc", "", b"{\"b\":true}")).status, 200);
+
+    let all = entries(core::handle(&app, request("GET", "/_audit", "", b"")));
+    let summary = all.iter()
+        .map(|entry| (entry.method.as_str(), entry.path.as_str(), entry.status))
+        .collect::<Vec<_>>();
+    assert_eq!(summary, vec![("PUT", "/doc", 200), ("PUT", "/doc", 200), ("PUT", "/doc", 422), ("DELETE", "/doc", 200),
+        ("PUT", "/other/doc", 200)]);
+    let first = audit::value_hash(&json!({"a": 1}));
+    let second = audit::value_hash(&json!({"a": 2}));
+    assert_eq!((all[0].old_hash.clone(), all[0].new_hash.clone()), (None, Some(first.clone())));
+    assert_eq!((all[1].old_hash.clone(), all[1].new_hash.clone()), (Some(first), Some(second.clone())));
+    assert_eq!((all[2].old_hash.clone(), all[2].new_hash.clone()), (Some(second.clone()), Some(second.clone())));
+    assert_eq!((all[3].old_hash.clone(), all[3].new_hash.clone()), (Some(second), None));
+    assert_eq!(all[3].request_id, "DELETE /doc");
+
+    let other = entries(core::handle(&app, request("GET", "/_audit", "prefix=/other&since=1", b"")));
+    assert_eq!(other.iter().map(|entry| entry.path.as_str()).collect::<Vec<_>>(), vec!["/other/doc"]);
+    assert_eq!(core::handle(&app, request("GET", "/_audit", "until=yesterday", b"")).status, 400);
+    assert!(!app.is_visible(&Path::new(storage_dir.as_str()).join(audit::AUDIT_PATH)));
+    assert!(app.get(vec![String::from(audit::AUDIT_PATH)], String::from("bob")).is_err());
+    assert_eq!(serde_json::to_value(&all[3]).ok().and_then(|v| v.get("new_hash").cloned()), None::<Value>);
+    fs::remove_dir_all(storage_dir).unwrap();
+}
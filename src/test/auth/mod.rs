@@ -0,0 +1,131 @@
+extern crate base64;
+extern crate rand;
+use std::collections::HashMap;
+use std::fs;
+use std::time::Instant;
+
+use self::rand::Rng;
+
+use super::super::auth::{self, hash, AuthErr, Authenticator, PasswordHash};
+use super::super::config::{AuthConfig, JwtConfig};
+use super::super::server::http::core::{self, Request};
+use super::super::RestApp;
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .gen_ascii_chars()
+        .take(len)
+        .collect::<String>()
+}
+
+fn request(method: &str, path: &[&str], authorization: Option<String>, body: &[u8]) -> Request {
+    Request {
+        method: String::from(method),
+        path: path.iter().map(|p| String::from(*p)).collect(),
+        query: HashMap::new(),
+        headers: authorization.into_iter().map(|value| (String::from("authorization"), value)).collect(),
+        body: body.to_vec(),
+        client_subject: None,
+        spooled: None,
+    }
+}
+
+fn basic(user: &str, password: &str) -> Option<String> {
+    Some(format!("Basic {}", base64::encode(format!("{}:{}", user, password).as_bytes())))
+}
+
+#[test]
+fn test_hashes_match_published_vectors() {
+    assert_eq!(hash::to_hex(&hash::sha256(b"abc")),
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    assert_eq!(hash::to_hex(&hash::sha256(&[b'a'; 1000])),
+        "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3");
+    assert_eq!(hash::to_hex(&hash::hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+        "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+    assert_eq!(hash::to_hex(&hash::pbkdf2_sha256(b"password", b"salt", 2, 32)),
+        "ae4d0c95af6b46d32d0adff928f06dd02a303f8ef3c251dfd6e2d85a95474c43");
+    assert_eq!(hash::to_hex(&hash::sha1(b"abc")), "a9993e364706816aba3e25717850c26c9cd0d89d");
+    assert_eq!(hash::to_hex(&hash::sha1(&[b'a'; 1000])), "291e9a6c66994949b57ba5e650361e98fc36b1ba");
+}
+
+#[test]
+fn test_users_and_tokens_files() {
+    let dir = random_string(16);
+    fs::create_dir_all(&dir).unwrap();
+    let users_file = format!("{}/users", dir);
+    let tokens_file = format!("{}/tokens", dir);
+    let token = auth::new_token();
+    let fresh = PasswordHash::new("s3cret:with colon");
+    assert!(fresh.to_string().starts_with("$pbkdf2-sha256$600000$"));
+    assert_eq!(PasswordHash::parse(fresh.to_string().as_str()), Some(fresh));
+    let stored = PasswordHash::with_salt("s3cret:with colon", b"older salt".to_vec(), 10000);
+    fs::write(&users_file, format!("# users\nalice:{}\n", stored)).unwrap();
+    fs::write(&tokens_file, format!("\nci:{}\n", auth::token_hash(token.as_str()))).unwrap();
+
+    let config = AuthConfig { enabled: true, users_file: Some(users_file.clone()), tokens_file: Some(tokens_file), ..AuthConfig::default() };
+    let authenticator = Authenticator::from_config(&config, &JwtConfig::default()).unwrap();
+    assert_eq!(authenticator.authenticate(basic("alice", "s3cret:with colon").as_deref()).map(|p| p.name).ok(), Some(String::from("alice")));
+    assert_eq!(authenticator.authenticate(Some(format!("Bearer {}", token).as_str())).map(|p| p.name).ok(), Some(String::from("ci")));
+    match authenticator.authenticate(basic("alice", "wrong").as_deref()) {
+        Err(AuthErr::InvalidCredentials) => (),
+        other => panic!("expected InvalidCredentials, got {:?}", other)
+    }
+    match authenticator.authenticate(None) {
+        Err(AuthErr::MissingCredentials) => (),
+        other => panic!("expected MissingCredentials, got {:?}", other)
+    }
+    assert_eq!(authenticator.challenges(), "Basic realm=\"rust-monad\", Bearer realm=\"rust-monad\"");
+
+    fs::write(&users_file, "alice:plaintext\n").unwrap();
+    match Authenticator::from_config(&config, &JwtConfig::default()) {
+        Err(AuthErr::Parse { line: 1, .. }) => (),
+        other => panic!("expected a parse error, got {:?}", other)
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_passwords_are_hashed_once_and_guessed_slowly() {
+    let users = vec![
+        (String::from("alice"), PasswordHash::with_salt("pw", b"salt".to_vec(), 200_000)),
+        (String::from("bob"), PasswordHash::with_salt("pw", b"salt".to_vec(), 1)),
+    ].into_iter().collect::<HashMap<String, PasswordHash>>();
+    let authenticator = Authenticator::new(users, HashMap::new());
+    let started = Instant::now();
+    assert!(authenticator.authenticate(basic("alice", "pw").as_deref()).is_ok());
+    let hashed = started.elapsed();
+    let started = Instant::now();
+    assert!(authenticator.clone().authenticate(basic("alice", "pw").as_deref()).is_ok());
+    assert!(started.elapsed() * 10 < hashed, "the password was hashed again");
+    assert!(authenticator.authenticate(basic("alice", "pw2").as_deref()).is_err());
+
+    (0..10).for_each(|_| match authenticator.authenticate(basic("bob", "wrong").as_deref()) {
+        Err(AuthErr::InvalidCredentials) => (),
+        other => panic!("expected InvalidCredentials, got {:?}", other)
+    });
+    match authenticator.authenticate(basic("bob", "pw").as_deref()) {
+        Err(AuthErr::TooManyAttempts(ref user)) if user == "bob" => (),
+        other => panic!("expected TooManyAttempts, got {:?}", other)
+    }
+    assert!(authenticator.authenticate(basic("alice", "pw").as_deref()).is_ok());
+}
+
+#[test]
+fn test_requests_need_credentials_when_enabled() {
+    let storage_dir = random_string(16);
+    let users = vec![(String::from("alice"), PasswordHash::with_salt("pw", b"salt".to_vec(), 1))]
+        .into_iter()
+        .collect();
+    let app = RestApp::new(storage_dir.clone()).with_authenticator(Authenticator::new(users, HashMap::new()));
+
+    let response = core::handle(&app, request("PUT", &["doc"], None, b"{\"a\":1}"));
+    assert_eq!(response.status, 401);
+    assert!(response.headers.contains(&("www-authenticate", String::from("Basic realm=\"rust-monad\""))));
+    assert_eq!(core::handle(&app, request("GET", &["doc"], basic("alice", "nope"), b"")).status, 401);
+    assert_eq!(core::handle(&app, request("PUT", &["doc"], basic("alice", "pw"), b"{\"a\":1}")).status, 200);
+    assert_eq!(core::handle(&app, request("GET", &["doc"], basic("alice", "pw"), b"")).status, 200);
+
+    let open = RestApp::new(storage_dir.clone());
+    assert_eq!(core::handle(&open, request("GET", &["doc"], None, b"")).status, 200);
+    fs::remove_dir_all(storage_dir).unwrap();
+}
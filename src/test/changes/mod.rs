@@ -0,0 +1,195 @@
+extern crate rand;
+extern crate serde_json;
+use std::fs;
+use std::path::Path;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use self::rand::Rng;
+use self::serde_json::{json, Value};
+
+use super::super::api::{API, APIErr, Operation};
+use super::super::api::changes::{self, Batch, ChangeFeed};
+use super::super::server::http::core::{self, Body, Next, Request, Response};
+use super::super::RestApp;
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .gen_ascii_chars()
+        .take(len)
+        .collect::<String>()
+}
+
+fn request(path: &str, query: &str, headers: &[(&str, &str)]) -> Request {
+    Request {
+        method: String::from("GET"),
+        path: core::split_path(path),
+        query: core::parse_query(Some(query)),
+        headers: headers.iter().map(|(name, value)| (String::from(*name), String::from(*value))).collect(),
+        body: Vec::new(),
+        client_subject: None,
+        spooled: None,
+    }
+}
+
+fn batch(response: Response) -> Batch {
+    match response.body {
+        Body::Bytes(bytes) => serde_json::from_slice(bytes.as_slice()).unwrap(),
+        _ => Batch::default()
+    }
+}
+
+fn path(p: &str) -> Vec<String> {
+    core::split_path(p)
+}
+
+#[test]
+fn test_feed_keeps_the_latest_changes() {
+    let feed = ChangeFeed::new(2);
+    (0..3).for_each(|n| { feed.publish("root", Operation::Write, &path("/a"), json!(n), None); });
+    assert_eq!(feed.last_seq(), 3);
+
+    let batch = feed.wait(1, Duration::from_secs(0), |_| true).unwrap();
+    assert_eq!(batch.changes.iter().map(|change| change.seq).collect::<Vec<u64>>(), vec![2, 3]);
+    assert_eq!(batch.changes[1].value, json!(2));
+    assert_eq!(batch.last_seq, 3);
+    match feed.wait(0, Duration::from_secs(0), |_| true) {
+        Err(APIErr::Gone(0)) => (),
+        other => panic!("expected Gone, got {:?}", other.map(|batch| batch.last_seq))
+    }
+    assert!(feed.wait(4, Duration::from_secs(0), |_| true).is_err());
+    assert_eq!(feed.wait(3, Duration::from_secs(0), |_| true).map(|batch| batch.changes.len()).ok(), Some(0));
+}
+
+#[test]
+fn test_feed_wakes_waiting_readers() {
+    let feed = Arc::new(ChangeFeed::new(10));
+    let publisher = feed.clone();
+    let started = Instant::now();
+    let handle = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        publisher.publish("root", Operation::Write, &path("/skipped"), json!(1), None);
+        publisher.publish("root", Operation::Delete, &path("/kept"), Value::Null, None);
+    });
+    let batch = feed.wait(0, Duration::from_secs(10), |change| change.path == "/kept").unwrap();
+    handle.join().unwrap();
+    assert!(started.elapsed() < Duration::from_secs(10));
+    assert_eq!(batch.changes.len(), 1);
+    assert_eq!((batch.changes[0].seq, batch.changes[0].operation), (2, Operation::Delete));
+
+    let started = Instant::now();
+    assert_eq!(feed.wait(2, Duration::from_millis(100), |_| true).map(|batch| batch.changes.len()).ok(), Some(0));
+    assert!(started.elapsed() >= Duration::from_millis(100));
+}
+
+#[test]
+fn test_feed_wakes_watchers_without_a_thread_each() {
+    let feed = ChangeFeed::new(10);
+    let (woken, wakes) = mpsc::channel();
+    let waker = |name: &'static str| -> changes::Waker {
+        let woken = woken.clone();
+        Box::new(move || woken.send(name).unwrap())
+    };
+    feed.watch(0, None, waker("change"));
+    feed.watch(0, Some(Instant::now() + Duration::from_millis(100)), waker("deadline"));
+    feed.watch(0, Some(Instant::now() + Duration::from_secs(60)), waker("later"));
+    assert_eq!(wakes.recv_timeout(Duration::from_secs(10)), Ok("deadline"));
+    assert!(wakes.try_recv().is_err());
+
+    feed.publish("root", Operation::Write, &path("/a"), json!(1), None);
+    let mut woken = wakes.iter().take(2).collect::<Vec<&str>>();
+    woken.sort();
+    assert_eq!(woken, vec!["change", "later"]);
+
+    // Readers already behind are woken at once.
+    feed.watch(0, None, Box::new(|| ()));
+    let deadline = Instant::now() + Duration::from_secs(60);
+    let batch = feed.poll(0, deadline, &|_: &changes::Change| true, Box::new(|| panic!("nothing to wait for"))).unwrap();
+    assert_eq!(batch.map(|batch| batch.last_seq), Some(1));
+    assert!(feed.poll(1, deadline, &|_: &changes::Change| true, Box::new(|| ())).unwrap().is_none());
+}
+
+#[test]
+fn test_long_polls_wait_for_a_change() {
+    let storage_dir = random_string(16);
+    let app = RestApp::new(storage_dir.clone()).with_changes(ChangeFeed::open(storage_dir.as_str(), 100).unwrap());
+    let response = core::handle(&app, request("/_changes", "since=0&timeout=10", &[]));
+    assert_eq!(response.status, 200);
+    let mut pending = match response.body {
+        Body::Pending(pending) => pending,
+        _ => panic!("expected a pending long poll")
+    };
+    let (woken, wakes) = mpsc::channel();
+    let waker = || -> changes::Waker {
+        let woken = woken.clone();
+        Box::new(move || woken.send(()).unwrap())
+    };
+    assert!(matches!(pending.poll_next(waker()), Next::Wait));
+
+    app.put(path("/doc"), json!(1), String::from("anon")).unwrap();
+    wakes.recv_timeout(Duration::from_secs(10)).unwrap();
+    let batch = match pending.poll_next(waker()) {
+        Next::Chunk(chunk) => serde_json::from_slice::<Batch>(chunk.unwrap().as_slice()).unwrap(),
+        _ => panic!("expected the change")
+    };
+    assert_eq!(batch.changes.len(), 1);
+    assert!(matches!(pending.poll_next(waker()), Next::End));
+    fs::remove_dir_all(storage_dir).unwrap();
+}
+
+#[test]
+fn test_changes_are_served() {
+    let storage_dir = random_string(16);
+    assert_eq!(core::handle(&RestApp::new(storage_dir.clone()), request("/_changes", "", &[])).status, 404);
+    let feed = ChangeFeed::open(storage_dir.as_str(), 100).unwrap();
+    let app = RestApp::new(storage_dir.clone()).with_changes(feed);
+
+    app.put(path("/a/doc"), json!({"n": 1}), String::from("anon")).unwrap();
+    app.put(path("/b"), json!(true), String::from("anon")).unwrap();
+    app.delete(path("/a/doc"), String::from("anon")).unwrap();
+
+    let all = batch(core::handle(&app, request("/_changes", "since=0&timeout=0", &[])));
+    let summary = all.changes.iter()
+        .map(|change| (change.seq, change.operation, change.path.as_str()))
+        .collect::<Vec<_>>();
+    assert_eq!(summary, vec![(1, Operation::Write, "/a/doc"), (2, Operation::Write, "/b"), (3, Operation::Delete, "/a/doc")]);
+    assert_eq!(all.changes[0].value, json!({"n": 1}));
+    assert_eq!(all.last_seq, 3);
+
+    let below = batch(core::handle(&app, request("/_changes", "since=1&prefix=/a&timeout=0", &[])));
+    assert_eq!(below.changes.iter().map(|change| change.seq).collect::<Vec<u64>>(), vec![3]);
+    let resumed = batch(core::handle(&app, request("/_changes", "timeout=0", &[("last-event-id", "2")])));
+    assert_eq!(resumed.changes.len(), 1);
+    assert_eq!(core::handle(&app, request("/_changes", "since=9", &[])).status, 410);
+    assert_eq!(core::handle(&app, request("/_changes", "since=soon", &[])).status, 400);
+    assert!(!app.is_visible(&Path::new(storage_dir.as_str()).join(changes::CHANGES_PATH)));
+
+    let reopened = ChangeFeed::open(storage_dir.as_str(), 100).unwrap();
+    assert_eq!(reopened.last_seq(), 3);
+    fs::remove_dir_all(storage_dir).unwrap();
+}
+
+#[test]
+fn test_changes_are_streamed_as_events() {
+    let storage_dir = random_string(16);
+    let app = RestApp::new(storage_dir.clone()).with_changes(ChangeFeed::open(storage_dir.as_str(), 100).unwrap());
+    app.put(path("/doc"), json!(1), String::from("anon")).unwrap();
+
+    let response = core::handle(&app, request("/_changes", "since=0", &[("accept", "text/event-stream")]));
+    assert_eq!(response.status, 200);
+    assert!(response.headers.contains(&("content-type", String::from("text/event-stream"))));
+    let mut events = match response.body {
+        Body::Pending(pending) => core::wait_for(pending),
+        _ => panic!("expected an event stream")
+    };
+    let first = String::from_utf8(events.next().unwrap().unwrap()).unwrap();
+    assert!(first.starts_with("id: 1\nevent: change\ndata: {"));
+    assert!(first.ends_with("\n\n"));
+
+    app.put(path("/doc"), json!(2), String::from("anon")).unwrap();
+    let second = String::from_utf8(events.next().unwrap().unwrap()).unwrap();
+    assert!(second.starts_with("id: 2\n"));
+    assert!(second.contains("\"value\":2"));
+    fs::remove_dir_all(storage_dir).unwrap();
+}
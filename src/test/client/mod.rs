@@ -0,0 +1,64 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::thread;
+use std::time::Duration;
+
+use super::super::server::http::client::{self, Url};
+
+/// Listens on a free local port, answering every request with `head`
+/// followed by `body`, returning the URL to ask.
+fn server(head: &'static str, body: Vec<u8>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    thread::spawn(move || for stream in listener.incoming() {
+        let mut stream = stream.unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut line = String::new();
+        while reader.read_line(&mut line).map(|read| read > 2).unwrap_or(false) {
+            line.clear();
+        }
+        let _ = stream.write_all(head.as_bytes()).and_then(|()| stream.write_all(body.as_slice()));
+    });
+    url
+}
+
+fn get(url: &str, limit: u64) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    client::exchange("GET", url, &[], &[], Duration::from_secs(5), limit, &mut body).map(|_| body)
+}
+
+#[test]
+fn test_urls() {
+    let url = Url::parse("https://primary.example.com/base").unwrap();
+    assert_eq!((url.tls, url.host.as_str(), url.port, url.target.as_str()), (true, "primary.example.com", 443, "/base"));
+    assert_eq!(Url::parse("http://primary").map(|url| (url.tls, url.port)), Ok((false, 80)));
+    assert!(Url::parse("ftp://primary").is_err());
+    assert!(!Url::parse("http://primary:3000").unwrap().may_carry_credentials());
+    for url in ["https://primary", "http://localhost:3000", "http://127.0.0.1", "http://[::1]:3000"].iter() {
+        assert!(Url::parse(url).unwrap().may_carry_credentials(), "{}", url);
+    }
+}
+
+#[test]
+fn test_credentials_are_not_sent_in_the_clear() {
+    let headers = [("Authorization", String::from("Bearer mk_secret"))];
+    let refused = client::request("GET", "http://primary.invalid/", &headers, &[], Duration::from_secs(5));
+    assert_eq!(refused.map(|_| ()).map_err(|err| err.kind()), Err(io::ErrorKind::PermissionDenied));
+
+    let url = server("HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\n", b"ok".to_vec());
+    assert_eq!(client::get(url.as_str(), &headers, Duration::from_secs(5)).map(|response| response.body).ok(), Some(b"ok".to_vec()));
+}
+
+#[test]
+fn test_response_bodies_are_limited() {
+    let declared = server("HTTP/1.1 200 OK\r\ncontent-length: 1000000000000\r\nconnection: close\r\n\r\n", Vec::new());
+    assert_eq!(get(declared.as_str(), 1024).map_err(|err| err.kind()), Err(io::ErrorKind::InvalidData));
+
+    let chunked = server("HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\nconnection: close\r\n\r\n",
+                         b"400\r\n".iter().cloned().chain(vec![b'a'; 1024]).chain(b"\r\nffffffffff\r\n".iter().cloned()).collect());
+    assert_eq!(get(chunked.as_str(), 4096).map_err(|err| err.kind()), Err(io::ErrorKind::InvalidData));
+
+    let unsized_body = server("HTTP/1.1 200 OK\r\nconnection: close\r\n\r\n", vec![b'a'; 10000]);
+    assert_eq!(get(unsized_body.as_str(), 1024).map_err(|err| err.kind()), Err(io::ErrorKind::InvalidData));
+    assert_eq!(get(unsized_body.as_str(), 10000).map(|body| body.len()).ok(), Some(10000));
+}
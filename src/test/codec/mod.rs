@@ -0,0 +1,50 @@
+extern crate serde_json;
+
+use self::serde_json::json;
+use super::super::api::APIErr;
+use super::super::server::http::codec::Format;
+
+#[test]
+fn test_round_trip_every_format() {
+    let json = json!({
+        "name":"test_obj",
+        "age": 43,
+        "lat":48.9999,
+        "cant_even":true,
+        "nothing":null,
+        "conquests":["birth", {"event":"learned"}, [1, 2]]
+    });
+    for format in [Format::Json, Format::Cbor, Format::MsgPack, Format::Yaml].iter() {
+        let decoded = format.encode(&json).and_then(|bytes| format.decode(bytes.as_slice()));
+        assert_eq!(decoded.ok(), Some(json.clone()), "round trip through {:?}", format);
+    }
+}
+
+#[test]
+fn test_content_type() {
+    assert_eq!(Format::from_content_type(None).ok(), Some(Format::Json));
+    assert_eq!(Format::from_content_type(Some("application/json; charset=utf-8")).ok(), Some(Format::Json));
+    assert_eq!(Format::from_content_type(Some("Application/CBOR")).ok(), Some(Format::Cbor));
+    assert_eq!(Format::from_content_type(Some("application/x-msgpack")).ok(), Some(Format::MsgPack));
+    match Format::from_content_type(Some("application/x-www-form-urlencoded")) {
+        Err(APIErr::UnsupportedMediaType(_)) => (),
+        other => panic!("expected UnsupportedMediaType, got {:?}", other)
+    }
+    match Format::from_content_type(Some("text/xml")) {
+        Err(APIErr::UnsupportedMediaType(_)) => (),
+        other => panic!("expected UnsupportedMediaType, got {:?}", other)
+    }
+}
+
+#[test]
+fn test_accept() {
+    assert_eq!(Format::from_accept(None).ok(), Some(Format::Json));
+    assert_eq!(Format::from_accept(Some("*/*")).ok(), Some(Format::Json));
+    assert_eq!(Format::from_accept(Some("text/html, application/yaml")).ok(), Some(Format::Yaml));
+    assert_eq!(Format::from_accept(Some("application/json;q=0.5, application/msgpack")).ok(), Some(Format::MsgPack));
+    assert_eq!(Format::from_accept(Some("application/cbor;q=0, */*;q=0.1")).ok(), Some(Format::Json));
+    match Format::from_accept(Some("text/html")) {
+        Err(APIErr::NotAcceptable(_)) => (),
+        other => panic!("expected NotAcceptable, got {:?}", other)
+    }
+}
@@ -0,0 +1,116 @@
+extern crate rand;
+extern crate serde_json;
+use std::fs;
+use std::path::Path;
+
+use self::rand::Rng;
+use self::rand::distributions::Alphanumeric;
+use self::serde_json::json;
+use super::super::api::API;
+use super::super::config::{Config, ConfigErr, DEFAULT_STORAGE_DIR};
+use super::super::RestApp;
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .collect::<String>()
+}
+
+fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+    vars.iter()
+        .map(|(name, value)| (String::from(*name), String::from(*value)))
+        .collect()
+}
+
+#[test]
+fn test_file_then_environment() {
+    let file = "[listener]\naddress = \"127.0.0.1:8080\"\n\n[limits]\nmax_depth = 8\n\n[logging]\nlevel = \"debug\"\n";
+    let mut config = Config::parse_toml(file, Path::new("rest.toml")).unwrap();
+    assert_eq!(config.listener.address, "127.0.0.1:8080");
+    assert_eq!(config.listener.engine, "hyper");
+    assert_eq!(config.storage.root, Path::new(DEFAULT_STORAGE_DIR));
+    assert_eq!(config.limits.max_depth, 8);
+    assert_eq!(config.limits.max_members, Config::default().limits.max_members);
+
+    assert!(config.apply_env(env(&[
+        ("RUST_MONAD_LISTENER_ENGINE", "iron"),
+        ("RUST_MONAD_LIMITS_MAX_DEPTH", "4"),
+        ("RUST_MONAD_CONFIG", "ignored.toml"),
+        ("PATH", "/bin")])).is_ok());
+    assert_eq!(config.listener.engine, "iron");
+    assert_eq!(config.limits.max_depth, 4);
+
+    assert!(config.set("storage.root", "elsewhere").is_ok());
+    assert_eq!(config.storage.root, Path::new("elsewhere"));
+    assert!(config.validate().is_ok());
+    assert_eq!(config.listen_addr().map(|addr| addr.port()).ok(), Some(8080));
+}
+
+#[test]
+fn test_invalid_settings_are_reported() {
+    match Config::parse_toml("[listener]\nport = 3000\n", Path::new("rest.toml")) {
+        Err(ConfigErr::Parse(_, message)) => assert!(message.contains("port")),
+        other => panic!("expected a parse error, got {:?}", other)
+    }
+
+    let mut config = Config::default();
+    match config.apply_env(env(&[("RUST_MONAD_LIMITS_MAX_DEPTH", "deep")])) {
+        Err(ConfigErr::Invalid { key, .. }) => assert_eq!(key, "limits.max_depth"),
+        other => panic!("expected an invalid setting, got {:?}", other)
+    }
+    match config.apply_env(env(&[("RUST_MONAD_LISTENER_PORT", "3000")])) {
+        Err(ConfigErr::UnknownKey(name)) => assert_eq!(name, "RUST_MONAD_LISTENER_PORT"),
+        other => panic!("expected an unknown setting, got {:?}", other)
+    }
+
+    for (key, value) in [("listener.address", "localhost"), ("listener.engine", "nginx"),
+        ("storage.backend", "s3"), ("limits.max_members", "0"), ("logging.level", "loud"),
+        ("auth.enabled", "true"), ("tenancy.mode", "team"), ("replication.primary", "ftp://primary"),
+        ("replication.poll_secs", "301"), ("cors.allowed_origins", "app.example.com"), ("storage.root", "/"),
+        ("tls.cert_file", "server.crt"), ("tls.client_ca_file", "ca.crt"), ("tls.require_client_cert", "true")].iter() {
+        let mut config = Config::default();
+        assert!(config.set(key, value).is_ok());
+        match config.validate() {
+            Err(ConfigErr::Invalid { key: found, .. }) => assert_eq!(found.as_str(), *key),
+            other => panic!("expected {} to be rejected, got {:?}", key, other)
+        }
+    }
+
+    let mut config = Config::default();
+    config.apply_env(env(&[("RUST_MONAD_TLS_CERT_FILE", "server.crt"), ("RUST_MONAD_TLS_KEY_FILE", "server.key"),
+        ("RUST_MONAD_LISTENER_ENGINE", "iron")])).unwrap();
+    match config.validate() {
+        Err(ConfigErr::Invalid { key, .. }) => assert_eq!(key, "listener.engine"),
+        other => panic!("expected TLS on iron to be rejected, got {:?}", other)
+    }
+    config.listener.engine = String::from("hyper");
+    assert!(config.validate().is_ok());
+
+    let mut config = Config::default();
+    config.set("replication.primary", "http://primary:3000").unwrap();
+    config.set("replication.authorization", "Bearer mk_secret").unwrap();
+    match config.validate() {
+        Err(ConfigErr::Invalid { key, .. }) => assert_eq!(key, "replication.authorization"),
+        other => panic!("expected credentials over http:// to be rejected, got {:?}", other)
+    }
+    for primary in ["https://primary", "http://127.0.0.1:3000", "http://localhost:3000"].iter() {
+        config.set("replication.primary", primary).unwrap();
+        assert!(config.validate().is_ok(), "{} should be accepted", primary);
+    }
+}
+
+#[test]
+fn test_absolute_storage_root() {
+    let root = ::std::env::temp_dir().join(random_string(16));
+    let file = format!("[storage]\nroot = {}\n", json!(root.to_str().unwrap()));
+    let config = Config::parse_toml(file.as_str(), Path::new("rest.toml")).unwrap();
+    assert!(config.validate().is_ok());
+    assert_eq!(config.storage.root, root);
+
+    let app = RestApp::new(config.storage.root.clone()).with_limits(config.limits);
+    assert!(app.put(vec![String::from("doc")], json!({"a": 1}), String::from("anon")).is_ok());
+    assert!(root.join("doc").is_dir());
+    assert!(!Path::new(root.strip_prefix("/").unwrap()).exists());
+    fs::remove_dir_all(root).unwrap();
+}
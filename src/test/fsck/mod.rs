@@ -0,0 +1,107 @@
+extern crate serde_json;
+extern crate rand;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process;
+
+use self::serde_json::json;
+use self::rand::Rng;
+use super::super::api::API;
+use super::super::api::fsck::{self, IssueKind};
+use super::super::api::snapshot::Spool;
+use super::super::RestApp;
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .gen_ascii_chars()
+        .take(len)
+        .collect::<String>()
+}
+
+#[test]
+fn test_fsck_clean_tree() {
+    let storage_dir = random_string(16);
+    let api = RestApp::new(storage_dir.clone());
+    let user = String::from("tilda");
+    let path = ["root".to_string()].to_vec();
+    assert!(api.put(path.clone(), json!({"name":"test_obj","list":[1, 2, {"x":null}]}), user.clone()).is_ok());
+
+    let issues = fsck::fsck(&api, false);
+    assert_eq!(issues.map(|issues| issues.len()).ok(), Some(0));
+    fs::remove_dir_all(storage_dir).unwrap();
+}
+
+#[test]
+fn test_fsck_reports_and_repairs() {
+    let storage_dir = random_string(16);
+    let api = RestApp::new(storage_dir.clone());
+    let user = String::from("tilda");
+    let path = ["root".to_string(), "doc".to_string()].to_vec();
+    let json = json!({"list":["a","b","c"],"sub":{"k":"v"},"gone":"x","empty":null});
+    assert!(api.put(path.clone(), json, user.clone()).is_ok());
+
+    let doc = PathBuf::from(storage_dir.clone()).join("root").join("doc");
+    fs::remove_dir_all(doc.join("list").join("1")).ok();
+    fs::remove_file(doc.join("list").join("1")).ok();
+    fs::remove_file(doc.join("sub").join("__class_declaration__")).unwrap();
+    fs::write(doc.join("gone"), "STRING\n").unwrap();
+    fs::write(doc.join("empty"), "NULL\n").unwrap();
+    fs::write(doc.join("__expires__.missing"), "EXPIRY\n1\n").unwrap();
+
+    let kinds = |issues: Vec<fsck::Issue>| {
+        let mut kinds = issues.into_iter()
+            .map(|issue| format!("{:?}", issue.kind))
+            .collect::<Vec<String>>();
+        kinds.sort();
+        kinds
+    };
+    let paths = |issues: Vec<fsck::Issue>| {
+        let mut paths = issues.into_iter().map(|issue| issue.path).collect::<Vec<PathBuf>>();
+        paths.sort();
+        paths
+    };
+    let expected = [IssueKind::ArrayGaps, IssueKind::MissingClass, IssueKind::MissingClass,
+        IssueKind::OrphanedExpiry, IssueKind::TruncatedField, IssueKind::TruncatedField]
+        .iter()
+        .map(|kind| format!("{:?}", kind))
+        .collect::<Vec<String>>();
+
+    // root was created implicitly by the put, so it has no class declaration either
+    assert_eq!(fsck::fsck(&api, false).map(kinds).ok(), Some(expected));
+    assert_eq!(fsck::fsck(&api, false).map(paths).ok(), Some(vec![
+        PathBuf::from("root"), PathBuf::from("root/doc/__expires__.missing"), PathBuf::from("root/doc/empty"),
+        PathBuf::from("root/doc/gone"), PathBuf::from("root/doc/list"), PathBuf::from("root/doc/sub")]));
+    let repaired = fsck::fsck(&api, true).unwrap();
+    assert!(repaired.iter().all(|issue| issue.repaired));
+    assert_eq!(fsck::fsck(&api, false).map(|issues| issues.len()).ok(), Some(0));
+
+    assert_eq!(api.get(path.clone(), user.clone()).ok(),
+        Some(json!({"list":["a","c"],"sub":{"k":"v"},"empty":null})));
+    assert_eq!(api.get(["root".to_string()].to_vec(), user.clone()).ok(),
+        Some(json!({"doc":{"list":["a","c"],"sub":{"k":"v"},"empty":null}})));
+    fs::remove_dir_all(storage_dir).unwrap();
+}
+
+
+#[test]
+fn test_fsck_leaves_staging_in_use_alone() {
+    let storage_dir = random_string(16);
+    let api = RestApp::new(storage_dir.clone());
+    assert!(api.put(["root".to_string()].to_vec(), json!({"k":"v"}), String::from("tilda")).is_ok());
+
+    let mut spool = Spool::create(&api).unwrap();
+    spool.write_all(b"still being written").unwrap();
+    let orphan = format!("{}.staging-{}-0-0-0", storage_dir, process::id());
+    fs::create_dir_all(&orphan).unwrap();
+
+    let issues = fsck::fsck(&api, true).unwrap();
+    assert_eq!(issues.iter().map(|issue| (issue.path.clone(), &issue.kind, issue.repaired)).collect::<Vec<_>>(),
+        vec![(PathBuf::from("..").join(&orphan), &IssueKind::OrphanedStaging, true)]);
+    assert!(!PathBuf::from(&orphan).exists());
+    spool.write_all(b" after the check").unwrap();
+
+    drop(spool);
+    assert_eq!(fsck::fsck(&api, false).map(|issues| issues.len()).ok(), Some(0));
+    fs::remove_dir_all(storage_dir).unwrap();
+}
\ No newline at end of file
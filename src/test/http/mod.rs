@@ -0,0 +1,278 @@
+extern crate serde_json;
+extern crate rand;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use self::serde_json::{json, Value};
+use self::rand::Rng;
+use std::io;
+
+use super::super::api::APIErr;
+use super::super::api::changes::ChangeFeed;
+use super::super::api::limits::Limits;
+use super::super::auth::{self, Authenticator};
+use super::super::server::http::{self, Engine};
+use super::super::server::http::cors::Cors;
+use super::super::server::http::problem::Problem;
+use super::super::RestApp;
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .gen_ascii_chars()
+        .take(len)
+        .collect::<String>()
+}
+
+struct Reply {
+    status: u16,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+impl Reply {
+    fn json(&self) -> Option<Value> {
+        serde_json::from_slice(self.body.as_slice()).ok()
+    }
+}
+
+fn dechunk(mut body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    loop {
+        let line_end = body.windows(2).position(|w| w == b"\r\n").unwrap();
+        let size = usize::from_str_radix(String::from_utf8_lossy(&body[..line_end]).trim(), 16).unwrap();
+        if size == 0 {
+            return out;
+        }
+        out.extend_from_slice(&body[line_end + 2..line_end + 2 + size]);
+        body = &body[line_end + 4 + size..];
+    }
+}
+
+/// Just enough HTTP/1.1 to talk to either engine: one request per
+/// connection, with chunked responses reassembled.
+fn request(addr: SocketAddr, method: &str, path: &str, headers: &[(&str, &str)], body: &[u8]) -> Reply {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut head = format!("{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n", method, path, addr, body.len());
+    headers.iter().for_each(|(name, value)| head.push_str(format!("{}: {}\r\n", name, value).as_str()));
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).unwrap();
+    stream.write_all(body).unwrap();
+
+    let mut raw = Vec::new();
+    stream.read_to_end(&mut raw).unwrap();
+    let split = raw.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    let head = String::from_utf8_lossy(&raw[..split]).into_owned();
+    let mut lines = head.split("\r\n");
+    let status = lines.next().unwrap().split(' ').nth(1).unwrap().parse::<u16>().unwrap();
+    let headers = lines
+        .flat_map(|line| {
+            let mut kv = line.splitn(2, ':');
+            match (kv.next(), kv.next()) {
+                (Some(name), Some(value)) => Some((name.trim().to_lowercase(), String::from(value.trim()))),
+                _ => None
+            }
+        })
+        .collect::<HashMap<String, String>>();
+    let body = &raw[split + 4..];
+    let body = if headers.get("transfer-encoding").map(|t| t.contains("chunked")).unwrap_or(false) {
+        dechunk(body)
+    } else {
+        body.to_vec()
+    };
+    Reply { status, headers, body }
+}
+
+fn start(engine: Engine, app: RestApp) -> SocketAddr {
+    let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    thread::spawn(move || http::serve(engine, app, addr, None));
+    for _ in 0..100 {
+        if TcpStream::connect(addr).is_ok() {
+            return addr;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("{:?} did not start on {}", engine, addr);
+}
+
+/// Every engine must answer these the same way.
+fn conformance(engine: Engine) {
+    let storage_dir = random_string(16);
+    let limits = Limits { max_body_length: 1024, ..Limits::default() };
+    let app = RestApp::new(storage_dir.clone()).with_limits(limits).with_admins(vec![String::from("anon")]);
+    let addr = start(engine, app);
+    let json = json!({"name":"test_obj","list":[1, 2, {"x":null}]});
+    let body = json.to_string().into_bytes();
+
+    assert_eq!(request(addr, "PUT", "/root/doc", &[], body.as_slice()).status, 200);
+    let reply = request(addr, "GET", "/root/doc", &[], b"");
+    assert_eq!(reply.status, 200);
+    assert_eq!(reply.headers.get("content-type").map(String::as_str), Some("application/json"));
+    assert_eq!(reply.json(), Some(json.clone()));
+
+    let reply = request(addr, "GET", "/root/doc/list", &[("Accept", "application/yaml")], b"");
+    assert_eq!(reply.status, 200);
+    assert_eq!(reply.headers.get("content-type").map(String::as_str), Some("application/yaml"));
+
+    assert_eq!(request(addr, "PUT", "/root/ttl", &[("X-TTL", "60")], b"1").status, 200);
+    let reply = request(addr, "GET", "/root/ttl", &[], b"");
+    assert!(reply.headers.get("x-ttl").and_then(|ttl| ttl.parse::<u64>().ok()).filter(|ttl| *ttl <= 60).is_some());
+    assert_eq!(request(addr, "PUT", "/root/ttl", &[("X-TTL", "abc")], b"1").status, 400);
+    assert_eq!(request(addr, "PUT", "/root/ttl?ttl=-5", &[], b"1").status, 400);
+
+    let reply = request(addr, "PUT", "/root/bad", &[], b"{not json");
+    assert_eq!(reply.status, 422);
+    assert_eq!(reply.headers.get("content-type").map(String::as_str), Some("application/problem+json"));
+    assert_eq!(reply.json().map(|problem| (problem["status"].clone(), problem["instance"].clone())),
+        Some((json!(422), json!("/root/bad"))));
+    assert_eq!(request(addr, "PUT", "/root/bad", &[("Content-Type", "text/html")], b"1").status, 415);
+    assert_eq!(request(addr, "GET", "/root/doc", &[("Accept", "text/html")], b"").status, 406);
+    assert_eq!(request(addr, "PUT", "/root/big", &[], vec![b'1'; 2048].as_slice()).status, 413);
+    assert_eq!(request(addr, "PATCH", "/root/doc", &[], b"{}").status, 405);
+    for &(method, path, allow) in [("PUT", "/_changes", "GET"), ("DELETE", "/_export/root", "GET"),
+        ("PUT", "/_bulk", "POST"), ("DELETE", "/_snapshot", "GET, PUT"), ("PUT", "/_subscribe/root", "GET"),
+        ("PUT", "/_fsck", "GET, POST")].iter() {
+        let reply = request(addr, method, path, &[], b"{}");
+        assert_eq!((reply.status, reply.headers.get("allow").map(String::as_str)), (405, Some(allow)));
+    }
+    for reserved in ["_changes", "_export", "_bulk", "_snapshot", "_subscribe", "_fsck"].iter() {
+        assert!(!Path::new(&storage_dir).join(reserved).exists());
+    }
+
+    let reply = request(addr, "PUT", "/root/doc/name/first", &[], b"\"a\"");
+    assert_eq!(reply.status, 409);
+    assert_eq!(reply.json().map(|problem| problem["path"].clone()), Some(json!("/root/doc/name")));
+    assert_eq!(request(addr, "PUT", "/root/doc/list", &[], b"1").status, 409);
+    assert_eq!(request(addr, "PUT", "/root/doc/name", &[], b"{}").status, 409);
+    assert_eq!(request(addr, "GET", "/root/doc", &[], b"").json(), Some(json.clone()));
+
+    let export = request(addr, "GET", "/_export/root/doc", &[], b"");
+    assert_eq!(export.status, 200);
+    assert_eq!(export.headers.get("content-type").map(String::as_str), Some("application/x-ndjson"));
+    let reply = request(addr, "POST", "/_bulk/root/copy", &[], export.body.as_slice());
+    assert_eq!(reply.status, 200);
+    assert!(String::from_utf8_lossy(reply.body.as_slice()).lines().all(|line| line.contains("\"ok\":true")));
+    assert_eq!(request(addr, "GET", "/root/copy", &[], b"").json(), Some(json.clone()));
+
+    let archive = request(addr, "GET", "/_snapshot/root/copy", &[], b"");
+    assert_eq!(archive.status, 200);
+    assert_eq!(request(addr, "PUT", "/root/copy", &[], b"{}").status, 200);
+    assert_eq!(request(addr, "PUT", "/_snapshot/root/copy", &[], archive.body.as_slice()).status, 200);
+    assert_eq!(request(addr, "GET", "/root/copy", &[], b"").json(), Some(json.clone()));
+
+    assert_eq!(request(addr, "DELETE", "/root/copy", &[], b"").status, 200);
+    assert_eq!(request(addr, "GET", "/root/copy", &[], b"").status, 404);
+    assert_eq!(request(addr, "DELETE", "/root/copy", &[], b"").status, 404);
+    fs::remove_dir_all(storage_dir).unwrap();
+}
+
+/// Every engine must let browsers on allowed origins in, and only them.
+fn cors(engine: Engine) {
+    let storage_dir = random_string(16);
+    let token = auth::new_token();
+    let tokens = [(auth::token_hash(token.as_str()), String::from("web"))].iter().cloned().collect();
+    let app = RestApp::new(storage_dir.clone())
+        .with_authenticator(Authenticator::new(HashMap::new(), tokens))
+        .with_cors(Cors {
+            allowed_origins: vec![String::from("https://app.example.com")],
+            allowed_methods: vec![String::from("GET"), String::from("PUT")],
+            allowed_headers: vec![String::from("authorization"), String::from("content-type")],
+            allow_credentials: true,
+            max_age: 600,
+        });
+    let addr = start(engine, app);
+    let origin = ("Origin", "https://app.example.com");
+
+    let reply = request(addr, "OPTIONS", "/doc", &[origin, ("Access-Control-Request-Method", "PUT"),
+        ("Access-Control-Request-Headers", "Authorization, Content-Type")], b"");
+    assert_eq!(reply.status, 204);
+    assert_eq!(reply.headers.get("access-control-allow-origin").map(String::as_str), Some("https://app.example.com"));
+    assert_eq!(reply.headers.get("access-control-allow-methods").map(String::as_str), Some("GET, PUT"));
+    assert_eq!(reply.headers.get("access-control-allow-credentials").map(String::as_str), Some("true"));
+    assert_eq!(reply.headers.get("access-control-max-age").map(String::as_str), Some("600"));
+    assert_eq!(request(addr, "OPTIONS", "/doc", &[origin, ("Access-Control-Request-Method", "DELETE")], b"").status, 403);
+    assert_eq!(request(addr, "OPTIONS", "/doc", &[origin, ("Access-Control-Request-Method", "PUT"),
+        ("Access-Control-Request-Headers", "x-secret")], b"").status, 403);
+    let reply = request(addr, "OPTIONS", "/doc", &[("Origin", "https://evil.example.com"), ("Access-Control-Request-Method", "GET")], b"");
+    assert_eq!(reply.status, 403);
+    assert!(!reply.headers.contains_key("access-control-allow-origin"));
+
+    let bearer = format!("Bearer {}", token);
+    let reply = request(addr, "PUT", "/doc", &[origin, ("Authorization", bearer.as_str())], b"{\"a\":1}");
+    assert_eq!(reply.status, 200);
+    assert_eq!(reply.headers.get("access-control-allow-origin").map(String::as_str), Some("https://app.example.com"));
+    assert_eq!(reply.headers.get("vary").map(String::as_str), Some("origin"));
+    assert_eq!(reply.headers.get("access-control-expose-headers").map(String::as_str),
+        Some("x-ttl, x-request-id, allow, www-authenticate"));
+    let reply = request(addr, "GET", "/doc", &[origin], b"");
+    assert_eq!(reply.status, 401);
+    assert_eq!(reply.headers.get("access-control-allow-origin").map(String::as_str), Some("https://app.example.com"));
+    let reply = request(addr, "GET", "/doc", &[("Origin", "https://evil.example.com"), ("Authorization", bearer.as_str())], b"");
+    assert_eq!(reply.status, 200);
+    assert!(!reply.headers.contains_key("access-control-allow-origin"));
+    fs::remove_dir_all(storage_dir).unwrap();
+}
+
+#[test]
+fn test_hyper_conformance() {
+    conformance(Engine::Hyper);
+    cors(Engine::Hyper);
+}
+
+#[test]
+fn test_iron_conformance() {
+    conformance(Engine::Iron);
+    cors(Engine::Iron);
+}
+
+#[test]
+fn test_hyper_answers_while_changes_are_long_polled() {
+    let storage_dir = random_string(16);
+    let addr = start(Engine::Hyper, RestApp::new(storage_dir.clone()).with_changes(ChangeFeed::new(10)));
+    let polls = (0..16)
+        .map(|_| thread::spawn(move || request(addr, "GET", "/_changes?since=0&timeout=30", &[], b"").json()))
+        .collect::<Vec<_>>();
+    thread::sleep(Duration::from_millis(500));
+
+    let started = Instant::now();
+    assert_eq!(request(addr, "PUT", "/doc", &[("Content-Type", "application/json")], b"{\"a\":1}").status, 200);
+    assert!(started.elapsed() < Duration::from_secs(2), "the write waited for the long polls");
+    polls.into_iter().for_each(|poll| assert_eq!(poll.join().unwrap().map(|batch| batch["last_seq"].clone()), Some(json!(1))));
+    assert!(started.elapsed() < Duration::from_secs(10), "the long polls were not woken by the write");
+    fs::remove_dir_all(storage_dir).unwrap();
+}
+
+#[test]
+fn test_hyper_only_upgrades_subscriptions() {
+    let storage_dir = random_string(16);
+    let addr = start(Engine::Hyper, RestApp::new(storage_dir.clone()));
+    let upgrade = [("Upgrade", "websocket"), ("Connection", "Upgrade"), ("Content-Type", "application/json")];
+    assert_eq!(request(addr, "PUT", "/doc", &upgrade, b"{\"a\":1}").status, 200);
+    assert_eq!(request(addr, "GET", "/doc", &upgrade, b"").json(), Some(json!({"a": 1})));
+    fs::remove_dir_all(storage_dir).unwrap();
+}
+
+#[test]
+fn test_problem_names_every_nested_error() {
+    let err = APIErr::Aggregate(vec![
+        APIErr::Conflict(String::from("/my/thing")),
+        APIErr::IO(io::Error::new(io::ErrorKind::NotFound, "gone"))]);
+    let problem = Problem::from_err(&err).at(String::from("/my"));
+    assert_eq!(problem.status, 409);
+    assert_eq!(serde_json::from_slice::<Value>(problem.to_vec().as_slice()).ok(), Some(json!({
+        "type": "about:blank",
+        "title": "Conflict",
+        "status": 409,
+        "detail": "2 errors occurred",
+        "instance": "/my",
+        "errors": [
+            {"type": "about:blank", "title": "Conflict", "status": 409, "path": "/my/thing",
+                "detail": "/my/thing cannot change between a field and a collection"},
+            {"type": "about:blank", "title": "Not Found", "status": 404, "detail": "no document exists at this path"}
+        ]
+    })));
+}
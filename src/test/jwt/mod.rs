@@ -0,0 +1,210 @@
+extern crate base64;
+extern crate rand;
+extern crate serde_json;
+use std::collections::HashMap;
+use std::fs;
+
+use self::rand::Rng;
+use self::serde_json::{json, Value};
+
+use super::super::api::acl::Permission;
+use super::super::auth::{hash, AuthErr, Authenticator};
+use super::super::auth::jwt::Verifier;
+use super::super::config::{AuthConfig, JwtConfig};
+use super::super::server::http::core::{self, Request};
+use super::super::RestApp;
+
+const ISSUER: &str = "https://issuer.test";
+const AUDIENCE: &str = "rust-monad";
+const SECRET: &str = "correct horse battery staple";
+
+// A 2048 bit key and RS256 tokens signed with its private half, which is
+// not kept. Both tokens are for `alice` with the `read` scope; one expired
+// in 2001 and the other expires in 2100.
+const PUBLIC_KEY: &str = concat!(
+    "-----BEGIN PUBLIC KEY-----\n",
+    "MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEApzLAhknRtDbHXDxO2rhV\n",
+    "GtS9TdBCsmirfVTIKcw1mn+i8N+DAkvWm/tYwR2Sb7bgIWePUq1ppVcDn/hs3HjB\n",
+    "dLKYjRGHxZD45iAHLse7HbkH7tm4RyNu1E7RUhAvghCfloToFO0tnQdwM6bZQWJd\n",
+    "fzVzbuZmBXghBsT9e/pBMCJpXWN3dVlTqVhE9D4dmHl607koBSfXU5kxHPocFU8C\n",
+    "UPIL/VlVnAOwoSCZJrLRVc3j13nPFEt3bh8snRw1BJu6LSEYV6/GH9juCO3GQI5O\n",
+    "/Ua0eLDwMFyDtPpDxkT8GAESuiZONkPN+UeiPdosHfdlceVfHx4PQKeUr6J44/S7\n",
+    "gQIDAQAB\n",
+    "-----END PUBLIC KEY-----\n",
+);
+const JWKS: &str = "{\"keys\":[{\"kty\":\"RSA\",\"kid\":\"k1\",\"use\":\"sig\",\"alg\":\"RS256\",\"n\":\"pzLAhknRtDb\
+    HXDxO2rhVGtS9TdBCsmirfVTIKcw1mn-i8N-DAkvWm_tYwR2Sb7bgIWePUq1ppVcDn_hs3HjBdLKYjRGHxZD45iAHLse7Hbk\
+    H7tm4RyNu1E7RUhAvghCfloToFO0tnQdwM6bZQWJdfzVzbuZmBXghBsT9e_pBMCJpXWN3dVlTqVhE9D4dmHl607koBSfXU5k\
+    xHPocFU8CUPIL_VlVnAOwoSCZJrLRVc3j13nPFEt3bh8snRw1BJu6LSEYV6_GH9juCO3GQI5O_Ua0eLDwMFyDtPpDxkT8GAE\
+    SuiZONkPN-UeiPdosHfdlceVfHx4PQKeUr6J44_S7gQ\",\"e\":\"AQAB\"}]}";
+const RS256_TOKEN: &str = "eyJhbGciOiJSUzI1NiIsInR5cCI6IkpXVCIsImtpZCI6ImsxIn0.eyJzdWIiOiJhbGljZSIsImlzcyI6Imh0dHBzOi8vaXNz\
+    dWVyLnRlc3QiLCJhdWQiOiJydXN0LW1vbmFkIiwiZXhwIjo0MTAyNDQ0ODAwLCJzY29wZSI6InJlYWQifQ.UZy6mrQmh12ui\
+    sdkTQNt-StwbUINm6JFT6Z7CevhvLDV7PJKLOSlzRh6mbOllE-V6ZmwMskY5kN6Edyc84GAYwPTKhbMQaifDqsGM1yTl3_7n\
+    -WV7uzGP04X6uS2iIlDb4hu8MNuo3Lcq_ZQFHLLB0HDld7sGaTivB4WXEWexu0CW-myfHHeThgemVupW88Elo-Hb6ajDMlCQ\
+    jHeQFv9g6DdnzKwo3gF_44ayT6GvQjYQH4GjFb3sP4AwY725C2PO3QnynwySkpZOeqQVLAJU9OVDoVb3ygBm2AzrsNutUamF\
+    i8VOSvXpDA1TPSRLLM1VCLLEVVuUQ2m8sWg1E7zjg";
+const EXPIRED_RS256_TOKEN: &str = "eyJhbGciOiJSUzI1NiIsInR5cCI6IkpXVCIsImtpZCI6ImsxIn0.eyJzdWIiOiJhbGljZSIsImlzcyI6Imh0dHBzOi8vaXNz\
+    dWVyLnRlc3QiLCJhdWQiOiJydXN0LW1vbmFkIiwiZXhwIjoxMDAwMDAwMDAwLCJzY29wZSI6InJlYWQifQ.I-SaSeZGuhMht\
+    MaUiBoZ2_RAF4XdnhlWn8-DkZOo2kjXmzYH3TkXGLuAx-YC8zFeHP22bx9sW6NVknRAUN_51xOD62AUFydA2XGFmpLLQXhj2\
+    BxVng4Cp1j-r39FF4CQPDjzGiZkYtmGnRj_L9oi5mYMmAQm9_gVQ8KbZ6vBqoC-Ky0e-4yNZS3qHNlqrGB-KzV1UxdBNVw87\
+    LweSc9Rr7q2asAKbMp2ZU4wAHTVZWB1BDFnY7LEaEvAeyyMuS2pJ5xhRfd-XKhrHmo4kbwSueYN0_q7fWjDmslBoNxyU4Fnd\
+    _NejohUC6FCRL7g7Y8ez4cVT5K0vgRuaHgOthuWww";
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .gen_ascii_chars()
+        .take(len)
+        .collect::<String>()
+}
+
+fn encode(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn hs256(secret: &[u8], header: Value, claims: Value) -> String {
+    let message = format!("{}.{}", encode(header.to_string().as_bytes()), encode(claims.to_string().as_bytes()));
+    let signature = encode(&hash::hmac_sha256(secret, message.as_bytes()));
+    format!("{}.{}", message, signature)
+}
+
+fn token(claims: Value) -> String {
+    hs256(SECRET.as_bytes(), json!({"alg": "HS256", "typ": "JWT"}), claims)
+}
+
+fn claims(sub: &str, scope: &str) -> Value {
+    json!({"sub": sub, "iss": ISSUER, "aud": [AUDIENCE, "other"], "exp": 4102444800u64, "scope": scope})
+}
+
+fn config() -> JwtConfig {
+    JwtConfig {
+        secret: Some(String::from(SECRET)),
+        issuer: Some(String::from(ISSUER)),
+        audience: Some(String::from(AUDIENCE)),
+        scopes_claim: Some(String::from("scope")),
+        ..JwtConfig::default()
+    }
+}
+
+fn assert_invalid(verifier: &Verifier, token: &str, reason: &str) {
+    match verifier.verify(token) {
+        Err(AuthErr::InvalidToken(ref r)) if r.contains(reason) => (),
+        other => panic!("expected {:?} to be refused as {}, got {:?}", token, reason, other)
+    }
+}
+
+fn request(method: &str, path: &[&str], token: &str, body: &[u8]) -> Request {
+    Request {
+        method: String::from(method),
+        path: path.iter().map(|p| String::from(*p)).collect(),
+        query: HashMap::new(),
+        headers: vec![(String::from("authorization"), format!("Bearer {}", token))].into_iter().collect(),
+        body: body.to_vec(),
+        client_subject: None,
+        spooled: None,
+    }
+}
+
+#[test]
+fn test_hs256_tokens_are_checked() {
+    let verifier = Verifier::from_config(&config()).unwrap().unwrap();
+    let claims_of = |token: &str| verifier.verify(token).map(|c| (c.user, c.scopes)).ok();
+    assert_eq!(claims_of(token(claims("alice", "read write")).as_str()),
+        Some((String::from("alice"), Some(vec![Permission::Read, Permission::Write]))));
+    let mut listed = claims("bob", "");
+    listed["scope"] = json!(["delete", "fly"]);
+    assert_eq!(claims_of(token(listed).as_str()), Some((String::from("bob"), Some(vec![Permission::Delete]))));
+
+    let mut expired = claims("alice", "read");
+    expired["exp"] = json!(1000000000);
+    assert_invalid(&verifier, token(expired).as_str(), "expired");
+    let mut early = claims("alice", "read");
+    early["nbf"] = json!(4102444800u64);
+    assert_invalid(&verifier, token(early).as_str(), "not yet valid");
+    let patient = Verifier::from_config(&JwtConfig { leeway: 60, ..config() }).unwrap().unwrap();
+    let mut distant = claims("alice", "read");
+    distant["exp"] = json!(u64::MAX);
+    assert_eq!(patient.verify(token(distant.clone()).as_str()).map(|c| c.user).ok(), Some(String::from("alice")));
+    distant["nbf"] = json!(u64::MAX);
+    assert_invalid(&patient, token(distant).as_str(), "not yet valid");
+    let mut foreign = claims("alice", "read");
+    foreign["aud"] = json!("someone-else");
+    assert_invalid(&verifier, token(foreign).as_str(), "audience");
+    let mut forged = claims("alice", "read");
+    forged["iss"] = json!("https://evil.test");
+    assert_invalid(&verifier, token(forged).as_str(), "issuer");
+    let mut endless = claims("alice", "read");
+    endless.as_object_mut().unwrap().remove("exp");
+    assert_invalid(&verifier, token(endless.clone()).as_str(), "no exp claim");
+    let lenient = Verifier::from_config(&JwtConfig { allow_missing_exp: true, ..config() }).unwrap().unwrap();
+    assert_eq!(lenient.verify(token(endless).as_str()).map(|c| c.user).ok(), Some(String::from("alice")));
+    let mut anonymous = claims("", "read");
+    anonymous.as_object_mut().unwrap().remove("sub");
+    assert_invalid(&verifier, token(anonymous).as_str(), "no sub claim");
+
+    let signed = token(claims("alice", "read"));
+    let parts = signed.split('.').collect::<Vec<&str>>();
+    let tampered = format!("{}.{}.{}", parts[0], encode(claims("admin", "read").to_string().as_bytes()), parts[2]);
+    assert_invalid(&verifier, tampered.as_str(), "signature");
+    assert_invalid(&verifier, hs256(b"guessed", json!({"alg": "HS256"}), claims("alice", "read")).as_str(), "signature");
+    let unsigned = format!("{}.{}.", encode(b"{\"alg\":\"none\"}"), encode(claims("alice", "read").to_string().as_bytes()));
+    assert_invalid(&verifier, unsigned.as_str(), "algorithm");
+    assert_invalid(&verifier, "not.a.jwt", "malformed");
+}
+
+#[test]
+fn test_rs256_tokens_are_checked_against_public_keys() {
+    let dir = random_string(16);
+    fs::create_dir_all(&dir).unwrap();
+    let pem_file = format!("{}/key.pem", dir);
+    let jwks_file = format!("{}/jwks.json", dir);
+    fs::write(&pem_file, PUBLIC_KEY).unwrap();
+    fs::write(&jwks_file, JWKS).unwrap();
+
+    for keys in [(Some(pem_file.clone()), None), (None, Some(jwks_file.clone()))].iter() {
+        let config = JwtConfig {
+            public_key_file: keys.0.clone(),
+            jwks_file: keys.1.clone(),
+            scopes_claim: Some(String::from("scope")),
+            ..config()
+        };
+        let verifier = Verifier::from_config(&JwtConfig { secret: None, ..config }).unwrap().unwrap();
+        assert_eq!(verifier.verify(RS256_TOKEN).ok().map(|c| (c.user, c.scopes)),
+            Some((String::from("alice"), Some(vec![Permission::Read]))));
+        assert_invalid(&verifier, EXPIRED_RS256_TOKEN, "expired");
+
+        let mut signature = base64::decode_config(RS256_TOKEN.rsplit('.').next().unwrap(), base64::URL_SAFE_NO_PAD).unwrap();
+        signature[100] ^= 1;
+        let (message, _) = RS256_TOKEN.split_at(RS256_TOKEN.rfind('.').unwrap());
+        assert_invalid(&verifier, format!("{}.{}", message, encode(&signature)).as_str(), "signature");
+
+        // The public key is no secret, so it must not pass as an HMAC key.
+        let confused = hs256(PUBLIC_KEY.as_bytes(), json!({"alg": "HS256"}), claims("alice", "admin"));
+        assert_invalid(&verifier, confused.as_str(), "signature");
+    }
+
+    fs::write(&pem_file, "-----BEGIN PUBLIC KEY-----\nAAAA\n-----END PUBLIC KEY-----\n").unwrap();
+    match Verifier::from_config(&JwtConfig { public_key_file: Some(pem_file), ..JwtConfig::default() }) {
+        Err(AuthErr::Parse { .. }) => (),
+        other => panic!("expected a parse error, got {:?}", other)
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_token_scopes_limit_requests() {
+    let storage_dir = random_string(16);
+    let auth = AuthConfig { enabled: true, ..AuthConfig::default() };
+    let authenticator = Authenticator::from_config(&auth, &config()).unwrap();
+    assert_eq!(authenticator.challenges(), "Bearer realm=\"rust-monad\"");
+    let app = RestApp::new(storage_dir.clone()).with_authenticator(authenticator);
+    let reader = token(claims("alice", "read"));
+    let writer = token(claims("alice", "read write"));
+
+    assert_eq!(core::handle(&app, request("PUT", &["doc"], reader.as_str(), b"{\"a\":1}")).status, 403);
+    assert_eq!(core::handle(&app, request("PUT", &["doc"], writer.as_str(), b"{\"a\":1}")).status, 200);
+    assert_eq!(core::handle(&app, request("GET", &["doc"], reader.as_str(), b"")).status, 200);
+    assert_eq!(core::handle(&app, request("DELETE", &["doc"], writer.as_str(), b"")).status, 403);
+    assert_eq!(core::handle(&app, request("GET", &["_acl"], writer.as_str(), b"")).status, 403);
+    assert_eq!(core::handle(&app, request("GET", &["doc"], RS256_TOKEN, b"")).status, 401);
+    fs::remove_dir_all(storage_dir).unwrap();
+}
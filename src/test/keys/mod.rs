@@ -0,0 +1,160 @@
+extern crate base64;
+extern crate rand;
+extern crate serde_json;
+extern crate tar;
+use std::collections::HashMap;
+use std::fs;
+
+use self::rand::Rng;
+use self::serde_json::{json, Value};
+
+use super::super::api::{API, FORMAT_VERSION};
+use super::super::api::keys::{self, NewKey};
+use super::super::api::acl::Permission;
+use super::super::api::snapshot;
+use super::super::auth::{self, Authenticator, PasswordHash};
+use super::super::server::http::core::{self, Body, Request, Response};
+use super::super::RestApp;
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .gen_ascii_chars()
+        .take(len)
+        .collect::<String>()
+}
+
+fn request(method: &str, path: &[&str], authorization: String, body: &[u8]) -> Request {
+    Request {
+        method: String::from(method),
+        path: path.iter().map(|p| String::from(*p)).collect(),
+        query: HashMap::new(),
+        headers: vec![(String::from("authorization"), authorization)].into_iter().collect(),
+        body: body.to_vec(),
+        client_subject: None,
+        spooled: None,
+    }
+}
+
+fn basic(user: &str, password: &str) -> String {
+    format!("Basic {}", base64::encode(format!("{}:{}", user, password).as_bytes()))
+}
+
+fn bearer(secret: &str) -> String {
+    format!("Bearer {}", secret)
+}
+
+fn json_body(response: Response) -> Value {
+    let bytes = match response.body {
+        Body::Empty | Body::Upgrade(_) => Vec::new(),
+        Body::Bytes(bytes) => bytes,
+        Body::Stream(chunks) => chunks.flat_map(|chunk| chunk.unwrap()).collect(),
+        Body::Pending(pending) => core::wait_for(pending).flat_map(|chunk| chunk.unwrap()).collect(),
+    };
+    serde_json::from_slice(bytes.as_slice()).unwrap_or(Value::Null)
+}
+
+fn app(storage_dir: &str) -> RestApp {
+    let users = ["root", "bob"].iter()
+        .map(|name| (String::from(*name), PasswordHash::with_salt("pw", b"salt".to_vec(), 1)))
+        .collect();
+    RestApp::new(String::from(storage_dir))
+        .with_authenticator(Authenticator::new(users, HashMap::new()))
+        .with_admins(vec![String::from("root")])
+}
+
+#[test]
+fn test_keys_are_created_rotated_and_revoked() {
+    let storage_dir = random_string(16);
+    let app = app(storage_dir.as_str());
+    let root = basic("root", "pw");
+
+    let created = core::handle(&app, request("POST", &["_keys"], root.clone(), b"{\"name\":\"ci\",\"owner\":\"bob\"}"));
+    assert_eq!(created.status, 201);
+    let created = json_body(created);
+    let id = created["id"].as_str().unwrap().to_string();
+    let secret = created["secret"].as_str().unwrap().to_string();
+    assert!(secret.starts_with(keys::KEY_PREFIX));
+
+    let listed = json_body(core::handle(&app, request("GET", &["_keys"], root.clone(), b"")));
+    assert_eq!(listed.as_array().map(Vec::len), Some(1));
+    assert_eq!(listed[0]["owner"], json!("bob"));
+    assert!(listed[0].get("hash").is_none() && listed[0].get("secret").is_none());
+    let stored = fs::read_dir(format!("{}/_keys/{}", storage_dir, id)).unwrap()
+        .flat_map(|entry| fs::read_to_string(entry.unwrap().path()).ok())
+        .collect::<String>();
+    assert!(!stored.contains(secret.as_str()));
+
+    assert_eq!(core::handle(&app, request("PUT", &["doc"], bearer(&secret), b"{\"a\":1}")).status, 200);
+    assert!(!app.is_visible(&std::path::Path::new(storage_dir.as_str()).join(keys::KEYS_PATH)));
+    assert_eq!(core::handle(&app, request("GET", &["_keys"], bearer(&secret), b"")).status, 403);
+    assert_eq!(core::handle(&app, request("GET", &["_keys"], basic("bob", "pw"), b"")).status, 403);
+
+    let rotated = core::handle(&app, request("POST", &["_keys", id.as_str(), "rotate"], root.clone(), b""));
+    assert_eq!(rotated.status, 200);
+    let rotated = json_body(rotated)["secret"].as_str().unwrap().to_string();
+    assert_eq!(core::handle(&app, request("GET", &["doc"], bearer(&secret), b"")).status, 401);
+    assert_eq!(core::handle(&app, request("GET", &["doc"], bearer(&rotated), b"")).status, 200);
+
+    assert_eq!(core::handle(&app, request("DELETE", &["_keys", id.as_str()], root.clone(), b"")).status, 200);
+    assert_eq!(core::handle(&app, request("GET", &["doc"], bearer(&rotated), b"")).status, 401);
+    assert_eq!(core::handle(&app, request("GET", &["_keys", id.as_str()], root.clone(), b"")).status, 404);
+    assert_eq!(core::handle(&app, request("GET", &["doc"], bearer("rmk_nonsense"), b"")).status, 401);
+    fs::remove_dir_all(storage_dir).unwrap();
+}
+
+#[test]
+fn test_keys_carry_scopes_and_expiry() {
+    let storage_dir = random_string(16);
+    let app = app(storage_dir.as_str());
+    let new = |expires_at, scopes| NewKey { name: String::from("k"), owner: String::from("bob"), expires_at, scopes };
+
+    let (_, reader) = keys::create(&app, new(None, Some(vec![Permission::Read]))).unwrap();
+    let (_, expired) = keys::create(&app, new(Some(1), None)).unwrap();
+    assert!(keys::create(&app, NewKey { owner: String::new(), ..new(None, None) }).is_err());
+
+    assert!(app.put(vec![String::from("doc")], json!({"a": 1}), String::from("root")).is_ok());
+    assert_eq!(core::handle(&app, request("GET", &["doc"], bearer(&reader), b"")).status, 200);
+    assert_eq!(core::handle(&app, request("DELETE", &["doc"], bearer(&reader), b"")).status, 403);
+    assert_eq!(core::handle(&app, request("GET", &["doc"], bearer(&expired), b"")).status, 401);
+    assert!(app.get(vec![String::from(keys::KEYS_PATH)], String::from("bob")).is_err());
+    assert_eq!(keys::list(&app).map(|keys| keys.len()).ok(), Some(2));
+    fs::remove_dir_all(storage_dir).unwrap();
+}
+
+/// An archive of the whole of `storage_dir`, everything in it included.
+fn archive_of(storage_dir: &str, secrets: bool) -> Vec<u8> {
+    let manifest = json!({"format_version": FORMAT_VERSION, "prefix": [], "created_at": 0, "secrets": secrets});
+    let manifest = manifest.to_string().into_bytes();
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    let mut builder = tar::Builder::new(Vec::new());
+    builder.append_data(&mut header, "manifest.json", manifest.as_slice()).unwrap();
+    builder.append_dir_all("data", storage_dir).unwrap();
+    builder.into_inner().unwrap()
+}
+
+#[test]
+fn test_only_keys_the_server_created_are_trusted() {
+    let storage_dir = random_string(16);
+    let other_dir = random_string(16);
+    let app = app(storage_dir.as_str());
+    assert!(app.put(vec![String::from("doc")], json!({"a": 1}), String::from("root")).is_ok());
+
+    let secret = format!("{}forgedkey123_{}", keys::KEY_PREFIX, random_string(16));
+    let forged = json!({"id": "forgedkey123", "name": "k", "owner": "root", "created_at": 0, "hash": auth::token_hash(&secret)});
+    assert!(app.put(vec![String::from(keys::KEYS_PATH), String::from("forgedkey123")], forged, String::from("root")).is_err());
+    assert_eq!(core::handle(&app, request("GET", &["doc"], bearer(&secret), b"")).status, 401);
+
+    let other = self::app(other_dir.as_str());
+    let new = NewKey { name: String::from("k"), owner: String::from("root"), expires_at: None, scopes: None };
+    let (_, planted) = keys::create(&other, new).unwrap();
+    assert!(snapshot::restore(&app, Vec::new(), archive_of(other_dir.as_str(), true).as_slice(), false).is_err());
+    let smuggled = archive_of(other_dir.as_str(), false);
+    assert_eq!(core::handle(&app, request("PUT", &["_snapshot"], basic("root", "pw"), smuggled.as_slice())).status, 200);
+    assert_eq!(core::handle(&app, request("GET", &["doc"], bearer(&planted), b"")).status, 401);
+    assert_eq!(keys::list(&app).map(|keys| keys.len()).ok(), Some(0));
+    fs::remove_dir_all(storage_dir).unwrap();
+    fs::remove_dir_all(other_dir).unwrap();
+}
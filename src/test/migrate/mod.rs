@@ -0,0 +1,54 @@
+extern crate serde_json;
+extern crate rand;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use self::serde_json::json;
+use self::rand::Rng;
+use super::super::api::{API, APIErr, FORMAT_VERSION};
+use super::super::api::migrate;
+use super::super::RestApp;
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .gen_ascii_chars()
+        .take(len)
+        .collect::<String>()
+}
+
+#[test]
+fn test_unversioned_store_is_migrated() {
+    let storage_dir = random_string(16);
+    let copy_dir = random_string(16);
+    let api = RestApp::new(storage_dir.clone());
+    let user = String::from("tilda");
+    let json = json!({"root":{"name":"test_obj","list":[1, 2]}});
+    assert!(api.put(vec![String::from("root")], json["root"].clone(), user.clone()).is_ok());
+    assert_eq!(migrate::read_version(Path::new(&storage_dir)).ok(), Some(0));
+
+    assert_eq!(migrate::migrate_into(&api, Path::new(&copy_dir)).map(|applied| applied.len()).ok(), Some(1));
+    assert_eq!(migrate::read_version(Path::new(&storage_dir)).ok(), Some(0));
+    assert_eq!(migrate::read_version(Path::new(&copy_dir)).ok(), Some(FORMAT_VERSION));
+    assert!(migrate::migrate_into(&api, Path::new(&copy_dir)).is_err());
+
+    assert_eq!(migrate::open(&api).map(|applied| applied.len()).ok(), Some(1));
+    assert_eq!(migrate::read_version(Path::new(&storage_dir)).ok(), Some(FORMAT_VERSION));
+    assert_eq!(migrate::open(&api).map(|applied| applied.len()).ok(), Some(0));
+    assert_eq!(api.get(Vec::new(), user.clone()).ok(), Some(json));
+    fs::remove_dir_all(storage_dir).unwrap();
+    fs::remove_dir_all(copy_dir).unwrap();
+}
+
+#[test]
+fn test_newer_store_is_refused() {
+    let storage_dir = random_string(16);
+    let api = RestApp::new(storage_dir.clone());
+    assert!(migrate::open(&api).is_ok());
+    assert!(migrate::write_version(&api, &PathBuf::from(storage_dir.clone()), FORMAT_VERSION + 1).is_ok());
+
+    match migrate::open(&api) {
+        Err(APIErr::UnsupportedVersion(version)) => assert_eq!(version, FORMAT_VERSION + 1),
+        other => panic!("expected the store to be refused, got {:?}", other.map(|_| ()))
+    }
+    fs::remove_dir_all(storage_dir).unwrap();
+}
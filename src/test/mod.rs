@@ -1 +1,20 @@
-mod api;
\ No newline at end of file
+mod acl;
+mod api;
+mod audit;
+mod auth;
+mod changes;
+mod client;
+mod codec;
+mod config;
+mod fsck;
+mod http;
+mod jwt;
+mod keys;
+mod migrate;
+mod ndjson;
+mod replication;
+mod snapshot;
+mod tenant;
+mod tls;
+mod webhooks;
+mod websocket;
\ No newline at end of file
@@ -0,0 +1,68 @@
+extern crate serde_json;
+extern crate rand;
+use std::fs;
+
+use self::serde_json::json;
+use self::rand::Rng;
+use super::super::api::API;
+use super::super::api::APIErr;
+use super::super::api::ndjson::{self, ExportMode};
+use super::super::RestApp;
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .gen_ascii_chars()
+        .take(len)
+        .collect::<String>()
+}
+
+fn export_to_string(api: &RestApp, prefix: Vec<String>, mode: ExportMode) -> String {
+    ndjson::export(api.clone(), prefix, mode, String::from("tilda"))
+        .and_then(|records| records.collect::<Result<Vec<Vec<u8>>, APIErr>>())
+        .map(|chunks| String::from_utf8(chunks.concat()).unwrap())
+        .unwrap()
+}
+
+#[test]
+fn test_export_import_round_trip() {
+    let storage_dir = random_string(16) ;
+    let api = RestApp::new(storage_dir.clone());
+    let user = String::from("tilda");
+    let json = json!({
+        "name":"test_obj",
+        "empty":{},
+        "none":[],
+        "conquests":["birth", {"event":"learned","children":["frank", {"nodeId":1}]}, [true, null]]
+    });
+    let source = ["source".to_string()].to_vec();
+    assert!(api.put(source.clone(), json.clone(), user.clone()).is_ok());
+
+    for (mode, target) in [(ExportMode::Leaves, "leaves"), (ExportMode::Documents, "documents")].iter() {
+        let dump = export_to_string(&api, source.clone(), *mode);
+        let target = [target.to_string()].to_vec();
+        let results = ndjson::import(&api, target.clone(), dump.as_bytes(), user.clone());
+        assert!(results.iter().all(|r| r.ok), "{:?} import failed: {:?}", mode, results);
+        assert_eq!(api.get(target.clone(), user.clone()).ok(), Some(json.clone()));
+        assert_eq!(export_to_string(&api, target, *mode), dump);
+    }
+
+    fs::remove_dir_all(storage_dir);
+}
+
+#[test]
+fn test_import_reports_each_line() {
+    let storage_dir = random_string(16) ;
+    let api = RestApp::new(storage_dir.clone());
+    let user = String::from("tilda");
+    let input = "{\"path\":[\"a\"],\"value\":1}\n\nnot json\n{\"path\":[\"b\"],\"value\":{\"c\":true},\"ttl\":60}\n";
+
+    assert!(api.put(["root".to_string()].to_vec(), json!({}), user.clone()).is_ok());
+    let results = ndjson::import(&api, ["root".to_string()].to_vec(), input.as_bytes(), user.clone());
+    assert_eq!(results.iter().map(|r| (r.line, r.ok)).collect::<Vec<(usize, bool)>>(),
+        [(1, true), (2, true), (3, false), (4, true)].to_vec());
+    assert!(results[2].error.is_some());
+    assert_eq!(api.get(["root".to_string()].to_vec(), user.clone()).ok(), Some(json!({"a":1,"b":{"c":true}})));
+    assert!(api.ttl(["root".to_string(), "b".to_string()].to_vec(), user).ok().and_then(|ttl| ttl).is_some());
+
+    fs::remove_dir_all(storage_dir);
+}
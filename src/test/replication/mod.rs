@@ -0,0 +1,258 @@
+extern crate rand;
+extern crate serde_json;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use self::rand::Rng;
+use self::rand::distributions::Alphanumeric;
+use self::serde_json::{json, Value};
+
+use super::super::api::{acl, keys, tenant, API};
+use super::super::api::acl::Acl;
+use super::super::api::changes::ChangeFeed;
+use super::super::api::replication::{self, Replica, Settings};
+use super::super::api::tenant::Tenancy;
+use super::super::auth::Authenticator;
+use super::super::server::http::core::{self, Body, Request, Response};
+use super::super::RestApp;
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .collect::<String>()
+}
+
+/// Serves `app` on a free local port, for GET requests only, returning its
+/// base URL. Only the `Authorization` header is passed on.
+fn primary(app: RestApp) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    thread::spawn(move || for stream in listener.incoming() {
+        let mut stream = stream.unwrap();
+        let app = app.clone();
+        thread::spawn(move || {
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let target = line.split_whitespace().nth(1).unwrap_or("/").to_string();
+            let mut headers = HashMap::new();
+            loop {
+                line.clear();
+                if reader.read_line(&mut line).unwrap() == 0 || line.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':').filter(|(name, _)| name.eq_ignore_ascii_case("authorization")) {
+                    headers.insert(name.to_lowercase(), String::from(value.trim()));
+                }
+            }
+            let (path, query) = match target.split_once('?') {
+                Some((path, query)) => (path, Some(query)),
+                None => (target.as_str(), None)
+            };
+            let response = core::handle(&app, Request {
+                method: String::from("GET"),
+                path: core::split_path(path),
+                query: core::parse_query(query),
+                headers,
+                body: Vec::new(),
+                client_subject: None,
+                spooled: None,
+            });
+            let body = match response.body {
+                Body::Bytes(bytes) => bytes,
+                Body::Stream(chunks) => chunks.flat_map(|chunk| chunk.unwrap()).collect(),
+                Body::Pending(pending) => core::wait_for(pending).flat_map(|chunk| chunk.unwrap()).collect(),
+                _ => Vec::new()
+            };
+            write!(stream, "HTTP/1.1 {} Whatever\r\ncontent-length: {}\r\nconnection: close\r\n\r\n", response.status, body.len()).unwrap();
+            stream.write_all(body.as_slice()).unwrap();
+        });
+    });
+    url
+}
+
+fn wait_for<F: Fn() -> bool>(done: F) {
+    let started = Instant::now();
+    while !done() {
+        assert!(started.elapsed() < Duration::from_secs(10), "timed out");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn request(method: &str, path: &str, body: &[u8]) -> Request {
+    Request {
+        method: String::from(method),
+        path: core::split_path(path),
+        query: HashMap::new(),
+        headers: HashMap::new(),
+        body: body.to_vec(),
+        client_subject: None,
+        spooled: None,
+    }
+}
+
+fn json_body(response: Response) -> Value {
+    match response.body {
+        Body::Bytes(bytes) => serde_json::from_slice(bytes.as_slice()).unwrap(),
+        _ => Value::Null
+    }
+}
+
+fn app(storage_dir: &str, capacity: usize) -> RestApp {
+    fs::create_dir_all(storage_dir).unwrap();
+    RestApp::new(String::from(storage_dir))
+        .with_changes(ChangeFeed::open(storage_dir, capacity).unwrap())
+        .with_admins(vec![String::from("anon")])
+}
+
+/// A follower of `primary` kept in `storage_dir`, with the replication
+/// thread already running.
+fn follower(storage_dir: &str, primary: &str) -> (RestApp, Arc<Replica>) {
+    follower_with(storage_dir, primary, None)
+}
+
+fn follower_with(storage_dir: &str, primary: &str, authorization: Option<String>) -> (RestApp, Arc<Replica>) {
+    let replica = Arc::new(Replica::new(Settings {
+        primary: String::from(primary),
+        authorization,
+        poll: Duration::from_secs(1),
+        retry: Duration::from_millis(50),
+        timeout: Duration::from_secs(5),
+    }));
+    let app = app(storage_dir, 100);
+    replication::spawn(app.clone().with_admins(vec![String::from(replication::USER)]), replica.clone());
+    (app.with_replica(replica.clone()), replica)
+}
+
+fn get(app: &RestApp, path: &str) -> Option<Value> {
+    app.get(core::split_path(path), String::from("anon")).ok()
+}
+
+#[test]
+fn test_follower_applies_changes_and_refuses_writes() {
+    let primary_dir = random_string(16);
+    let follower_dir = random_string(16);
+    let leader = app(primary_dir.as_str(), 100);
+    leader.put(core::split_path("/a"), json!({"x": 1}), String::from("anon")).unwrap();
+    let (follower, replica) = follower(follower_dir.as_str(), primary(leader.clone()).as_str());
+    wait_for(|| get(&follower, "/a") == Some(json!({"x": 1})));
+
+    leader.put(core::split_path("/b"), json!({"y": [1, 2]}), String::from("anon")).unwrap();
+    leader.put(core::split_path("/a/x"), json!(2), String::from("anon")).unwrap();
+    leader.delete(core::split_path("/b"), String::from("anon")).unwrap();
+    wait_for(|| get(&follower, "/a") == Some(json!({"x": 2})) && replica.status().position == 4);
+    assert_eq!(get(&follower, "/b"), None);
+    assert!(!follower.is_visible(&Path::new(follower_dir.as_str()).join(replication::REPLICATION_PATH)));
+
+    let refused = core::handle(&follower, request("PUT", "/c", b"{\"z\": 3}"));
+    assert_eq!(refused.status, 405);
+    assert!(refused.headers.contains(&("allow", String::from("GET"))));
+    assert_eq!(core::handle(&follower, request("POST", "/_webhooks", b"{}")).status, 405);
+    assert_eq!(core::handle(&follower, request("GET", "/a/x", b"")).status, 200);
+
+    let status = json_body(core::handle(&follower, request("GET", "/_replication", b"")));
+    assert_eq!((status["position"].clone(), status["primary_seq"].clone(), status["lag"].clone()), (json!(4), json!(4), json!(0)));
+    assert_eq!(status["resyncs"], json!(1));
+    assert_eq!(core::handle(&RestApp::new(follower_dir.clone()), request("GET", "/_replication", b"")).status, 403);
+    assert_eq!(core::handle(&leader, request("GET", "/_replication", b"")).status, 404);
+    fs::remove_dir_all(primary_dir).unwrap();
+    fs::remove_dir_all(follower_dir).unwrap();
+}
+
+#[test]
+fn test_follower_resyncs_when_changes_are_gone() {
+    let primary_dir = random_string(16);
+    let follower_dir = random_string(16);
+    let leader = app(primary_dir.as_str(), 2);
+    let url = primary(leader.clone());
+    (1..6).for_each(|n| leader.put(vec![format!("doc{}", n)], json!({"n": n}), String::from("anon")).unwrap());
+    fs::create_dir_all(Path::new(follower_dir.as_str()).join(replication::REPLICATION_PATH)).unwrap();
+    fs::write(Path::new(follower_dir.as_str()).join(replication::REPLICATION_PATH).join("position"),
+              json!({"primary": url, "seq": 1}).to_string()).unwrap();
+    let (follower, replica) = follower(follower_dir.as_str(), url.as_str());
+    wait_for(|| replica.status().resyncs == 1 && replica.status().position == 5);
+    assert_eq!(get(&follower, "/doc1"), Some(json!({"n": 1})));
+    assert_eq!(get(&follower, "/doc5"), Some(json!({"n": 5})));
+
+    fs::remove_dir_all(Path::new(primary_dir.as_str()).join("doc1")).unwrap();
+    assert_eq!(core::handle(&follower, request("POST", "/_replication/resync", b"")).status, 202);
+    wait_for(|| replica.status().resyncs == 2);
+    assert_eq!(get(&follower, "/doc1"), None);
+    leader.put(core::split_path("/doc6"), json!({"n": 6}), String::from("anon")).unwrap();
+    wait_for(|| get(&follower, "/doc6") == Some(json!({"n": 6})));
+    fs::remove_dir_all(primary_dir).unwrap();
+    fs::remove_dir_all(follower_dir).unwrap();
+}
+
+
+#[test]
+fn test_follower_keeps_keys_rules_and_expiry() {
+    let primary_dir = random_string(16);
+    let follower_dir = random_string(16);
+    let leader = app(primary_dir.as_str(), 100);
+    let (follower, replica) = follower(follower_dir.as_str(), primary(leader.clone()).as_str());
+    wait_for(|| replica.status().resyncs == 1);
+
+    let new = keys::NewKey { name: String::from("ci"), owner: String::from("bob"), expires_at: None, scopes: None };
+    let (key, secret) = keys::create(&leader, new).unwrap();
+    wait_for(|| keys::authenticate(&follower, secret.as_str()).ok().and_then(|p| p).map(|p| p.name) == Some(String::from("bob")));
+    let (_, rotated) = keys::rotate(&leader, key.id.as_str()).unwrap();
+    wait_for(|| keys::authenticate(&follower, rotated.as_str()).map(|p| p.is_some()).unwrap_or(false));
+    assert_eq!(keys::authenticate(&follower, secret.as_str()).map(|p| p.is_some()).ok(), Some(false));
+    keys::revoke(&leader, key.id.as_str()).unwrap();
+    wait_for(|| keys::authenticate(&follower, rotated.as_str()).map(|p| p.is_none()).unwrap_or(false));
+
+    let rules = Acl::from_value(json!({"rules": [{"prefix": "/", "principal": "*", "permissions": ["read"]}]})).unwrap();
+    acl::store(&leader, Some(&rules)).unwrap();
+    wait_for(|| acl::load(&follower).ok() == Some(Some(rules.clone())));
+    acl::store(&leader, None).unwrap();
+    wait_for(|| acl::load(&follower).ok() == Some(None));
+
+    leader.put_with_ttl(core::split_path("/session"), json!({"user": "bob"}), Some(1), String::from("anon")).unwrap();
+    // the expiry file, not the ttl, which is gone once the second is up
+    wait_for(|| Path::new(follower_dir.as_str()).join("__expires__.session").exists());
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(leader.sweep(Path::new(primary_dir.as_str()).to_path_buf()).ok(), Some(1));
+    wait_for(|| !Path::new(follower_dir.as_str()).join("session").exists());
+    fs::remove_dir_all(primary_dir).unwrap();
+    fs::remove_dir_all(follower_dir).unwrap();
+}
+
+#[test]
+fn test_follower_follows_every_tenant() {
+    let primary_dir = random_string(16);
+    let follower_dir = random_string(16);
+    let leader = app(primary_dir.as_str(), 100)
+        .with_authenticator(Authenticator::new(HashMap::new(), HashMap::new()))
+        .with_admins(vec![String::from(replication::USER)])
+        .with_tenancy(Tenancy::PerUser);
+    let new = keys::NewKey { name: String::from("follower"), owner: String::from(replication::USER), expires_at: None, scopes: None };
+    let (_, secret) = keys::create(&leader, new).unwrap();
+    tenant::create(&leader, "red").unwrap();
+    leader.for_tenant("red").put(core::split_path("/doc"), json!({"n": 1}), String::from(replication::USER)).unwrap();
+    let url = primary(leader.clone());
+    let (follower, replica) = follower_with(follower_dir.as_str(), url.as_str(), Some(format!("Bearer {}", secret)));
+    wait_for(|| replica.status().resyncs == 1);
+    assert_eq!(get(&follower.for_tenant("red"), "/doc"), Some(json!({"n": 1})));
+
+    tenant::create(&leader, "blue").unwrap();
+    leader.for_tenant("blue").put(core::split_path("/doc"), json!({"n": 2}), String::from(replication::USER)).unwrap();
+    leader.for_tenant("red").put(core::split_path("/doc/n"), json!(3), String::from(replication::USER)).unwrap();
+    wait_for(|| get(&follower.for_tenant("blue"), "/doc") == Some(json!({"n": 2})));
+    wait_for(|| get(&follower.for_tenant("red"), "/doc") == Some(json!({"n": 3})));
+    assert_eq!(tenant::list(&follower).ok(), Some(vec![String::from("blue"), String::from("red")]));
+    assert_eq!(get(&follower, "/doc"), None);
+
+    tenant::drop(&leader, "red").unwrap();
+    wait_for(|| !tenant::exists(&follower, "red"));
+    assert_eq!(replica.status().resyncs, 1);
+    fs::remove_dir_all(primary_dir).unwrap();
+    fs::remove_dir_all(follower_dir).unwrap();
+}
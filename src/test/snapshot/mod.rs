@@ -0,0 +1,221 @@
+extern crate serde_json;
+extern crate rand;
+extern crate tar;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use self::serde_json::json;
+use self::rand::Rng;
+use self::rand::distributions::Alphanumeric;
+use super::super::api::{acl, API, FORMAT_VERSION, Operation};
+use super::super::api::acl::Acl;
+use super::super::api::changes::ChangeFeed;
+use super::super::api::snapshot;
+use super::super::server::http::core::{self, Body, Request};
+use super::super::RestApp;
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .collect::<String>()
+}
+
+fn request(method: &str, path: &str, query: &[(&str, &str)], body: &[u8]) -> Request {
+    Request {
+        method: String::from(method),
+        path: core::split_path(path),
+        query: query.iter().map(|(k, v)| (String::from(*k), String::from(*v))).collect(),
+        headers: HashMap::new(),
+        body: body.to_vec(),
+        client_subject: None,
+        spooled: None,
+    }
+}
+
+fn archive(app: &RestApp, query: &[(&str, &str)]) -> Vec<u8> {
+    let response = core::handle(app, request("GET", "/_snapshot", query, b""));
+    assert_eq!(response.status, 200);
+    match response.body {
+        Body::Stream(chunks) => chunks.flat_map(|chunk| chunk.unwrap()).collect(),
+        _ => panic!("expected a streamed archive")
+    }
+}
+
+#[test]
+fn test_snapshot_and_restore() {
+    let storage_dir = random_string(16) ;
+    let api = RestApp::new(storage_dir.clone());
+    let user = String::from("tilda");
+    let path = ["root".to_string(), "domain".to_string()].to_vec();
+    let json = json!({"name":"test_obj","conquests":["birth", {"event":"learned"}],"empty":{}});
+    assert!(api.put(["root".to_string()].to_vec(), json!({}), user.clone()).is_ok());
+    assert!(api.put(path.clone(), json.clone(), user.clone()).is_ok());
+
+    for gzip in [true, false].iter() {
+        let mut archive = Vec::new();
+        assert!(snapshot::snapshot(&api, path.clone(), &mut archive, *gzip, false).is_ok());
+
+        assert!(api.put(path.clone(), json!({"name":"changed","added":1}), user.clone()).is_ok());
+        let manifest = snapshot::restore(&api, path.clone(), archive.as_slice(), false);
+        assert_eq!(manifest.map(|m| (m.format_version, m.prefix)).ok(), Some((FORMAT_VERSION, path.clone())));
+        assert_eq!(api.get(path.clone(), user.clone()).ok(), Some(json.clone()));
+
+        let copy = ["root".to_string(), "copy".to_string()].to_vec();
+        assert!(snapshot::restore(&api, copy.clone(), archive.as_slice(), false).is_err());
+        assert!(api.get(copy, user.clone()).is_err());
+    }
+
+    fs::remove_dir_all(storage_dir).unwrap();
+}
+
+#[test]
+fn test_restore_rejects_bad_archive() {
+    let storage_dir = random_string(16) ;
+    let api = RestApp::new(storage_dir.clone());
+    let user = String::from("tilda");
+    let path = ["root".to_string()].to_vec();
+    assert!(api.put(path.clone(), json!({"name":"test_obj"}), user.clone()).is_ok());
+
+    assert!(snapshot::restore(&api, path.clone(), "not an archive".as_bytes(), false).is_err());
+    assert_eq!(api.get(path.clone(), user.clone()).ok(), Some(json!({"name":"test_obj"})));
+
+    let leftovers = fs::read_dir(".").unwrap()
+        .flat_map(|entry| entry.ok())
+        .flat_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| name.starts_with(&format!("{}.staging", storage_dir)))
+        .count();
+    assert_eq!(leftovers, 0);
+
+    fs::remove_dir_all(storage_dir).unwrap();
+}
+
+#[test]
+fn test_secrets_are_only_archived_when_asked_for() {
+    let storage_dir = random_string(16);
+    fs::create_dir_all(storage_dir.as_str()).unwrap();
+    let api = RestApp::new(storage_dir.clone()).with_admins(vec![String::from("anon")]);
+    let rules = Acl::from_value(json!({"rules": [{"prefix": "/", "principal": "*", "permissions": ["read"]}]})).unwrap();
+    assert!(api.put(vec![String::from("doc")], json!({"a": 1}), String::from("anon")).is_ok());
+    assert!(acl::store(&api, Some(&rules)).is_ok());
+
+    let without = archive(&api, &[]);
+    let with = archive(&api, &[("secrets", "true"), ("format", "tar")]);
+    assert!(acl::store(&api, None).is_ok());
+
+    assert_eq!(core::handle(&api, request("PUT", "/_snapshot", &[], without.as_slice())).status, 200);
+    assert_eq!(acl::load(&api).ok(), Some(None));
+    assert_eq!(api.get(vec![String::from("doc")], String::from("anon")).ok(), Some(json!({"a": 1})));
+    assert_eq!(core::handle(&api, request("PUT", "/_snapshot", &[], with.as_slice())).status, 200);
+    assert_eq!(acl::load(&api).ok(), Some(Some(rules)));
+
+    assert_eq!(core::handle(&api, request("PUT", "/_snapshot/doc", &[], with.as_slice())).status, 422);
+    let rule_admins = RestApp::new(storage_dir.clone());
+    assert_eq!(core::handle(&rule_admins, request("GET", "/_snapshot", &[("secrets", "true")], b"")).status, 403);
+    fs::remove_dir_all(storage_dir).unwrap();
+}
+
+/// An archive of `/doc` holding `entries` as they are, however unsafe.
+fn crafted(entries: &[(&[u8], tar::EntryType, &[u8])]) -> Vec<u8> {
+    let manifest = json!({"format_version": FORMAT_VERSION, "prefix": ["doc"], "created_at": 0}).to_string().into_bytes();
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    let mut builder = tar::Builder::new(Vec::new());
+    builder.append_data(&mut header, "manifest.json", manifest.as_slice()).unwrap();
+    for (name, kind, content) in entries {
+        let mut header = tar::Header::new_old();
+        header.as_old_mut().name[..name.len()].copy_from_slice(name);
+        header.set_entry_type(*kind);
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        if *kind != tar::EntryType::Regular {
+            header.set_link_name("/").unwrap();
+        }
+        header.set_cksum();
+        builder.append(&header, *content).unwrap();
+    }
+    builder.into_inner().unwrap()
+}
+
+#[test]
+fn test_restore_only_unpacks_files_and_directories_inside_the_archive() {
+    let storage_dir = random_string(16);
+    let escape = format!("{}-escape", storage_dir);
+    let api = RestApp::new(storage_dir.clone());
+    let user = String::from("tilda");
+    let doc = vec![String::from("doc")];
+    fs::create_dir_all(storage_dir.as_str()).unwrap();
+    assert!(api.put(doc.clone(), json!("before"), user.clone()).is_ok());
+
+    let field = b"STRING\nafter\n";
+    let outside = format!("data/../../{}", escape);
+    let refused = [
+        crafted(&[(b"data", tar::EntryType::Symlink, b"")]),
+        crafted(&[(b"data", tar::EntryType::Link, b"")]),
+        crafted(&[(b"data", tar::EntryType::Regular, field), (outside.as_bytes(), tar::EntryType::Regular, field)]),
+        crafted(&[(b"/data", tar::EntryType::Regular, field)]),
+    ];
+    for archive in refused.iter() {
+        assert!(snapshot::restore(&api, doc.clone(), archive.as_slice(), false).is_err());
+        assert_eq!(api.get(doc.clone(), user.clone()).ok(), Some(json!("before")));
+    }
+    assert!(!std::path::Path::new(escape.as_str()).exists());
+
+    let archive = crafted(&[(b"data", tar::EntryType::Regular, field)]);
+    assert!(snapshot::restore(&api, doc.clone(), archive.as_slice(), false).is_ok());
+    assert_eq!(api.get(doc.clone(), user.clone()).ok(), Some(json!("after")));
+    fs::remove_dir_all(storage_dir).unwrap();
+}
+
+#[test]
+fn test_restores_are_published() {
+    let storage_dir = random_string(16);
+    let api = RestApp::new(storage_dir.clone()).with_changes(ChangeFeed::new(100));
+    let user = String::from("tilda");
+    let path = |name: &str| vec![String::from(name)];
+    assert!(api.put(path("a"), json!({"x": 1}), user.clone()).is_ok());
+    assert!(api.put(path("b"), json!(2), user.clone()).is_ok());
+    let mut whole = Vec::new();
+    assert!(snapshot::snapshot(&api, Vec::new(), &mut whole, true, false).is_ok());
+    let mut part = Vec::new();
+    assert!(snapshot::snapshot(&api, path("a"), &mut part, true, false).is_ok());
+
+    assert!(api.put(path("a"), json!({"x": 3}), user.clone()).is_ok());
+    let feed = api.changes().unwrap();
+    let since = feed.last_seq();
+    assert!(snapshot::restore(&api, path("a"), part.as_slice(), false).is_ok());
+    let changes = feed.wait(since, Duration::from_secs(0), |_| true).unwrap().changes;
+    assert_eq!(changes.iter().map(|c| (c.operation, c.path.clone(), c.value.clone())).collect::<Vec<_>>(),
+        vec![(Operation::Write, String::from("/a"), json!({"x": 1}))]);
+
+    assert!(api.put(path("c"), json!(4), user.clone()).is_ok());
+    let since = feed.last_seq();
+    assert!(snapshot::restore(&api, Vec::new(), whole.as_slice(), false).is_ok());
+    let changes = feed.wait(since, Duration::from_secs(0), |_| true).unwrap().changes;
+    assert_eq!(changes.iter().map(|c| (c.operation, c.path.clone())).collect::<Vec<_>>(),
+        vec![(Operation::Write, String::from("/a")), (Operation::Write, String::from("/b")), (Operation::Delete, String::from("/c"))]);
+    fs::remove_dir_all(storage_dir).unwrap();
+}
+
+#[test]
+fn test_writes_go_on_while_a_snapshot_is_read_slowly() {
+    let storage_dir = random_string(16);
+    let api = RestApp::new(storage_dir.clone());
+    let user = String::from("tilda");
+    let big = (0..64).map(|_| random_string(1024)).collect::<Vec<String>>();
+    assert!(api.put(vec![String::from("big")], json!(big), user.clone()).is_ok());
+
+    let mut archive = snapshot::snapshot_stream(&api, Vec::new(), false, false).unwrap();
+    assert!(archive.next().is_some());
+    let (done, written) = mpsc::channel();
+    let writer = api.clone();
+    thread::spawn(move || done.send(writer.put(vec![String::from("doc")], json!(1), user).is_ok()).unwrap());
+    assert_eq!(written.recv_timeout(Duration::from_secs(10)).ok(), Some(true));
+    drop(archive);
+    fs::remove_dir_all(storage_dir).unwrap();
+}
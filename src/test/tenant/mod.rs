@@ -0,0 +1,96 @@
+extern crate serde_json;
+extern crate rand;
+use std::collections::HashMap;
+use std::fs;
+
+use self::serde_json::{json, Value};
+use self::rand::Rng;
+
+use super::super::api::API;
+use super::super::api::tenant::{self, Tenancy};
+use super::super::server::http::core::{self, Body, Request, Response};
+use super::super::RestApp;
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .gen_ascii_chars()
+        .take(len)
+        .collect::<String>()
+}
+
+fn request(method: &str, path: &[&str], tenant: &str, body: &[u8]) -> Request {
+    Request {
+        method: String::from(method),
+        path: path.iter().map(|p| String::from(*p)).collect(),
+        query: HashMap::new(),
+        headers: vec![(String::from("x-tenant"), String::from(tenant))].into_iter().collect(),
+        body: body.to_vec(),
+        client_subject: None,
+        spooled: None,
+    }
+}
+
+fn body(response: Response) -> Vec<u8> {
+    match response.body {
+        Body::Empty | Body::Upgrade(_) => Vec::new(),
+        Body::Bytes(bytes) => bytes,
+        Body::Stream(chunks) => chunks.flat_map(|chunk| chunk.unwrap()).collect(),
+        Body::Pending(pending) => core::wait_for(pending).flat_map(|chunk| chunk.unwrap()).collect(),
+    }
+}
+
+#[test]
+fn test_tenant_names() {
+    assert!(tenant::is_valid_name("team-a.prod_1"));
+    for name in ["", ".", "..", "_tenants", ".hidden", "a/b", "a\\b", "caf\u{e9}"].iter() {
+        assert!(!tenant::is_valid_name(name), "{:?} should be refused", name);
+    }
+    assert_eq!(Tenancy::from_config("header", "X-Team"), Some(Tenancy::PerHeader(String::from("x-team"))));
+    assert_eq!(Tenancy::from_config("shared", "x-tenant"), None);
+}
+
+#[test]
+fn test_tenants_are_isolated() {
+    let storage_dir = random_string(16);
+    let app = RestApp::new(storage_dir.clone())
+        .with_tenancy(Tenancy::PerHeader(String::from("x-tenant")))
+        .with_admins(vec![String::from("anon")]);
+
+    assert_eq!(core::handle(&app, request("PUT", &["_tenants", "red"], "", b"")).status, 201);
+    assert_eq!(core::handle(&app, request("PUT", &["_tenants", "blue"], "", b"")).status, 201);
+    assert_eq!(core::handle(&app, request("PUT", &["_tenants", "red"], "", b"")).status, 409);
+    assert_eq!(core::handle(&app, request("PUT", &["_tenants", ".."], "", b"")).status, 400);
+    let listed = body(core::handle(&app, request("GET", &["_tenants"], "", b"")));
+    assert_eq!(serde_json::from_slice::<Value>(listed.as_slice()).ok(), Some(json!(["blue", "red"])));
+
+    assert_eq!(core::handle(&app, request("PUT", &["doc"], "red", b"{\"color\":\"red\"}")).status, 200);
+    assert_eq!(core::handle(&app, request("GET", &["doc"], "blue", b"")).status, 404);
+    assert_eq!(core::handle(&app, request("GET", &["doc"], "green", b"")).status, 403);
+    assert_eq!(core::handle(&app, request("GET", &["..", "red", "doc"], "blue", b"")).status, 400);
+    assert_eq!(core::handle(&app, request("GET", &["_tenants", "red", "doc"], "blue", b"")).status, 404);
+    let red = body(core::handle(&app, request("GET", &[], "red", b"")));
+    assert_eq!(serde_json::from_slice::<Value>(red.as_slice()).ok(), Some(json!({"doc": {"color": "red"}})));
+
+    let export = String::from_utf8(body(core::handle(&app, request("GET", &["_tenants", "red"], "", b"")))).unwrap();
+    assert!(export.contains("\"color\"") && export.contains("\"red\""));
+
+    assert_eq!(core::handle(&app, request("DELETE", &["_tenants", "red"], "", b"")).status, 200);
+    assert_eq!(core::handle(&app, request("GET", &["doc"], "red", b"")).status, 403);
+    assert_eq!(tenant::list(&app).ok(), Some(vec![String::from("blue")]));
+    assert!(app.get(vec![String::from("..")], String::from("anon")).is_err());
+    fs::remove_dir_all(storage_dir).unwrap();
+}
+
+#[test]
+fn test_only_admins_manage_tenants() {
+    let storage_dir = random_string(16);
+    fs::create_dir_all(storage_dir.as_str()).unwrap();
+    let app = RestApp::new(storage_dir.clone())
+        .with_tenancy(Tenancy::PerHeader(String::from("x-tenant")))
+        .with_admins(vec![String::from("root")]);
+
+    assert_eq!(core::handle(&app, request("PUT", &["_tenants", "red"], "", b"")).status, 403);
+    assert_eq!(core::handle(&app, request("GET", &["_tenants"], "", b"")).status, 403);
+    assert_eq!(tenant::list(&app).ok(), Some(Vec::new()));
+    fs::remove_dir_all(storage_dir).unwrap();
+}
@@ -0,0 +1,199 @@
+extern crate openssl;
+extern crate rand;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use self::openssl::asn1::Asn1Time;
+use self::openssl::bn::BigNum;
+use self::openssl::ec::{EcGroup, EcKey};
+use self::openssl::hash::MessageDigest;
+use self::openssl::nid::Nid;
+use self::openssl::pkey::{PKey, Private};
+use self::openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use self::openssl::x509::extension::{BasicConstraints, SubjectAlternativeName};
+use self::openssl::x509::{X509, X509Name};
+use self::rand::Rng;
+use self::rand::distributions::Alphanumeric;
+
+use super::super::auth::Authenticator;
+use super::super::server::http::tls::{Acceptor, Settings};
+use super::super::server::http::{self, Engine};
+use super::super::RestApp;
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .collect::<String>()
+}
+
+fn key() -> PKey<Private> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+}
+
+/// A certificate for `name`, signed by `issuer` or by itself.
+fn certificate(name: &str, key: &PKey<Private>, issuer: Option<(&X509, &PKey<Private>)>) -> X509 {
+    let mut subject = X509Name::builder().unwrap();
+    subject.append_entry_by_nid(Nid::COMMONNAME, name).unwrap();
+    let subject = subject.build();
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_serial_number(&BigNum::from_u32(rand::thread_rng().gen::<u32>()).unwrap().to_asn1_integer().unwrap()).unwrap();
+    builder.set_subject_name(&subject).unwrap();
+    builder.set_issuer_name(issuer.map_or(&subject, |(cert, _)| cert.subject_name())).unwrap();
+    builder.set_pubkey(key).unwrap();
+    builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+    match issuer {
+        None => builder.append_extension(BasicConstraints::new().critical().ca().build().unwrap()).unwrap(),
+        Some((ca, _)) => {
+            let san = SubjectAlternativeName::new().dns(name).build(&builder.x509v3_context(Some(ca), None)).unwrap();
+            builder.append_extension(san).unwrap();
+        }
+    }
+    builder.sign(issuer.map_or(key, |(_, key)| key), MessageDigest::sha256()).unwrap();
+    builder.build()
+}
+
+struct Pki {
+    ca: X509,
+    ca_key: PKey<Private>,
+}
+
+impl Pki {
+    fn new() -> Pki {
+        let ca_key = key();
+        Pki { ca: certificate("test ca", &ca_key, None), ca_key }
+    }
+
+    fn issue(&self, name: &str) -> (X509, PKey<Private>) {
+        let key = key();
+        (certificate(name, &key, Some((&self.ca, &self.ca_key))), key)
+    }
+
+    /// Writes a certificate and key for `name` to `<dir>/server.crt` and
+    /// `<dir>/server.key`.
+    fn issue_server(&self, dir: &str, name: &str) {
+        let (cert, key) = self.issue(name);
+        fs::write(Path::new(dir).join("server.crt"), cert.to_pem().unwrap()).unwrap();
+        fs::write(Path::new(dir).join("server.key"), key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+    }
+}
+
+fn start(app: RestApp, acceptor: Acceptor) -> SocketAddr {
+    let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    thread::spawn(move || http::serve(Engine::Hyper, app, addr, Some(acceptor)));
+    for _ in 0..100 {
+        if TcpStream::connect(addr).is_ok() {
+            return addr;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("the TLS server did not start on {}", addr);
+}
+
+/// Sends `GET path` over TLS, trusting only `pki`'s authority, and returns
+/// the status and the common name of the certificate the server presented,
+/// or `None` when the handshake failed.
+fn get(addr: SocketAddr, pki: &Pki, client: Option<&(X509, PKey<Private>)>, path: &str) -> Option<(u16, String)> {
+    let mut connector = SslConnector::builder(SslMethod::tls_client()).unwrap();
+    connector.cert_store_mut().add_cert(pki.ca.clone()).unwrap();
+    connector.set_verify(SslVerifyMode::PEER);
+    if let Some((cert, key)) = client {
+        connector.set_certificate(cert).unwrap();
+        connector.set_private_key(key).unwrap();
+    }
+    let mut stream = connector.build().connect("localhost", TcpStream::connect(addr).unwrap()).ok()?;
+    let server = stream.ssl().peer_certificate().unwrap().subject_name()
+        .entries_by_nid(Nid::COMMONNAME).next().unwrap().data().to_string().unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path).ok()?;
+    let mut raw = Vec::new();
+    // TLS 1.3 reports a refused client certificate on the first read.
+    stream.read_to_end(&mut raw).ok()?;
+    let status = String::from_utf8_lossy(&raw).split(' ').nth(1)?.parse::<u16>().ok()?;
+    Some((status, server))
+}
+
+fn settings(dir: &str, require_client_cert: bool) -> Settings {
+    Settings {
+        cert_file: Path::new(dir).join("server.crt").to_string_lossy().into_owned(),
+        key_file: Path::new(dir).join("server.key").to_string_lossy().into_owned(),
+        client_ca_file: Some(Path::new(dir).join("ca.crt").to_string_lossy().into_owned()),
+        require_client_cert,
+    }
+}
+
+#[test]
+fn test_client_certificates_name_the_principal() {
+    let storage_dir = random_string(16);
+    fs::create_dir_all(storage_dir.as_str()).unwrap();
+    let pki = Pki::new();
+    pki.issue_server(storage_dir.as_str(), "localhost");
+    fs::write(Path::new(storage_dir.as_str()).join("ca.crt"), pki.ca.to_pem().unwrap()).unwrap();
+    let app = RestApp::new(storage_dir.clone())
+        .with_authenticator(Authenticator::new(HashMap::new(), HashMap::new()))
+        .with_admins(vec![String::from("alice")]);
+    let addr = start(app, Acceptor::new(settings(storage_dir.as_str(), false)).unwrap());
+
+    let alice = pki.issue("alice");
+    let bob = pki.issue("bob");
+    let stranger = {
+        let other = Pki::new();
+        other.issue("alice")
+    };
+    assert_eq!(get(addr, &pki, Some(&alice), "/_keys"), Some((200, String::from("localhost"))));
+    assert_eq!(get(addr, &pki, Some(&bob), "/_keys").map(|(status, _)| status), Some(403));
+    assert_eq!(get(addr, &pki, Some(&bob), "/doc").map(|(status, _)| status), Some(404));
+    assert_eq!(get(addr, &pki, None, "/doc").map(|(status, _)| status), Some(401));
+    assert_eq!(get(addr, &pki, Some(&stranger), "/doc"), None);
+
+    let app = RestApp::new(storage_dir.clone()).with_authenticator(Authenticator::new(HashMap::new(), HashMap::new()));
+    let addr = start(app, Acceptor::new(settings(storage_dir.as_str(), true)).unwrap());
+    assert_eq!(get(addr, &pki, Some(&bob), "/doc").map(|(status, _)| status), Some(404));
+    assert_eq!(get(addr, &pki, None, "/doc"), None);
+    fs::remove_dir_all(storage_dir).unwrap();
+}
+
+#[test]
+fn test_certificate_is_reloaded_when_its_files_change() {
+    let storage_dir = random_string(16);
+    fs::create_dir_all(storage_dir.as_str()).unwrap();
+    let pki = Pki::new();
+    pki.issue_server(storage_dir.as_str(), "localhost");
+    let settings = Settings { client_ca_file: None, ..settings(storage_dir.as_str(), false) };
+    let acceptor = Acceptor::new(settings.clone()).unwrap();
+    acceptor.watch().unwrap();
+    let addr = start(RestApp::new(storage_dir.clone()), acceptor);
+    let served = || get(addr, &pki, None, "/doc").map(|(_, name)| name);
+    assert_eq!(served(), Some(String::from("localhost")));
+
+    // A key that no longer matches the certificate is refused, and the
+    // certificate loaded before is kept.
+    fs::write(settings.key_file.as_str(), key().private_key_to_pem_pkcs8().unwrap()).unwrap();
+    assert!(Acceptor::new(settings.clone()).is_err());
+    thread::sleep(Duration::from_millis(1500));
+    assert_eq!(served(), Some(String::from("localhost")));
+
+    let (cert, key) = pki.issue("localhost");
+    let renewed = cert.serial_number().to_bn().unwrap();
+    fs::write(settings.cert_file.as_str(), cert.to_pem().unwrap()).unwrap();
+    fs::write(settings.key_file.as_str(), key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+    let started = Instant::now();
+    loop {
+        let mut connector = SslConnector::builder(SslMethod::tls_client()).unwrap();
+        connector.cert_store_mut().add_cert(pki.ca.clone()).unwrap();
+        let stream = connector.build().connect("localhost", TcpStream::connect(addr).unwrap()).unwrap();
+        if stream.ssl().peer_certificate().unwrap().serial_number().to_bn().unwrap() == renewed {
+            break;
+        }
+        assert!(started.elapsed() < Duration::from_secs(10), "the renewed certificate was not served");
+        thread::sleep(Duration::from_millis(50));
+    }
+    fs::remove_dir_all(storage_dir).unwrap();
+}
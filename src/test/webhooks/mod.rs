@@ -0,0 +1,234 @@
+extern crate rand;
+extern crate serde_json;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use self::rand::Rng;
+use self::serde_json::{json, Value};
+
+use super::super::api::{tenant, API, Operation};
+use super::super::api::changes::ChangeFeed;
+use super::super::api::webhooks::{self, Payload, Settings};
+use super::super::server::http::core::{self, Body, Request, Response};
+use super::super::RestApp;
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .gen_ascii_chars()
+        .take(len)
+        .collect::<String>()
+}
+
+/// What the stand-in receiver was sent: the headers, with lower case
+/// names, and the body.
+type Received = Arc<Mutex<Vec<(HashMap<String, String>, Vec<u8>)>>>;
+
+/// Listens on a free local port, answering the n-th request with the n-th
+/// of `statuses`, or the last of them once they run out.
+fn receiver(statuses: Vec<u16>) -> (String, Received) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let received: Received = Arc::new(Mutex::new(Vec::new()));
+    let log = received.clone();
+    thread::spawn(move || for (n, stream) in listener.incoming().enumerate() {
+        let mut stream = stream.unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut headers = HashMap::new();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        loop {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            match line.trim().split_once(':') {
+                Some((name, value)) => headers.insert(name.trim().to_lowercase(), String::from(value.trim())),
+                None => break
+            };
+        }
+        let mut body = vec![0u8; headers.get("content-length").map_or(0, |length| usize::from_str(length).unwrap())];
+        reader.read_exact(&mut body).unwrap();
+        log.lock().unwrap().push((headers, body));
+        let status = statuses.get(n).or(statuses.last()).cloned().unwrap_or(200);
+        write!(stream, "HTTP/1.1 {} Whatever\r\ncontent-length: 0\r\nconnection: close\r\n\r\n", status).unwrap();
+    });
+    (url, received)
+}
+
+fn wait_for<F: Fn() -> bool>(done: F) {
+    let started = Instant::now();
+    while !done() {
+        assert!(started.elapsed() < Duration::from_secs(10), "timed out");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn request(method: &str, path: &str, body: &[u8]) -> Request {
+    Request {
+        method: String::from(method),
+        path: core::split_path(path),
+        query: HashMap::new(),
+        headers: HashMap::new(),
+        body: body.to_vec(),
+        client_subject: None,
+        spooled: None,
+    }
+}
+
+fn json_body(response: Response) -> Value {
+    match response.body {
+        Body::Bytes(bytes) => serde_json::from_slice(bytes.as_slice()).unwrap(),
+        _ => Value::Null
+    }
+}
+
+fn app(storage_dir: &str) -> RestApp {
+    RestApp::new(String::from(storage_dir))
+        .with_changes(ChangeFeed::open(storage_dir, 100).unwrap())
+        .with_admins(vec![String::from("anon")])
+}
+
+fn settings(max_attempts: u32) -> Settings {
+    Settings { max_attempts, backoff: Duration::from_millis(20), timeout: Duration::from_secs(5) }
+}
+
+#[test]
+fn test_deliveries_are_signed_and_retried() {
+    let storage_dir = random_string(16);
+    let app = app(storage_dir.as_str());
+    let (url, received) = receiver(vec![500, 200]);
+    let new = json!({"prefix": "/orders", "url": url, "secret": "s3cret"}).to_string();
+    let created = core::handle(&app, request("POST", "/_webhooks", new.as_bytes()));
+    assert_eq!(created.status, 201);
+    let created = json_body(created);
+    assert!(created.get("secret").is_none());
+    assert_eq!(created["events"], json!(["write", "delete"]));
+    let ftp = json!({"url": "ftp://example.com", "secret": "s"}).to_string();
+    assert_eq!(core::handle(&app, request("POST", "/_webhooks", ftp.as_bytes())).status, 400);
+    let listed = json_body(core::handle(&app, request("GET", "/_webhooks", b"")));
+    assert_eq!(listed.as_array().map(Vec::len), Some(1));
+    assert!(!app.is_visible(&std::path::Path::new(storage_dir.as_str()).join(webhooks::WEBHOOKS_PATH)));
+    assert_eq!(core::handle(&RestApp::new(storage_dir.clone()), request("GET", "/_webhooks", b"")).status, 403);
+
+    webhooks::spawn(app.clone(), settings(3)).unwrap();
+    app.put(core::split_path("/other"), json!({"skipped": true}), String::from("anon")).unwrap();
+    app.put(core::split_path("/orders/1"), json!({"total": 5}), String::from("anon")).unwrap();
+    wait_for(|| received.lock().unwrap().len() == 2);
+
+    let deliveries = received.lock().unwrap().clone();
+    let (ref headers, ref body) = deliveries[1];
+    assert_eq!(headers.get(webhooks::SIGNATURE_HEADER), Some(&webhooks::signature("s3cret", body.as_slice())));
+    assert_eq!(headers.get(webhooks::EVENT_HEADER).map(String::as_str), Some("write"));
+    assert_eq!(deliveries[0].0.get(webhooks::DELIVERY_HEADER), headers.get(webhooks::DELIVERY_HEADER));
+    let payload: Payload = serde_json::from_slice(body.as_slice()).unwrap();
+    assert_eq!((payload.event, payload.path.as_str(), payload.value), (Operation::Write, "/orders/1", json!({"total": 5})));
+    assert_eq!(payload.webhook, created["id"].as_str().unwrap());
+
+    app.delete(core::split_path("/orders/1"), String::from("anon")).unwrap();
+    wait_for(|| received.lock().unwrap().len() == 3);
+    let deleted: Payload = serde_json::from_slice(received.lock().unwrap()[2].1.as_slice()).unwrap();
+    assert_eq!((deleted.event, deleted.value), (Operation::Delete, Value::Null));
+    fs::remove_dir_all(storage_dir).unwrap();
+}
+
+#[test]
+fn test_failed_deliveries_become_dead_letters() {
+    let storage_dir = random_string(16);
+    let app = app(storage_dir.as_str());
+    let (url, received) = receiver(vec![503]);
+    let new = json!({"url": url, "secret": "s3cret", "events": ["delete"]}).to_string();
+    assert_eq!(core::handle(&app, request("POST", "/_webhooks", new.as_bytes())).status, 201);
+
+    webhooks::spawn(app.clone(), settings(2)).unwrap();
+    app.put(core::split_path("/doc"), json!({"a": 1}), String::from("anon")).unwrap();
+    app.delete(core::split_path("/doc"), String::from("anon")).unwrap();
+    wait_for(|| webhooks::dead_letters(&app).map(|letters| letters.len()).unwrap_or(0) == 1);
+    assert_eq!(received.lock().unwrap().len(), 2);
+
+    let letters = json_body(core::handle(&app, request("GET", "/_dead_letters", b"")));
+    assert_eq!(letters[0]["attempts"], json!(2));
+    assert_eq!(letters[0]["error"], json!("the receiver answered 503"));
+    assert_eq!(letters[0]["payload"]["event"], json!("delete"));
+    let id = letters[0]["id"].as_str().unwrap().to_string();
+    let path = format!("/_dead_letters/{}", id);
+    assert_eq!(core::handle(&app, request("GET", path.as_str(), b"")).status, 200);
+    assert_eq!(core::handle(&RestApp::new(storage_dir.clone()), request("GET", path.as_str(), b"")).status, 403);
+    assert_eq!(core::handle(&app, request("DELETE", path.as_str(), b"")).status, 200);
+    assert_eq!(core::handle(&app, request("GET", path.as_str(), b"")).status, 404);
+    fs::remove_dir_all(storage_dir).unwrap();
+}
+
+#[test]
+fn test_unfinished_deliveries_are_resumed() {
+    let storage_dir = random_string(16);
+    let app = app(storage_dir.as_str());
+    let (url, received) = receiver(vec![200]);
+    let left = json!({
+        "attempts": 1,
+        "webhook": {"id": "abcdefghijkl", "prefix": "/", "url": url, "events": ["write"], "created_at": 0, "secret": "s3cret"},
+        "payload": {"delivery": "mnopqrstuvwx", "webhook": "abcdefghijkl", "event": "write", "seq": 1, "path": "/doc",
+            "value": {"a": 1}, "timestamp": 0},
+    });
+    let deliveries = app.root_path().join(webhooks::DELIVERIES_PATH);
+    app.ensure_path(deliveries.clone()).unwrap();
+    app.write_path(deliveries.join("mnopqrstuvwx"), left, String::new()).unwrap();
+    assert_eq!(core::handle(&RestApp::new(storage_dir.clone()), request("GET", "/_deliveries", b"")).status, 403);
+
+    webhooks::spawn(app.clone(), settings(3)).unwrap();
+    wait_for(|| received.lock().unwrap().len() == 1);
+    let (ref headers, ref body) = received.lock().unwrap()[0];
+    assert_eq!(headers.get(webhooks::SIGNATURE_HEADER), Some(&webhooks::signature("s3cret", body.as_slice())));
+    wait_for(|| !std::path::Path::new(storage_dir.as_str()).join(webhooks::DELIVERIES_PATH).join("mnopqrstuvwx").exists());
+    fs::remove_dir_all(storage_dir).unwrap();
+}
+
+#[test]
+fn test_a_slow_receiver_only_holds_up_its_own_deliveries() {
+    let storage_dir = random_string(16);
+    let app = app(storage_dir.as_str());
+    let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+    let slow = json!({"url": format!("http://{}/hook", silent.local_addr().unwrap()), "secret": "s"}).to_string();
+    assert_eq!(core::handle(&app, request("POST", "/_webhooks", slow.as_bytes())).status, 201);
+    let (url, received) = receiver(vec![200]);
+    let fast = json!({"url": url, "secret": "s"}).to_string();
+    assert_eq!(core::handle(&app, request("POST", "/_webhooks", fast.as_bytes())).status, 201);
+
+    webhooks::spawn(app.clone(), Settings { timeout: Duration::from_secs(30), ..settings(1) }).unwrap();
+    let started = Instant::now();
+    app.put(core::split_path("/doc"), json!({"a": 1}), String::from("anon")).unwrap();
+    app.put(core::split_path("/doc"), json!({"a": 2}), String::from("anon")).unwrap();
+    wait_for(|| received.lock().unwrap().len() == 2);
+    assert!(started.elapsed() < Duration::from_secs(10));
+    drop(silent);
+    wait_for(|| webhooks::dead_letters(&app).map(|letters| letters.len()).unwrap_or(0) == 2);
+    fs::remove_dir_all(storage_dir).unwrap();
+}
+
+#[test]
+fn test_webhooks_are_sent_the_changes_of_their_tenant() {
+    let storage_dir = random_string(16);
+    let app = app(storage_dir.as_str());
+    let (url, received) = receiver(vec![200]);
+    let new = json!({"tenant": "red", "url": url, "secret": "s3cret"}).to_string();
+    assert_eq!(core::handle(&app, request("POST", "/_webhooks", new.as_bytes())).status, 201);
+    let invalid = json!({"tenant": "_tenants", "url": url, "secret": "s3cret"}).to_string();
+    assert_eq!(core::handle(&app, request("POST", "/_webhooks", invalid.as_bytes())).status, 400);
+    tenant::create(&app, "red").unwrap();
+    tenant::create(&app, "blue").unwrap();
+
+    webhooks::spawn(app.clone(), settings(3)).unwrap();
+    app.put(core::split_path("/doc"), json!(0), String::from("anon")).unwrap();
+    app.for_tenant("blue").put(core::split_path("/doc"), json!(1), String::from("anon")).unwrap();
+    app.for_tenant("red").put(core::split_path("/doc"), json!(2), String::from("anon")).unwrap();
+    wait_for(|| received.lock().unwrap().len() == 1);
+    thread::sleep(Duration::from_millis(100));
+    let deliveries = received.lock().unwrap().clone();
+    assert_eq!(deliveries.len(), 1);
+    let payload: Payload = serde_json::from_slice(deliveries[0].1.as_slice()).unwrap();
+    assert_eq!((payload.tenant.as_deref(), payload.path.as_str(), payload.value), (Some("red"), "/doc", json!(2)));
+    fs::remove_dir_all(storage_dir).unwrap();
+}
@@ -0,0 +1,200 @@
+extern crate rand;
+extern crate serde_json;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+
+use self::rand::Rng;
+use self::rand::distributions::Alphanumeric;
+use self::serde_json::{json, Value};
+
+use super::super::api::API;
+use super::super::api::acl::{self, Acl};
+use super::super::api::changes::ChangeFeed;
+use super::super::server::http::core::{self, Body, Request};
+use super::super::server::http::websocket::{self, Frame};
+use super::super::RestApp;
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .collect::<String>()
+}
+
+/// One end of an in-memory connection.
+struct Pipe {
+    receiver: Option<Receiver<Vec<u8>>>,
+    sender: Option<SyncSender<Vec<u8>>>,
+    chunk: io::Cursor<Vec<u8>>,
+}
+
+impl Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.position() as usize == self.chunk.get_ref().len() {
+            match self.receiver.as_ref().and_then(|receiver| receiver.recv().ok()) {
+                Some(chunk) => self.chunk = io::Cursor::new(chunk),
+                None => return Ok(0)
+            }
+        }
+        self.chunk.read(buf)
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sender.as_ref()
+            .and_then(|sender| sender.send(buf.to_vec()).ok())
+            .map(|()| buf.len())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn pipe(capacity: usize) -> (Pipe, Pipe) {
+    let (sender, receiver) = mpsc::sync_channel(capacity);
+    let reader = Pipe { receiver: Some(receiver), sender: None, chunk: io::Cursor::new(Vec::new()) };
+    (reader, Pipe { receiver: None, sender: Some(sender), chunk: io::Cursor::new(Vec::new()) })
+}
+
+/// The client's side of a subscription: frames to the server are masked,
+/// frames from it are not.
+struct Client {
+    to_server: Pipe,
+    from_server: Pipe,
+}
+
+impl Client {
+    fn send(&mut self, opcode: u8, payload: &[u8]) {
+        self.to_server.write_all(websocket::encode_frame(opcode, payload, Some([7, 1, 2, 3])).as_slice()).unwrap();
+    }
+
+    fn frame(&mut self) -> Frame {
+        let mut head = [0u8; 2];
+        self.from_server.read_exact(&mut head).unwrap();
+        assert_eq!(head[1] & 0x80, 0);
+        let length = match head[1] {
+            126 => {
+                let mut length = [0u8; 2];
+                self.from_server.read_exact(&mut length).unwrap();
+                u16::from_be_bytes(length) as usize
+            }
+            n => n as usize
+        };
+        let mut payload = vec![0u8; length];
+        self.from_server.read_exact(&mut payload).unwrap();
+        Frame { opcode: head[0] & 0x0f, payload }
+    }
+
+    fn message(&mut self) -> Value {
+        let frame = self.frame();
+        assert_eq!(frame.opcode, websocket::OP_TEXT);
+        serde_json::from_slice(frame.payload.as_slice()).unwrap()
+    }
+}
+
+fn handshake(path: &str) -> Request {
+    Request {
+        method: String::from("GET"),
+        path: core::split_path(path),
+        query: core::parse_query(None),
+        headers: vec![("upgrade", "websocket"), ("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")].into_iter()
+            .map(|(name, value)| (String::from(name), String::from(value)))
+            .collect(),
+        body: Vec::new(),
+        client_subject: None,
+        spooled: None,
+    }
+}
+
+fn connect(app: &RestApp, path: &str, capacity: usize) -> Client {
+    let response = core::handle(app, handshake(path));
+    assert_eq!(response.status, 101);
+    assert!(response.headers.contains(&("sec-websocket-accept", String::from("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="))));
+    let session = match response.body {
+        Body::Upgrade(session) => session,
+        _ => panic!("expected an upgrade")
+    };
+    let (server_input, to_server) = pipe(16);
+    let (from_server, server_output) = pipe(capacity);
+    thread::spawn(move || session(Box::new(server_input), Box::new(server_output)));
+    Client { to_server, from_server }
+}
+
+fn path(p: &str) -> Vec<String> {
+    core::split_path(p)
+}
+
+#[test]
+fn test_frames_round_trip() {
+    assert_eq!(websocket::accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    let payload = vec![b'x'; 300];
+    let encoded = websocket::encode_frame(websocket::OP_TEXT, payload.as_slice(), Some([1, 2, 3, 4]));
+    let frame = websocket::read_frame(&mut encoded.as_slice()).unwrap();
+    assert_eq!(frame, Frame { opcode: websocket::OP_TEXT, payload });
+    let unmasked = websocket::encode_frame(websocket::OP_TEXT, b"hi", None);
+    assert!(websocket::read_frame(&mut unmasked.as_slice()).is_err());
+}
+
+#[test]
+fn test_subscribers_get_the_value_then_changes() {
+    let storage_dir = random_string(16);
+    let app = RestApp::new(storage_dir.clone()).with_changes(ChangeFeed::open(storage_dir.as_str(), 1000).unwrap());
+    let mut request = handshake("/_subscribe/a");
+    request.headers.remove("upgrade");
+    assert_eq!(core::handle(&app, request).status, 426);
+    app.put(path("/a"), json!({"doc": {"n": 1}}), String::from("anon")).unwrap();
+
+    let mut client = connect(&app, "/_subscribe/a", 16);
+    assert_eq!(client.message(), json!({"type": "value", "path": "/a", "value": {"doc": {"n": 1}}}));
+    app.put(path("/b"), json!(true), String::from("anon")).unwrap();
+    app.put(path("/a/doc"), json!({"n": 2}), String::from("anon")).unwrap();
+    let change = client.message();
+    assert_eq!((change["type"].clone(), change["seq"].clone()), (json!("change"), json!(3)));
+    assert_eq!((change["path"].clone(), change["value"].clone()), (json!("/a/doc"), json!({"n": 2})));
+
+    client.send(websocket::OP_TEXT, b"{\"subscribe\": \"/b\"}");
+    assert_eq!(client.message(), json!({"type": "value", "path": "/b", "value": true}));
+    client.send(websocket::OP_TEXT, b"{\"unsubscribe\": \"/a\"}");
+    client.send(websocket::OP_PING, b"still there?");
+    assert_eq!(client.frame(), Frame { opcode: websocket::OP_PONG, payload: b"still there?".to_vec() });
+    app.put(path("/a/doc"), json!({"n": 3}), String::from("anon")).unwrap();
+    app.delete(path("/b"), String::from("anon")).unwrap();
+    let change = client.message();
+    assert_eq!((change["operation"].clone(), change["path"].clone()), (json!("delete"), json!("/b")));
+
+    client.send(websocket::OP_TEXT, b"nonsense");
+    assert_eq!(client.message()["type"], json!("error"));
+    client.send(websocket::OP_CLOSE, &websocket::CLOSE_NORMAL.to_be_bytes());
+    assert_eq!(client.frame(), Frame { opcode: websocket::OP_CLOSE, payload: websocket::CLOSE_NORMAL.to_be_bytes().to_vec() });
+    fs::remove_dir_all(storage_dir).unwrap();
+}
+
+#[test]
+fn test_subscriptions_are_authorized_and_bounded() {
+    let storage_dir = random_string(16);
+    let app = RestApp::new(storage_dir.clone()).with_changes(ChangeFeed::open(storage_dir.as_str(), 1000).unwrap());
+    let rules = Acl {
+        rules: vec![acl::Rule { prefix: String::from("/open"), principal: String::from("*"), permissions: vec![acl::Permission::Read, acl::Permission::Write] }],
+        groups: Default::default(),
+    };
+    acl::store(&app, Some(&rules)).unwrap();
+    assert_eq!(core::handle(&app, handshake("/_subscribe/closed")).status, 403);
+
+    // The client reads nothing until the server has given up on it.
+    let mut client = connect(&app, "/_subscribe/open", 0);
+    (0..websocket::BUFFER + 50).for_each(|n| app.put(path("/open/doc"), json!({"n": n}), String::from("anon")).unwrap());
+    let mut last = client.message();
+    assert_eq!(last["type"], json!("value"));
+    while last["type"] != json!("overflow") {
+        last = client.message();
+    }
+    let close = client.frame();
+    assert_eq!(close.opcode, websocket::OP_CLOSE);
+    assert_eq!(close.payload[..2].to_vec(), websocket::CLOSE_OVERFLOW.to_be_bytes().to_vec());
+    fs::remove_dir_all(storage_dir).unwrap();
+}